@@ -17,19 +17,126 @@ use cc::Build;
 //     }
 // }
 
+/// Features reaching for peripherals or instructions the host simulator does not have
+const SIM_UNSUPPORTED: &[&str] = &[
+    "callback-budget",
+    "cpu-usage",
+    "crash-handler",
+    "kernel-stats",
+    "mpu-isolation",
+    "mpu-stack-guard",
+    "systick-handler",
+    "test_harness",
+    "tickless",
+    "trace-systemview",
+    "trustzone",
+];
+
+/// Features keeping state for one core only, or which RP2040's Cortex-M0+ lacks
+const AMP_UNSUPPORTED: &[&str] = &[
+    "critical-section",
+    "mpu-isolation",
+    "mpu-stack-guard",
+    "rtos-awareness",
+    "sim",
+    "test_harness",
+    "tickless",
+    "trace-ram",
+    "trace-systemview",
+];
+
 fn main() -> Result<(), Box<Error>> {
     let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
     println!("cargo:rustc-link-search={}", out_dir.display());
 
     let target: String = env::var("TARGET").unwrap();
+    // Cortex-M4F: PendSV also saves and restores the FPU registers of threads using them
+    let fpu = env::var_os("CARGO_FEATURE_CORTEX_M4F").is_some();
+    if fpu && !target.starts_with("thumbv7em-") {
+        panic!(
+            "the cortex-m4f feature needs a thumbv7em target, not {}",
+            target
+        );
+    }
     let asm_file: Option<String> = match target.as_str() {
         "thumbv6m-none-eabi" => Some("thumbv6m-none-eabi.s".to_string()),
         "thumbv7m-none-eabi" => Some("thumbv6m-none-eabi.s".to_string()),
+        "thumbv7em-none-eabi" | "thumbv7em-none-eabihf" if fpu => {
+            Some("thumbv7em-none-eabihf.s".to_string())
+        }
         "thumbv7em-none-eabi" => Some("thumbv7em-none-eabi.s".to_string()),
+        "thumbv7em-none-eabihf" => {
+            println!("cargo:warning=FPU registers are not saved on thread switches, enable the cortex-m4f feature");
+            Some("thumbv7em-none-eabi.s".to_string())
+        }
+        "thumbv8m.base-none-eabi" => Some("thumbv8m.base-none-eabi.s".to_string()),
+        "thumbv8m.main-none-eabi" => Some("thumbv8m.main-none-eabi.s".to_string()),
+        "thumbv8m.main-none-eabihf" => {
+            println!("cargo:warning=FPU registers are not saved on thread switches");
+            Some("thumbv8m.main-none-eabi.s".to_string())
+        }
         _ => None,
     };
+    // ARMv6-M (Cortex-M0/M0+) lacks some debug and system features, e.g. the DWT cycle counter,
+    // and so does ARMv8-M baseline (Cortex-M23)
+    println!("cargo:rustc-check-cfg=cfg(armv6m)");
+    if target.as_str() == "thumbv6m-none-eabi" || target.as_str() == "thumbv8m.base-none-eabi" {
+        println!("cargo:rustc-cfg=armv6m");
+    }
+    // ARMv8-M mainline (Cortex-M33) checks thread stacks against PSPLIM/MSPLIM in hardware
+    println!("cargo:rustc-check-cfg=cfg(armv8m)");
+    if target.starts_with("thumbv8m.main-") {
+        println!("cargo:rustc-cfg=armv8m");
+    }
+    if !target.starts_with("thumbv8m.main-") && env::var_os("CARGO_FEATURE_TRUSTZONE").is_some() {
+        panic!(
+            "the trustzone feature needs a thumbv8m.main target, not {}",
+            target
+        );
+    }
+    if target.starts_with("thumbv8m.") && env::var_os("CARGO_FEATURE_MPU_STACK_GUARD").is_some() {
+        panic!("the mpu-stack-guard feature supports the ARMv7-M MPU only, not ARMv8-M");
+    }
+    if target.starts_with("thumbv8m.") && env::var_os("CARGO_FEATURE_MPU_ISOLATION").is_some() {
+        panic!("the mpu-isolation feature supports the ARMv7-M MPU only, not ARMv8-M");
+    }
+    // the simulator replaces the assembly, and the hardware it stands in for
+    if env::var_os("CARGO_FEATURE_SIM").is_some() {
+        if asm_file.is_some() {
+            panic!("the sim feature is for host targets, not {}", target);
+        }
+        for feature in SIM_UNSUPPORTED {
+            let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+            if env::var_os(var).is_some() {
+                panic!(
+                    "the {} feature needs the hardware, it cannot be used with sim",
+                    feature
+                );
+            }
+        }
+    }
+    // each RP2040 core runs a kernel, PendSV picks the state of its core
+    let amp = env::var_os("CARGO_FEATURE_RP2040_AMP").is_some();
+    if amp {
+        if target != "thumbv6m-none-eabi" {
+            panic!(
+                "the rp2040-amp feature needs the thumbv6m-none-eabi target, not {}",
+                target
+            );
+        }
+        for feature in AMP_UNSUPPORTED {
+            let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+            if env::var_os(var).is_some() {
+                panic!("the {} feature cannot be used with rp2040-amp", feature);
+            }
+        }
+    }
     if let Some(ref file) = asm_file {
-        Build::new().file(file).compile("asm");
+        let mut build = Build::new();
+        if amp {
+            build.flag("-Wa,--defsym,RP2040_AMP=1");
+        }
+        build.file(file).compile("asm");
     } else {
         // return Result::Err(Box::new(
         // 	TargetArchError(format!("Unsupported target {}", target).into())));
@@ -38,6 +145,9 @@ fn main() -> Result<(), Box<Error>> {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=thumbv6m-none-eabi.s");
     println!("cargo:rerun-if-changed=thumbv7em-none-eabi");
+    println!("cargo:rerun-if-changed=thumbv7em-none-eabihf.s");
+    println!("cargo:rerun-if-changed=thumbv8m.base-none-eabi.s");
+    println!("cargo:rerun-if-changed=thumbv8m.main-none-eabi.s");
 
     Ok(())
 }
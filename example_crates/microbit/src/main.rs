@@ -4,11 +4,7 @@
 extern crate panic_semihosting;
 use cortex_m_rt::entry;
 use cortex_m_semihosting::hprintln;
-use cortexm_threads::{create_thread, init};
-
-extern "C" {
-    fn SysTick();
-}
+use cortexm_threads::{create_thread, init, yield_now, Stack};
 
 #[entry]
 fn main() -> ! {
@@ -19,10 +15,10 @@ fn main() -> ! {
         while p.CLOCK.events_lfclkstarted.read().bits() == 0 {}
         p.CLOCK.events_lfclkstarted.write(|w| unsafe { w.bits(0) });
     }
-    let mut stack1 = [0xDEADBEEF; 256];
-    let mut stack2 = [0xDEADBEEF; 256];
-    let _ = create_thread(&mut stack1, user_task1);
-    let _ = create_thread(&mut stack2, user_task2);
+    static STACK1: Stack<256> = Stack::new();
+    static STACK2: Stack<256> = Stack::new();
+    let _ = create_thread(STACK1.take().unwrap(), user_task1);
+    let _ = create_thread(STACK2.take().unwrap(), user_task2);
     init();
 }
 
@@ -33,9 +29,7 @@ pub fn user_task1() -> ! {
         for _i in 1..50000 {
             cortex_m::asm::nop();
         }
-        unsafe {
-            SysTick();
-        }
+        yield_now();
     }
 }
 
@@ -46,8 +40,6 @@ pub fn user_task2() -> ! {
         for _i in 1..50000 {
             cortex_m::asm::nop();
         }
-        unsafe {
-            SysTick();
-        }
+        yield_now();
     }
 }
@@ -5,7 +5,7 @@ extern crate panic_semihosting;
 use cortex_m::peripheral::syst::SystClkSource;
 use cortex_m_rt::entry;
 use cortex_m_semihosting::hprintln;
-use cortexm_threads::{create_thread, create_thread_with_config, init, sleep};
+use cortexm_threads::{create_thread, create_thread_with_config, init, sleep, Stack};
 
 #[entry]
 fn main() -> ! {
@@ -16,14 +16,14 @@ fn main() -> ! {
     syst.enable_counter();
     syst.enable_interrupt();
 
-    let mut stack1 = [0xDEADBEEF; 512];
-    let mut stack2 = [0xDEADBEEF; 512];
-    let _ = create_thread(&mut stack1, || loop {
+    static STACK1: Stack<512> = Stack::new();
+    static STACK2: Stack<512> = Stack::new();
+    let _ = create_thread(STACK1.take().unwrap(), || loop {
         let _ = hprintln!("in user task 1 !!");
         sleep(50);
     });
     let _ = create_thread_with_config(
-        &mut stack2,
+        STACK2.take().unwrap(),
         || loop {
             let _ = hprintln!("in user task 2 !!");
             sleep(30);
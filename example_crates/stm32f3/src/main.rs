@@ -13,7 +13,7 @@ use f3::{
     Lsm303dlhc,
 };
 
-use cortexm_threads::{create_thread_with_config, init, sleep};
+use cortexm_threads::{create_thread_with_config, init, sleep, Stack};
 
 static mut LEDS: Option<Leds> = None;
 static mut SENSOR: Option<Lsm303dlhc> = None;
@@ -51,10 +51,10 @@ fn main() -> ! {
     syst.enable_counter();
     syst.enable_interrupt();
 
-    let mut stack1 = [0xDEADBEEF; 1024];
-    let mut stack2 = [0xDEADBEEF; 1024];
-    let _ = create_thread_with_config(&mut stack1, user_task_1, 0xff, true);
-    let _ = create_thread_with_config(&mut stack2, user_task_2, 0x00, false);
+    static STACK1: Stack<1024> = Stack::new();
+    static STACK2: Stack<1024> = Stack::new();
+    let _ = create_thread_with_config(STACK1.take().unwrap(), user_task_1, 0xff, true);
+    let _ = create_thread_with_config(STACK2.take().unwrap(), user_task_2, 0x00, false);
     init();
 }
 
@@ -0,0 +1,134 @@
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#ifndef CORTEXM_THREADS_H
+#define CORTEXM_THREADS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Timeout of the blocking calls waiting until they succeed
+#define CMT_WAIT_FOREVER UINT32_MAX
+
+#define CMT_ERR_TOO_MANY_THREADS -1
+
+#define CMT_ERR_STACK_TOO_SMALL -2
+
+#define CMT_ERR_NO_CREATE_PRIV -3
+
+#define CMT_ERR_NO_SUCH_THREAD -4
+
+#define CMT_ERR_TIMED_OUT -5
+
+#define CMT_ERR_QUEUE_FULL -6
+
+#define CMT_ERR_STACK_IN_USE -9
+
+#define CMT_ERR_BAD_REGION -10
+
+#define CMT_ERR_NOT_HOLDER -11
+
+// Storage of a `Mutex<()>`, opaque to C
+typedef struct cmt_mutex {
+  size_t _opaque[4];
+} cmt_mutex_t;
+
+// Storage of a queue, opaque to C
+typedef struct cmt_queue {
+  size_t _opaque[10];
+} cmt_queue_t;
+
+// Create a thread running `entry(arg)` on the `words` u32's at `stack`, with priority
+// `priority` (higher runs first). The thread exits when `entry` returns. Returns the id
+// of the thread.
+//
+// # Safety
+// `stack` must point to `words` u32's used by nothing else for as long as the thread runs.
+int32_t cmt_thread_create(uint32_t *stack,
+                          size_t words,
+                          void (*entry)(void*),
+                          void *arg,
+                          uint8_t priority,
+                          bool privileged);
+
+// Start the scheduler, see `init`
+void cmt_init(void) __attribute__((noreturn));
+
+// Id of the calling thread
+int32_t cmt_thread_self(void);
+
+// Exit the calling thread, see `exit`
+void cmt_exit(void) __attribute__((noreturn));
+
+// Sleep for `ticks` ticks, see `sleep`
+void cmt_sleep(uint32_t ticks);
+
+// Let threads of the same priority run, see `yield_now`
+void cmt_yield(void);
+
+// Wake thread `thread` if it sleeps or is blocked, see `wake`
+int32_t cmt_wake(int32_t thread);
+
+// Block until thread `thread` exits, for at most `timeout` ticks, see `join`
+int32_t cmt_join(int32_t thread, uint32_t timeout);
+
+// Number of ticks since `cmt_init`
+uint64_t cmt_ticks(void);
+
+// Make `mutex` an unlocked mutex
+//
+// # Safety
+// `mutex` must be valid for writes, and not be in use by a thread
+void cmt_mutex_init(cmt_mutex_t *mutex);
+
+// Lock `mutex`, blocking for at most `timeout` ticks while another thread holds it. Returns
+// 0 once locked, `CMT_ERR_TIMED_OUT` if it was not unlocked in time.
+//
+// # Safety
+// `mutex` must have been initialized with `cmt_mutex_init`
+int32_t cmt_mutex_lock(cmt_mutex_t *mutex, uint32_t timeout);
+
+// Unlock `mutex`, locked by the calling thread with `cmt_mutex_lock`. Returns 0 once
+// unlocked, `CMT_ERR_NOT_HOLDER` if the calling thread does not hold it.
+//
+// # Safety
+// `mutex` must have been initialized with `cmt_mutex_init`
+int32_t cmt_mutex_unlock(cmt_mutex_t *mutex);
+
+// Make `queue` an empty queue of up to `capacity` items of `item_size` bytes, stored in
+// `buffer`
+//
+// # Safety
+// `queue` must be valid for writes, and not be in use by a thread. `buffer` must hold
+// `capacity * item_size` bytes used by nothing else for as long as the queue is used.
+void cmt_queue_init(cmt_queue_t *queue,
+                    void *buffer,
+                    size_t item_size,
+                    size_t capacity);
+
+// Copy the item at `item` to the back of `queue`, blocking for at most `timeout` ticks while
+// it is full, and wake the highest priority thread waiting to receive. Returns 0 once sent,
+// `CMT_ERR_QUEUE_FULL` if the queue is full and `timeout` is 0, `CMT_ERR_TIMED_OUT` if it
+// stayed full. Items are copied with interrupts disabled, so they should be small.
+//
+// # Safety
+// `queue` must have been initialized with `cmt_queue_init`, and `item` must point to an
+// item of its size
+int32_t cmt_queue_send(cmt_queue_t *queue, const void *item, uint32_t timeout);
+
+// Copy the oldest item of `queue` to `item` and remove it, blocking for at most `timeout`
+// ticks while the queue is empty, and wake the highest priority thread waiting to send.
+// Returns 0 once received, `CMT_ERR_TIMED_OUT` if nothing was sent in time.
+//
+// # Safety
+// `queue` must have been initialized with `cmt_queue_init`, and `item` must have room for
+// an item of its size
+int32_t cmt_queue_receive(cmt_queue_t *queue, void *item, uint32_t timeout);
+
+// Number of items in `queue`
+//
+// # Safety
+// `queue` must have been initialized with `cmt_queue_init`
+size_t cmt_queue_len(const cmt_queue_t *queue);
+
+#endif /* CORTEXM_THREADS_H */
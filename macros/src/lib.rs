@@ -0,0 +1,293 @@
+//! Attributes declaring threads of `cortexm-threads`, re-exported by it with the `macros`
+//! feature.
+//!
+//! `#[thread(stack = 1024, priority = 3)]` on a `fn() -> !` generates a module of the same
+//! name holding the thread's `Stack`, with a `spawn()` function creating the thread on it.
+//! `#[threads]` on an inline module adds a `spawn_all()` function, spawning every thread
+//! declared in it.
+//!
+//! Example:
+//! ```
+//! #[threads]
+//! mod tasks {
+//!     use cortexm_threads::{sleep, thread};
+//!
+//!     #[thread(stack = 512, priority = 3, name = "blinky")]
+//!     fn blinky() -> ! {
+//!         loop {
+//!             toggle_led();
+//!             sleep(50);
+//!         }
+//!     }
+//!
+//!     #[thread(stack = 1024, privileged)]
+//!     fn comms() -> ! {
+//!         loop {
+//!             poll_uart();
+//!             sleep(1);
+//!         }
+//!     }
+//! }
+//!
+//! #[entry]
+//! fn main() -> ! {
+//!     tasks::spawn_all().unwrap();
+//!     init();
+//! }
+//! ```
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Configuration of a thread, from the arguments of `#[thread]`
+struct ThreadArgs {
+    stack: Option<Literal>,
+    priority: Option<Literal>,
+    privileged: bool,
+    name: Option<Literal>,
+}
+
+/// Declare a thread: `fn` must be a `fn() -> !`. Arguments:
+/// * stack = words: size of the stack above the canary in u32's, required
+/// * priority = n: higher numeric value means higher priority, 0 if omitted
+/// * privileged: run the thread in privileged mode
+/// * name = "name": name reported in `ThreadInfo`, the function's name if omitted
+///
+/// A module named after the function is added next to it, with `spawn()` creating the
+/// thread. `spawn()` can be called once: the stack is then owned by the thread, and later
+/// calls return `Error::StackInUse`.
+#[proc_macro_attribute]
+pub fn thread(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = match parse_args(attr) {
+        Ok(args) => args,
+        Err((span, msg)) => return error(span, msg),
+    };
+    let stack = match args.stack {
+        Some(stack) => stack,
+        None => return error(Span::call_site(), "missing `stack = words` argument"),
+    };
+    let (vis, name) = match parse_fn(item.clone()) {
+        Ok(found) => found,
+        Err((span, msg)) => return error(span, msg),
+    };
+    let thread_name = match args.name {
+        Some(name) => name.to_string(),
+        None => format!("{:?}", name.to_string()),
+    };
+    let priority = args
+        .priority
+        .map_or_else(|| String::from("0"), |p| p.to_string());
+    let generated = format!(
+        "{vis} mod {name} {{
+            /// Create the thread on its own stack. Fails with `Error::StackInUse` if it was
+            /// already spawned.
+            pub fn spawn() -> ::core::result::Result<
+                ::cortexm_threads::ThreadHandle,
+                ::cortexm_threads::Error,
+            > {{
+                static STACK: ::cortexm_threads::Stack<{stack}> = ::cortexm_threads::Stack::new();
+                let stack = STACK.take().ok_or(::cortexm_threads::Error::StackInUse)?;
+                ::cortexm_threads::Thread::new(stack, super::{name})
+                    .priority({priority}){privileged}
+                    .name({thread_name})
+                    .spawn()
+            }}
+        }}",
+        vis = vis,
+        name = name,
+        stack = stack,
+        priority = priority,
+        privileged = if args.privileged { ".privileged()" } else { "" },
+        thread_name = thread_name,
+    );
+    let mut out = item;
+    out.extend(generated.parse::<TokenStream>().unwrap());
+    out
+}
+
+/// Add `spawn_all()` to an inline module, spawning the threads declared in it with
+/// `#[thread]` in the order of declaration. It stops at the first thread which cannot be
+/// created and returns its error.
+#[proc_macro_attribute]
+pub fn threads(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if let Some(tt) = attr.into_iter().next() {
+        return error(tt.span(), "#[threads] takes no arguments");
+    }
+    let mut tokens: Vec<TokenTree> = item.into_iter().collect();
+    let pos = tokens.iter().position(|tt| is_ident(tt, "mod"));
+    let body = match pos.and_then(|pos| tokens.get(pos + 2)) {
+        Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => g.clone(),
+        _ => {
+            return error(
+                Span::call_site(),
+                "#[threads] must be put on an inline `mod`",
+            )
+        }
+    };
+    let names = thread_fns(body.stream());
+    let mut spawn_all = String::from(
+        "/// Spawn every thread declared with `#[thread]` in this module
+        pub fn spawn_all() -> ::core::result::Result<(), ::cortexm_threads::Error> {",
+    );
+    for name in names {
+        spawn_all.push_str(&format!("{}::spawn()?;", name));
+    }
+    spawn_all.push_str("::core::result::Result::Ok(()) }");
+    let mut stream = body.stream();
+    stream.extend(spawn_all.parse::<TokenStream>().unwrap());
+    let mut group = Group::new(Delimiter::Brace, stream);
+    group.set_span(body.span());
+    tokens[pos.unwrap() + 2] = TokenTree::Group(group);
+    tokens.into_iter().collect()
+}
+
+/// Parse the comma separated `key = value` and `key` arguments of `#[thread]`
+fn parse_args(attr: TokenStream) -> Result<ThreadArgs, (Span, &'static str)> {
+    let mut args = ThreadArgs {
+        stack: None,
+        priority: None,
+        privileged: false,
+        name: None,
+    };
+    let mut tokens = attr.into_iter().peekable();
+    while let Some(tt) = tokens.next() {
+        let key = match tt {
+            TokenTree::Ident(key) => key,
+            other => return Err((other.span(), "expected an argument name")),
+        };
+        let value = match tokens.peek() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '=' => {
+                tokens.next();
+                match tokens.next() {
+                    Some(value) => Some(value),
+                    None => return Err((key.span(), "missing value")),
+                }
+            }
+            _ => None,
+        };
+        match (key.to_string().as_str(), value) {
+            ("stack", Some(TokenTree::Literal(words))) => args.stack = Some(words),
+            ("priority", Some(TokenTree::Literal(priority))) => args.priority = Some(priority),
+            ("name", Some(TokenTree::Literal(name))) if name.to_string().starts_with('"') => {
+                args.name = Some(name)
+            }
+            ("privileged", None) => args.privileged = true,
+            ("privileged", Some(TokenTree::Ident(b))) if b.to_string() == "true" => {
+                args.privileged = true
+            }
+            ("privileged", Some(TokenTree::Ident(b))) if b.to_string() == "false" => {
+                args.privileged = false
+            }
+            ("stack", _) => return Err((key.span(), "expected `stack = words`")),
+            ("priority", _) => return Err((key.span(), "expected `priority = n`")),
+            ("name", _) => return Err((key.span(), "expected `name = \"name\"`")),
+            ("privileged", _) => return Err((key.span(), "expected `privileged`")),
+            _ => {
+                return Err((
+                    key.span(),
+                    "unknown argument, expected stack, priority, privileged or name",
+                ))
+            }
+        }
+        match tokens.next() {
+            None => break,
+            Some(TokenTree::Punct(p)) if p.as_char() == ',' => {}
+            Some(other) => return Err((other.span(), "expected `,`")),
+        }
+    }
+    Ok(args)
+}
+
+/// Visibility and name of the function `item`, which must be a plain `fn`
+fn parse_fn(item: TokenStream) -> Result<(String, Ident), (Span, &'static str)> {
+    let mut vis = String::new();
+    let mut tokens = item.into_iter().peekable();
+    while let Some(tt) = tokens.next() {
+        match tt {
+            // outer attributes, e.g. doc comments
+            TokenTree::Punct(ref p) if p.as_char() == '#' => {
+                tokens.next();
+            }
+            TokenTree::Ident(ref i) if i.to_string() == "pub" => {
+                vis.push_str("pub");
+                if let Some(TokenTree::Group(g)) = tokens.peek() {
+                    if g.delimiter() == Delimiter::Parenthesis {
+                        vis.push_str(&g.to_string());
+                        tokens.next();
+                    }
+                }
+            }
+            TokenTree::Ident(ref i) if i.to_string() == "fn" => {
+                return match tokens.next() {
+                    Some(TokenTree::Ident(name)) => Ok((vis, name)),
+                    _ => Err((i.span(), "expected the name of the function")),
+                };
+            }
+            other => return Err((other.span(), "#[thread] must be put on a plain `fn() -> !`")),
+        }
+    }
+    Err((Span::call_site(), "#[thread] must be put on a function"))
+}
+
+/// Names of the functions in `body` with a `#[thread]` attribute
+fn thread_fns(body: TokenStream) -> Vec<Ident> {
+    let mut names = Vec::new();
+    let mut marked = false;
+    let mut tokens = body.into_iter().peekable();
+    while let Some(tt) = tokens.next() {
+        match tt {
+            TokenTree::Punct(ref p) if p.as_char() == '#' => {
+                if let Some(TokenTree::Group(g)) = tokens.next() {
+                    marked |= is_thread_attr(g.stream());
+                }
+            }
+            TokenTree::Ident(ref i) if i.to_string() == "fn" => {
+                if let (true, Some(TokenTree::Ident(name))) = (marked, tokens.next()) {
+                    names.push(name);
+                }
+                marked = false;
+            }
+            // the body of an item
+            TokenTree::Group(ref g) if g.delimiter() == Delimiter::Brace => marked = false,
+            TokenTree::Punct(ref p) if p.as_char() == ';' => marked = false,
+            _ => {}
+        }
+    }
+    names
+}
+
+/// Whether the contents of an attribute are `thread(..)`, possibly with a path
+fn is_thread_attr(attr: TokenStream) -> bool {
+    let mut last = None;
+    for tt in attr {
+        match tt {
+            TokenTree::Ident(i) => last = Some(i.to_string()),
+            TokenTree::Punct(ref p) if p.as_char() == ':' => {}
+            TokenTree::Group(_) => break,
+            _ => return false,
+        }
+    }
+    last.as_deref() == Some("thread")
+}
+
+fn is_ident(tt: &TokenTree, name: &str) -> bool {
+    matches!(tt, TokenTree::Ident(i) if i.to_string() == name)
+}
+
+/// `compile_error! { msg }`, reported at `span`
+fn error(span: Span, msg: &str) -> TokenStream {
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut lit = Literal::string(msg);
+    lit.set_span(span);
+    let mut args = Group::new(Delimiter::Brace, TokenTree::Literal(lit).into());
+    args.set_span(span);
+    vec![
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(args),
+    ]
+    .into_iter()
+    .collect()
+}
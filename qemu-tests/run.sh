@@ -0,0 +1,18 @@
+#!/bin/sh
+# Run every test suite under QEMU for every target, e.g. `./run.sh` or
+# `TARGETS=thumbv6m-none-eabi ./run.sh`. A suite which does not finish within a minute fails.
+cd "$(dirname "$0")" || exit 1
+TARGETS="${TARGETS:-thumbv6m-none-eabi thumbv7m-none-eabi thumbv7em-none-eabi}"
+status=0
+for target in $TARGETS; do
+    for suite in src/bin/*.rs; do
+        name=$(basename "$suite" .rs)
+        if timeout 60 cargo run --quiet --release --target "$target" --bin "$name"; then
+            echo "ok      $target $name"
+        else
+            echo "FAILED  $target $name"
+            status=1
+        fi
+    done
+done
+exit $status
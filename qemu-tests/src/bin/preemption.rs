@@ -0,0 +1,45 @@
+//! A thread which never gives up the CPU is preempted by the tick for a higher priority one
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use cortex_m_rt::entry;
+use cortexm_threads::{sleep, Stack};
+use panic_semihosting as _;
+use qemu_tests::{check, create_test_thread_with_config, pass, start};
+
+static SPINS: AtomicU32 = AtomicU32::new(0);
+static DONE: AtomicBool = AtomicBool::new(false);
+
+static STACK1: Stack<512> = Stack::new();
+static STACK2: Stack<512> = Stack::new();
+
+/// Counts as fast as it can, without sleeping or yielding
+fn spinner() -> ! {
+    while !DONE.load(Ordering::Relaxed) {
+        // single writer, and ARMv6-M has no atomic read-modify-write
+        SPINS.store(SPINS.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+    pass()
+}
+
+/// Runs on each wake although the spinner never stops, and sees it progress in between
+fn sampler() -> ! {
+    let mut last = SPINS.load(Ordering::Relaxed);
+    for _ in 0..5 {
+        sleep(2);
+        let spins = SPINS.load(Ordering::Relaxed);
+        check!(spins > last);
+        last = spins;
+    }
+    DONE.store(true, Ordering::Relaxed);
+    pass()
+}
+
+#[entry]
+fn main() -> ! {
+    create_test_thread_with_config(STACK1.take().unwrap(), spinner, 1, false).unwrap();
+    create_test_thread_with_config(STACK2.take().unwrap(), sampler, 2, false).unwrap();
+    start()
+}
@@ -0,0 +1,62 @@
+//! Mutexes keep contending threads out, and mailboxes block and time out
+#![no_std]
+#![no_main]
+
+use cortex_m_rt::entry;
+use cortexm_threads::{sleep, ticks, yield_now, Mailbox, Mutex, Stack};
+use panic_semihosting as _;
+use qemu_tests::{check, create_test_thread_with_config, pass, start};
+
+const INCREMENTS: u32 = 100;
+
+static COUNT: Mutex<u32> = Mutex::new(0);
+static FINISHED: Mutex<u32> = Mutex::new(0);
+static READINGS: Mailbox<u32> = Mailbox::new();
+
+static STACK1: Stack<512> = Stack::new();
+static STACK2: Stack<512> = Stack::new();
+static STACK3: Stack<512> = Stack::new();
+static STACK4: Stack<512> = Stack::new();
+
+/// Increments the count in two steps, giving the other incrementer a turn in between
+fn incrementer() -> ! {
+    for _ in 0..INCREMENTS {
+        let mut count = COUNT.lock();
+        let seen = *count;
+        yield_now();
+        *count = seen + 1;
+    }
+    let mut finished = FINISHED.lock();
+    *finished += 1;
+    if *finished == 2 {
+        check!(*COUNT.lock() == 2 * INCREMENTS);
+    }
+    drop(finished);
+    pass()
+}
+
+fn producer() -> ! {
+    sleep(10);
+    READINGS.post(7);
+    pass()
+}
+
+fn consumer() -> ! {
+    // nothing is posted before tick 10
+    let start = ticks();
+    check!(READINGS.take_timeout(5).is_err());
+    check!(ticks() - start >= 5);
+    check!(READINGS.take() == 7);
+    check!(ticks() - start >= 10);
+    pass()
+}
+
+#[entry]
+fn main() -> ! {
+    create_test_thread_with_config(STACK1.take().unwrap(), incrementer, 1, false).unwrap();
+    create_test_thread_with_config(STACK2.take().unwrap(), incrementer, 1, false).unwrap();
+    // mailboxes are only available to privileged threads
+    create_test_thread_with_config(STACK3.take().unwrap(), producer, 2, true).unwrap();
+    create_test_thread_with_config(STACK4.take().unwrap(), consumer, 3, true).unwrap();
+    start()
+}
@@ -0,0 +1,76 @@
+//! Ready threads run highest priority first, and `yield_now` alternates threads of equal
+//! priority
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use cortex_m_rt::entry;
+use cortexm_threads::{with_critical, yield_now, Stack};
+use panic_semihosting as _;
+use qemu_tests::{check, create_test_thread_with_config, pass, start};
+
+/// Steps in the order they ran: 1 for the high thread, 10 and 11 for the first and second
+/// step of thread A, 20 and 21 for thread B
+static STEPS: [AtomicU8; 5] = [
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+];
+static RAN: AtomicUsize = AtomicUsize::new(0);
+
+static STACK1: Stack<512> = Stack::new();
+static STACK2: Stack<512> = Stack::new();
+static STACK3: Stack<512> = Stack::new();
+static STACK4: Stack<512> = Stack::new();
+
+fn step(id: u8) {
+    with_critical(|| {
+        let ran = RAN.load(Ordering::Relaxed);
+        if ran < STEPS.len() {
+            STEPS[ran].store(id, Ordering::Relaxed);
+        }
+        RAN.store(ran + 1, Ordering::Relaxed);
+    });
+}
+
+fn high() -> ! {
+    step(1);
+    pass()
+}
+
+fn mid_a() -> ! {
+    step(10);
+    yield_now();
+    step(11);
+    pass()
+}
+
+fn mid_b() -> ! {
+    step(20);
+    yield_now();
+    step(21);
+    pass()
+}
+
+/// Runs once the others are done, and checks the order they ran in
+fn low() -> ! {
+    let steps = STEPS.each_ref().map(|step| step.load(Ordering::Relaxed));
+    check!(RAN.load(Ordering::Relaxed) == 5);
+    check!(steps[0] == 1);
+    // whichever of A and B starts, they take turns
+    check!(steps[1] % 10 == 0 && steps[2] % 10 == 0 && steps[1] != steps[2]);
+    check!(steps[3] == steps[1] + 1 && steps[4] == steps[2] + 1);
+    pass()
+}
+
+#[entry]
+fn main() -> ! {
+    create_test_thread_with_config(STACK1.take().unwrap(), low, 1, false).unwrap();
+    create_test_thread_with_config(STACK2.take().unwrap(), mid_a, 2, false).unwrap();
+    create_test_thread_with_config(STACK3.take().unwrap(), mid_b, 2, false).unwrap();
+    create_test_thread_with_config(STACK4.take().unwrap(), high, 3, false).unwrap();
+    start()
+}
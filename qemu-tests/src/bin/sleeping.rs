@@ -0,0 +1,59 @@
+//! Sleeping threads wake at their tick, in deadline order
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m_rt::entry;
+use cortexm_threads::{sleep, sleep_until, ticks, Stack};
+use panic_semihosting as _;
+use qemu_tests::{check, create_test_thread_with_config, pass, start};
+
+/// Tick at which the short sleeper woke, 0 before
+static SHORT_WOKE: AtomicU32 = AtomicU32::new(0);
+
+static STACK1: Stack<512> = Stack::new();
+static STACK2: Stack<512> = Stack::new();
+static STACK3: Stack<512> = Stack::new();
+
+fn sleeper() -> ! {
+    let start = ticks();
+    sleep(10);
+    let slept = ticks() - start;
+    check!((10..=11).contains(&slept));
+    // a period computed from the previous wake does not drift
+    let mut next = ticks();
+    for _ in 0..5 {
+        next += 7;
+        sleep_until(next);
+        check!(ticks() - next <= 1);
+    }
+    // a wake tick already passed returns at once
+    let now = ticks();
+    sleep_until(now - 1);
+    check!(ticks() - now <= 1);
+    pass()
+}
+
+fn short() -> ! {
+    sleep(3);
+    SHORT_WOKE.store(ticks() as u32, Ordering::Relaxed);
+    pass()
+}
+
+/// Goes to sleep before the short sleeper, for longer, and must wake after it
+fn long() -> ! {
+    sleep(6);
+    let short_woke = SHORT_WOKE.load(Ordering::Relaxed) as u64;
+    check!(short_woke != 0);
+    check!(ticks() >= short_woke + 3);
+    pass()
+}
+
+#[entry]
+fn main() -> ! {
+    create_test_thread_with_config(STACK1.take().unwrap(), sleeper, 1, false).unwrap();
+    create_test_thread_with_config(STACK2.take().unwrap(), short, 1, false).unwrap();
+    create_test_thread_with_config(STACK3.take().unwrap(), long, 2, false).unwrap();
+    start()
+}
@@ -0,0 +1,37 @@
+//! Integration tests of the kernel on an LM3S6965 emulated by QEMU.
+//!
+//! Each binary in `src/bin` is a test suite: it creates its test threads with
+//! `create_test_thread_with_config` and calls `start`. The threads check what they observe
+//! with `check!` and end with `pass()`; the harness exits QEMU once all of them reported, with
+//! a failure status if any failed or panicked. `run.sh` runs every suite for every target.
+#![no_std]
+
+pub use cortex_m_semihosting::hprintln;
+pub use cortexm_threads::test_harness::{create_test_thread_with_config, fail, pass};
+
+/// Processor clock of the emulated LM3S6965
+pub const CORE_HZ: u32 = 12_000_000;
+/// Tick rate of the tests
+pub const TICK_HZ: u32 = 1_000;
+
+/// Start the kernel with the test threads created so far, ticking at `TICK_HZ`
+pub fn start() -> ! {
+    let cp = cortex_m::Peripherals::take().unwrap();
+    cortexm_threads::init_with_systick(cp.SYST, TICK_HZ, CORE_HZ)
+}
+
+/// Fail the current test thread, printing the condition and where it is, unless `cond` holds
+#[macro_export]
+macro_rules! check {
+    ($cond:expr) => {
+        if !$cond {
+            $crate::hprintln!(
+                "{}:{}: check failed: {}",
+                file!(),
+                line!(),
+                stringify!($cond)
+            );
+            $crate::fail();
+        }
+    };
+}
@@ -0,0 +1,42 @@
+//! Priority aging, against starvation of low priority threads.
+//!
+//! With aging enabled, a ready thread which has not run for a set number of ticks gets a
+//! priority boost, and another one each time that many ticks pass again without it running.
+//! Once picked to run it drops back to its own priority, so it gets a slice of CPU time
+//! without taking over from the threads which outranked it.
+
+use crate::lifecycle::{self, ThreadEvent};
+use crate::{with_state, ThreadsState};
+
+/// Boost threads which have been ready for `after_ticks` ticks without running by `boost`
+/// priority levels, up to 255. An `after_ticks` of 0 disables aging, which is the default.
+/// Aging looks at every thread on each scheduler run, so it costs time proportional to the
+/// number of threads.
+///
+/// # Example
+/// ```
+/// // the logger runs at least about once a second, even with busy control threads
+/// set_priority_aging(1_000, 1);
+/// ```
+pub fn set_priority_aging(after_ticks: u32, boost: u8) {
+    with_state(|s| {
+        s.aging_ticks = after_ticks;
+        s.aging_boost = boost;
+    });
+}
+
+/// Boost the ready threads, other than the running one, which waited for the aging period
+pub(crate) fn age(s: &mut ThreadsState, now: u64) {
+    if s.aging_ticks == 0 {
+        return;
+    }
+    for idx in 1..s.threads.len() {
+        let thread = &s.threads[idx];
+        if thread.runnable() && idx != s.idx && now - thread.ready_since >= s.aging_ticks as u64 {
+            let priority = thread.priority.saturating_add(s.aging_boost);
+            s.threads[idx].ready_since = now;
+            s.set_priority(idx, priority);
+            lifecycle::report(s, ThreadEvent::Starved, idx);
+        }
+    }
+}
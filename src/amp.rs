@@ -0,0 +1,418 @@
+//! Both cores of the RP2040, each running its own kernel, with the `rp2040-amp` feature.
+//!
+//! The two Cortex-M0+ cores share memory but not their interrupt masks, SysTick or NVIC, so
+//! each core runs an independent scheduler: the kernel state, the critical section flag and
+//! the work queue are kept per core, selected by the SIO CPUID register, and PendSV switches
+//! between the threads of the core it runs on. Core 0 starts from reset as usual and starts
+//! core 1 with `spawn_on_core`; each core then creates its threads and calls `init` or
+//! `init_with_systick` with its own SysTick. The tick rate set by `set_tick_hz` is shared.
+//!
+//! Thread handles and kernel objects (mutexes, mailboxes, queues and the rest) belong to the
+//! kernel of one core, and must only be used by the threads and handlers of that core.
+//! Threads of different cores exchange messages through a `CoreChannel`, whose buffer is
+//! guarded by an SIO hardware spinlock. A thread blocked on a channel by the other core is
+//! woken through the inter-core FIFO: the kernel defines the SIO_IRQ_PROC0 and
+//! SIO_IRQ_PROC1 handlers, which wake the threads of their core waiting on the channel whose
+//! address they read from the FIFO, and `init` enables the one of the calling core. The
+//! application must not define these handlers nor use the FIFO once `init` ran.
+
+use core::cell::UnsafeCell;
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::arch::{Arch, Port};
+use crate::critical::CriticalSection;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, scheduler_started, wake_all, Error, WakeReason, NO_DEADLINE};
+
+/// Number of cores, each running a kernel
+pub(crate) const CORES: usize = 2;
+
+/// SIO CPUID, 0 on core 0 and 1 on core 1
+const SIO_CPUID: u32 = 0xD000_0000;
+/// SIO FIFO status of the calling core
+const SIO_FIFO_ST: u32 = 0xD000_0050;
+/// SIO FIFO to the other core
+const SIO_FIFO_WR: u32 = 0xD000_0054;
+/// SIO FIFO from the other core
+const SIO_FIFO_RD: u32 = 0xD000_0058;
+/// SIO spinlock guarding the channels, 14 being reserved for an OS by the Pico SDK
+const SIO_SPINLOCK: u32 = 0xD000_0100 + 14 * 4;
+/// FIFO_ST: the FIFO from the other core holds a word
+const FIFO_VLD: u32 = 1 << 0;
+/// FIFO_ST: the FIFO to the other core has room
+const FIFO_RDY: u32 = 1 << 1;
+/// NVIC interrupt set-enable register
+const NVIC_ISER: u32 = 0xE000_E100;
+/// Interrupt number of SIO_IRQ_PROC0, SIO_IRQ_PROC1 follows
+const FIFO_IRQ: u32 = 15;
+/// Vector table offset register
+const SCB_VTOR: u32 = 0xE000_ED08;
+
+/// `main` of core 1, passed by `spawn_on_core`
+static CORE1_MAIN: AtomicUsize = AtomicUsize::new(0);
+
+/// Core running the caller, 0 or 1
+pub fn core_id() -> usize {
+    unsafe { ptr::read_volatile(SIO_CPUID as *const u32) as usize }
+}
+
+/// Start core 1 on `stack`, running `main`, which typically creates the threads of core 1
+/// and calls `init` or `init_with_systick`. Core 1 takes the interrupts of core 0's vector
+/// table, and must be waiting in the boot ROM, as after a reset. `core` must be 1. Panics
+/// unless called from core 0 before `init`, as the launch handshake goes through the FIFO
+/// which carries wakeups once the kernel runs.
+///
+/// # Example
+/// ```
+/// static CORE1_STACK: Stack<1024> = Stack::new();
+///
+/// fn core1_main() -> ! {
+///     let cp = unsafe { cortex_m::Peripherals::steal() };
+///     create_thread(SENSOR_STACK.take().unwrap(), sensor_thread).unwrap();
+///     init_with_systick(cp.SYST, 1_000, 125_000_000)
+/// }
+///
+/// spawn_on_core(1, CORE1_STACK.take().unwrap(), core1_main);
+/// create_thread(UI_STACK.take().unwrap(), ui_thread).unwrap();
+/// init_with_systick(cp.SYST, 1_000, 125_000_000)
+/// ```
+pub fn spawn_on_core(core: usize, stack: &'static mut [u32], main: fn() -> !) {
+    if core != 1 || core_id() != 0 {
+        panic!("spawn_on_core: only core 0 can start core 1");
+    }
+    if scheduler_started() {
+        panic!("spawn_on_core: must be called before init");
+    }
+    CORE1_MAIN.store(main as usize, Ordering::Relaxed);
+    // the initial stack pointer must be 8 byte aligned
+    let sp = (stack.as_ptr() as usize + stack.len() * 4) & !7;
+    let vector_table = unsafe { ptr::read_volatile(SCB_VTOR as *const u32) };
+    let entry: extern "C" fn() -> ! = core1_entry;
+    // the boot ROM echoes each word, and starts over on a mismatch
+    let sequence = [0, 0, 1, vector_table, sp as u32, entry as usize as u32];
+    let mut i = 0;
+    while i < sequence.len() {
+        let word = sequence[i];
+        if word == 0 {
+            while fifo_pop().is_some() {}
+            // the boot ROM waits for an event before reading the FIFO
+            Port::signal_event();
+        }
+        fifo_push(word);
+        let reply = loop {
+            if let Some(reply) = fifo_pop() {
+                break reply;
+            }
+        };
+        i = if reply == word { i + 1 } else { 0 };
+    }
+}
+
+/// Entry point of core 1, on the stack given to `spawn_on_core`
+extern "C" fn core1_entry() -> ! {
+    let main: fn() -> ! = unsafe { mem::transmute(CORE1_MAIN.load(Ordering::Relaxed)) };
+    main()
+}
+
+/// Drain the FIFO from the other core and enable the FIFO interrupt of the calling core.
+/// Called by `init`.
+pub(crate) fn enable_doorbell() {
+    while fifo_pop().is_some() {}
+    unsafe {
+        // any write clears the overflow flags
+        ptr::write_volatile(SIO_FIFO_ST as *mut u32, 0);
+        ptr::write_volatile(NVIC_ISER as *mut u32, 1 << (FIFO_IRQ + core_id() as u32));
+    }
+}
+
+/// Inter-core FIFO interrupt of core 0
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn SIO_IRQ_PROC0() {
+    ring_doorbells();
+}
+
+/// Inter-core FIFO interrupt of core 1
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn SIO_IRQ_PROC1() {
+    ring_doorbells();
+}
+
+/// Wake the threads of this core waiting on the channels whose doorbells the other core sent
+fn ring_doorbells() {
+    while let Some(word) = fifo_pop() {
+        let doorbell = unsafe { &*(word as usize as *const Doorbell) };
+        doorbell.wake_here();
+    }
+    unsafe { ptr::write_volatile(SIO_FIFO_ST as *mut u32, 0) };
+}
+
+fn fifo_push(word: u32) {
+    unsafe {
+        while ptr::read_volatile(SIO_FIFO_ST as *const u32) & FIFO_RDY == 0 {}
+        ptr::write_volatile(SIO_FIFO_WR as *mut u32, word);
+    }
+}
+
+fn fifo_pop() -> Option<u32> {
+    unsafe {
+        if ptr::read_volatile(SIO_FIFO_ST as *const u32) & FIFO_VLD == 0 {
+            return None;
+        }
+        Some(ptr::read_volatile(SIO_FIFO_RD as *const u32))
+    }
+}
+
+/// Run `f` holding the channel spinlock, with interrupts disabled so a handler of this core
+/// does not spin on it forever
+fn locked<R>(f: impl FnOnce() -> R) -> R {
+    let mask = Port::disable_interrupts();
+    // reading the spinlock claims it, 0 means the other core holds it
+    while unsafe { ptr::read_volatile(SIO_SPINLOCK as *const u32) } == 0 {}
+    fence(Ordering::Acquire);
+    let result = f();
+    fence(Ordering::Release);
+    unsafe { ptr::write_volatile(SIO_SPINLOCK as *mut u32, 0) };
+    Port::restore_interrupts(mask);
+    result
+}
+
+/// Threads of each core waiting on a channel
+struct Doorbell {
+    /// cores with threads blocked, or about to block, on the channel, under the spinlock
+    waiting: UnsafeCell<[bool; CORES]>,
+    /// threads blocked on the channel, each list under the critical section of its core
+    waiters: [UnsafeCell<WaitList>; CORES],
+}
+
+impl Doorbell {
+    /// Cores with threads waiting, which are to be woken. Must hold the spinlock.
+    unsafe fn take_waiting(&self) -> [bool; CORES] {
+        mem::replace(&mut *self.waiting.get(), [false; CORES])
+    }
+
+    /// Wake the threads which waited on the channel in `cores`, those of the other core
+    /// through the FIFO
+    fn ring(&self, cores: [bool; CORES]) {
+        let me = core_id();
+        for (core, &waiting) in cores.iter().enumerate() {
+            if !waiting {
+                continue;
+            }
+            if core == me {
+                self.wake_here();
+            } else {
+                fifo_push(self as *const Doorbell as usize as u32);
+            }
+        }
+    }
+
+    /// Wake the threads of this core waiting on the channel, to check it again
+    fn wake_here(&self) {
+        {
+            let mut cs = CriticalSection::enter();
+            wake_all(
+                crate::state(&mut cs),
+                unsafe { &mut *self.waiters[core_id()].get() },
+                WakeReason::Signaled,
+            );
+        }
+        schedule();
+    }
+}
+
+/// Queue of up to `N` items of type `T` between threads and handlers of both cores. The
+/// threads must be privileged, see `svc`.
+///
+/// Example:
+/// ```
+/// static SAMPLES: CoreChannel<u16, 32> = CoreChannel::new();
+///
+/// // thread on core 1
+/// loop {
+///     SAMPLES.send(adc.read());
+///     sleep(10);
+/// }
+///
+/// // thread on core 0
+/// loop {
+///     filter.feed(SAMPLES.receive());
+/// }
+/// ```
+pub struct CoreChannel<T, const N: usize> {
+    buf: UnsafeCell<MaybeUninit<[T; N]>>,
+    /// index of the oldest item, under the spinlock
+    head: UnsafeCell<usize>,
+    /// number of items, under the spinlock
+    len: UnsafeCell<usize>,
+    doorbell: Doorbell,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for CoreChannel<T, N> {}
+
+impl<T, const N: usize> CoreChannel<T, N> {
+    pub const fn new() -> Self {
+        CoreChannel {
+            buf: UnsafeCell::new(MaybeUninit::uninit()),
+            head: UnsafeCell::new(0),
+            len: UnsafeCell::new(0),
+            doorbell: Doorbell {
+                waiting: UnsafeCell::new([false; CORES]),
+                waiters: [
+                    UnsafeCell::new(WaitList::new(WaitOrder::Priority)),
+                    UnsafeCell::new(WaitList::new(WaitOrder::Priority)),
+                ],
+            },
+        }
+    }
+
+    /// Append `value`, waking the threads waiting to receive on either core. Gives `value`
+    /// back if the channel is full. Can be called from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        let woken = locked(|| unsafe { self.push(value) })?;
+        self.doorbell.ring(woken);
+        Ok(())
+    }
+
+    /// Append `value`, blocking while the channel is full
+    pub fn send(&self, value: T) {
+        match self.send_until(value, NO_DEADLINE) {
+            Ok(()) => {}
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `send`, but gives up with Err(Error::TimedOut) if the channel stayed full for
+    /// `ticks` ticks
+    pub fn send_timeout(&self, value: T, ticks: u32) -> Result<(), Error> {
+        self.send_until(value, crate::ticks() + ticks as u64)
+    }
+
+    /// Take the oldest item, or None if the channel is empty. Can be called from interrupt
+    /// handlers.
+    pub fn try_receive(&self) -> Option<T> {
+        let (value, woken) = locked(|| unsafe { self.pop() })?;
+        self.doorbell.ring(woken);
+        Some(value)
+    }
+
+    /// Take the oldest item, blocking until one is sent
+    pub fn receive(&self) -> T {
+        match self.receive_until(NO_DEADLINE) {
+            Ok(value) => value,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `receive`, but gives up with Err(Error::TimedOut) if nothing was sent within
+    /// `ticks` ticks
+    pub fn receive_timeout(&self, ticks: u32) -> Result<T, Error> {
+        self.receive_until(crate::ticks() + ticks as u64)
+    }
+
+    /// Number of items which can be received
+    pub fn len(&self) -> usize {
+        locked(|| unsafe { *self.len.get() })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn send_until(&self, value: T, deadline: u64) -> Result<(), Error> {
+        let mut value = value;
+        loop {
+            // interrupts are disabled from the check until the thread is blocked, so a
+            // wakeup for this core is handled once the thread waits
+            let cs = CriticalSection::enter();
+            match locked(|| unsafe { self.push_or_wait(value) }) {
+                Ok(woken) => {
+                    drop(cs);
+                    self.doorbell.ring(woken);
+                    return Ok(());
+                }
+                Err(full) => value = full,
+            }
+            let list = self.doorbell.waiters[core_id()].get();
+            if unsafe { block_current(cs, list, deadline) } == WakeReason::Timeout {
+                return self.try_send(value).map_err(|_| Error::TimedOut);
+            }
+        }
+    }
+
+    fn receive_until(&self, deadline: u64) -> Result<T, Error> {
+        loop {
+            // as in `send_until`
+            let cs = CriticalSection::enter();
+            if let Some((value, woken)) = locked(|| unsafe { self.pop_or_wait() }) {
+                drop(cs);
+                self.doorbell.ring(woken);
+                return Ok(value);
+            }
+            let list = self.doorbell.waiters[core_id()].get();
+            if unsafe { block_current(cs, list, deadline) } == WakeReason::Timeout {
+                return self.try_receive().ok_or(Error::TimedOut);
+            }
+        }
+    }
+
+    /// Append `value`, returning the cores to wake. Must hold the spinlock.
+    unsafe fn push(&self, value: T) -> Result<[bool; CORES], T> {
+        let len = *self.len.get();
+        if len == N {
+            return Err(value);
+        }
+        let slot = (*self.head.get() + len) % N;
+        ptr::write((self.buf.get() as *mut T).add(slot), value);
+        *self.len.get() = len + 1;
+        Ok(self.doorbell.take_waiting())
+    }
+
+    /// Take the oldest item, with the cores to wake. Must hold the spinlock.
+    unsafe fn pop(&self) -> Option<(T, [bool; CORES])> {
+        let len = *self.len.get();
+        if len == 0 {
+            return None;
+        }
+        let head = *self.head.get();
+        let value = ptr::read((self.buf.get() as *const T).add(head));
+        *self.head.get() = (head + 1) % N;
+        *self.len.get() = len - 1;
+        Some((value, self.doorbell.take_waiting()))
+    }
+
+    /// `push`, or mark this core waiting if the channel is full
+    unsafe fn push_or_wait(&self, value: T) -> Result<[bool; CORES], T> {
+        let result = self.push(value);
+        if result.is_err() {
+            (*self.doorbell.waiting.get())[core_id()] = true;
+        }
+        result
+    }
+
+    /// `pop`, or mark this core waiting if the channel is empty
+    unsafe fn pop_or_wait(&self) -> Option<(T, [bool; CORES])> {
+        let result = self.pop();
+        if result.is_none() {
+            (*self.doorbell.waiting.get())[core_id()] = true;
+        }
+        result
+    }
+}
+
+impl<T, const N: usize> Default for CoreChannel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for CoreChannel<T, N> {
+    fn drop(&mut self) {
+        while let Some((value, _)) = unsafe { self.pop() } {
+            drop(value);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Processor-specific parts of the kernel, behind the `Arch` interface.
+//!
+//! The scheduler core only reaches the processor through `Port`, the implementation of `Arch`
+//! for the target: masking interrupts for critical sections, requesting a thread switch,
+//! waiting for an event while idle, and laying out the initial context of a thread. A port to
+//! another architecture, e.g. RISC-V or Cortex-R, adds a module implementing `Arch` with its
+//! assembly, selected by its feature in place of `cortexm`.
+//!
+//! The switch code of a port finds the kernel state at the address given to
+//! `set_kernel_state`. It switches from the thread control block at `curr` to the one at
+//! `next`, the first two words of the state, saving and loading the stack pointer in the
+//! first word of each thread control block, and sets `curr` to `next`.
+//!
+//! Peripherals are not covered: SysTick is reached through `TickSource`, and the features
+//! using the DWT, the MPU or the fault status registers are specific to Cortex-M.
+
+#[cfg(not(feature = "sim"))]
+mod cortexm;
+#[cfg(not(feature = "sim"))]
+pub(crate) use self::cortexm::CortexM as Port;
+#[cfg(feature = "sim")]
+pub(crate) use crate::sim::Host as Port;
+
+/// What the kernel needs from the processor
+pub(crate) trait Arch {
+    /// Disable interrupts, returning the interrupt mask to pass to `restore_interrupts`. Bit
+    /// 0 of the mask is set if interrupts were disabled already.
+    fn disable_interrupts() -> u32;
+
+    /// Restore the interrupt mask returned by `disable_interrupts`. A switch requested while
+    /// interrupts were disabled takes place once they are enabled.
+    fn restore_interrupts(mask: u32);
+
+    /// Have the switch code replace the thread at `curr` with the one at `next` as soon as
+    /// interrupts are enabled, and before the caller goes on if they are
+    fn request_switch();
+
+    /// Wait for an event or an interrupt, while idle
+    fn wait_for_event();
+
+    /// Wake the other cores waiting for an event
+    #[cfg(feature = "rp2040-amp")]
+    fn signal_event();
+
+    /// Wait for an interrupt, while idle with the tick stopped
+    #[cfg(feature = "tickless")]
+    fn wait_for_interrupt();
+
+    /// Complete outstanding memory accesses and refetch instructions, after reprogramming
+    /// memory protection
+    #[cfg(any(feature = "mpu-stack-guard", feature = "mpu-isolation"))]
+    fn barrier();
+
+    /// Run a busy loop of `iterations` iterations of constant length, see `delay_us`
+    fn spin(iterations: u32);
+
+    /// Whether an interrupt or exception handler is running
+    fn in_handler() -> bool;
+
+    /// Whether thread code runs unprivileged, and must enter the kernel through
+    /// `kernel_call`
+    fn unprivileged() -> bool;
+
+    /// Write the context a thread starts from at the top of `stack`: running `entry` with
+    /// `args` as its first two arguments, in privileged mode if `privileged`. Returns the
+    /// index of the word the saved stack pointer points to, the frame's lowest word.
+    fn init_frame(stack: &mut [u32], entry: usize, args: [usize; 2], privileged: bool) -> usize;
+
+    /// Tell the switch code where the kernel state of the calling core is
+    fn set_kernel_state(state: usize);
+
+    /// Start the thread at `next`, leaving the caller's context for good
+    ///
+    /// # Safety
+    /// Only to be called once, by `init`, with `next` set
+    unsafe fn start() -> !;
+
+    /// Run kernel call `call` with arguments `a`, `b` and `c` in privileged mode, see `svc`
+    fn kernel_call(call: u32, a: usize, b: usize, c: usize) -> usize;
+
+    /// Make the running thread unprivileged, taking exceptions on the stack at `msp` from now
+    /// on, with stack limits `msplim` and `psplim` where the processor checks them
+    ///
+    /// # Safety
+    /// `msp` must be the top of a free stack area, and interrupts disabled
+    unsafe fn drop_privileges(msp: u32, msplim: u32, psplim: u32);
+}
@@ -0,0 +1,177 @@
+//! Cortex-M port, with the switch code in the assembly file picked by build.rs.
+//!
+//! PendSV switches threads, saving R4 to R11 below the exception frame of the thread on its
+//! process stack, and with the `cortex-m4f` feature the EXC_RETURN of the thread below them,
+//! which tells whether its frame holds FPU registers. SVCall starts the first thread and runs
+//! kernel calls, see `svc`.
+//!
+//! FPU context is switched lazily, so threads which never run a floating point instruction
+//! do not pay the cost of saving 33 more registers. The core sets CONTROL.FPCA on the first
+//! floating point instruction of a thread, and PendSV finds it as bit 4 of EXC_RETURN
+//! clear: only then does it save S16-S31 with R4-R11, and restore them. S0-S15 and FPSCR
+//! belong to the exception frame, in which lazy stacking only reserves room, written once
+//! PendSV touches the FPU for such a thread. A thread keeps FPCA from its first floating
+//! point instruction until it exits or restarts. Both mechanisms are enabled in FPCCR out of
+//! reset, `start` sets them again in case a boot loader cleared them.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::Arch;
+
+/// Address of the kernel state, read by the PendSV handler
+#[cfg(not(feature = "rp2040-amp"))]
+#[no_mangle]
+static __CORTEXM_THREADS_GLOBAL_PTR: AtomicU32 = AtomicU32::new(0);
+/// Address of the kernel state of each core, indexed by the PendSV handler with SIO CPUID
+#[cfg(feature = "rp2040-amp")]
+#[no_mangle]
+static __CORTEXM_THREADS_GLOBAL_PTR: [AtomicU32; crate::amp::CORES] =
+    [AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Interrupt control and state register
+const ICSR: u32 = 0xE000ED04;
+/// Floating-point context control register
+#[cfg(feature = "cortex-m4f")]
+const FPCCR: u32 = 0xE000EF34;
+/// FPCCR: set CONTROL.FPCA on floating point instructions
+#[cfg(feature = "cortex-m4f")]
+const FPCCR_ASPEN: u32 = 1 << 31;
+/// FPCCR: only reserve room for S0-S15 and FPSCR in exception frames, until used
+#[cfg(feature = "cortex-m4f")]
+const FPCCR_LSPEN: u32 = 1 << 30;
+
+// functions defined in assembly
+extern "C" {
+    fn __CORTEXM_THREADS_primask_save() -> u32;
+    fn __CORTEXM_THREADS_primask_restore(primask: u32);
+    fn __CORTEXM_THREADS_wfe();
+    fn __CORTEXM_THREADS_wfi();
+    #[cfg(feature = "rp2040-amp")]
+    fn __CORTEXM_THREADS_sev();
+    fn __CORTEXM_THREADS_barrier();
+    fn __CORTEXM_THREADS_spin(iterations: u32);
+    fn __CORTEXM_THREADS_start() -> !;
+    fn __CORTEXM_THREADS_kernel_call(call: u32, a: usize, b: usize, c: usize) -> usize;
+    fn __CORTEXM_THREADS_ipsr() -> u32;
+    fn __CORTEXM_THREADS_control() -> u32;
+    fn __CORTEXM_THREADS_drop_privileges(msp: u32, msplim: u32, psplim: u32);
+}
+
+pub(crate) struct CortexM;
+
+impl Arch for CortexM {
+    fn disable_interrupts() -> u32 {
+        unsafe { __CORTEXM_THREADS_primask_save() }
+    }
+
+    fn restore_interrupts(mask: u32) {
+        unsafe { __CORTEXM_THREADS_primask_restore(mask) }
+    }
+
+    fn request_switch() {
+        unsafe {
+            let pend = ptr::read_volatile(ICSR as *const u32);
+            ptr::write_volatile(ICSR as *mut u32, pend | 1 << 28);
+            // PendSV is taken before the next instruction, also on the M7
+            __CORTEXM_THREADS_barrier();
+        }
+    }
+
+    fn wait_for_event() {
+        unsafe { __CORTEXM_THREADS_wfe() }
+    }
+
+    #[cfg(feature = "rp2040-amp")]
+    fn signal_event() {
+        unsafe { __CORTEXM_THREADS_sev() }
+    }
+
+    #[cfg(feature = "tickless")]
+    fn wait_for_interrupt() {
+        unsafe { __CORTEXM_THREADS_wfi() }
+    }
+
+    #[cfg(any(feature = "mpu-stack-guard", feature = "mpu-isolation"))]
+    fn barrier() {
+        unsafe { __CORTEXM_THREADS_barrier() }
+    }
+
+    fn spin(iterations: u32) {
+        unsafe { __CORTEXM_THREADS_spin(iterations) }
+    }
+
+    fn in_handler() -> bool {
+        unsafe { __CORTEXM_THREADS_ipsr() != 0 }
+    }
+
+    fn unprivileged() -> bool {
+        unsafe { __CORTEXM_THREADS_ipsr() == 0 && __CORTEXM_THREADS_control() & 1 != 0 }
+    }
+
+    #[cfg_attr(not(feature = "cortex-m4f"), allow(unused_variables))]
+    fn init_frame(stack: &mut [u32], entry: usize, args: [usize; 2], privileged: bool) -> usize {
+        // the exception frame must be 8 byte aligned, leave a word unused at the top if needed
+        let end = stack.as_ptr() as usize + stack.len() * 4;
+        let idx = if end & 7 == 0 {
+            stack.len() - 1
+        } else {
+            stack.len() - 2
+        };
+        stack[idx] = 1 << 24; // xPSR
+        stack[idx - 1] = entry as u32; // PC
+        stack[idx - 2] = 0xFFFFFFFD; // LR
+        stack[idx - 3] = 0xCCCCCCCC; // R12
+        stack[idx - 4] = 0x33333333; // R3
+        stack[idx - 5] = 0x22222222; // R2
+        stack[idx - 6] = args[1] as u32; // R1
+        stack[idx - 7] = args[0] as u32; // R0
+
+        // new threads start with a basic frame
+        #[cfg(not(feature = "cortex-m4f"))]
+        let top = idx - 7;
+        #[cfg(feature = "cortex-m4f")]
+        let top = {
+            stack[idx - 8] = if privileged { 0xFFFFFFF9 } else { 0xFFFFFFFD }; // EXC_RETURN
+            idx - 8
+        };
+        // aditional regs
+        stack[top - 1] = 0x77777777; // R7
+        stack[top - 2] = 0x66666666; // R6
+        stack[top - 3] = 0x55555555; // R5
+        stack[top - 4] = 0x44444444; // R4
+        stack[top - 5] = 0xBBBBBBBB; // R11
+        stack[top - 6] = 0xAAAAAAAA; // R10
+        stack[top - 7] = 0x99999999; // R9
+        stack[top - 8] = 0x88888888; // R8
+        top - 8
+    }
+
+    #[cfg(not(feature = "rp2040-amp"))]
+    fn set_kernel_state(state: usize) {
+        __CORTEXM_THREADS_GLOBAL_PTR.store(state as u32, Ordering::SeqCst);
+    }
+
+    #[cfg(feature = "rp2040-amp")]
+    fn set_kernel_state(state: usize) {
+        __CORTEXM_THREADS_GLOBAL_PTR[crate::amp::core_id()].store(state as u32, Ordering::SeqCst);
+    }
+
+    unsafe fn start() -> ! {
+        // without ASPEN, PendSV would never see FPU context to save
+        #[cfg(feature = "cortex-m4f")]
+        {
+            let fpccr = ptr::read_volatile(FPCCR as *const u32);
+            ptr::write_volatile(FPCCR as *mut u32, fpccr | FPCCR_ASPEN | FPCCR_LSPEN);
+        }
+        __CORTEXM_THREADS_start()
+    }
+
+    fn kernel_call(call: u32, a: usize, b: usize, c: usize) -> usize {
+        unsafe { __CORTEXM_THREADS_kernel_call(call, a, b, c) }
+    }
+
+    unsafe fn drop_privileges(msp: u32, msplim: u32, psplim: u32) {
+        __CORTEXM_THREADS_drop_privileges(msp, msplim, psplim)
+    }
+}
@@ -0,0 +1,83 @@
+//! Thread awareness for debuggers, with the `rtos-awareness` feature.
+//!
+//! OpenOCD, probe-rs and GDB scripts list the threads of an RTOS by reading its thread table
+//! from the target. The `_kernel_info` symbol describes where things are: the kernel state is
+//! at the address held by `__CORTEXM_THREADS_GLOBAL_PTR` once `init` ran, and `KernelInfo`
+//! gives the offsets of its fields and of the thread control block fields, which depend on the
+//! target and the features. With the saved stack pointer of a thread and the layout of the
+//! context PendSV saves, the debugger rebuilds the registers of each switched out thread and
+//! unwinds its stack.
+
+use core::mem::{offset_of, size_of};
+use core::ptr;
+
+use crate::{ThreadControlBlock, ThreadsState, MAX_THREADS};
+
+/// Saved context: r4-r11, then the exception frame pushed by the core
+pub const CONTEXT_R4_R11: u32 = 0;
+/// Saved context: r8-r11, r4-r7, then the exception frame (ARMv6-M and ARMv8-M baseline)
+pub const CONTEXT_R8_R11_R4_R7: u32 = 1;
+/// Saved context: r4-r11, EXC_RETURN, s16-s31 if bit 4 of EXC_RETURN is clear, then the
+/// exception frame, with FPU registers if bit 4 is clear (`cortex-m4f` feature)
+pub const CONTEXT_R4_R11_EXC_RETURN: u32 = 2;
+
+/// Layout of the kernel state, read by debuggers from the `_kernel_info` symbol. All offsets
+/// are in bytes.
+#[repr(C)]
+pub struct KernelInfo {
+    /// "CMTK" as a little-endian word
+    pub magic: u32,
+    /// version of this layout, bumped when fields are added
+    pub version: u32,
+    /// number of entries of the thread table, slot 0 being the idle thread
+    pub max_threads: u32,
+    /// offset in the kernel state of the address of the running thread's control block
+    pub curr: u32,
+    /// offset in the kernel state of the thread table
+    pub threads: u32,
+    /// size of a thread control block
+    pub tcb_size: u32,
+    /// offsets in a thread control block of the saved stack pointer, the status (one byte,
+    /// 0 for a free slot), the effective priority (one byte), the name (a `&str`: address
+    /// then length), the lowest address of the stack and its size in words
+    pub tcb_sp: u32,
+    pub tcb_status: u32,
+    pub tcb_priority: u32,
+    pub tcb_name: u32,
+    pub tcb_stack_base: u32,
+    pub tcb_stack_words: u32,
+    /// one of the `CONTEXT_*` constants
+    pub context: u32,
+}
+
+#[cfg(all(not(armv6m), not(feature = "cortex-m4f")))]
+const CONTEXT: u32 = CONTEXT_R4_R11;
+#[cfg(armv6m)]
+const CONTEXT: u32 = CONTEXT_R8_R11_R4_R7;
+#[cfg(all(not(armv6m), feature = "cortex-m4f"))]
+const CONTEXT: u32 = CONTEXT_R4_R11_EXC_RETURN;
+
+#[export_name = "_kernel_info"]
+#[used]
+static KERNEL_INFO: KernelInfo = KernelInfo {
+    magic: u32::from_le_bytes(*b"CMTK"),
+    version: 1,
+    max_threads: MAX_THREADS as u32,
+    curr: offset_of!(ThreadsState, curr) as u32,
+    threads: offset_of!(ThreadsState, threads) as u32,
+    tcb_size: size_of::<ThreadControlBlock>() as u32,
+    tcb_sp: offset_of!(ThreadControlBlock, sp) as u32,
+    tcb_status: offset_of!(ThreadControlBlock, status) as u32,
+    tcb_priority: offset_of!(ThreadControlBlock, priority) as u32,
+    tcb_name: offset_of!(ThreadControlBlock, name) as u32,
+    tcb_stack_base: offset_of!(ThreadControlBlock, stack_base) as u32,
+    tcb_stack_words: offset_of!(ThreadControlBlock, stack_words) as u32,
+    context: CONTEXT,
+};
+
+/// Reference `_kernel_info`, so the linker keeps it even though no code reads it
+pub(crate) fn keep() {
+    unsafe {
+        ptr::read_volatile(&KERNEL_INFO.magic);
+    }
+}
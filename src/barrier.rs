@@ -0,0 +1,157 @@
+//! Rendezvous of a fixed number of threads.
+//!
+//! A `Barrier` blocks threads calling `wait` until `n` of them have arrived, then releases
+//! them together. It can be reused: the next `n` calls form the next round.
+//!
+//! A thread which gives up with `wait_timeout` leaves the round it arrived in, so it does not
+//! count towards releasing it. A thread deleted while waiting does not: it still counts as
+//! arrived, and its round is released one thread early.
+//!
+//! Unprivileged threads wait through kernel calls, see `svc`.
+
+use core::cell::UnsafeCell;
+
+use crate::critical::CriticalSection;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{
+    block_current, block_enter, schedule, svc, wake_all, Error, ThreadsState, WakeReason,
+    NO_DEADLINE,
+};
+
+struct State {
+    /// threads waiting in the current round
+    arrived: usize,
+    /// number of completed rounds, tells waiters their round is over
+    round: u32,
+    waiters: WaitList,
+}
+
+impl State {
+    /// Count one more thread in the round, and release the round if it is the `n`th
+    fn arrive(&mut self, handler: &mut ThreadsState, n: usize) -> bool {
+        self.arrived += 1;
+        if self.arrived < n {
+            return false;
+        }
+        self.arrived = 0;
+        self.round = self.round.wrapping_add(1);
+        wake_all(handler, &mut self.waiters, WakeReason::Signaled);
+        true
+    }
+}
+
+/// Point where `n` threads wait for each other
+///
+/// Example:
+/// ```
+/// static STARTED: Barrier = Barrier::new(3);
+///
+/// // in each of the three periodic threads
+/// STARTED.wait();
+/// let mut period = Periodic::new(10);
+/// loop {
+///     work();
+///     period.next_period();
+/// }
+/// ```
+pub struct Barrier {
+    n: usize,
+    state: UnsafeCell<State>,
+}
+
+unsafe impl Sync for Barrier {}
+
+impl Barrier {
+    /// Create a barrier releasing threads once `n` of them wait on it. A barrier for zero or
+    /// one thread never blocks.
+    pub const fn new(n: usize) -> Self {
+        Barrier {
+            n,
+            state: UnsafeCell::new(State {
+                arrived: 0,
+                round: 0,
+                waiters: WaitList::new(WaitOrder::Fifo),
+            }),
+        }
+    }
+
+    /// Block until `n` threads are waiting, then release all of them. Returns true in the
+    /// thread which arrived last, e.g. to let one of them report the round, false in the
+    /// others.
+    pub fn wait(&self) -> bool {
+        match self.wait_until(NO_DEADLINE) {
+            Ok(last) => last,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `wait`, but gives up with Err(Error::TimedOut) if the round was not released
+    /// within `ticks` ticks. The thread then no longer counts as arrived.
+    pub fn wait_timeout(&self, ticks: u32) -> Result<bool, Error> {
+        self.wait_until(crate::ticks() + ticks as u64)
+    }
+
+    fn wait_until(&self, deadline: u64) -> Result<bool, Error> {
+        if svc::from_unprivileged_thread() {
+            return svc::barrier_wait(self as *const Self as usize, deadline);
+        }
+        let state = self.state.get();
+        unsafe {
+            let mut cs = CriticalSection::enter();
+            if (*state).arrive(crate::state(&mut cs), self.n) {
+                drop(cs);
+                schedule();
+                return Ok(true);
+            }
+            let round = (*state).round;
+            while (*state).round == round {
+                let reason = block_current(cs, &mut (*state).waiters, deadline);
+                cs = CriticalSection::enter();
+                // released at the deadline counts as released
+                if reason == WakeReason::Timeout && (*state).round == round {
+                    (*state).arrived -= 1;
+                    return Err(Error::TimedOut);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Kernel part of `wait_until` for the barrier at address `barrier`, run by a kernel call:
+/// return Ok(2) if the caller releases the round, Ok(1) once its round was released, or block
+/// the caller until `deadline` and return Ok(0). `retry` is set when the thread runs again
+/// after blocking: the round was released if the waker took it off the wait list.
+///
+/// # Safety
+/// `barrier` must be the address of a live `Barrier`
+pub(crate) unsafe fn wait_or_block(
+    handler: &mut ThreadsState,
+    barrier: usize,
+    deadline: u64,
+    retry: bool,
+) -> Result<usize, Error> {
+    let barrier = &*(barrier as *const Barrier);
+    let state = &mut *barrier.state.get();
+    let idx = handler.idx;
+    if !retry {
+        if state.arrive(handler, barrier.n) {
+            return Ok(2);
+        }
+    } else if !state.waiters.contains(idx) {
+        return Ok(1);
+    } else if handler.threads[idx].wake_reason == WakeReason::Timeout {
+        state.waiters.remove(idx);
+        state.arrived = state.arrived.saturating_sub(1);
+        return Err(Error::TimedOut);
+    }
+    let waiters = &mut state.waiters;
+    match block_enter(handler, deadline, |_, idx| waiters.insert(idx)) {
+        None => Ok(0),
+        Some(_) => {
+            state.waiters.remove(idx);
+            state.arrived = state.arrived.saturating_sub(1);
+            Err(Error::TimedOut)
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! One-to-many signalling between threads and interrupt handlers.
+//!
+//! A `Broadcast` delivers each published value to every subscriber: each has its own slot,
+//! holding a clone of the latest value it did not take yet, and all waiting subscribers are
+//! woken by a publish. As with a `Mailbox`, a slow subscriber gets the most recent value
+//! instead of working through a backlog, e.g. the current system state.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+
+use crate::critical::CriticalSection;
+use crate::select::sealed::Source;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, wake_all, Error, WakeReason, NO_DEADLINE};
+
+struct Slot<T> {
+    subscribed: bool,
+    value: Option<T>,
+}
+
+impl<T> Slot<T> {
+    const FREE: Slot<T> = Slot {
+        subscribed: false,
+        value: None,
+    };
+}
+
+/// Channel delivering values of type `T` to up to `SUBS` subscribers. Publishers and
+/// subscribers must be privileged threads or interrupt handlers, see `svc`.
+///
+/// Example:
+/// ```
+/// static STATE: Broadcast<SystemState, 4> = Broadcast::new();
+///
+/// // power manager thread
+/// STATE.publish(SystemState::LowBattery);
+///
+/// // display, logger and radio threads each
+/// let mut state = STATE.subscribe().unwrap();
+/// loop {
+///     match state.recv() {
+///         SystemState::LowBattery => dim(),
+///         _ => {}
+///     }
+/// }
+/// ```
+pub struct Broadcast<T, const SUBS: usize> {
+    slots: UnsafeCell<[Slot<T>; SUBS]>,
+    readers: UnsafeCell<WaitList>,
+}
+
+unsafe impl<T: Send, const SUBS: usize> Sync for Broadcast<T, SUBS> {}
+
+impl<T, const SUBS: usize> Broadcast<T, SUBS> {
+    pub const fn new() -> Self {
+        Broadcast {
+            slots: UnsafeCell::new([Slot::FREE; SUBS]),
+            readers: UnsafeCell::new(WaitList::new(WaitOrder::Priority)),
+        }
+    }
+
+    /// Take a free subscriber slot. Returns None if all `SUBS` slots are taken. The
+    /// subscriber gets the values published from now on.
+    pub fn subscribe(&self) -> Option<Subscriber<'_, T, SUBS>> {
+        let _cs = CriticalSection::enter();
+        let slots = unsafe { &mut *self.slots.get() };
+        let idx = slots.iter().position(|slot| !slot.subscribed)?;
+        slots[idx].subscribed = true;
+        Some(Subscriber {
+            broadcast: self,
+            idx,
+            _not_sync: PhantomData,
+        })
+    }
+
+    /// Give every subscriber a clone of `value`, replacing the ones they did not take, and
+    /// wake the subscribers waiting in `recv`. Returns the number of subscribers. Values are
+    /// cloned and replaced ones dropped with interrupts disabled, so cloning should be cheap.
+    /// Can be called from interrupt handlers.
+    pub fn publish(&self, value: T) -> usize
+    where
+        T: Clone,
+    {
+        let count = {
+            let mut cs = CriticalSection::enter();
+            let slots = unsafe { &mut *self.slots.get() };
+            let mut count = 0;
+            for slot in slots.iter_mut().filter(|slot| slot.subscribed) {
+                slot.value = Some(value.clone());
+                count += 1;
+            }
+            wake_all(
+                crate::state(&mut cs),
+                unsafe { &mut *self.readers.get() },
+                WakeReason::Signaled,
+            );
+            count
+        };
+        schedule();
+        count
+    }
+
+    /// Number of subscribers
+    pub fn subscribers(&self) -> usize {
+        let _cs = CriticalSection::enter();
+        let slots = unsafe { &*self.slots.get() };
+        slots.iter().filter(|slot| slot.subscribed).count()
+    }
+
+    /// Take the value in slot `idx`, inside a critical section
+    fn take(&self, idx: usize) -> Option<T> {
+        unsafe { (*self.slots.get())[idx].value.take() }
+    }
+}
+
+impl<T, const SUBS: usize> Default for Broadcast<T, SUBS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Subscription to a `Broadcast`, owned by one thread. Its slot is freed when it is dropped.
+pub struct Subscriber<'a, T, const SUBS: usize> {
+    broadcast: &'a Broadcast<T, SUBS>,
+    idx: usize,
+    // the slot has a single reader
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send, const SUBS: usize> Send for Subscriber<'_, T, SUBS> {}
+
+impl<T, const SUBS: usize> Subscriber<'_, T, SUBS> {
+    /// Take the value published since the last `recv`, blocking until one is published
+    pub fn recv(&mut self) -> T {
+        match self.recv_until(NO_DEADLINE) {
+            Ok(value) => value,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `recv`, but gives up with Err(Error::TimedOut) if nothing was published within
+    /// `ticks` ticks
+    pub fn recv_timeout(&mut self, ticks: u32) -> Result<T, Error> {
+        self.recv_until(crate::ticks() + ticks as u64)
+    }
+
+    /// Take the value published since the last `recv`, if any, without blocking
+    pub fn try_recv(&mut self) -> Option<T> {
+        let _cs = CriticalSection::enter();
+        self.broadcast.take(self.idx)
+    }
+
+    fn recv_until(&mut self, deadline: u64) -> Result<T, Error> {
+        let broadcast = self.broadcast;
+        let mut cs = CriticalSection::enter();
+        loop {
+            if let Some(value) = broadcast.take(self.idx) {
+                return Ok(value);
+            }
+            let reason = unsafe { block_current(cs, broadcast.readers.get(), deadline) };
+            if reason == WakeReason::Timeout {
+                let _cs = CriticalSection::enter();
+                return broadcast.take(self.idx).ok_or(Error::TimedOut);
+            }
+            cs = CriticalSection::enter();
+        }
+    }
+}
+
+impl<T, const SUBS: usize> Drop for Subscriber<'_, T, SUBS> {
+    fn drop(&mut self) {
+        let value = {
+            let _cs = CriticalSection::enter();
+            let slot = unsafe { &mut (*self.broadcast.slots.get())[self.idx] };
+            slot.subscribed = false;
+            slot.value.take()
+        };
+        // dropped outside the critical section
+        drop(value);
+    }
+}
+
+impl<T, const SUBS: usize> Source for Subscriber<'_, T, SUBS> {
+    fn ready(&self) -> bool {
+        unsafe { (*self.broadcast.slots.get())[self.idx].value.is_some() }
+    }
+
+    fn wait_list(&self) -> *mut WaitList {
+        self.broadcast.readers.get()
+    }
+}
@@ -0,0 +1,100 @@
+//! Execution budget for user callbacks run from scheduler context.
+//!
+//! Every hook the kernel calls from scheduler, interrupt, fault or idle context goes through
+//! `run_callback`: the switch, trace, lifecycle, stack overflow, inversion, deadlock, budget
+//! supervisor, crash, tick and idle hooks. The panic and deadline miss hooks run in the thread
+//! they are about and take its time, like the rest of its code. With the `callback-budget`
+//! feature, the time spent in the callback is measured with the DWT cycle counter
+//! (Cortex-M3 and above) and compared with a configurable budget. An overrun is reported to
+//! a user diagnostic function instead of silently stretching the tick or switch path.
+//! Without the feature, `run_callback` is a plain call.
+
+#[cfg(feature = "callback-budget")]
+use core::ptr;
+
+#[cfg(feature = "callback-budget")]
+const DEMCR: u32 = 0xE000EDFC;
+#[cfg(feature = "callback-budget")]
+const DWT_CTRL: u32 = 0xE0001000;
+#[cfg(feature = "callback-budget")]
+const DWT_CYCCNT: u32 = 0xE0001004;
+
+/// Cycle budget of callbacks, kept in the kernel state
+#[cfg(feature = "callback-budget")]
+#[derive(Clone, Copy)]
+pub(crate) struct Budget {
+    cycles: u32,
+    on_overrun: Option<fn(&'static str, u32)>,
+}
+
+/// Cycle budget of callbacks, kept in the kernel state
+#[cfg(not(feature = "callback-budget"))]
+#[derive(Clone, Copy)]
+pub(crate) struct Budget;
+
+impl Budget {
+    pub(crate) const fn new() -> Self {
+        #[cfg(feature = "callback-budget")]
+        {
+            Budget {
+                cycles: 0,
+                on_overrun: None,
+            }
+        }
+        #[cfg(not(feature = "callback-budget"))]
+        {
+            Budget
+        }
+    }
+}
+
+/// Set the maximum number of cycles a user callback may run from scheduler context, and the
+/// function called with the callback's name and measured cycles when it overruns. A budget
+/// of 0 disables the check. Enables the DWT cycle counter.
+///
+/// # Example
+/// ```
+/// fn overrun(name: &'static str, cycles: u32) {
+///     let _ = hprintln!("callback {} took {} cycles", name, cycles);
+/// }
+/// set_callback_budget(2_000, overrun);
+/// ```
+#[cfg(feature = "callback-budget")]
+pub fn set_callback_budget(cycles: u32, on_overrun: fn(&'static str, u32)) {
+    unsafe {
+        let demcr = ptr::read_volatile(DEMCR as *const u32);
+        ptr::write_volatile(DEMCR as *mut u32, demcr | 1 << 24);
+        let ctrl = ptr::read_volatile(DWT_CTRL as *const u32);
+        ptr::write_volatile(DWT_CTRL as *mut u32, ctrl | 1);
+    }
+    crate::with_state(|s| {
+        s.callback_budget = Budget {
+            cycles,
+            on_overrun: Some(on_overrun),
+        }
+    });
+}
+
+/// Run user callback `f`, named `name` in overrun reports, checking it against `budget`
+#[cfg(feature = "callback-budget")]
+pub(crate) fn run_callback<R>(budget: &Budget, name: &'static str, f: impl FnOnce() -> R) -> R {
+    if budget.cycles == 0 {
+        return f();
+    }
+    let start = unsafe { ptr::read_volatile(DWT_CYCCNT as *const u32) };
+    let ret = f();
+    let spent = unsafe { ptr::read_volatile(DWT_CYCCNT as *const u32) }.wrapping_sub(start);
+    if spent > budget.cycles {
+        if let Some(on_overrun) = budget.on_overrun {
+            on_overrun(name, spent);
+        }
+    }
+    ret
+}
+
+/// Run user callback `f`
+#[cfg(not(feature = "callback-budget"))]
+#[inline(always)]
+pub(crate) fn run_callback<R>(_budget: &Budget, _name: &'static str, f: impl FnOnce() -> R) -> R {
+    f()
+}
@@ -0,0 +1,185 @@
+//! Zero-copy passing of pool buffers between threads.
+//!
+//! A `BufQueue` is a `Pool` with a queue of its blocks. A producer allocates a buffer from
+//! it, fills it in place and sends it; the consumer receives the same block, and the block
+//! goes back to the pool when the consumer drops it. Only the block index travels through the
+//! queue, so large frames are never copied. The queue has a slot for every block, so sending
+//! never fails or blocks, and can be done from interrupt handlers.
+
+use core::cell::UnsafeCell;
+use core::mem;
+
+use crate::critical::CriticalSection;
+use crate::select::sealed::Source;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, wake_one, Error, Pool, PoolBox, WakeReason, NO_DEADLINE};
+
+/// Blocks sent and not yet received, in sending order
+struct Queue<const N: usize> {
+    slots: [u16; N],
+    /// slot of the oldest block
+    head: usize,
+    len: usize,
+}
+
+/// `N` buffers of type `T`, and a queue passing them from producers to consumers. Only
+/// privileged threads and interrupt handlers can use it, see `svc`.
+///
+/// Example:
+/// ```
+/// static FRAMES: BufQueue<[u8; 2048], 4> = BufQueue::new();
+///
+/// // radio thread
+/// loop {
+///     if let Ok(mut frame) = FRAMES.alloc([0; 2048]) {
+///         radio.receive(&mut frame[..]);
+///         FRAMES.send(frame);
+///     }
+/// }
+///
+/// // protocol thread, the buffer returns to the pool at the end of each iteration
+/// loop {
+///     let frame = FRAMES.recv();
+///     handle(&frame[..]);
+/// }
+/// ```
+pub struct BufQueue<T, const N: usize> {
+    pool: Pool<T, N>,
+    queue: UnsafeCell<Queue<N>>,
+    receivers: UnsafeCell<WaitList>,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for BufQueue<T, N> {}
+
+impl<T, const N: usize> BufQueue<T, N> {
+    pub const fn new() -> Self {
+        BufQueue {
+            pool: Pool::new(),
+            queue: UnsafeCell::new(Queue {
+                slots: [0; N],
+                head: 0,
+                len: 0,
+            }),
+            receivers: UnsafeCell::new(WaitList::new(WaitOrder::Priority)),
+        }
+    }
+
+    /// Move `value` into a free buffer, to be filled and sent. Gives `value` back if all
+    /// buffers are in use.
+    pub fn alloc(&self, value: T) -> Result<PoolBox<'_, T, N>, T> {
+        self.pool.alloc(value)
+    }
+
+    /// Number of free buffers
+    pub fn available(&self) -> usize {
+        self.pool.available()
+    }
+
+    /// Queue buffer `buf` and wake the highest priority thread waiting in `recv`. Can be
+    /// called from interrupt handlers. Panics if `buf` was not allocated from this queue.
+    pub fn send(&self, buf: PoolBox<'_, T, N>) {
+        assert!(
+            core::ptr::eq(buf.pool, &self.pool),
+            "BufQueue::send: buffer from another pool"
+        );
+        let idx = buf.idx;
+        // the block now belongs to the queue, until a receiver takes it
+        mem::forget(buf);
+        {
+            let mut cs = CriticalSection::enter();
+            let queue = unsafe { &mut *self.queue.get() };
+            queue.slots[(queue.head + queue.len) % N] = idx as u16;
+            queue.len += 1;
+            wake_one(
+                crate::state(&mut cs),
+                unsafe { &mut *self.receivers.get() },
+                WakeReason::Signaled,
+            );
+        }
+        schedule();
+    }
+
+    /// Take the oldest buffer sent, blocking until one is sent
+    pub fn recv(&self) -> PoolBox<'_, T, N> {
+        match self.recv_until(NO_DEADLINE) {
+            Ok(buf) => buf,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `recv`, but gives up with Err(Error::TimedOut) if nothing was sent within `ticks`
+    /// ticks
+    pub fn recv_timeout(&self, ticks: u32) -> Result<PoolBox<'_, T, N>, Error> {
+        self.recv_until(crate::ticks() + ticks as u64)
+    }
+
+    /// Take the oldest buffer sent, if any, without blocking
+    pub fn try_recv(&self) -> Option<PoolBox<'_, T, N>> {
+        let _cs = CriticalSection::enter();
+        self.take()
+    }
+
+    /// Number of buffers sent and not yet received
+    pub fn len(&self) -> usize {
+        let _cs = CriticalSection::enter();
+        unsafe { (*self.queue.get()).len }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take the oldest buffer, inside a critical section
+    fn take(&self) -> Option<PoolBox<'_, T, N>> {
+        let queue = unsafe { &mut *self.queue.get() };
+        if queue.len == 0 {
+            return None;
+        }
+        let idx = queue.slots[queue.head] as usize;
+        queue.head = (queue.head + 1) % N;
+        queue.len -= 1;
+        Some(PoolBox {
+            pool: &self.pool,
+            idx,
+        })
+    }
+
+    fn recv_until(&self, deadline: u64) -> Result<PoolBox<'_, T, N>, Error> {
+        unsafe {
+            let mut cs = CriticalSection::enter();
+            loop {
+                if let Some(buf) = self.take() {
+                    return Ok(buf);
+                }
+                if block_current(cs, self.receivers.get(), deadline) == WakeReason::Timeout {
+                    let _cs = CriticalSection::enter();
+                    return self.take().ok_or(Error::TimedOut);
+                }
+                cs = CriticalSection::enter();
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Default for BufQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for BufQueue<T, N> {
+    fn drop(&mut self) {
+        // drop the values of the buffers nobody received
+        while self.take().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Source for BufQueue<T, N> {
+    fn ready(&self) -> bool {
+        unsafe { (*self.queue.get()).len > 0 }
+    }
+
+    fn wait_list(&self) -> *mut WaitList {
+        self.receivers.get()
+    }
+}
@@ -0,0 +1,184 @@
+//! Thread configuration by name instead of position.
+//!
+//! `Thread` collects the configuration of a thread and creates it with `spawn`. Options left
+//! out keep their defaults, and options added later do not change the calls already
+//! written, unlike the arguments of `create_thread_with_config` and its variants.
+
+use crate::{
+    create_thread_closure_with_config, create_thread_with_arg_and_config,
+    create_thread_with_config, scheduler_resume, scheduler_suspend, set_thread_name, Error,
+    ThreadHandle,
+};
+#[cfg(feature = "mpu-isolation")]
+use crate::{set_thread_regions, MpuRegion, MPU_REGIONS};
+
+// public in a private module, so the trait cannot be implemented outside the crate
+mod sealed {
+    use crate::{Error, ThreadHandle};
+
+    /// What a thread runs, see `Thread::new`, `Thread::with_arg` and `Thread::closure`
+    pub trait Entry {
+        fn create(
+            self,
+            stack: &'static mut [u32],
+            priority: u8,
+            privileged: bool,
+        ) -> Result<ThreadHandle, Error>;
+    }
+}
+
+use sealed::Entry;
+
+/// `handler_fn(arg)`
+pub struct WithArg {
+    handler_fn: fn(usize) -> !,
+    arg: usize,
+}
+
+/// A closure, stored at the top of the stack
+pub struct Closure<F>(F);
+
+impl Entry for fn() -> ! {
+    fn create(
+        self,
+        stack: &'static mut [u32],
+        priority: u8,
+        privileged: bool,
+    ) -> Result<ThreadHandle, Error> {
+        create_thread_with_config(stack, self, priority, privileged)
+    }
+}
+
+impl Entry for WithArg {
+    fn create(
+        self,
+        stack: &'static mut [u32],
+        priority: u8,
+        privileged: bool,
+    ) -> Result<ThreadHandle, Error> {
+        create_thread_with_arg_and_config(stack, self.handler_fn, self.arg, priority, privileged)
+    }
+}
+
+impl<F> Entry for Closure<F>
+where
+    F: FnOnce() + Send + 'static,
+{
+    fn create(
+        self,
+        stack: &'static mut [u32],
+        priority: u8,
+        privileged: bool,
+    ) -> Result<ThreadHandle, Error> {
+        create_thread_closure_with_config(stack, self.0, priority, privileged)
+    }
+}
+
+/// Configuration of a thread to create. By default it has the lowest priority, runs
+/// unprivileged, and is unnamed.
+///
+/// # Example
+/// ```
+/// static STACK1: Stack<512> = Stack::new();
+///
+/// let comms = Thread::new(STACK1.take().unwrap(), comms_task)
+///     .priority(3)
+///     .privileged()
+///     .name("comms")
+///     .spawn()?;
+/// ```
+pub struct Thread<E> {
+    stack: &'static mut [u32],
+    entry: E,
+    priority: u8,
+    privileged: bool,
+    name: Option<&'static str>,
+    #[cfg(feature = "mpu-isolation")]
+    regions: &'static [MpuRegion],
+}
+
+impl Thread<fn() -> !> {
+    /// A thread running `handler_fn` on `stack`, see `create_thread`
+    pub fn new(stack: &'static mut [u32], handler_fn: fn() -> !) -> Self {
+        Thread::with_entry(stack, handler_fn)
+    }
+}
+
+impl Thread<WithArg> {
+    /// A thread running `handler_fn(arg)` on `stack`, see `create_thread_with_arg`
+    pub fn with_arg(stack: &'static mut [u32], handler_fn: fn(usize) -> !, arg: usize) -> Self {
+        Thread::with_entry(stack, WithArg { handler_fn, arg })
+    }
+}
+
+impl<F> Thread<Closure<F>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    /// A thread running closure `f` on `stack`, see `create_thread_closure`
+    pub fn closure(stack: &'static mut [u32], f: F) -> Self {
+        Thread::with_entry(stack, Closure(f))
+    }
+}
+
+impl<E: Entry> Thread<E> {
+    fn with_entry(stack: &'static mut [u32], entry: E) -> Self {
+        Thread {
+            stack,
+            entry,
+            priority: 0x00,
+            privileged: false,
+            name: None,
+            #[cfg(feature = "mpu-isolation")]
+            regions: &[],
+        }
+    }
+
+    /// Higher numeric value means higher priority
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Run the thread in privileged mode
+    pub fn privileged(mut self) -> Self {
+        self.privileged = true;
+        self
+    }
+
+    /// Name shown by `thread_info` and `for_each_thread`, see `set_thread_name`
+    pub fn name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Memory the thread may access besides its stack, see `set_thread_regions`
+    #[cfg(feature = "mpu-isolation")]
+    pub fn regions(mut self, regions: &'static [MpuRegion]) -> Self {
+        self.regions = regions;
+        self
+    }
+
+    /// Create the thread. Returns the id of the created thread, which can be passed to
+    /// `join`.
+    pub fn spawn(self) -> Result<ThreadHandle, Error> {
+        #[cfg(feature = "mpu-isolation")]
+        if self.regions.len() > MPU_REGIONS {
+            return Err(Error::BadRegion);
+        }
+        // the thread must not run before it is named
+        scheduler_suspend();
+        let created = self
+            .entry
+            .create(self.stack, self.priority, self.privileged);
+        if let (Ok(thread), Some(name)) = (created, self.name) {
+            let _ = set_thread_name(thread, name);
+        }
+        #[cfg(feature = "mpu-isolation")]
+        if let Ok(thread) = created {
+            let _ = set_thread_regions(thread, self.regions);
+        }
+        scheduler_resume();
+        created
+    }
+}
@@ -0,0 +1,118 @@
+//! Busy-wait delays shorter than a tick.
+//!
+//! `sleep` cannot wait less than a tick, and a thread switch costs more than a delay of a few
+//! microseconds. `delay_us` spins instead, without giving up the processor. On ARMv7-M and
+//! ARMv8-M mainline it counts processor cycles with the DWT cycle counter. The counter is
+//! private to privileged code, and Cortex-M0/M0+/M23 have none, so there a loop of known
+//! length is run, calibrated against SysTick when `init` starts.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::arch::{Arch, Port};
+use crate::critical::with_critical;
+use crate::cycles::{SYST_CSR, SYST_CVR, SYST_RVR};
+use crate::tick_hz;
+#[cfg(not(armv6m))]
+use crate::{cycles, svc};
+
+/// Iterations of the loop timed by `calibrate`
+const CALIBRATION_SPINS: u32 = 64;
+
+/// Processor clock in Hz, 0 while unknown
+static CORE_HZ: AtomicU32 = AtomicU32::new(0);
+/// Cycles taken by one iteration of `Port::spin`, an estimate until calibrated
+static SPIN_CYCLES: AtomicU32 = AtomicU32::new(4);
+
+/// Set the processor clock frequency used by `delay_us`. `init_with_systick` sets it, and
+/// `init` derives it from the SysTick reload value and `tick_hz` when SysTick runs from the
+/// processor clock; other applications must call it.
+pub fn set_core_hz(hz: u32) {
+    CORE_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Processor clock frequency as known to `delay_us`, 0 if unknown
+pub fn core_hz() -> u32 {
+    CORE_HZ.load(Ordering::Relaxed)
+}
+
+/// Busy-wait for at least `us` microseconds, without switching threads. Interrupts and
+/// threads of higher priority may make it last longer. Usable from threads and handlers.
+/// Panics if the processor clock is unknown, see `set_core_hz`.
+///
+/// # Example
+/// ```
+/// cs_pin.set_low();
+/// delay_us(5); // setup time of the device
+/// spi.write(&command);
+/// ```
+pub fn delay_us(us: u32) {
+    // the simulated processor is infinitely fast
+    if cfg!(feature = "sim") {
+        return;
+    }
+    let hz = core_hz();
+    assert!(
+        hz != 0,
+        "delay_us: the processor clock is unknown, see set_core_hz"
+    );
+    let cycles = (us as u64 * hz as u64).div_ceil(1_000_000);
+    #[cfg(not(armv6m))]
+    if !svc::from_unprivileged_thread() {
+        wait_cycles(cycles);
+        return;
+    }
+    let per_spin = SPIN_CYCLES.load(Ordering::Relaxed) as u64;
+    spin(cycles.div_ceil(per_spin));
+}
+
+/// Wait for `cycles` cycles of the DWT cycle counter
+#[cfg(not(armv6m))]
+fn wait_cycles(mut cycles: u64) {
+    // the counter wraps at 32 bits, wait in chunks it cannot wrap past
+    while cycles > 0 {
+        let chunk = cycles.min(1 << 31) as u32;
+        let start = cycles::count();
+        while cycles::count().wrapping_sub(start) < chunk {}
+        cycles -= chunk as u64;
+    }
+}
+
+fn spin(mut iterations: u64) {
+    while iterations > 0 {
+        let chunk = iterations.min(u32::MAX as u64);
+        Port::spin(chunk as u32);
+        iterations -= chunk;
+    }
+}
+
+/// Time the spin loop with SysTick, and take the processor clock from SysTick if it is still
+/// unknown. Does nothing unless SysTick is running from the processor clock. Called by `init`,
+/// in privileged mode.
+pub(crate) fn calibrate() {
+    with_critical(|| unsafe {
+        let csr = ptr::read_volatile(SYST_CSR as *const u32);
+        let reload = ptr::read_volatile(SYST_RVR as *const u32);
+        // ENABLE and CLKSOURCE, and a period the loop cannot wrap past twice
+        if csr & 0b101 != 0b101 || reload < CALIBRATION_SPINS * 16 {
+            return;
+        }
+        let before = ptr::read_volatile(SYST_CVR as *const u32);
+        Port::spin(CALIBRATION_SPINS);
+        let after = ptr::read_volatile(SYST_CVR as *const u32);
+        // SysTick counts down, and restarts from the reload value after 0
+        let elapsed = if after <= before {
+            before - after
+        } else {
+            before + reload + 1 - after
+        };
+        SPIN_CYCLES.store(
+            elapsed.div_ceil(CALIBRATION_SPINS).max(1),
+            Ordering::Relaxed,
+        );
+        if core_hz() == 0 {
+            let hz = (reload as u64 + 1) * tick_hz() as u64;
+            set_core_hz(hz.min(u32::MAX as u64) as u32);
+        }
+    })
+}
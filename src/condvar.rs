@@ -0,0 +1,125 @@
+//! Condition variables, for waiting until data protected by a `Mutex` changes.
+//!
+//! A thread holding a mutex waits on a `CondVar`, which unlocks the mutex and blocks the thread
+//! in the same critical section, so a notification sent between the two cannot be missed. The
+//! mutex is locked again before `wait` returns.
+//!
+//! Unprivileged threads wait and notify through kernel calls, see `svc`.
+
+use core::cell::UnsafeCell;
+use core::mem;
+
+use crate::critical::CriticalSection;
+use crate::mutex::MutexGuard;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, svc, wake_all, wake_one, Error, WakeReason, NO_DEADLINE};
+
+/// Threads waiting for a condition on data protected by a `Mutex`
+///
+/// Example:
+/// ```
+/// static QUEUE: Mutex<Deque<Job, 8>> = Mutex::new(Deque::new());
+/// static NOT_EMPTY: CondVar = CondVar::new();
+///
+/// // producer
+/// QUEUE.lock().push_back(job);
+/// NOT_EMPTY.notify_one();
+///
+/// // consumer
+/// let mut queue = QUEUE.lock();
+/// while queue.is_empty() {
+///     queue = NOT_EMPTY.wait(queue);
+/// }
+/// let job = queue.pop_front();
+/// ```
+pub struct CondVar {
+    waiters: UnsafeCell<WaitList>,
+}
+
+unsafe impl Sync for CondVar {}
+
+impl CondVar {
+    pub const fn new() -> Self {
+        CondVar {
+            waiters: UnsafeCell::new(WaitList::new(WaitOrder::Priority)),
+        }
+    }
+
+    /// Unlock the mutex of `guard` and block until notified, then lock the mutex again.
+    /// Wakeups do not guarantee the condition holds, so check it again in a loop.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_until(guard, NO_DEADLINE).0
+    }
+
+    /// Like `wait`, but also returns Err(Error::TimedOut) if not notified within `ticks`
+    /// ticks. The mutex is locked again in either case.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        ticks: u32,
+    ) -> (MutexGuard<'a, T>, Result<(), Error>) {
+        self.wait_until(guard, crate::ticks() + ticks as u64)
+    }
+
+    /// Wake the highest priority waiting thread
+    pub fn notify_one(&self) {
+        if svc::from_unprivileged_thread() {
+            return svc::wake_list(self.waiters.get() as usize, false);
+        }
+        {
+            let mut cs = CriticalSection::enter();
+            wake_one(
+                crate::state(&mut cs),
+                unsafe { &mut *self.waiters.get() },
+                WakeReason::Signaled,
+            );
+        }
+        schedule();
+    }
+
+    /// Wake all waiting threads
+    pub fn notify_all(&self) {
+        if svc::from_unprivileged_thread() {
+            return svc::wake_list(self.waiters.get() as usize, true);
+        }
+        {
+            let mut cs = CriticalSection::enter();
+            wake_all(
+                crate::state(&mut cs),
+                unsafe { &mut *self.waiters.get() },
+                WakeReason::Signaled,
+            );
+        }
+        schedule();
+    }
+
+    fn wait_until<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: u64,
+    ) -> (MutexGuard<'a, T>, Result<(), Error>) {
+        let mutex = guard.mutex;
+        // unlocked below, inside the critical section the thread blocks in
+        mem::forget(guard);
+        let reason = if svc::from_unprivileged_thread() {
+            let lock = mutex as *const _ as usize;
+            svc::condvar_wait(self.waiters.get() as usize, lock, deadline)
+        } else {
+            let mut cs = CriticalSection::enter();
+            mutex.release(crate::state(&mut cs));
+            unsafe { block_current(cs, self.waiters.get(), deadline) }
+        };
+        let guard = mutex.lock();
+        if reason == WakeReason::Timeout {
+            (guard, Err(Error::TimedOut))
+        } else {
+            (guard, Ok(()))
+        }
+    }
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,121 @@
+//! Panic containment, stopping or restarting a panicking thread instead of the whole system.
+//!
+//! The `#[panic_handler]` belongs to the application, so the kernel cannot catch panics by
+//! itself: the application's handler calls `contain_panic` first. For a panic raised by a
+//! thread, it tells the hook set with `set_panic_hook`, then terminates the thread or starts
+//! it over and never returns. It returns for panics it cannot contain: those raised in an
+//! interrupt handler, in the idle thread, before `init`, or inside the kernel with its state
+//! possibly half updated, which the application handles as before. Unprivileged threads
+//! have their panics contained through kernel calls.
+//!
+//! A thread cannot rebuild its own stack while running on it, so a restarted thread gives up
+//! the CPU with its slot reserved, and the scheduler starts it over once it is switched out.
+
+use core::panic::PanicInfo;
+
+use crate::arch::{Arch, Port};
+use crate::lifecycle::{self, ThreadEvent};
+use crate::{
+    critical, insert_tcb, record, release_thread, renew_tcb, schedule, svc, with_state,
+    ThreadHandle, ThreadsState, TraceKind,
+};
+
+/// What to do with a thread which panicked, returned by the hook set with `set_panic_hook`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PanicAction {
+    /// terminate the thread, as if it called `exit`
+    Exit,
+    /// start the thread over from its entry function, see `restart`. Threads running a
+    /// closure cannot be restarted, they exit instead.
+    Restart,
+}
+
+/// Set a function called with the id of a thread which panicked and the panic information,
+/// returning what to do with the thread. It runs in the panicking thread, from
+/// `contain_panic`. Without a hook, panicking threads exit.
+///
+/// # Example
+/// ```
+/// fn thread_panicked(thread: ThreadHandle, info: &PanicInfo) -> PanicAction {
+///     let _ = hprintln!("thread {} {}", thread.id(), info);
+///     PanicAction::Restart
+/// }
+/// set_panic_hook(thread_panicked);
+/// ```
+pub fn set_panic_hook(hook: fn(ThreadHandle, &PanicInfo) -> PanicAction) {
+    with_state(|s| s.panic_hook = Some(hook));
+}
+
+/// Contain a panic to the thread which raised it, to be called first thing in the
+/// application's panic handler. Does not return if the panic was raised by a thread, see the
+/// module documentation for the panics which are not contained.
+///
+/// # Example
+/// ```
+/// #[panic_handler]
+/// fn panic(info: &PanicInfo) -> ! {
+///     contain_panic(info);
+///     // not raised by a thread
+///     cortex_m::peripheral::SCB::sys_reset()
+/// }
+/// ```
+pub fn contain_panic(info: &PanicInfo) {
+    if critical::held() || Port::in_handler() {
+        return;
+    }
+    if svc::from_unprivileged_thread() {
+        // the idle thread runs privileged, and threads only run unprivileged after init
+        let action = match svc::panic_hook() {
+            Some(hook) => hook(svc::thread_id(), info),
+            None => PanicAction::Exit,
+        };
+        svc::panicked(action == PanicAction::Restart);
+        loop {
+            Port::wait_for_event();
+        }
+    }
+    let (inited, idx, hook) = with_state(|s| (s.inited, s.idx, s.panic_hook));
+    if !inited || idx == 0 {
+        return;
+    }
+    // a panic inside a `CriticalGuard` leaves interrupts disabled, and the thread could not
+    // give up the CPU
+    Port::restore_interrupts(0);
+    let action = match hook {
+        Some(hook) => hook(ThreadHandle(idx), info),
+        None => PanicAction::Exit,
+    };
+    with_state(|s| stop_panicked(s, idx, action == PanicAction::Restart));
+    schedule();
+    loop {
+        Port::wait_for_event();
+    }
+}
+
+/// Terminate thread `idx`, which panicked, as if it called `exit`. With `restart`, keep its
+/// slot for it to be started over once switched out instead, unless it cannot be restarted.
+pub(crate) fn stop_panicked(s: &mut ThreadsState, idx: usize, restart: bool) {
+    let restart = restart && s.threads[idx].restartable;
+    release_thread(s, idx);
+    if restart {
+        s.restarts |= 1 << idx;
+    }
+}
+
+/// Start over the panicked threads which were switched out since
+pub(crate) fn restart_panicked(s: &mut ThreadsState) {
+    for idx in 1..s.threads.len() {
+        if s.restarts & (1 << idx) == 0 || s.curr == &s.threads[idx] as *const _ as usize {
+            continue;
+        }
+        s.restarts &= !(1 << idx);
+        let old = s.threads[idx];
+        // the stack was big enough when the thread was created
+        if let Ok(tcb) = renew_tcb(&old) {
+            insert_tcb(s, idx, tcb);
+            record(s, TraceKind::Create, idx, 0);
+            lifecycle::report(s, ThreadEvent::Created, idx);
+        }
+    }
+}
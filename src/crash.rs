@@ -0,0 +1,141 @@
+//! HardFault handler reporting the crashed thread, with the `crash-handler` feature.
+//!
+//! The feature defines the `HardFault` handler, taking the exception frame the way the
+//! `cortex-m-rt` trampoline passes it, so the application must not define its own. MemManage,
+//! BusFault and UsageFault escalate to HardFault unless the application enables them, and
+//! their cause is in the fault status registers of the report.
+//!
+//! The handler finds the crashed thread from the address of the exception frame: a fault in
+//! a thread pushes the frame on that thread's stack. It hands a `CrashInfo` to the hook set
+//! with `set_crash_hook`, e.g. to store it in flash or print it over RTT, then resets the
+//! core. The kernel state is read as it was at the fault, without a critical section, since
+//! the fault may have hit while the kernel held one.
+
+use core::ptr;
+
+use crate::arch::{Arch, Port};
+use crate::{kernel, with_state, ThreadHandle, ThreadStatus};
+
+#[cfg(not(armv6m))]
+/// Configurable fault status register: MemManage, BusFault and UsageFault causes
+const CFSR: u32 = 0xE000ED28;
+#[cfg(not(armv6m))]
+/// HardFault status register
+const HFSR: u32 = 0xE000ED2C;
+#[cfg(not(armv6m))]
+/// MemManage fault address register, valid when CFSR bit 7 is set
+const MMFAR: u32 = 0xE000ED34;
+#[cfg(not(armv6m))]
+/// BusFault address register, valid when CFSR bit 15 is set
+const BFAR: u32 = 0xE000ED38;
+/// Application interrupt and reset control register
+const AIRCR: u32 = 0xE000ED0C;
+
+/// Registers pushed by the core on exception entry, as passed to `HardFault`
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+/// What is known about a crash, passed to the hook set with `set_crash_hook`
+#[derive(Clone, Copy, Debug)]
+pub struct CrashInfo {
+    /// crashed thread, None if the fault hit an interrupt handler or happened before `init`
+    /// on the main stack
+    pub thread: Option<ThreadHandle>,
+    /// name of the crashed thread given with `set_thread_name`, empty if none
+    pub name: &'static str,
+    /// R0-R3, R12, LR, PC and xPSR as stacked at the fault
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+    /// stack pointer at the fault, where the exception frame was pushed
+    pub sp: u32,
+    /// fault status registers, CFSR and HFSR are 0 on ARMv6-M and ARMv8-M baseline
+    pub cfsr: u32,
+    pub hfsr: u32,
+    /// faulting addresses, valid when the MMARVALID and BFARVALID bits of `cfsr` are set
+    pub mmfar: u32,
+    pub bfar: u32,
+    /// tick count at the fault
+    pub tick: u64,
+}
+
+/// Set a function called with the crash report before the HardFault handler resets the
+/// core. It runs in the HardFault handler, and should only store or send the report.
+///
+/// # Example
+/// ```
+/// fn crashed(crash: &CrashInfo) {
+///     let _ = hprintln!("thread {:?} {} crashed at pc {:#x}, cfsr {:#x}",
+///         crash.thread, crash.name, crash.pc, crash.cfsr);
+/// }
+/// set_crash_hook(crashed);
+/// ```
+pub fn set_crash_hook(hook: fn(&CrashInfo)) {
+    with_state(|s| s.crash_hook = Some(hook));
+}
+
+/// HardFault handler, called by the `cortex-m-rt` trampoline with the exception frame
+///
+/// # Safety
+/// Only to be called by the core on a HardFault, through the trampoline
+#[no_mangle]
+pub unsafe extern "C" fn HardFault(frame: &ExceptionFrame) -> ! {
+    let s = &*kernel().as_ptr();
+    let sp = frame as *const ExceptionFrame as usize as u32;
+    // the running thread may differ from s.idx while a switch is pending
+    let thread = s.threads.iter().position(|tcb| {
+        tcb.status != ThreadStatus::Free
+            && sp as usize >= tcb.stack_base
+            && (sp as usize) < tcb.stack_base + tcb.stack_words as usize * 4
+    });
+    // the fault status registers are not implemented on ARMv6-M and ARMv8-M baseline
+    #[cfg(not(armv6m))]
+    let (cfsr, hfsr, mmfar, bfar) = (
+        ptr::read_volatile(CFSR as *const u32),
+        ptr::read_volatile(HFSR as *const u32),
+        ptr::read_volatile(MMFAR as *const u32),
+        ptr::read_volatile(BFAR as *const u32),
+    );
+    #[cfg(armv6m)]
+    let (cfsr, hfsr, mmfar, bfar) = (0, 0, 0, 0);
+    let crash = CrashInfo {
+        thread: thread.map(ThreadHandle),
+        name: thread.map_or("", |idx| s.threads[idx].name),
+        r0: frame.r0,
+        r1: frame.r1,
+        r2: frame.r2,
+        r3: frame.r3,
+        r12: frame.r12,
+        lr: frame.lr,
+        pc: frame.pc,
+        xpsr: frame.xpsr,
+        sp,
+        cfsr,
+        hfsr,
+        mmfar,
+        bfar,
+        tick: s.ticks,
+    };
+    if let Some(hook) = s.crash_hook {
+        crate::budget::run_callback(&s.callback_budget, "crash hook", || hook(&crash));
+    }
+    // SYSRESETREQ, with the key
+    ptr::write_volatile(AIRCR as *mut u32, 0x05FA_0004);
+    loop {
+        Port::wait_for_event();
+    }
+}
@@ -0,0 +1,181 @@
+//! Critical sections and data shared between threads and interrupt handlers.
+//!
+//! Kernel state is kept in `Shared` cells, which only hand out a reference while the caller
+//! holds a `CriticalSection`. A critical section disables interrupts, so no thread switch or
+//! handler can observe the state half updated, and a context holding one cannot enter
+//! another, so at most one reference to shared data exists at any time.
+//!
+//! Critical sections save PRIMASK and restore it when they end instead of enabling
+//! interrupts, so kernel functions can be called with interrupts already disabled, e.g. from
+//! `with_critical`, and leave them disabled.
+//!
+//! `CriticalGuard` and `with_critical` are the public counterparts for application data. They
+//! can be nested and used from handlers. With the `critical-section` feature the same
+//! mechanism also implements the `critical-section` crate.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::{Arch, Port};
+
+/// Set while a critical section is held
+#[cfg(not(feature = "rp2040-amp"))]
+static HELD: AtomicBool = AtomicBool::new(false);
+/// Set while a critical section is held, by each core
+#[cfg(feature = "rp2040-amp")]
+static HELD: [AtomicBool; crate::amp::CORES] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// Critical section flag of the calling core
+#[cfg(not(feature = "rp2040-amp"))]
+fn held_flag() -> &'static AtomicBool {
+    &HELD
+}
+
+/// Critical section flag of the calling core
+#[cfg(feature = "rp2040-amp")]
+fn held_flag() -> &'static AtomicBool {
+    &HELD[crate::amp::core_id()]
+}
+
+/// Proof that interrupts are disabled. The interrupt mask found on entry is restored when it
+/// is dropped.
+pub(crate) struct CriticalSection {
+    primask: u32,
+}
+
+impl CriticalSection {
+    /// Disable interrupts. Panics if a critical section is already held, e.g. when a hook
+    /// called from scheduler context calls back into the kernel, and if called from an
+    /// unprivileged thread, for which disabling interrupts does nothing: the objects reaching
+    /// this from a thread are restricted to privileged threads, see `svc`.
+    pub(crate) fn enter() -> Self {
+        if Port::unprivileged() {
+            panic!("kernel object not available to unprivileged threads");
+        }
+        let primask = Port::disable_interrupts();
+        if held_flag().load(Ordering::Relaxed) {
+            panic!("kernel called from inside a critical section");
+        }
+        held_flag().store(true, Ordering::Relaxed);
+        CriticalSection { primask }
+    }
+
+    /// Give up the critical section with interrupts still disabled, for code which enables
+    /// them itself
+    pub(crate) fn leave_masked(self) {
+        held_flag().store(false, Ordering::Relaxed);
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for CriticalSection {
+    fn drop(&mut self) {
+        held_flag().store(false, Ordering::Relaxed);
+        Port::restore_interrupts(self.primask);
+    }
+}
+
+/// Whether a critical section is held, e.g. by kernel code which panicked
+pub(crate) fn held() -> bool {
+    held_flag().load(Ordering::Relaxed)
+}
+
+/// Data only accessible inside a critical section
+pub(crate) struct Shared<T> {
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Shared {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Borrow the data for as long as `cs` is borrowed
+    pub(crate) fn borrow<'cs>(&'cs self, _cs: &'cs mut CriticalSection) -> &'cs mut T {
+        unsafe { &mut *self.value.get() }
+    }
+
+    /// Address of the data, for code outside Rust such as the PendSV handler
+    pub(crate) fn as_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+}
+
+/// Interrupts disabled for as long as it lives. Dropping it restores the interrupt mask found
+/// when it was created, so guards can be nested, and can be used from interrupt handlers.
+/// Interrupts can only be disabled by privileged code, for unprivileged threads the guard
+/// leaves interrupts unchanged.
+///
+/// Kernel functions can be called while a guard is held, they leave interrupts disabled.
+/// They must not block: the thread only gives up the CPU once interrupts are enabled again.
+///
+/// # Example
+/// ```
+/// let guard = CriticalGuard::new();
+/// let reading = adc.take_sample();
+/// HISTORY.push(reading);
+/// drop(guard);
+/// ```
+pub struct CriticalGuard {
+    primask: u32,
+    // the mask must be restored by the context which saved it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl CriticalGuard {
+    /// Disable interrupts, saving the previous mask
+    pub fn new() -> Self {
+        CriticalGuard {
+            primask: Port::disable_interrupts(),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for CriticalGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for CriticalGuard {
+    fn drop(&mut self) {
+        Port::restore_interrupts(self.primask);
+    }
+}
+
+/// Run `f` with interrupts disabled, then restore the interrupt mask found on entry, see
+/// `CriticalGuard`.
+///
+/// # Example
+/// ```
+/// static mut COUNT: u32 = 0;
+/// with_critical(|| unsafe { COUNT += 1 });
+/// ```
+pub fn with_critical<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = CriticalGuard::new();
+    f()
+}
+
+#[cfg(feature = "critical-section")]
+struct PrimaskCriticalSection;
+
+#[cfg(feature = "critical-section")]
+critical_section::set_impl!(PrimaskCriticalSection);
+
+#[cfg(feature = "critical-section")]
+unsafe impl critical_section::Impl for PrimaskCriticalSection {
+    unsafe fn acquire() -> critical_section::RawRestoreState {
+        // PRIMASK bit 0 set means interrupts were already disabled
+        Port::disable_interrupts() & 1 != 0
+    }
+
+    unsafe fn release(masked: critical_section::RawRestoreState) {
+        Port::restore_interrupts(masked as u32);
+    }
+}
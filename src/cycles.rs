@@ -0,0 +1,85 @@
+//! Cycle timestamps for CPU usage accounting, scheduler statistics, SystemView tracing and
+//! `delay_us`.
+//!
+//! On ARMv7-M (Cortex-M3/M4) the DWT cycle counter is used. ARMv6-M (Cortex-M0/M0+) has no
+//! cycle counter, so the count is derived from the tick count and the SysTick current value,
+//! which assumes `SysTick()` is only called from the SysTick exception.
+
+#[cfg(any(
+    not(armv6m),
+    feature = "cpu-usage",
+    feature = "kernel-stats",
+    feature = "trace-systemview"
+))]
+use core::ptr;
+
+#[cfg(feature = "cpu-usage")]
+use crate::ThreadsState;
+
+#[cfg(not(armv6m))]
+const DEMCR: u32 = 0xE000EDFC;
+#[cfg(not(armv6m))]
+const DWT_CTRL: u32 = 0xE0001000;
+#[cfg(not(armv6m))]
+const DWT_CYCCNT: u32 = 0xE0001004;
+
+pub(crate) const SYST_CSR: u32 = 0xE000E010;
+pub(crate) const SYST_RVR: u32 = 0xE000E014;
+pub(crate) const SYST_CVR: u32 = 0xE000E018;
+
+/// Start the cycle counter
+pub(crate) fn enable() {
+    #[cfg(not(armv6m))]
+    unsafe {
+        let demcr = ptr::read_volatile(DEMCR as *const u32);
+        ptr::write_volatile(DEMCR as *mut u32, demcr | 1 << 24);
+        let ctrl = ptr::read_volatile(DWT_CTRL as *const u32);
+        ptr::write_volatile(DWT_CTRL as *mut u32, ctrl | 1);
+    }
+}
+
+/// Value of the DWT cycle counter, wrapping at 32 bits
+#[cfg(not(armv6m))]
+pub(crate) fn count() -> u32 {
+    unsafe { ptr::read_volatile(DWT_CYCCNT as *const u32) }
+}
+
+/// Current cycle count, given the current tick count. Only the low 32 bits are meaningful
+/// on ARMv7-M.
+#[cfg(any(
+    feature = "cpu-usage",
+    feature = "kernel-stats",
+    feature = "trace-systemview"
+))]
+pub(crate) fn now(ticks: u64) -> u64 {
+    #[cfg(not(armv6m))]
+    {
+        let _ = ticks;
+        count() as u64
+    }
+    #[cfg(armv6m)]
+    unsafe {
+        let reload = ptr::read_volatile(SYST_RVR as *const u32) as u64;
+        let current = ptr::read_volatile(SYST_CVR as *const u32) as u64;
+        ticks * (reload + 1) + (reload - current)
+    }
+}
+
+/// Current cycle count, given the current tick count, wrapping at 32 bits
+#[cfg(feature = "trace-systemview")]
+pub(crate) fn timestamp(ticks: u64) -> u32 {
+    now(ticks) as u32
+}
+
+/// Cycles elapsed since the previous call. Must be called at least once per cycle counter
+/// wrap (every tick is enough).
+#[cfg(feature = "cpu-usage")]
+pub(crate) fn lap(s: &mut ThreadsState) -> u64 {
+    let now = now(s.ticks);
+    #[cfg(not(armv6m))]
+    let elapsed = (now as u32).wrapping_sub(s.last_cycles as u32) as u64;
+    #[cfg(armv6m)]
+    let elapsed = now.saturating_sub(s.last_cycles);
+    s.last_cycles = now;
+    elapsed
+}
@@ -0,0 +1,123 @@
+//! Deadlock detection on mutexes, with the `deadlock-detection` feature.
+//!
+//! Mutexes remember the thread holding them, and a thread blocking on a mutex remembers which
+//! one. Before a thread blocks, the chain of holders is followed: the holder of the mutex, the
+//! mutex that holder is blocked on, its holder, and so on. Coming back to the thread about to
+//! block means the threads of the chain wait on each other forever. The chain is then reported
+//! to a hook, or the kernel panics.
+//!
+//! The mutex a thread is blocked on is also tracked with the `inversion-detection` feature,
+//! see `inversion`. Without either feature, nothing is tracked beyond the holder.
+
+use crate::ThreadsState;
+#[cfg(feature = "deadlock-detection")]
+use crate::{mutex, with_state, ThreadHandle, ThreadStatus, MAX_THREADS};
+
+/// Threads waiting on each other, passed to the hook set with `set_deadlock_hook`
+#[cfg(feature = "deadlock-detection")]
+#[derive(Clone, Copy, Debug)]
+pub struct Deadlock {
+    len: usize,
+    /// (thread, address of the mutex it waits for) pairs, the mutex being held by the next
+    /// thread, and the last one's by the first
+    chain: [(ThreadHandle, usize); MAX_THREADS],
+}
+
+#[cfg(feature = "deadlock-detection")]
+impl Deadlock {
+    /// Threads of the cycle, each with the address of the `Mutex` it waits for, which is held
+    /// by the next thread. The first thread is the one which was about to block, and the
+    /// mutex of the last thread is held by the first one.
+    pub fn chain(&self) -> &[(ThreadHandle, usize)] {
+        &self.chain[..self.len]
+    }
+}
+
+/// Set a function called with the cycle when a thread is about to block on a mutex in a
+/// deadlock. It runs in the thread, with interrupts disabled; the thread then blocks. Without
+/// a hook, the kernel panics.
+///
+/// # Example
+/// ```
+/// fn deadlock(cycle: &Deadlock) {
+///     for (thread, mutex) in cycle.chain() {
+///         let _ = hprintln!("thread {} waits for mutex {:#x}", thread.id(), mutex);
+///     }
+/// }
+/// set_deadlock_hook(deadlock);
+/// ```
+#[cfg(feature = "deadlock-detection")]
+pub fn set_deadlock_hook(hook: fn(&Deadlock)) {
+    with_state(|s| s.deadlock_hook = Some(hook));
+}
+
+/// Note that the running thread is about to block on the mutex at address `lock`, and report
+/// a deadlock if the holders of the mutex wait for it
+#[cfg(any(feature = "deadlock-detection", feature = "inversion-detection"))]
+pub(crate) fn wait_for(s: &mut ThreadsState, lock: usize) {
+    let me = s.idx;
+    s.threads[me].blocked_on = lock;
+    #[cfg(feature = "inversion-detection")]
+    {
+        s.threads[me].inversion_reported = false;
+    }
+    #[cfg(feature = "deadlock-detection")]
+    check_cycle(s, me, lock);
+}
+
+/// Report a deadlock if thread `me`, about to block on the mutex at address `lock`, is
+/// waited for by the holders of the mutex
+#[cfg(feature = "deadlock-detection")]
+fn check_cycle(s: &mut ThreadsState, me: usize, lock: usize) {
+    let mut cycle = Deadlock {
+        len: 0,
+        chain: [(ThreadHandle(0), 0); MAX_THREADS],
+    };
+    let mut thread = me;
+    let mut lock = lock;
+    while cycle.len < MAX_THREADS {
+        cycle.chain[cycle.len] = (ThreadHandle(thread), lock);
+        cycle.len += 1;
+        thread = match unsafe { mutex::holder(lock) } {
+            Some(holder) => holder,
+            None => return,
+        };
+        if thread == me {
+            break;
+        }
+        // a holder which is not blocked on a mutex will unlock it eventually
+        lock = s.threads[thread].blocked_on;
+        if lock == 0 || s.threads[thread].status != ThreadStatus::Blocked {
+            return;
+        }
+    }
+    if thread != me {
+        // a cycle among other threads, reported when they blocked
+        return;
+    }
+    #[cfg(feature = "defmt")]
+    defmt::trace!("deadlock: thread {} waits on itself", me);
+    match s.deadlock_hook {
+        Some(hook) => {
+            crate::budget::run_callback(&s.callback_budget, "deadlock hook", || hook(&cycle))
+        }
+        None => panic!("deadlock of {} threads", cycle.len),
+    }
+}
+
+/// Note that the running thread is about to block on the mutex at address `lock`
+#[cfg(not(any(feature = "deadlock-detection", feature = "inversion-detection")))]
+#[inline(always)]
+pub(crate) fn wait_for(_s: &mut ThreadsState, _lock: usize) {}
+
+/// Note that the running thread no longer waits for a mutex
+#[cfg(any(feature = "deadlock-detection", feature = "inversion-detection"))]
+pub(crate) fn done_waiting(s: &mut ThreadsState) {
+    let idx = s.idx;
+    s.threads[idx].blocked_on = 0;
+}
+
+/// Note that the running thread no longer waits for a mutex
+#[cfg(not(any(feature = "deadlock-detection", feature = "inversion-detection")))]
+#[inline(always)]
+pub(crate) fn done_waiting(_s: &mut ThreadsState) {}
@@ -0,0 +1,66 @@
+//! Delay provider for embedded-hal drivers, with the `embedded-hal` (1.0) or `embedded-hal-02`
+//! (0.2) features.
+//!
+//! HAL drivers wait for their devices through a delay provider they are given, usually one
+//! busy-waiting on a timer. `ThreadDelay` puts the calling thread to sleep instead, so other
+//! threads run while a driver waits.
+
+use crate::time::{ns_to_ticks, sleep_ticks};
+
+/// Delay provider sleeping the calling thread. A delay is rounded up to whole ticks, plus one
+/// as the current tick is partly over, so it lasts at least as long as asked and up to two
+/// ticks longer. Panics if used from an interrupt handler, like `sleep`.
+///
+/// # Example
+/// ```
+/// let mut display = Ssd1306::new(i2c, ThreadDelay);
+/// display.reset(); // sleeps while the reset pulse lasts
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadDelay;
+
+impl ThreadDelay {
+    fn delay(&mut self, ns: u64) {
+        if ns == 0 {
+            return;
+        }
+        sleep_ticks(ns_to_ticks(ns) + 1);
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::delay::DelayNs for ThreadDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        self.delay(ns as u64);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.delay(us as u64 * 1_000);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.delay(ms as u64 * 1_000_000);
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+macro_rules! impl_delay_02 {
+    ($($t:ty),*) => {
+        $(
+            impl embedded_hal_02::blocking::delay::DelayMs<$t> for ThreadDelay {
+                fn delay_ms(&mut self, ms: $t) {
+                    self.delay(ms as u64 * 1_000_000);
+                }
+            }
+
+            impl embedded_hal_02::blocking::delay::DelayUs<$t> for ThreadDelay {
+                fn delay_us(&mut self, us: $t) {
+                    self.delay(us as u64 * 1_000);
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl_delay_02!(u8, u16, u32);
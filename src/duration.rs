@@ -0,0 +1,68 @@
+//! Sleeps and timeouts given as `fugit` durations, with the `fugit` feature.
+//!
+//! `sleep_for(500.millis())` is converted to ticks with the tick rate set by `set_tick_hz` or
+//! `init_with_systick`, so call sites do not depend on how the tick timer is programmed.
+
+use crate::time::{sleep_ticks, tick_hz};
+
+// public in a private module, so the trait cannot be implemented outside the crate
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A `fugit::Duration`, of u32 or u64 ticks of any rate
+pub trait IntoTicks: sealed::Sealed {
+    /// Number of kernel ticks lasting at least the duration
+    fn into_ticks(self) -> u64;
+}
+
+/// Kernel ticks lasting at least `ticks` ticks of NOM / DENOM seconds
+fn convert(ticks: u64, nom: u32, denom: u32) -> u64 {
+    let scaled = ticks as u128 * nom as u128 * tick_hz() as u128;
+    let kernel_ticks = scaled.div_ceil(denom as u128);
+    kernel_ticks.min(u64::MAX as u128) as u64
+}
+
+impl<const NOM: u32, const DENOM: u32> sealed::Sealed for fugit::Duration<u32, NOM, DENOM> {}
+
+impl<const NOM: u32, const DENOM: u32> IntoTicks for fugit::Duration<u32, NOM, DENOM> {
+    fn into_ticks(self) -> u64 {
+        convert(self.ticks() as u64, NOM, DENOM)
+    }
+}
+
+impl<const NOM: u32, const DENOM: u32> sealed::Sealed for fugit::Duration<u64, NOM, DENOM> {}
+
+impl<const NOM: u32, const DENOM: u32> IntoTicks for fugit::Duration<u64, NOM, DENOM> {
+    fn into_ticks(self) -> u64 {
+        convert(self.ticks(), NOM, DENOM)
+    }
+}
+
+/// Number of ticks lasting at least `duration`, for the `_timeout` variants of blocking
+/// calls. Saturates at u32::MAX.
+///
+/// # Example
+/// ```
+/// use fugit::ExtU32;
+///
+/// let reply = replies.recv_timeout(to_ticks(20.millis()))?;
+/// ```
+pub fn to_ticks<D: IntoTicks>(duration: D) -> u32 {
+    duration.into_ticks().min(u32::MAX as u64) as u32
+}
+
+/// Make the current thread sleep for `duration`, rounded up to whole ticks, see `sleep`
+///
+/// # Example
+/// ```
+/// use fugit::ExtU32;
+///
+/// loop {
+///     toggle_led();
+///     sleep_for(500.millis());
+/// }
+/// ```
+pub fn sleep_for<D: IntoTicks>(duration: D) {
+    sleep_ticks(duration.into_ticks());
+}
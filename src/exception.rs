@@ -0,0 +1,98 @@
+//! Priorities of the exceptions the kernel runs in.
+//!
+//! PendSV switches threads, so it must have the lowest priority: otherwise it can preempt an
+//! interrupt handler and resume a thread before the handler has returned. `init` gives PendSV
+//! the lowest priority the core implements, and SysTick and SVCall the next higher one, unless
+//! the application set its own with `set_exception_priorities`.
+
+use core::ptr;
+
+use crate::with_state;
+
+/// System handler priority register 2, SVCall priority in bits 24-31
+const SHPR2: u32 = 0xE000ED1C;
+/// System handler priority register 3, PendSV priority in bits 16-23, SysTick in bits 24-31
+const SHPR3: u32 = 0xE000ED20;
+
+/// Priorities of the exceptions used by the kernel. Lower values are more urgent, and the core
+/// ignores the low bits of each value it does not implement.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ExceptionPriorities {
+    pub svcall: u8,
+    pub pendsv: u8,
+    pub systick: u8,
+}
+
+/// Have `init` program `priorities` instead of the default ones. PendSV should still be less
+/// urgent than every interrupt whose handler uses the kernel, and than SysTick.
+///
+/// # Example
+/// ```
+/// // 4 priority bits, interrupts at 0x00-0xc0 preempt the tick
+/// set_exception_priorities(ExceptionPriorities {
+///     svcall: 0xe0,
+///     pendsv: 0xf0,
+///     systick: 0xe0,
+/// });
+/// init();
+/// ```
+pub fn set_exception_priorities(priorities: ExceptionPriorities) {
+    with_state(|s| s.exception_priorities = Some(priorities));
+}
+
+/// Program the priorities set by the application, or PendSV at the lowest priority and
+/// SysTick and SVCall one step above it
+pub(crate) fn configure(custom: Option<ExceptionPriorities>) {
+    let priorities = custom.unwrap_or_else(|| {
+        let lowest = lowest_priority();
+        let above = lowest - (lowest & lowest.wrapping_neg());
+        ExceptionPriorities {
+            svcall: above,
+            pendsv: lowest,
+            systick: above,
+        }
+    });
+    // both registers are word accessible only on ARMv6-M
+    unsafe {
+        let shpr2 = ptr::read_volatile(SHPR2 as *const u32);
+        ptr::write_volatile(
+            SHPR2 as *mut u32,
+            (shpr2 & 0x00ff_ffff) | ((priorities.svcall as u32) << 24),
+        );
+        let shpr3 = ptr::read_volatile(SHPR3 as *const u32);
+        ptr::write_volatile(
+            SHPR3 as *mut u32,
+            (shpr3 & 0xffff)
+                | ((priorities.systick as u32) << 24)
+                | ((priorities.pendsv as u32) << 16),
+        );
+    }
+}
+
+/// Least urgent priority the core implements, found by writing all ones to the PendSV field,
+/// as unimplemented low bits read as zero. The field is restored afterwards.
+pub(crate) fn lowest_priority() -> u8 {
+    unsafe {
+        let shpr3 = ptr::read_volatile(SHPR3 as *const u32);
+        ptr::write_volatile(SHPR3 as *mut u32, shpr3 | 0xff << 16);
+        let lowest = (ptr::read_volatile(SHPR3 as *const u32) >> 16) as u8;
+        ptr::write_volatile(SHPR3 as *mut u32, shpr3);
+        lowest
+    }
+}
+
+/// Priorities currently in the system handler priority registers
+#[cfg(feature = "validation")]
+pub(crate) fn programmed() -> ExceptionPriorities {
+    let (shpr2, shpr3) = unsafe {
+        (
+            ptr::read_volatile(SHPR2 as *const u32),
+            ptr::read_volatile(SHPR3 as *const u32),
+        )
+    };
+    ExceptionPriorities {
+        svcall: (shpr2 >> 24) as u8,
+        pendsv: (shpr3 >> 16) as u8,
+        systick: (shpr3 >> 24) as u8,
+    }
+}
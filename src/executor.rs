@@ -0,0 +1,120 @@
+//! Futures run to completion by a thread.
+//!
+//! `block_on` polls a future from the calling thread. While the future is pending, the thread
+//! is parked: it blocks like in `sleep`, and other threads run. Its waker unparks it, from an
+//! interrupt handler or another thread, so an async driver written for an executor runs
+//! unchanged in a priority-scheduled thread. A waker woken while its thread is not parked,
+//! e.g. while the future is being polled, is remembered, so the next park returns at once
+//! and no wake-up is lost.
+
+use core::future::Future;
+use core::mem;
+use core::pin::pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use crate::critical::CriticalSection;
+use crate::{
+    block_enter, expect_thread, get_thread_id, record, schedule, state, svc, with_state,
+    ThreadHandle, ThreadsState, TraceKind, WakeReason, NO_DEADLINE,
+};
+
+/// Data of the wakers is the id of the thread they unpark
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_raw, wake_raw, wake_raw, drop_raw);
+
+fn clone_raw(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+fn wake_raw(data: *const ()) {
+    let _ = unpark(data as usize);
+}
+
+fn drop_raw(_: *const ()) {}
+
+/// Run `future` to completion in the calling thread, parking the thread while it is pending,
+/// and return its output. Panics if called from an interrupt handler. The idle thread, and
+/// code running before `init`, cannot be parked: there it polls `future` in a loop.
+///
+/// # Example
+/// ```
+/// fn sensor_task() -> ! {
+///     let mut uart = AsyncUart::new(UART0);
+///     loop {
+///         let reading = block_on(uart.read_frame());
+///         process(reading);
+///     }
+/// }
+/// ```
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    expect_thread("block_on");
+    let mut future = pin!(future);
+    let waker = thread_waker(get_thread_id());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        park();
+    }
+}
+
+/// A waker unparking thread `thread` from `block_on`. Handles are reused, so a waker kept
+/// after its thread exited may make a newer thread poll its future once more.
+pub fn thread_waker(thread: ThreadHandle) -> Waker {
+    unsafe { Waker::from_raw(RawWaker::new(thread.0 as *const (), &VTABLE)) }
+}
+
+/// Block the current thread until one of its wakers is woken, unless one already was
+fn park() {
+    if svc::from_unprivileged_thread() {
+        return svc::park();
+    }
+    let mut cs = CriticalSection::enter();
+    if park_current(state(&mut cs)) {
+        drop(cs);
+        schedule();
+    }
+}
+
+/// Kernel part of `park`. Returns false if the current thread does not block.
+pub(crate) fn park_current(s: &mut ThreadsState) -> bool {
+    nap_current(s, NO_DEADLINE).is_none()
+}
+
+/// Park the current thread until `deadline`, kernel part of `sleep_or_event`. Returns the
+/// reason it does not block, if it does not: `Woken` if a waker was woken since it last ran.
+pub(crate) fn nap_current(s: &mut ThreadsState, deadline: u64) -> Option<WakeReason> {
+    let idx = s.idx;
+    if mem::replace(&mut s.threads[idx].notified, false) {
+        return Some(WakeReason::Woken);
+    }
+    block_enter(s, deadline, |s, idx| s.threads[idx].parked = true)
+}
+
+fn unpark(thread: usize) -> bool {
+    if svc::from_unprivileged_thread() {
+        return svc::unpark(thread);
+    }
+    let woken = with_state(|s| unpark_thread(s, thread));
+    if woken {
+        schedule();
+    }
+    woken
+}
+
+/// Make thread `thread` ready if it is parked, or have its next park return at once.
+/// Kernel part of a waker's `wake`. Returns whether it was woken.
+pub(crate) fn unpark_thread(s: &mut ThreadsState, thread: usize) -> bool {
+    let tcb = match s.threads.get_mut(thread) {
+        Some(tcb) => tcb,
+        None => return false,
+    };
+    if !tcb.parked {
+        tcb.notified = true;
+        return false;
+    }
+    let now = s.ticks;
+    s.wake(thread, WakeReason::Woken, now);
+    record(s, TraceKind::Wake, thread, WakeReason::Woken as u32);
+    true
+}
@@ -0,0 +1,342 @@
+//! C API, with the `ffi` feature.
+//!
+//! C modules linked into the firmware create threads and synchronize with Rust threads
+//! through the functions below, declared in `include/cortexm_threads.h`. The header is
+//! generated from this file with `cbindgen --config cbindgen.toml -o include/cortexm_threads.h`.
+//!
+//! Functions returning `int32_t` return 0 or a thread id on success, and a negative
+//! `CMT_ERR_*` code on failure. Timeouts are in ticks: 0 does not block, which is the only
+//! value allowed in interrupt handlers, and `CMT_WAIT_FOREVER` blocks until the operation
+//! succeeds.
+//!
+//! `cmt_mutex_t` and `cmt_queue_t` are opaque to C: declare them as globals and initialize
+//! them with `cmt_mutex_init` and `cmt_queue_init` before use. Mutexes can be used by any
+//! thread, queues only by privileged threads and interrupt handlers, see `svc`.
+
+use core::ffi::c_void;
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+use crate::critical::CriticalSection;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{
+    block_current, create_thread_raw, exit, init, join_timeout, schedule, state, wake_one, Error,
+    Mutex, MutexGuard, ThreadHandle, WakeReason, NO_DEADLINE,
+};
+
+/// Timeout of the blocking calls waiting until they succeed
+pub const CMT_WAIT_FOREVER: u32 = u32::MAX;
+
+/// Code returned to C for error `e`, the negated `ERR_*` code of the `error-codes` feature
+const fn code(e: Error) -> i32 {
+    -(e as i32) - 1
+}
+
+pub const CMT_ERR_TOO_MANY_THREADS: i32 = -1;
+pub const CMT_ERR_STACK_TOO_SMALL: i32 = -2;
+pub const CMT_ERR_NO_CREATE_PRIV: i32 = -3;
+pub const CMT_ERR_NO_SUCH_THREAD: i32 = -4;
+pub const CMT_ERR_TIMED_OUT: i32 = -5;
+pub const CMT_ERR_QUEUE_FULL: i32 = -6;
+pub const CMT_ERR_STACK_IN_USE: i32 = -9;
+pub const CMT_ERR_BAD_REGION: i32 = -10;
+pub const CMT_ERR_NOT_HOLDER: i32 = -11;
+
+// literals above, so cbindgen copies them to the header
+const _: () = assert!(
+    CMT_ERR_TOO_MANY_THREADS == code(Error::TooManyThreads)
+        && CMT_ERR_STACK_TOO_SMALL == code(Error::StackTooSmall)
+        && CMT_ERR_NO_CREATE_PRIV == code(Error::NoCreatePrivilege)
+        && CMT_ERR_NO_SUCH_THREAD == code(Error::NoSuchThread)
+        && CMT_ERR_TIMED_OUT == code(Error::TimedOut)
+        && CMT_ERR_QUEUE_FULL == code(Error::QueueFull)
+        && CMT_ERR_STACK_IN_USE == code(Error::StackInUse)
+        && CMT_ERR_BAD_REGION == code(Error::BadRegion)
+        && CMT_ERR_NOT_HOLDER == code(Error::NotHolder)
+);
+
+fn result(r: Result<usize, Error>) -> i32 {
+    match r {
+        Ok(value) => value as i32,
+        Err(e) => code(e),
+    }
+}
+
+fn deadline(timeout: u32) -> u64 {
+    if timeout == CMT_WAIT_FOREVER {
+        NO_DEADLINE
+    } else {
+        crate::ticks() + timeout as u64
+    }
+}
+
+/// Entry point of C threads, R0 holds the argument and R1 the entry function
+extern "C" fn c_trampoline(arg: *mut c_void, entry: extern "C" fn(*mut c_void)) -> ! {
+    entry(arg);
+    exit()
+}
+
+/// Create a thread running `entry(arg)` on the `words` u32's at `stack`, with priority
+/// `priority` (higher runs first). The thread exits when `entry` returns. Returns the id
+/// of the thread.
+///
+/// # Safety
+/// `stack` must point to `words` u32's used by nothing else for as long as the thread runs.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_thread_create(
+    stack: *mut u32,
+    words: usize,
+    entry: extern "C" fn(*mut c_void),
+    arg: *mut c_void,
+    priority: u8,
+    privileged: bool,
+) -> i32 {
+    let stack = core::slice::from_raw_parts_mut(stack, words);
+    let trampoline: extern "C" fn(*mut c_void, extern "C" fn(*mut c_void)) -> ! = c_trampoline;
+    let created = create_thread_raw(
+        stack,
+        trampoline as usize,
+        arg as usize,
+        entry as usize,
+        priority,
+        privileged,
+    );
+    result(created.map(|thread| thread.0))
+}
+
+/// Start the scheduler, see `init`
+#[no_mangle]
+pub extern "C" fn cmt_init() -> ! {
+    init()
+}
+
+/// Id of the calling thread
+#[no_mangle]
+pub extern "C" fn cmt_thread_self() -> i32 {
+    crate::get_thread_id().0 as i32
+}
+
+/// Exit the calling thread, see `exit`
+#[no_mangle]
+pub extern "C" fn cmt_exit() -> ! {
+    exit()
+}
+
+/// Sleep for `ticks` ticks, see `sleep`
+#[no_mangle]
+pub extern "C" fn cmt_sleep(ticks: u32) {
+    crate::sleep(ticks)
+}
+
+/// Let threads of the same priority run, see `yield_now`
+#[no_mangle]
+pub extern "C" fn cmt_yield() {
+    crate::yield_now()
+}
+
+/// Wake thread `thread` if it sleeps or is blocked, see `wake`
+#[no_mangle]
+pub extern "C" fn cmt_wake(thread: i32) -> i32 {
+    result(crate::wake(ThreadHandle(thread as usize)).map(|_| 0))
+}
+
+/// Block until thread `thread` exits, for at most `timeout` ticks, see `join`
+#[no_mangle]
+pub extern "C" fn cmt_join(thread: i32, timeout: u32) -> i32 {
+    let thread = ThreadHandle(thread as usize);
+    let joined = if timeout == CMT_WAIT_FOREVER {
+        crate::join(thread)
+    } else {
+        join_timeout(thread, timeout)
+    };
+    result(joined.map(|_| 0))
+}
+
+/// Number of ticks since `cmt_init`
+#[no_mangle]
+pub extern "C" fn cmt_ticks() -> u64 {
+    crate::ticks()
+}
+
+/// Storage of a `Mutex<()>`, opaque to C
+#[repr(C)]
+pub struct CmtMutex {
+    _opaque: [usize; 4],
+}
+
+const _: () = assert!(size_of::<Mutex<()>>() <= size_of::<CmtMutex>());
+const _: () = assert!(align_of::<Mutex<()>>() <= align_of::<CmtMutex>());
+
+/// Make `mutex` an unlocked mutex
+///
+/// # Safety
+/// `mutex` must be valid for writes, and not be in use by a thread
+#[no_mangle]
+pub unsafe extern "C" fn cmt_mutex_init(mutex: *mut CmtMutex) {
+    ptr::write(mutex.cast::<Mutex<()>>(), Mutex::new(()));
+}
+
+/// Lock `mutex`, blocking for at most `timeout` ticks while another thread holds it. Returns
+/// 0 once locked, `CMT_ERR_TIMED_OUT` if it was not unlocked in time.
+///
+/// # Safety
+/// `mutex` must have been initialized with `cmt_mutex_init`
+#[no_mangle]
+pub unsafe extern "C" fn cmt_mutex_lock(mutex: *mut CmtMutex, timeout: u32) -> i32 {
+    let mutex = &*mutex.cast::<Mutex<()>>();
+    let guard = match timeout {
+        0 => mutex.try_lock().ok_or(Error::TimedOut),
+        CMT_WAIT_FOREVER => Ok(mutex.lock()),
+        ticks => mutex.lock_timeout(ticks),
+    };
+    // the mutex stays locked until cmt_mutex_unlock
+    result(guard.map(|guard| {
+        core::mem::forget(guard);
+        0
+    }))
+}
+
+/// Unlock `mutex`, locked by the calling thread with `cmt_mutex_lock`. Returns 0 once
+/// unlocked, `CMT_ERR_NOT_HOLDER` if the calling thread does not hold it.
+///
+/// # Safety
+/// `mutex` must have been initialized with `cmt_mutex_init`
+#[no_mangle]
+pub unsafe extern "C" fn cmt_mutex_unlock(mutex: *mut CmtMutex) -> i32 {
+    let mutex = &*mutex.cast::<Mutex<()>>();
+    if !mutex.held() {
+        return CMT_ERR_NOT_HOLDER;
+    }
+    drop(MutexGuard::new(mutex));
+    0
+}
+
+/// Queue of fixed size items copied in and out of a buffer owned by C
+struct Queue {
+    buffer: *mut u8,
+    item_size: usize,
+    capacity: usize,
+    /// slot of the oldest item
+    head: usize,
+    len: usize,
+    readers: WaitList,
+    writers: WaitList,
+}
+
+/// Storage of a queue, opaque to C
+#[repr(C)]
+pub struct CmtQueue {
+    _opaque: [usize; 10],
+}
+
+const _: () = assert!(size_of::<Queue>() <= size_of::<CmtQueue>());
+const _: () = assert!(align_of::<Queue>() <= align_of::<CmtQueue>());
+
+/// Make `queue` an empty queue of up to `capacity` items of `item_size` bytes, stored in
+/// `buffer`
+///
+/// # Safety
+/// `queue` must be valid for writes, and not be in use by a thread. `buffer` must hold
+/// `capacity * item_size` bytes used by nothing else for as long as the queue is used.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_queue_init(
+    queue: *mut CmtQueue,
+    buffer: *mut c_void,
+    item_size: usize,
+    capacity: usize,
+) {
+    let new = Queue {
+        buffer: buffer.cast::<u8>(),
+        item_size,
+        capacity,
+        head: 0,
+        len: 0,
+        readers: WaitList::new(WaitOrder::Priority),
+        writers: WaitList::new(WaitOrder::Priority),
+    };
+    ptr::write(queue.cast::<Queue>(), new);
+}
+
+/// Copy the item at `item` to the back of `queue`, blocking for at most `timeout` ticks while
+/// it is full, and wake the highest priority thread waiting to receive. Returns 0 once sent,
+/// `CMT_ERR_QUEUE_FULL` if the queue is full and `timeout` is 0, `CMT_ERR_TIMED_OUT` if it
+/// stayed full. Items are copied with interrupts disabled, so they should be small.
+///
+/// # Safety
+/// `queue` must have been initialized with `cmt_queue_init`, and `item` must point to an
+/// item of its size
+#[no_mangle]
+pub unsafe extern "C" fn cmt_queue_send(
+    queue: *mut CmtQueue,
+    item: *const c_void,
+    timeout: u32,
+) -> i32 {
+    let queue = queue.cast::<Queue>();
+    let deadline = deadline(timeout);
+    let mut cs = CriticalSection::enter();
+    loop {
+        let q = &mut *queue;
+        if q.len < q.capacity {
+            let slot = (q.head + q.len) % q.capacity;
+            let dst = q.buffer.add(slot * q.item_size);
+            ptr::copy_nonoverlapping(item.cast::<u8>(), dst, q.item_size);
+            q.len += 1;
+            wake_one(state(&mut cs), &mut q.readers, WakeReason::Signaled);
+            drop(cs);
+            schedule();
+            return 0;
+        }
+        if timeout == 0 {
+            return CMT_ERR_QUEUE_FULL;
+        }
+        if block_current(cs, &mut q.writers, deadline) == WakeReason::Timeout {
+            return CMT_ERR_TIMED_OUT;
+        }
+        cs = CriticalSection::enter();
+    }
+}
+
+/// Copy the oldest item of `queue` to `item` and remove it, blocking for at most `timeout`
+/// ticks while the queue is empty, and wake the highest priority thread waiting to send.
+/// Returns 0 once received, `CMT_ERR_TIMED_OUT` if nothing was sent in time.
+///
+/// # Safety
+/// `queue` must have been initialized with `cmt_queue_init`, and `item` must have room for
+/// an item of its size
+#[no_mangle]
+pub unsafe extern "C" fn cmt_queue_receive(
+    queue: *mut CmtQueue,
+    item: *mut c_void,
+    timeout: u32,
+) -> i32 {
+    let queue = queue.cast::<Queue>();
+    let deadline = deadline(timeout);
+    let mut cs = CriticalSection::enter();
+    loop {
+        let q = &mut *queue;
+        if q.len > 0 {
+            let src = q.buffer.add(q.head * q.item_size);
+            ptr::copy_nonoverlapping(src, item.cast::<u8>(), q.item_size);
+            q.head = (q.head + 1) % q.capacity;
+            q.len -= 1;
+            wake_one(state(&mut cs), &mut q.writers, WakeReason::Signaled);
+            drop(cs);
+            schedule();
+            return 0;
+        }
+        if timeout == 0 || block_current(cs, &mut q.readers, deadline) == WakeReason::Timeout {
+            return CMT_ERR_TIMED_OUT;
+        }
+        cs = CriticalSection::enter();
+    }
+}
+
+/// Number of items in `queue`
+///
+/// # Safety
+/// `queue` must have been initialized with `cmt_queue_init`
+#[no_mangle]
+pub unsafe extern "C" fn cmt_queue_len(queue: *const CmtQueue) -> usize {
+    let _cs = CriticalSection::enter();
+    (*queue.cast::<Queue>()).len
+}
@@ -0,0 +1,100 @@
+//! Thread stacks allocated from the global allocator, with the `alloc` feature.
+//!
+//! `create_thread_alloc` takes the stack of a new thread from the heap instead of a static
+//! array. A thread cannot free the stack it runs on, so when it exits its slot keeps the
+//! allocation until the thread has been switched out; the stack is then freed in thread
+//! context, by the default idle thread or by the next `create_thread_alloc` call, so the
+//! allocator is never called from an interrupt handler.
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::slice;
+
+use crate::{
+    create_thread_closure_with_config, scheduler_resume, scheduler_suspend, with_state, Error,
+    ThreadHandle, ThreadStatus, MIN_STACK_WORDS,
+};
+
+/// Layout of a stack of `words` u32's, 8-byte aligned as required by the AAPCS
+fn layout(words: usize) -> Result<Layout, Error> {
+    Layout::from_size_align(words * 4, 8).map_err(|_| Error::StackTooSmall)
+}
+
+/// Create a thread running closure `f` with default configuration (lowest priority,
+/// unprivileged) on a stack of `stack_words` u32's allocated from the global allocator. The
+/// stack also holds the closure, see `create_thread_closure`, and is freed after the thread
+/// exits or is deleted. Returns Err(Error::OutOfMemory) if the allocation fails.
+///
+/// # Example
+/// ```
+/// for port in 0..4 {
+///     let _ = create_thread_alloc(1024, move || serve(port));
+/// }
+/// ```
+pub fn create_thread_alloc<F>(stack_words: usize, f: F) -> Result<ThreadHandle, Error>
+where
+    F: FnOnce() + Send + 'static,
+{
+    create_thread_alloc_with_config(stack_words, f, 0x00, false)
+}
+
+/// Create a thread on an allocated stack with explicit configuration, see
+/// `create_thread_alloc` and `create_thread_with_config`.
+pub fn create_thread_alloc_with_config<F>(
+    stack_words: usize,
+    f: F,
+    priority: u8,
+    priviliged: bool,
+) -> Result<ThreadHandle, Error>
+where
+    F: FnOnce() + Send + 'static,
+{
+    reclaim_stacks();
+    if stack_words < MIN_STACK_WORDS {
+        return Err(Error::StackTooSmall);
+    }
+    let layout = layout(stack_words)?;
+    let base = unsafe { alloc(layout) } as *mut u32;
+    if base.is_null() {
+        #[cfg(feature = "defmt")]
+        defmt::trace!("create thread: no memory for {} stack words", stack_words);
+        return Err(Error::OutOfMemory);
+    }
+    let stack = unsafe { slice::from_raw_parts_mut(base, stack_words) };
+    // the thread must not run, and exit, before its slot is marked as owning the stack
+    scheduler_suspend();
+    let created = create_thread_closure_with_config(stack, f, priority, priviliged);
+    if let Ok(thread) = created {
+        with_state(|s| s.threads[thread.0].heap_words = stack_words as u32);
+    }
+    scheduler_resume();
+    if created.is_err() {
+        unsafe { dealloc(base as *mut u8, layout) };
+    }
+    created
+}
+
+/// Free the allocated stacks of threads which exited and were switched out
+pub(crate) fn reclaim_stacks() {
+    loop {
+        let stack = with_state(|s| {
+            let idx = (1..s.threads.len()).find(|&i| {
+                s.threads[i].status == ThreadStatus::Free
+                    && s.threads[i].heap_words != 0
+                    && s.curr != &s.threads[i] as *const _ as usize
+            })?;
+            let words = s.threads[idx].heap_words as usize;
+            s.threads[idx].heap_words = 0;
+            Some((s.threads[idx].stack_base as *mut u8, words))
+        });
+        match stack {
+            Some((base, words)) => unsafe {
+                if let Ok(layout) = layout(words) {
+                    dealloc(base, layout);
+                }
+            },
+            None => return,
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Points in time and spans of time, counted in kernel ticks.
+//!
+//! The kernel counts ticks in 64 bits, which lasts about 584 million years at 1 kHz, so
+//! deadlines and uptime survive the 2^32 ticks at which a 32-bit count wraps after 49 days.
+//! `Instant` and `Duration` keep application code from truncating ticks to u32 on the way:
+//! instants are ordered by their tick counts, and moved by spans with wrapping arithmetic.
+
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+use crate::{ms_to_ticks, sleep_until, tick_hz, ticks, ticks_to_ms};
+
+/// Tick count at some moment, see `Instant::now`
+///
+/// Example:
+/// ```
+/// let deadline = Instant::now() + Duration::from_millis(500);
+/// while !link_up() {
+///     if Instant::now() >= deadline {
+///         return Err(LinkError::Timeout);
+///     }
+///     sleep(1);
+/// }
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current tick count
+    pub fn now() -> Self {
+        Instant(ticks())
+    }
+
+    /// The moment the tick count is `ticks`
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Instant(ticks)
+    }
+
+    /// Tick count of this moment, as taken by `sleep_until`
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to this moment, zero if `earlier` is later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier)
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// Time from `earlier` to this moment, None if `earlier` is later
+    pub fn checked_duration_since(self, earlier: Instant) -> Option<Duration> {
+        if self >= earlier {
+            Some(Duration(self.0 - earlier.0))
+        } else {
+            None
+        }
+    }
+
+    /// Time since this moment
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    /// Make the current thread sleep until this moment, see `sleep_until`
+    pub fn sleep_until(self) {
+        sleep_until(self.0);
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0.wrapping_sub(rhs.0))
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    /// Same as `duration_since`
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Number of ticks between two moments
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Duration(u64);
+
+impl Duration {
+    pub const ZERO: Duration = Duration(0);
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Duration(ticks)
+    }
+
+    /// Ticks lasting at least `ms` milliseconds at the tick rate set by `set_tick_hz`
+    pub fn from_millis(ms: u64) -> Self {
+        Duration(ms_to_ticks(ms))
+    }
+
+    /// Ticks lasting `secs` seconds at the tick rate set by `set_tick_hz`
+    pub fn from_secs(secs: u64) -> Self {
+        Duration(secs.saturating_mul(tick_hz() as u64))
+    }
+
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Length in milliseconds, rounded down
+    pub fn as_millis(self) -> u64 {
+        ticks_to_ms(self.0)
+    }
+
+    pub fn checked_add(self, rhs: Duration) -> Option<Duration> {
+        self.0.checked_add(rhs.0).map(Duration)
+    }
+
+    pub fn checked_sub(self, rhs: Duration) -> Option<Duration> {
+        self.0.checked_sub(rhs.0).map(Duration)
+    }
+
+    pub fn saturating_sub(self, rhs: Duration) -> Duration {
+        Duration(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Duration {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Duration) -> Duration {
+        Duration(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Duration {
+    fn sub_assign(&mut self, rhs: Duration) {
+        self.0 -= rhs.0;
+    }
+}
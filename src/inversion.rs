@@ -0,0 +1,76 @@
+//! Priority inversion detection, with the `inversion-detection` feature.
+//!
+//! On every tick, threads blocked on a mutex are compared with the thread holding it. A thread
+//! which waited longer than a set number of ticks for a mutex held by a lower priority thread
+//! suffers a priority inversion: any thread of intermediate priority can delay it further.
+//! Each such wait is reported once to a hook, which tells whether priority inheritance is
+//! needed, and where.
+
+use crate::{budget, mutex, with_state, ThreadHandle, ThreadStatus, ThreadsState};
+
+/// A thread kept waiting by a lower priority one, passed to the hook set with
+/// `set_inversion_hook`
+#[derive(Clone, Copy, Debug)]
+pub struct Inversion {
+    /// thread blocked on the mutex
+    pub blocked: ThreadHandle,
+    /// thread holding the mutex, of lower priority
+    pub holder: ThreadHandle,
+    /// address of the `Mutex`
+    pub mutex: usize,
+    /// ticks the blocked thread has waited so far
+    pub ticks: u64,
+}
+
+/// Report threads blocked for `after_ticks` ticks or more on a mutex held by a lower priority
+/// thread to `hook`. Each wait is reported once, from the tick handler. An `after_ticks` of 0
+/// disables the check, which is the default.
+///
+/// # Example
+/// ```
+/// fn inversion(inversion: &Inversion) {
+///     let _ = hprintln!("thread {} waited {} ticks for thread {}", inversion.blocked.id(),
+///         inversion.ticks, inversion.holder.id());
+/// }
+/// set_inversion_hook(5, inversion);
+/// ```
+pub fn set_inversion_hook(after_ticks: u32, hook: fn(&Inversion)) {
+    with_state(|s| {
+        s.inversion_ticks = after_ticks;
+        s.inversion_hook = Some(hook);
+    });
+}
+
+/// Report the inversions which lasted long enough since the previous tick
+pub(crate) fn check(s: &mut ThreadsState) {
+    let hook = match s.inversion_hook {
+        Some(hook) if s.inversion_ticks > 0 => hook,
+        _ => return,
+    };
+    let now = s.ticks;
+    for idx in 1..s.threads.len() {
+        let thread = &s.threads[idx];
+        if thread.status != ThreadStatus::Blocked
+            || thread.blocked_on == 0
+            || thread.inversion_reported
+            || now - thread.status_tick < s.inversion_ticks as u64
+        {
+            continue;
+        }
+        // the mutex outlives the wait of the thread blocked on it
+        let holder = match unsafe { mutex::holder(thread.blocked_on) } {
+            Some(holder) if s.threads[holder].priority < thread.priority => holder,
+            _ => continue,
+        };
+        let inversion = Inversion {
+            blocked: ThreadHandle(idx),
+            holder: ThreadHandle(holder),
+            mutex: thread.blocked_on,
+            ticks: now - thread.status_tick,
+        };
+        s.threads[idx].inversion_reported = true;
+        #[cfg(feature = "defmt")]
+        defmt::trace!("priority inversion: thread {} waits on {}", idx, holder);
+        budget::run_callback(&s.callback_budget, "inversion hook", || hook(&inversion));
+    }
+}
@@ -1,5 +1,5 @@
 //!
-//! A simple library for context-switching on ARM Cortex-M ( 0, 0+, 3, 4, 4F ) micro-processors
+//! A simple library for context-switching on ARM Cortex-M ( 0, 0+, 3, 4, 4F, 7, 23, 33 ) micro-processors
 //!
 //! Supports pre-emptive, priority based switching
 //!
@@ -17,7 +17,7 @@
 //! use cortex_m::peripheral::syst::SystClkSource;
 //! use cortex_m_rt::{entry, exception};
 //! use cortex_m_semihosting::{hprintln};
-//! use cortexm_threads::{init, create_thread, create_thread_with_config, sleep};
+//! use cortexm_threads::{init, create_thread, create_thread_with_config, sleep, Stack};
 //!
 //! #[entry]
 //! fn main() -> ! {
@@ -28,10 +28,10 @@
 //!     syst.enable_counter();
 //!     syst.enable_interrupt();
 //!
-//! 	let mut stack1 = [0xDEADBEEF; 512];
-//!     let mut stack2 = [0xDEADBEEF; 512];
+//! 	static STACK1: Stack<512> = Stack::new();
+//!     static STACK2: Stack<512> = Stack::new();
 //!     let _ = create_thread(
-//!         &mut stack1,
+//!         STACK1.take().unwrap(),
 //!         || {
 //!             loop {
 //!                 let _ = hprintln!("in task 1 !!");
@@ -39,7 +39,7 @@
 //!             }
 //!         });
 //!     let _ = create_thread_with_config(
-//!         &mut stack2,
+//!         STACK2.take().unwrap(),
 //!         || {
 //!             loop {
 //!                 let _ = hprintln!("in task 2 !!");
@@ -52,20 +52,323 @@
 //!     init();
 //! }
 //! ```
-#![no_std]
+#![cfg_attr(not(feature = "sim"), no_std)]
 
 use core::ptr;
 
-/// Returned by create_thread or create_thread_with_config as Err(ERR_TOO_MANY_THREADS)
-/// if creating a thread will cause more than 32 threads to exist (inclusing the idle thread)
-/// created by this library
+#[cfg(feature = "macros")]
+pub use cortexm_threads_macros::{thread, threads};
+
+mod aging;
+pub use aging::set_priority_aging;
+#[cfg(feature = "rp2040-amp")]
+mod amp;
+#[cfg(feature = "rp2040-amp")]
+pub use amp::{core_id, spawn_on_core, CoreChannel};
+mod arch;
+use arch::{Arch, Port};
+#[cfg(feature = "rtos-awareness")]
+mod awareness;
+#[cfg(feature = "rtos-awareness")]
+pub use awareness::{KernelInfo, CONTEXT_R4_R11, CONTEXT_R4_R11_EXC_RETURN, CONTEXT_R8_R11_R4_R7};
+mod barrier;
+pub use barrier::Barrier;
+mod broadcast;
+pub use broadcast::{Broadcast, Subscriber};
+mod builder;
+pub use builder::{Closure, Thread, WithArg};
+mod budget;
+#[cfg(feature = "callback-budget")]
+pub use budget::set_callback_budget;
+mod bufqueue;
+pub use bufqueue::BufQueue;
+mod busy;
+pub use busy::{core_hz, delay_us, set_core_hz};
+#[cfg(feature = "test_harness")]
+pub mod test_harness;
+
+mod condvar;
+pub use condvar::CondVar;
+mod contain;
+pub use contain::{contain_panic, set_panic_hook, PanicAction};
+#[cfg(feature = "crash-handler")]
+mod crash;
+#[cfg(feature = "crash-handler")]
+pub use crash::{set_crash_hook, CrashInfo, ExceptionFrame};
+mod critical;
+pub use critical::{with_critical, CriticalGuard};
+use critical::{CriticalSection, Shared};
+mod cycles;
+mod deadlock;
+#[cfg(any(feature = "embedded-hal", feature = "embedded-hal-02"))]
+mod delay;
+#[cfg(any(feature = "embedded-hal", feature = "embedded-hal-02"))]
+pub use delay::ThreadDelay;
+#[cfg(feature = "fugit")]
+mod duration;
+#[cfg(feature = "deadlock-detection")]
+pub use deadlock::{set_deadlock_hook, Deadlock};
+#[cfg(feature = "fugit")]
+pub use duration::{sleep_for, to_ticks, IntoTicks};
+mod exception;
+pub use exception::{set_exception_priorities, ExceptionPriorities};
+mod executor;
+pub use executor::{block_on, thread_waker};
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "alloc")]
+mod heap;
+#[cfg(feature = "alloc")]
+pub use heap::{create_thread_alloc, create_thread_alloc_with_config};
+mod instant;
+pub use instant::{Duration, Instant};
+#[cfg(feature = "inversion-detection")]
+mod inversion;
+#[cfg(feature = "inversion-detection")]
+pub use inversion::{set_inversion_hook, Inversion};
+mod lifecycle;
+pub use lifecycle::{set_lifecycle_hook, ThreadEvent};
+#[cfg(feature = "cpu-usage")]
+mod load;
+#[cfg(feature = "cpu-usage")]
+pub use load::{cpu_load_percent, LOAD_SLOTS, LOAD_SLOT_TICKS};
+mod mailbox;
+pub use mailbox::Mailbox;
+mod mode;
+pub use mode::{Modes, ThreadGroup};
+#[cfg(any(feature = "mpu-stack-guard", feature = "mpu-isolation"))]
+mod mpu;
+#[cfg(feature = "mpu-isolation")]
+pub use mpu::{set_thread_regions, MpuRegion, RegionAccess, MPU_REGIONS};
+mod mutex;
+pub use mutex::{Mutex, MutexGuard};
+mod periodic;
+pub use periodic::{
+    create_periodic_thread, create_periodic_thread_with_config, set_deadline_miss_hook, Periodic,
+    PhaseGroup, PhaseMember,
+};
+mod pool;
+pub use pool::{Pool, PoolBox};
+#[cfg(feature = "trace-ram")]
+mod postmortem;
+#[cfg(feature = "trace-ram")]
+pub use postmortem::{postmortem_clear, postmortem_log, POSTMORTEM_EVENTS};
+mod quota;
+pub use quota::{set_budget_supervisor, set_cpu_budget, BudgetAction};
+#[cfg(feature = "replay")]
+pub mod replay;
+mod ring;
+pub use ring::{Consumer, Producer, RingBuffer};
+mod rpc;
+pub use rpc::Rpc;
+#[cfg(feature = "trace-systemview")]
+mod rtt;
+mod rwlock;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard, RwPreference};
+mod sched;
+pub use sched::{set_scheduler, ReadyThreads, Scheduler};
+mod select;
+pub use select::{select, select_timeout, Select};
+#[cfg(feature = "sim")]
+pub mod sim;
+#[cfg(feature = "heapless")]
+mod spsc;
+#[cfg(feature = "heapless")]
+pub use spsc::{BlockingConsumer, SignalingProducer, SpscSignal};
+mod stack;
+pub use stack::Stack;
+#[cfg(feature = "kernel-stats")]
+mod stats;
+#[cfg(feature = "kernel-stats")]
+pub use stats::{kernel_stats, KernelStats};
+mod stream;
+pub use stream::{StreamBuffer, StreamReader, StreamWriter};
+mod svc;
+#[cfg(feature = "trace-systemview")]
+mod systemview;
+#[cfg(feature = "trace-systemview")]
+pub use systemview::start_systemview;
+#[cfg(feature = "cortex-m")]
+mod systick;
+#[cfg(feature = "cortex-m")]
+pub use systick::init_with_systick;
+#[cfg(feature = "systick-handler")]
+pub use systick::set_tick_hook;
+mod tick;
+pub use tick::{set_tick_rate, set_tick_source, SysTickSource, TickSource};
+#[cfg(feature = "tickless")]
+mod tickless;
+#[cfg(feature = "tickless")]
+pub use tickless::tickless_idle;
+mod time;
+mod timeout;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::{tls_get, tls_set, TLS_SLOTS};
+mod trace;
+#[cfg(feature = "trustzone")]
+mod trustzone;
+pub use time::{ms_to_ticks, set_tick_hz, tick_hz, ticks_to_ms, uptime_ms};
+use trace::record;
+#[cfg(feature = "trace")]
+pub use trace::set_trace_sink;
+pub use trace::{TraceEvent, TraceKind, RECORD_SIZE};
+#[cfg(feature = "trustzone")]
+pub use trustzone::{set_secure_context, set_secure_context_ops};
+#[cfg(feature = "validation")]
+mod validate;
+#[cfg(feature = "validation")]
+pub use validate::{
+    check_config, create_thread_checked, validate_thread, ConfigError, Issue, ValidationReport,
+};
+mod wait;
+use wait::WaitList;
+pub use wait::WaitOrder;
+mod workqueue;
+pub use workqueue::{defer, start_work_queue, WORK_QUEUE_LEN};
+
+/// Refers to a thread, returned when it is created and by `get_thread_id`. Handles are thread
+/// table slots, which are reused once a thread exits, so a handle kept after its thread
+/// exited may come to refer to a newer thread.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ThreadHandle(usize);
+
+impl ThreadHandle {
+    /// Index of the thread in the thread table, 0 for the idle thread. This is the thread id
+    /// found in trace records.
+    pub fn id(self) -> usize {
+        self.0
+    }
+}
+
+/// Errors returned by the fallible functions of this crate
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// creating a thread would cause more than MAX_THREADS threads to exist (including the
+    /// idle thread)
+    TooManyThreads,
+    /// array to be used as stack area is too small. Smallest size is MIN_STACK_WORDS u32's
+    StackTooSmall,
+    /// an unprivileged thread asked for a privileged thread, or for a higher priority than
+    /// its own
+    NoCreatePrivilege,
+    /// id does not refer to an existing thread. join also returns it for the idle thread and
+    /// the caller itself, or if called from outside a user thread
+    NoSuchThread,
+    /// `_timeout` variant of a blocking call woken because its timeout expired
+    TimedOut,
+    /// a queue has no room for another item
+    QueueFull,
+    /// thread runs a closure, which it consumed when it started, or is the running thread
+    NotRestartable,
+    /// the global allocator could not provide the memory, e.g. for a thread stack
+    OutOfMemory,
+    /// a `Stack` was already taken, e.g. by a `#[thread]` spawned twice, or an unprivileged
+    /// thread passed the stack of a running thread
+    StackInUse,
+    /// memory area which is not a valid MPU region: its size is not a power of two of at
+    /// least 32 bytes, or its address is not a multiple of its size. Also returned for more
+    /// than `MPU_REGIONS` regions.
+    BadRegion,
+    /// a thread unlocked a mutex which it does not hold
+    NotHolder,
+}
+
+#[cfg(feature = "error-codes")]
+impl Error {
+    /// Numeric code of the error, as returned before `Error` was introduced
+    pub fn code(self) -> u8 {
+        match self {
+            Error::TooManyThreads => ERR_TOO_MANY_THREADS,
+            Error::StackTooSmall => ERR_STACK_TOO_SMALL,
+            Error::NoCreatePrivilege => ERR_NO_CREATE_PRIV,
+            Error::NoSuchThread => ERR_NO_SUCH_THREAD,
+            Error::TimedOut => ERR_TIMED_OUT,
+            Error::QueueFull => ERR_QUEUE_FULL,
+            Error::NotRestartable => ERR_NOT_RESTARTABLE,
+            Error::OutOfMemory => ERR_OUT_OF_MEMORY,
+            Error::StackInUse => ERR_STACK_IN_USE,
+            Error::BadRegion => ERR_BAD_REGION,
+            Error::NotHolder => ERR_NOT_HOLDER,
+        }
+    }
+}
+
+#[cfg(feature = "error-codes")]
+impl From<Error> for u8 {
+    fn from(e: Error) -> u8 {
+        e.code()
+    }
+}
+
+/// Numeric code of Error::TooManyThreads
+#[cfg(feature = "error-codes")]
 pub static ERR_TOO_MANY_THREADS: u8 = 0x01;
-/// Returned by create_thread or create_thread_with_config as Err(ERR_STACK_TOO_SMALL)
-/// if array to be used as stack area is too small. Smallest size is 32 u32's
+/// Numeric code of Error::StackTooSmall
+#[cfg(feature = "error-codes")]
 pub static ERR_STACK_TOO_SMALL: u8 = 0x02;
-/// Returned by create_thread or create_thread_with_config as Err(ERR_NO_CREATE_PRIV)
-/// if called from an unprivileged thread
+/// Numeric code of Error::NoCreatePrivilege
+#[cfg(feature = "error-codes")]
 pub static ERR_NO_CREATE_PRIV: u8 = 0x03;
+/// Numeric code of Error::NoSuchThread
+#[cfg(feature = "error-codes")]
+pub static ERR_NO_SUCH_THREAD: u8 = 0x04;
+/// Numeric code of Error::TimedOut
+#[cfg(feature = "error-codes")]
+pub static ERR_TIMED_OUT: u8 = 0x05;
+/// Numeric code of Error::QueueFull
+#[cfg(feature = "error-codes")]
+pub static ERR_QUEUE_FULL: u8 = 0x06;
+/// Numeric code of Error::NotRestartable
+#[cfg(feature = "error-codes")]
+pub static ERR_NOT_RESTARTABLE: u8 = 0x07;
+/// Numeric code of Error::OutOfMemory
+#[cfg(feature = "error-codes")]
+pub static ERR_OUT_OF_MEMORY: u8 = 0x08;
+/// Numeric code of Error::StackInUse
+#[cfg(feature = "error-codes")]
+pub static ERR_STACK_IN_USE: u8 = 0x09;
+/// Numeric code of Error::BadRegion
+#[cfg(feature = "error-codes")]
+pub static ERR_BAD_REGION: u8 = 0x0A;
+/// Numeric code of Error::NotHolder
+#[cfg(feature = "error-codes")]
+pub static ERR_NOT_HOLDER: u8 = 0x0B;
+
+/// Maximum number of threads, including the idle thread. 32 by default, reduced with the
+/// `threads-4`, `threads-8` or `threads-16` features to save RAM on small parts. The
+/// smallest selected size wins.
+#[cfg(feature = "threads-4")]
+pub const MAX_THREADS: usize = 4;
+#[cfg(all(feature = "threads-8", not(feature = "threads-4")))]
+pub const MAX_THREADS: usize = 8;
+#[cfg(all(
+    feature = "threads-16",
+    not(any(feature = "threads-4", feature = "threads-8"))
+))]
+pub const MAX_THREADS: usize = 16;
+#[cfg(not(any(feature = "threads-4", feature = "threads-8", feature = "threads-16")))]
+pub const MAX_THREADS: usize = 32;
+
+/// Smallest stack area, in u32's, accepted for a thread
+pub const MIN_STACK_WORDS: usize = 32;
+
+/// Size in u32's of the idle thread's stack allocated by `init`. Use `init_with_idle` if the
+/// idle hook or idle body needs more.
+pub const IDLE_STACK_WORDS: usize = 64;
+
+/// Number of u32's at the bottom of each thread's stack painted with STACK_CANARY
+pub const CANARY_WORDS: usize = 4;
+/// Pattern painted at the bottom of thread stacks, overwritten when a stack overflows
+pub const STACK_CANARY: u32 = 0xC0DEFACE;
+/// Pattern the rest of a thread's stack is filled with at creation, to measure stack usage
+pub const STACK_FILL: u32 = 0xDEADBEEF;
+
+/// wake_tick of a thread blocked without timeout
+const NO_DEADLINE: u64 = u64::MAX;
 
 /// Context switching and threads' state
 #[repr(C)]
@@ -76,16 +379,139 @@ struct ThreadsState {
     // end fields used in assembly
     inited: bool,
     idx: usize,
-    add_idx: usize,
-    threads: [ThreadControlBlock; 32],
+    /// number of ticks handled since init
+    ticks: u64,
+    /// arrival counter for ordering threads on wait lists
+    wait_seq: u32,
+    /// nesting depth of `scheduler_suspend` calls
+    suspended: u32,
+    /// a switch was due while the scheduler was suspended
+    switch_missed: bool,
+    /// thread which called `yield_now` since the last switch, 0 if none
+    yielded: usize,
+    /// threads other than idle whose status is Idle, i.e. ready to run
+    ready: sched::ReadyQueue,
+    /// policy installed instead of the fixed-priority one
+    scheduler: Option<&'static dyn Scheduler>,
+    /// sleeping threads and blocked threads with a timeout, by wake tick
+    timeouts: timeout::TimeoutList,
+    /// ticks a ready thread waits before its priority is boosted, 0 if aging is off
+    aging_ticks: u32,
+    /// priority levels added by each aging boost
+    aging_boost: u8,
+    /// called with the thread id when a stack canary is found overwritten
+    stack_overflow_hook: Option<fn(ThreadHandle)>,
+    /// called with the thread id when a thread uses up its CPU budget
+    budget_supervisor: Option<fn(ThreadHandle)>,
+    /// called with the threads of a deadlock, instead of panicking
+    #[cfg(feature = "deadlock-detection")]
+    deadlock_hook: Option<fn(&Deadlock)>,
+    /// ticks a thread may wait for a lower priority mutex holder before it is reported
+    #[cfg(feature = "inversion-detection")]
+    inversion_ticks: u32,
+    /// called with priority inversions lasting inversion_ticks
+    #[cfg(feature = "inversion-detection")]
+    inversion_hook: Option<fn(&Inversion)>,
+    /// called with a thread which panicked, returns whether to restart it
+    panic_hook: Option<fn(ThreadHandle, &core::panic::PanicInfo) -> PanicAction>,
+    /// panicked threads to restart once switched out, bit i for thread i
+    restarts: u32,
+    /// called with the crash report by the HardFault handler
+    #[cfg(feature = "crash-handler")]
+    crash_hook: Option<fn(&CrashInfo)>,
+    /// called by a periodic thread overrunning its period
+    deadline_miss_hook: Option<fn(ThreadHandle)>,
+    /// called by the idle thread before each `wfe`
+    idle_hook: Option<fn()>,
+    /// called by the idle thread right before and after the core sleeps
+    sleep_hooks: Option<SleepHooks>,
+    /// called with the ids of the previous and next thread on every thread switch
+    switch_hook: Option<fn(ThreadHandle, ThreadHandle)>,
+    /// called on thread creation, exit, starvation and stack overflow
+    lifecycle_hook: Option<fn(ThreadEvent, &ThreadInfo)>,
+    /// called on every tick by the SysTick handler of the `systick-handler` feature
+    #[cfg(feature = "systick-handler")]
+    tick_hook: Option<fn()>,
+    /// exception priorities programmed by `init` instead of the default ones
+    exception_priorities: Option<ExceptionPriorities>,
+    /// timer calling `SysTick()`, stopped and restarted around tickless idle periods
+    tick_source: &'static dyn TickSource,
+    /// cycles accounted to all threads since init
+    total_cycles: u64,
+    /// cycle count at the last accounting
+    #[cfg(feature = "cpu-usage")]
+    last_cycles: u64,
+    /// idle and total cycles over the window of `cpu_load_percent`
+    #[cfg(feature = "cpu-usage")]
+    load: load::Load,
+    /// thread switches since init
+    #[cfg(feature = "kernel-stats")]
+    switches: u64,
+    /// longest scheduler run in cycles
+    #[cfg(feature = "kernel-stats")]
+    max_schedule_cycles: u32,
+    callback_budget: budget::Budget,
+    #[cfg(feature = "trace")]
+    trace_sink: Option<fn(&TraceEvent)>,
+    #[cfg(feature = "trace-systemview")]
+    systemview: systemview::State,
+    #[cfg(feature = "trustzone")]
+    secure_context_ops: Option<trustzone::SecureContextOps>,
+    threads: [ThreadControlBlock; MAX_THREADS],
 }
 
 /// Thread status
 #[repr(C)]
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum ThreadStatus {
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ThreadStatus {
+    /// slot is not in use, can be taken by a new thread
+    Free,
+    /// ready to run, or running
     Idle,
+    /// waiting for its wake tick to be reached
     Sleeping,
+    /// waiting on a wait list, e.g. for another thread to exit in `join`
+    Blocked,
+}
+
+/// Why a thread last went from a waiting state back to ready
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WakeReason {
+    /// thread has not been woken since it was created
+    None,
+    /// wake tick of a sleep, or timeout of a blocking call, was reached
+    Timeout,
+    /// the thread being joined exited
+    Exited,
+    /// another thread or an interrupt handed over the resource being waited for
+    Signaled,
+    /// woken early by `wake`
+    Woken,
+}
+
+/// Snapshot of a thread's state, returned by `thread_info`
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadInfo {
+    pub id: ThreadHandle,
+    /// name given with `set_thread_name`, empty if none
+    pub name: &'static str,
+    pub priority: u8,
+    pub privileged: bool,
+    pub status: ThreadStatus,
+    /// reason of the last transition from a waiting state to ready
+    pub wake_reason: WakeReason,
+    /// tick count at the last status transition
+    pub status_tick: u64,
+    /// size of the stack area in u32's
+    pub stack_size: usize,
+    /// most u32's of the stack area ever used, including the canary
+    pub stack_used: usize,
+    /// instances of a periodic thread which overran their period, see
+    /// `create_periodic_thread`
+    pub deadline_misses: u32,
 }
 
 /// A single thread's state
@@ -96,78 +522,415 @@ struct ThreadControlBlock {
     /// current stack pointer of this thread
     sp: u32,
     privileged: u32, // make it a word, assembly is easier. FIXME
+    /// lowest address the stack pointer may reach, loaded into PSPLIM/MSPLIM on ARMv8-M
+    #[cfg(armv8m)]
+    stack_limit: u32,
     // end fields used in assembly
+    /// effective priority, raised above base_priority by aging
     priority: u8,
+    /// priority the thread was created with
+    base_priority: u8,
+    /// highest ceiling of the priority ceiling mutexes the thread holds, 0 if none
+    ceiling: u8,
     status: ThreadStatus,
-    sleep_ticks: u32,
+    /// tick at which a sleeping or blocked thread becomes ready again, NO_DEADLINE if none
+    wake_tick: u64,
+    /// tick at which the thread last became ready or was switched out, or was boosted
+    ready_since: u64,
+    /// next thread on the timeout list, 0 if last
+    timeout_next: u8,
+    /// threads waiting for this one to exit
+    joiners: WaitList,
+    /// arrival order on the wait list this thread is blocked on
+    wait_seq: u32,
+    /// a waker of the thread was woken while it was not parked, see `executor`
+    notified: bool,
+    /// blocked in `block_on` until a waker of the thread is woken
+    parked: bool,
+    /// kept off the ready queue, see `ThreadGroup::suspend`
+    suspended: bool,
+    /// address of the mutex this thread is blocked on, 0 if none
+    #[cfg(any(feature = "deadlock-detection", feature = "inversion-detection"))]
+    blocked_on: usize,
+    /// priority inversion of the current wait was already reported
+    #[cfg(feature = "inversion-detection")]
+    inversion_reported: bool,
+    wake_reason: WakeReason,
+    status_tick: u64,
+    /// lowest address of the stack area, where the canary is painted
+    stack_base: usize,
+    /// size of the stack area in u32's
+    stack_words: u32,
+    /// address the thread started at
+    entry: usize,
+    /// R0 and R1 the thread started with
+    args: [usize; 2],
+    /// the thread can be started over from `entry`, see `restart`
+    restartable: bool,
+    name: &'static str,
+    /// cycles spent running this thread
+    cycles: u64,
+    /// periods overrun by a periodic thread
+    deadline_misses: u32,
+    /// CPU budget set with `set_cpu_budget`
+    quota: Option<quota::Quota>,
+    /// thread-local storage slots
+    #[cfg(feature = "tls")]
+    tls: [usize; TLS_SLOTS],
+    /// size in u32's of the stack allocated by `create_thread_alloc`, 0 if none. Kept once
+    /// the thread exited, until the stack is freed.
+    #[cfg(feature = "alloc")]
+    heap_words: u32,
+    /// times this thread was switched to
+    #[cfg(feature = "kernel-stats")]
+    switch_ins: u32,
+    /// stack overflow was already reported
+    overflowed: bool,
+    /// secure context handle of a thread calling secure services, 0 if none
+    #[cfg(feature = "trustzone")]
+    secure_context: u32,
+    /// memory the thread may access besides its stack, see `set_thread_regions`
+    #[cfg(feature = "mpu-isolation")]
+    regions: [MpuRegion; MPU_REGIONS],
+}
+
+impl ThreadControlBlock {
+    /// Sleeping or blocked, i.e. waiting for a wake tick or an event
+    fn waiting(&self) -> bool {
+        self.status == ThreadStatus::Sleeping || self.status == ThreadStatus::Blocked
+    }
+
+    /// Idle and not suspended, i.e. on the ready queue
+    fn runnable(&self) -> bool {
+        self.status == ThreadStatus::Idle && !self.suspended
+    }
+}
+
+impl ThreadsState {
+    /// Change the status of thread `idx`, keeping the ready queue and the timeout list in
+    /// step. The wake tick of a sleeping or blocked thread must be set before.
+    fn set_status(&mut self, idx: usize, status: ThreadStatus, now: u64) {
+        let priority = self.threads[idx].priority;
+        // the idle thread runs when the queue is empty, it is never in it
+        if idx > 0 && status == ThreadStatus::Idle && !self.threads[idx].suspended {
+            self.ready.insert(idx, priority);
+        } else if idx > 0 {
+            self.ready.remove(idx, priority);
+        }
+        if self.threads[idx].waiting() {
+            self.timeouts.remove(&mut self.threads, idx);
+        }
+        self.threads[idx].status = status;
+        if self.threads[idx].waiting() && self.threads[idx].wake_tick != NO_DEADLINE {
+            self.timeouts.insert(&mut self.threads, idx);
+        }
+        if status == ThreadStatus::Idle {
+            self.threads[idx].ready_since = now;
+        }
+        self.threads[idx].status_tick = now;
+    }
+
+    /// Whether slot `idx` can take a new thread: it is free, and neither kept for a panicked
+    /// thread about to restart nor holding an allocated stack not yet freed
+    fn slot_available(&self, idx: usize) -> bool {
+        #[cfg(feature = "alloc")]
+        if self.threads[idx].heap_words != 0 {
+            return false;
+        }
+        self.threads[idx].status == ThreadStatus::Free && self.restarts & (1 << idx) == 0
+    }
+
+    /// Change the effective priority of thread `idx`, keeping the ready queue in step
+    fn set_priority(&mut self, idx: usize, priority: u8) {
+        let old = self.threads[idx].priority;
+        if old == priority {
+            return;
+        }
+        if idx > 0 && self.threads[idx].runnable() {
+            self.ready.remove(idx, old);
+            self.ready.insert(idx, priority);
+        }
+        self.threads[idx].priority = priority;
+        record(self, TraceKind::Priority, idx, old as u32);
+    }
+
+    /// Set the mutex ceiling of thread `idx`, and its priority to the higher of the ceiling
+    /// and its base priority
+    fn set_ceiling(&mut self, idx: usize, ceiling: u8) {
+        self.threads[idx].ceiling = ceiling;
+        let priority = self.threads[idx].base_priority.max(ceiling);
+        self.set_priority(idx, priority);
+    }
+
+    /// Suspend or resume thread `idx`, other than the idle thread. A suspended thread keeps
+    /// its status, and still sleeps and blocks, but is not ready to run even when idle.
+    fn set_suspended(&mut self, idx: usize, suspended: bool, now: u64) {
+        if idx == 0 || self.threads[idx].suspended == suspended {
+            return;
+        }
+        self.threads[idx].suspended = suspended;
+        let priority = self.threads[idx].priority;
+        if suspended {
+            self.ready.remove(idx, priority);
+        } else if self.threads[idx].status == ThreadStatus::Idle {
+            self.ready.insert(idx, priority);
+            self.threads[idx].ready_since = now;
+        }
+    }
+
+    /// Make sleeping or blocked thread `idx` ready
+    fn wake(&mut self, idx: usize, reason: WakeReason, now: u64) {
+        self.set_status(idx, ThreadStatus::Idle, now);
+        self.threads[idx].wake_reason = reason;
+        self.threads[idx].parked = false;
+    }
 }
 
 // GLOBALS:
-#[no_mangle]
-static mut __CORTEXM_THREADS_GLOBAL_PTR: u32 = 0;
-static mut __CORTEXM_THREADS_GLOBAL: ThreadsState = ThreadsState {
+/// Kernel state before `init`
+const INITIAL_STATE: ThreadsState = ThreadsState {
     curr: 0,
     next: 0,
     inited: false,
     idx: 0,
-    add_idx: 1,
+    ticks: 0,
+    wait_seq: 0,
+    suspended: 0,
+    switch_missed: false,
+    yielded: 0,
+    ready: sched::ReadyQueue::new(),
+    scheduler: None,
+    timeouts: timeout::TimeoutList::new(),
+    aging_ticks: 0,
+    aging_boost: 0,
+    stack_overflow_hook: None,
+    budget_supervisor: None,
+    #[cfg(feature = "deadlock-detection")]
+    deadlock_hook: None,
+    #[cfg(feature = "inversion-detection")]
+    inversion_ticks: 0,
+    #[cfg(feature = "inversion-detection")]
+    inversion_hook: None,
+    panic_hook: None,
+    restarts: 0,
+    #[cfg(feature = "crash-handler")]
+    crash_hook: None,
+    deadline_miss_hook: None,
+    idle_hook: None,
+    sleep_hooks: None,
+    switch_hook: None,
+    lifecycle_hook: None,
+    #[cfg(feature = "systick-handler")]
+    tick_hook: None,
+    exception_priorities: None,
+    tick_source: &SysTickSource,
+    total_cycles: 0,
+    #[cfg(feature = "cpu-usage")]
+    last_cycles: 0,
+    #[cfg(feature = "cpu-usage")]
+    load: load::Load::new(),
+    #[cfg(feature = "kernel-stats")]
+    switches: 0,
+    #[cfg(feature = "kernel-stats")]
+    max_schedule_cycles: 0,
+    callback_budget: budget::Budget::new(),
+    #[cfg(feature = "trace")]
+    trace_sink: None,
+    #[cfg(feature = "trace-systemview")]
+    systemview: systemview::State::new(),
+    #[cfg(feature = "trustzone")]
+    secure_context_ops: None,
     threads: [ThreadControlBlock {
         sp: 0,
-        status: ThreadStatus::Idle,
+        status: ThreadStatus::Free,
         priority: 0,
+        base_priority: 0,
+        ceiling: 0,
         privileged: 0,
-        sleep_ticks: 0,
-    }; 32],
+        #[cfg(armv8m)]
+        stack_limit: 0,
+        wake_tick: 0,
+        ready_since: 0,
+        timeout_next: 0,
+        joiners: WaitList::new(WaitOrder::Priority),
+        wait_seq: 0,
+        notified: false,
+        parked: false,
+        suspended: false,
+        #[cfg(any(feature = "deadlock-detection", feature = "inversion-detection"))]
+        blocked_on: 0,
+        #[cfg(feature = "inversion-detection")]
+        inversion_reported: false,
+        wake_reason: WakeReason::None,
+        status_tick: 0,
+        stack_base: 0,
+        stack_words: 0,
+        entry: 0,
+        args: [0; 2],
+        restartable: false,
+        name: "",
+        cycles: 0,
+        deadline_misses: 0,
+        quota: None,
+        #[cfg(feature = "tls")]
+        tls: [0; TLS_SLOTS],
+        #[cfg(feature = "alloc")]
+        heap_words: 0,
+        #[cfg(feature = "kernel-stats")]
+        switch_ins: 0,
+        overflowed: false,
+        #[cfg(feature = "trustzone")]
+        secure_context: 0,
+        #[cfg(feature = "mpu-isolation")]
+        regions: [MpuRegion::NONE; MPU_REGIONS],
+    }; MAX_THREADS],
 };
+#[cfg(not(feature = "rp2040-amp"))]
+static KERNEL: Shared<ThreadsState> = Shared::new(INITIAL_STATE);
+/// Kernel state of each core, see the amp module
+#[cfg(feature = "rp2040-amp")]
+static KERNELS: [Shared<ThreadsState>; amp::CORES] =
+    [Shared::new(INITIAL_STATE), Shared::new(INITIAL_STATE)];
 // end GLOBALS
 
-// functions defined in assembly
-extern "C" {
-    fn __CORTEXM_THREADS_cpsid();
-    fn __CORTEXM_THREADS_cpsie();
-    fn __CORTEXM_THREADS_wfe();
+/// Kernel state of the calling core
+#[cfg(not(feature = "rp2040-amp"))]
+fn kernel() -> &'static Shared<ThreadsState> {
+    &KERNEL
+}
+
+/// Kernel state of the calling core
+#[cfg(feature = "rp2040-amp")]
+fn kernel() -> &'static Shared<ThreadsState> {
+    &KERNELS[amp::core_id()]
+}
+
+/// Kernel state, borrowed for as long as critical section `cs` is
+fn state(cs: &mut CriticalSection) -> &mut ThreadsState {
+    kernel().borrow(cs)
+}
+
+/// Run `f` on the kernel state inside a critical section
+fn with_state<R>(f: impl FnOnce(&mut ThreadsState) -> R) -> R {
+    let mut cs = CriticalSection::enter();
+    f(state(&mut cs))
 }
 
-/// Initialize the switcher system
+/// Initialize the switcher system and start the highest priority ready thread, or the idle
+/// thread if there is none. PendSV gets the lowest exception priority, see
+/// `set_exception_priorities`. Must be called from thread mode with interrupts enabled: the
+/// first thread is started by the SVCall handler, through `svc 0`. The caller's stack is not
+/// used again, except for the idle thread's stack of IDLE_STACK_WORDS u32's which `init`
+/// keeps in its own frame. With the `validation` feature, `init` panics with the
+/// `ConfigError` found by `check_config`, if any, before starting a thread.
 pub fn init() -> ! {
-    unsafe {
-        __CORTEXM_THREADS_cpsid();
-        let ptr: usize = core::intrinsics::transmute(&__CORTEXM_THREADS_GLOBAL);
-        __CORTEXM_THREADS_GLOBAL_PTR = ptr as u32;
-        __CORTEXM_THREADS_cpsie();
-        let mut idle_stack = [0xDEADBEEF; 64];
-        match create_tcb(
-            &mut idle_stack,
-            || loop {
-                __CORTEXM_THREADS_wfe();
-            },
-            0xff,
-            false,
-        ) {
-            Ok(tcb) => {
-                insert_tcb(0, tcb);
-            }
-            _ => panic!("Could not create idle thread"),
+    let mut idle_stack = [STACK_FILL; IDLE_STACK_WORDS];
+    // init never returns, so its frame outlives the idle thread
+    start(&mut idle_stack, None)
+}
+
+/// Like `init`, with `stack` as the idle thread's stack, and `body` run by the idle thread
+/// instead of the default loop, which calls the idle hook and waits for an event. The idle
+/// thread runs privileged whenever no other thread is ready, so `body` must never block.
+///
+/// # Example
+/// ```
+/// static IDLE_STACK: Stack<256> = Stack::new();
+///
+/// fn idle() -> ! {
+///     loop {
+///         log_flush();
+///         cortex_m::asm::wfi();
+///     }
+/// }
+///
+/// init_with_idle(IDLE_STACK.take().unwrap(), Some(idle));
+/// ```
+pub fn init_with_idle(stack: &'static mut [u32], body: Option<fn() -> !>) -> ! {
+    start(stack, body)
+}
+
+/// Start the kernel with the idle thread running on `stack`
+fn start(stack: &mut [u32], body: Option<fn() -> !>) -> ! {
+    if cfg!(feature = "sim") {
+        panic!("init: threads are started by sim::Simulator on the host");
+    }
+    Port::set_kernel_state(kernel().as_ptr() as usize);
+    let idle: fn() -> ! = body.unwrap_or(idle_loop);
+    // privileged, so the idle hook can reach the system control block
+    match create_tcb(stack, idle as usize, 0, 0, 0xff, true) {
+        Ok(tcb) => with_state(|s| {
+            insert_tcb(s, 0, tcb);
+            s.threads[0].name = "idle";
+        }),
+        _ => panic!("Could not create idle thread"),
+    }
+    exception::configure(with_state(|s| s.exception_priorities));
+    #[cfg(feature = "validation")]
+    if let Err(e) = validate::check(true) {
+        panic!("init: {:?}", e);
+    }
+    #[cfg(feature = "rtos-awareness")]
+    awareness::keep();
+    #[cfg(any(feature = "mpu-stack-guard", feature = "mpu-isolation"))]
+    mpu::init();
+    #[cfg(feature = "rp2040-amp")]
+    amp::enable_doorbell();
+    cycles::enable();
+    busy::calibrate();
+    with_state(|s| {
+        #[cfg(feature = "cpu-usage")]
+        cycles::lap(s);
+        s.inited = true;
+        // the SVCall handler switches to `next` without saving the context of init
+        let first = get_next_thread_idx(s);
+        switch_to(s, 0, first);
+    });
+    unsafe { Port::start() }
+}
+
+/// Default body of the idle thread
+fn idle_loop() -> ! {
+    loop {
+        #[cfg(feature = "alloc")]
+        heap::reclaim_stacks();
+        if let Some((hook, budget)) =
+            with_state(|s| s.idle_hook.map(|hook| (hook, s.callback_budget)))
+        {
+            budget::run_callback(&budget, "idle hook", hook);
         }
-        __CORTEXM_THREADS_GLOBAL.inited = true;
-        SysTick();
-        loop {
-            __CORTEXM_THREADS_wfe();
+        #[cfg(feature = "tickless")]
+        if tickless::tickless_idle() {
+            continue;
+        }
+        let hooks = with_state(|s| s.sleep_hooks);
+        if let Some(hooks) = hooks {
+            // woken by the next tick at the latest
+            (hooks.pre)(1);
+        }
+        Port::wait_for_event();
+        if let Some(hooks) = hooks {
+            (hooks.post)();
         }
     }
 }
 
 /// Create a thread with default configuration (lowest priority, unprivileged).
+/// Returns the id of the created thread, which can be passed to `join`. Unprivileged threads
+/// reach the kernel through kernel calls, which cover sleeping, mutexes and the calls about
+/// the thread itself; other kernel objects, such as mailboxes and queues, panic there and
+/// need a thread created privileged with `create_thread_with_config`.
 ///
 /// # Arguments
-/// * stack: mut array of u32's to be used as stack area
+/// * stack: area of u32's to be used as stack, e.g. from `Stack::take`. It is borrowed
+///   for the life of the program, so it cannot be reused or go out of scope under the thread
 /// * handler_fn: function to execute in created thread
 ///
 /// # Example
 /// ```
-/// let mut stack1 = [0xDEADBEEF; 512];
+/// static STACK1: Stack<512> = Stack::new();
 /// let _ = create_thread(
-///     &mut stack1,
+///     STACK1.take().unwrap(),
 ///     || {
 ///         loop {
 ///             let _ = hprintln!("in task 1 !!");
@@ -175,22 +938,29 @@ pub fn init() -> ! {
 ///         }
 ///     });
 ///```
-pub fn create_thread(stack: &mut [u32], handler_fn: fn() -> !) -> Result<(), u8> {
+pub fn create_thread(
+    stack: &'static mut [u32],
+    handler_fn: fn() -> !,
+) -> Result<ThreadHandle, Error> {
     create_thread_with_config(stack, handler_fn, 0x00, false)
 }
 
-/// Create a thread with explicit configuration
+/// Create a thread with explicit configuration.
+/// Returns the id of the created thread, which can be passed to `join`. `Thread` does the
+/// same with named options, e.g. `Thread::new(stack, handler_fn).priority(3).spawn()`.
+///
 /// # Arguments
-/// * stack: mut array of u32's to be used as stack area
+/// * stack: area of u32's to be used as stack, e.g. from `Stack::take`. It is borrowed
+///   for the life of the program, so it cannot be reused or go out of scope under the thread
 /// * handler_fn: function to execute in created thread
 /// * priority: higher numeric value means higher priority
 /// * privileged: run thread in privileged mode
 ///
 /// # Example
 /// ```
-/// let mut stack1 = [0xDEADBEEF; 512];
+/// static STACK1: Stack<512> = Stack::new();
 /// let _ = create_thread_with_config(
-///     &mut stack1,
+///     STACK1.take().unwrap(),
 ///     || {
 ///         loop {
 ///             let _ = hprintln!("in task 1 !!");
@@ -203,83 +973,880 @@ pub fn create_thread(stack: &mut [u32], handler_fn: fn() -> !) -> Result<(), u8>
 ///```
 /// FIXME: take stack memory as a vec (arrayvec?, smallvec?) instead of &[]
 pub fn create_thread_with_config(
-    stack: &mut [u32],
+    stack: &'static mut [u32],
     handler_fn: fn() -> !,
     priority: u8,
     priviliged: bool,
-) -> Result<(), u8> {
+) -> Result<ThreadHandle, Error> {
+    create_thread_raw(stack, handler_fn as usize, 0, 0, priority, priviliged)
+}
+
+/// Create a thread with default configuration (lowest priority, unprivileged) running
+/// `handler_fn(arg)`. This lets several threads run the same function on different data.
+///
+/// # Example
+/// ```
+/// fn uart_driver(port: usize) -> ! {
+///     loop {
+///         poll_uart(port);
+///         sleep(1);
+///     }
+/// }
+/// let _ = create_thread_with_arg(STACK1.take().unwrap(), uart_driver, 1);
+/// let _ = create_thread_with_arg(STACK2.take().unwrap(), uart_driver, 2);
+/// ```
+pub fn create_thread_with_arg(
+    stack: &'static mut [u32],
+    handler_fn: fn(usize) -> !,
+    arg: usize,
+) -> Result<ThreadHandle, Error> {
+    create_thread_with_arg_and_config(stack, handler_fn, arg, 0x00, false)
+}
+
+/// Create a thread running `handler_fn(arg)` with explicit configuration, see
+/// `create_thread_with_arg` and `create_thread_with_config`.
+pub fn create_thread_with_arg_and_config(
+    stack: &'static mut [u32],
+    handler_fn: fn(usize) -> !,
+    arg: usize,
+    priority: u8,
+    priviliged: bool,
+) -> Result<ThreadHandle, Error> {
+    let entry: extern "C-unwind" fn(usize, usize) -> ! = arg_trampoline;
+    create_thread_raw(
+        stack,
+        entry as usize,
+        arg,
+        handler_fn as usize,
+        priority,
+        priviliged,
+    )
+}
+
+/// Entry point of threads with an argument, R0 holds the argument and R1 the handler. It may
+/// unwind, so a panicking thread of the simulator reaches its host thread.
+extern "C-unwind" fn arg_trampoline(arg: usize, handler_fn: usize) -> ! {
+    let handler_fn: fn(usize) -> ! = unsafe { core::mem::transmute(handler_fn) };
+    handler_fn(arg)
+}
+
+/// Create a thread running closure `f` with default configuration (lowest priority,
+/// unprivileged). Unlike `create_thread`, the closure can own state moved into it, e.g. a
+/// peripheral handle, instead of reaching into globals. The closure is stored at the top of
+/// `stack`, so the stack area must be large enough for both. The thread exits when the
+/// closure returns.
+///
+/// # Example
+/// ```
+/// static STACK1: Stack<512> = Stack::new();
+/// let led = gpioe.pe9.into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper);
+/// let _ = create_thread_closure(STACK1.take().unwrap(), move || {
+///     let mut led = led;
+///     loop {
+///         led.toggle();
+///         sleep(50);
+///     }
+/// });
+/// ```
+pub fn create_thread_closure<F>(stack: &'static mut [u32], f: F) -> Result<ThreadHandle, Error>
+where
+    F: FnOnce() + Send + 'static,
+{
+    create_thread_closure_with_config(stack, f, 0x00, false)
+}
+
+/// Create a thread running closure `f` with explicit configuration, see
+/// `create_thread_closure` and `create_thread_with_config`.
+pub fn create_thread_closure_with_config<F>(
+    stack: &'static mut [u32],
+    f: F,
+    priority: u8,
+    priviliged: bool,
+) -> Result<ThreadHandle, Error>
+where
+    F: FnOnce() + Send + 'static,
+{
+    // carve out space for the closure at the top of the stack, keeping the new top 8-byte
+    // aligned as required for the initial frame
+    let base = stack.as_ptr() as usize;
+    let top = base + stack.len() * 4;
+    let align = core::cmp::max(core::mem::align_of::<F>(), 8);
+    let storage = top.wrapping_sub(core::mem::size_of::<F>()) & !(align - 1);
+    if storage < base || (storage - base) / 4 < MIN_STACK_WORDS {
+        #[cfg(feature = "defmt")]
+        defmt::trace!("create thread: no room for closure and stack");
+        return Err(Error::StackTooSmall);
+    }
+    let (frame, _) = stack.split_at_mut((storage - base) / 4);
+    let f_ptr = storage as *mut F;
     unsafe {
-        __CORTEXM_THREADS_cpsid();
-        let handler = &mut __CORTEXM_THREADS_GLOBAL;
-        if handler.add_idx >= handler.threads.len() {
-            return Err(ERR_TOO_MANY_THREADS);
-        }
-        if handler.inited && handler.threads[handler.idx].privileged == 0 {
-            return Err(ERR_NO_CREATE_PRIV);
-        }
-        match create_tcb(stack, handler_fn, priority, priviliged) {
-            Ok(tcb) => {
-                insert_tcb(handler.add_idx, tcb);
-                handler.add_idx = handler.add_idx + 1;
-            }
-            Err(e) => {
-                __CORTEXM_THREADS_cpsie();
-                return Err(e);
+        ptr::write(f_ptr, f);
+    }
+    let entry: extern "C-unwind" fn(*mut F) -> ! = closure_trampoline::<F>;
+    let created = create_thread_raw(
+        frame,
+        entry as usize,
+        f_ptr as usize,
+        0,
+        priority,
+        priviliged,
+    );
+    match created {
+        // the closure is consumed when the thread starts, it cannot run again
+        Ok(thread) => with_state(|s| s.threads[thread.0].restartable = false),
+        // thread was not created, the closure is still owned here
+        Err(_) => unsafe {
+            drop(ptr::read(f_ptr));
+        },
+    }
+    created
+}
+
+/// Entry point of closure threads, R0 holds the closure stored by
+/// `create_thread_closure_with_config`. It may unwind, see `arg_trampoline`.
+extern "C-unwind" fn closure_trampoline<F: FnOnce()>(f: *mut F) -> ! {
+    let f = unsafe { ptr::read(f) };
+    f();
+    exit()
+}
+
+/// Create a thread starting at address `pc` with `r0` and `r1` as its first two arguments
+fn create_thread_raw(
+    stack: &mut [u32],
+    pc: usize,
+    r0: usize,
+    r1: usize,
+    priority: u8,
+    priviliged: bool,
+) -> Result<ThreadHandle, Error> {
+    // unprivileged threads cannot disable interrupts, the kernel creates the thread for them
+    if svc::from_unprivileged_thread() {
+        return svc::spawn(stack, pc, r0, r1, priority, priviliged);
+    }
+    let mut cs = CriticalSection::enter();
+    insert_thread(state(&mut cs), stack, pc, r0, r1, priority, priviliged)
+}
+
+/// Create a thread in a free slot of the thread table
+fn insert_thread(
+    handler: &mut ThreadsState,
+    stack: &mut [u32],
+    pc: usize,
+    r0: usize,
+    r1: usize,
+    priority: u8,
+    priviliged: bool,
+) -> Result<ThreadHandle, Error> {
+    // slot 0 is reserved for the idle thread, reuse slots of exited threads
+    let idx = match (1..handler.threads.len()).find(|&i| handler.slot_available(i)) {
+        Some(idx) => idx,
+        None => {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("create thread: all {} slots in use", MAX_THREADS);
+            return Err(Error::TooManyThreads);
+        }
+    };
+    match create_tcb(stack, pc, r0, r1, priority, priviliged) {
+        Ok(tcb) => {
+            insert_tcb(handler, idx, tcb);
+            record(handler, TraceKind::Create, idx, 0);
+            lifecycle::report(handler, ThreadEvent::Created, idx);
+        }
+        Err(e) => {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("create thread: stack of {} words too small", stack.len());
+            return Err(e);
+        }
+    }
+    Ok(ThreadHandle(idx))
+}
+
+/// Terminate the current thread. Its slot is released and can be reused by a thread
+/// created later, and all threads blocked in `join` on it are woken up.
+///
+/// # Example
+/// ```
+/// static STACK1: Stack<512> = Stack::new();
+/// let _ = create_thread(
+///     STACK1.take().unwrap(),
+///     || {
+///         let _ = hprintln!("in task 1, exiting");
+///         exit();
+///     });
+/// ```
+pub fn exit() -> ! {
+    if svc::from_unprivileged_thread() {
+        // switched away for good as the call returns
+        svc::exit();
+    } else {
+        with_state(|handler| {
+            let idx = handler.idx;
+            if idx > 0 {
+                release_thread(handler, idx);
             }
+        });
+        // schedule another thread, this one will never be picked again
+        schedule();
+    }
+    loop {
+        Port::wait_for_event();
+    }
+}
+
+/// Terminate thread `thread`, which may be the current one, e.g. a worker which stopped
+/// responding. Its slot is released like on `exit`, and threads blocked in `join` on it are
+/// woken up. Anything the thread held stays as it was: a locked `Mutex` stays locked, and a
+/// thread created later in the slot may see one spurious wakeup from a wait list the deleted
+/// thread was blocked on, which the kernel's primitives tolerate. Deleting the current thread
+/// does not return. Returns Err(Error::NoSuchThread) for the idle thread or a thread which
+/// already exited. To start the same thread over on its own stack, use `restart` instead.
+///
+/// # Example
+/// ```
+/// static SPARE_MODEM_STACK: Stack<512> = Stack::new();
+///
+/// if watchdog_expired(modem) {
+///     let _ = delete(modem);
+///     // a replacement on a stack of its own
+///     modem = create_thread(SPARE_MODEM_STACK.take().unwrap(), modem_task)?;
+/// }
+/// ```
+pub fn delete(thread: ThreadHandle) -> Result<(), Error> {
+    let thread_id = thread.0;
+    with_state(|handler| {
+        if thread_id == 0
+            || thread_id >= handler.threads.len()
+            || handler.threads[thread_id].status == ThreadStatus::Free
+        {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("delete: no thread {}", thread_id);
+            return Err(Error::NoSuchThread);
+        }
+        release_thread(handler, thread_id);
+        if thread_id == handler.idx {
+            // the thread runs, or was picked to run next: pick another one
+            let next = get_next_thread_idx(handler);
+            switch_to(handler, thread_id, next);
         }
-        __CORTEXM_THREADS_cpsie();
         Ok(())
+    })?;
+    // a thread deleting itself is switched away here for good
+    schedule();
+    Ok(())
+}
+
+/// Start thread `thread` over from its entry function, with its original arguments, stack,
+/// priority and privilege, e.g. to recover a protocol task which stopped responding. What it
+/// held is not released, see `delete`; threads blocked in `join` on it keep waiting. Returns
+/// Err(Error::NotRestartable) for threads running a closure and for the running thread, and
+/// Err(Error::NoSuchThread) for the idle thread or a thread which already exited.
+///
+/// # Example
+/// ```
+/// if last_reply_tick + REPLY_TIMEOUT < ticks() {
+///     let _ = restart(modem);
+/// }
+/// ```
+pub fn restart(thread: ThreadHandle) -> Result<(), Error> {
+    let thread_id = thread.0;
+    with_state(|handler| {
+        if thread_id == 0
+            || thread_id >= handler.threads.len()
+            || handler.threads[thread_id].status == ThreadStatus::Free
+        {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("restart: no thread {}", thread_id);
+            return Err(Error::NoSuchThread);
+        }
+        let old = handler.threads[thread_id];
+        // PendSV would save the context of the running thread over the new frame
+        if !old.restartable || handler.curr == &handler.threads[thread_id] as *const _ as usize {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("restart: thread {} cannot be restarted", thread_id);
+            return Err(Error::NotRestartable);
+        }
+        let tcb = renew_tcb(&old)?;
+        // joiners are woken by the exit and wait again for the new run
+        release_thread(handler, thread_id);
+        insert_tcb(handler, thread_id, tcb);
+        record(handler, TraceKind::Create, thread_id, 0);
+        lifecycle::report(handler, ThreadEvent::Created, thread_id);
+        Ok(())
+    })?;
+    schedule();
+    Ok(())
+}
+
+/// Thread control block starting thread `old` over from its entry function, with the same
+/// stack, priority, privilege and name
+fn renew_tcb(old: &ThreadControlBlock) -> Result<ThreadControlBlock, Error> {
+    let stack = unsafe {
+        core::slice::from_raw_parts_mut(old.stack_base as *mut u32, old.stack_words as usize)
+    };
+    let mut tcb = create_tcb(
+        stack,
+        old.entry,
+        old.args[0],
+        old.args[1],
+        old.base_priority,
+        old.privileged != 0,
+    )?;
+    tcb.name = old.name;
+    #[cfg(feature = "alloc")]
+    {
+        tcb.heap_words = old.heap_words;
+    }
+    #[cfg(feature = "trustzone")]
+    {
+        tcb.secure_context = old.secure_context;
+    }
+    #[cfg(feature = "mpu-isolation")]
+    {
+        tcb.regions = old.regions;
+    }
+    Ok(tcb)
+}
+
+/// Free the slot of thread `idx` and wake the threads joining it
+fn release_thread(handler: &mut ThreadsState, idx: usize) {
+    lifecycle::report(handler, ThreadEvent::Exited, idx);
+    let now = handler.ticks;
+    handler.set_status(idx, ThreadStatus::Free, now);
+    record(handler, TraceKind::Exit, idx, 0);
+    let mut joiners = handler.threads[idx].joiners;
+    wake_all(handler, &mut joiners, WakeReason::Exited);
+    handler.threads[idx].joiners = joiners;
+}
+
+/// Block the current thread until thread `thread` exits. Returns
+/// immediately if that thread has already exited. Note that handles are reused, so a
+/// thread which exited long ago may have been replaced by a newer one.
+///
+/// # Example
+/// ```
+/// let worker = create_thread(STACK1.take().unwrap(), || {
+///     // do some work
+///     exit();
+/// }).unwrap();
+/// // in another thread:
+/// let _ = join(worker);
+/// ```
+pub fn join(thread: ThreadHandle) -> Result<(), Error> {
+    join_until(thread.0, NO_DEADLINE)
+}
+
+/// Like `join`, but gives up with Err(Error::TimedOut) if the thread has not exited within
+/// `ticks` ticks.
+pub fn join_timeout(thread: ThreadHandle, ticks: u32) -> Result<(), Error> {
+    join_until(thread.0, crate::ticks() + ticks as u64)
+}
+
+fn join_until(thread_id: usize, deadline: u64) -> Result<(), Error> {
+    let mut cs = CriticalSection::enter();
+    let handler = state(&mut cs);
+    if handler.idx == 0
+        || thread_id == 0
+        || thread_id == handler.idx
+        || thread_id >= handler.threads.len()
+    {
+        #[cfg(feature = "defmt")]
+        defmt::trace!("join: thread {} cannot join {}", handler.idx, thread_id);
+        return Err(Error::NoSuchThread);
     }
+    // exit() makes this thread runnable again, wake() may do so before the thread exited
+    while state(&mut cs).threads[thread_id].status != ThreadStatus::Free {
+        let reason = block_on_list(cs, |s| &mut s.threads[thread_id].joiners, deadline);
+        if reason == WakeReason::Timeout {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("join: timed out waiting for thread {}", thread_id);
+            return Err(Error::TimedOut);
+        }
+        cs = CriticalSection::enter();
+    }
+    Ok(())
 }
 
 /// Handle a tick event. Typically, this would be called as SysTick handler, but can be
-/// called anytime. Call from thread handler code to yield and switch context.
+/// called anytime. Threads should call `yield_now` to switch context instead, as every call
+/// advances the tick count and wakes sleeping threads early. With the `systick-handler`
+/// feature the kernel defines the SysTick exception handler, which calls this, and the
+/// function is no longer exported under the handler's name.
 ///
-/// * updates sleep_ticks field in sleeping threads, decreses by 1
-/// * if a sleeping thread has sleep_ticks == 0, wake it, i.e., change status to idle
+/// * advances the tick count by 1
+/// * if a sleeping thread's wake tick has been reached, wake it, i.e., change status to idle
 /// * find next thread to schedule
 /// * if context switch is required, will pend the PendSV exception, which will do the actual thread switching
-#[no_mangle]
+#[cfg_attr(not(feature = "systick-handler"), no_mangle)]
+#[allow(non_snake_case)]
 pub extern "C" fn SysTick() {
-    unsafe {
-        __CORTEXM_THREADS_cpsid();
+    with_state(|handler| {
+        if handler.inited {
+            handler.ticks += 1;
+            quota::charge(handler);
+            #[cfg(feature = "inversion-detection")]
+            inversion::check(handler);
+        }
+    });
+    schedule();
+}
+
+/// Give up the CPU to other ready threads of the same priority, without advancing the tick
+/// count. The calling thread stays ready and runs again when no other thread of its priority
+/// or higher is ready, so it returns at once if there is none.
+///
+/// # Example
+/// ```
+/// loop {
+///     process_chunk();
+///     yield_now();
+/// }
+/// ```
+pub fn yield_now() {
+    expect_thread("yield_now");
+    if svc::from_unprivileged_thread() {
+        return svc::yield_now();
+    }
+    with_state(yield_current);
+    schedule();
+}
+
+/// Let the scheduler pass over the current thread among threads of its priority, kernel
+/// part of `yield_now`
+fn yield_current(handler: &mut ThreadsState) {
+    let idx = handler.idx;
+    if handler.inited && idx > 0 {
+        handler.yielded = idx;
+        record(handler, TraceKind::Yield, idx, 0);
     }
-    let handler = unsafe { &mut __CORTEXM_THREADS_GLOBAL };
+}
+
+/// Find next thread to schedule, and pend PendSV if a context switch is required.
+/// Unlike `SysTick`, this does not advance the tick count.
+fn schedule() {
+    let mut cs = CriticalSection::enter();
+    schedule_in(state(&mut cs));
+}
+
+/// Body of `schedule`, for callers already in a critical section, e.g. kernel calls
+fn schedule_in(handler: &mut ThreadsState) {
     if handler.inited {
-        if handler.curr == handler.next {
-            // schedule a thread to be run
-            handler.idx = get_next_thread_idx();
-            unsafe {
-                handler.next = core::intrinsics::transmute(&handler.threads[handler.idx]);
-            }
+        #[cfg(feature = "kernel-stats")]
+        let start = stats::start(handler);
+        reschedule(handler);
+        #[cfg(feature = "kernel-stats")]
+        stats::finish(handler, start);
+    }
+}
+
+/// Body of `schedule`, run with interrupts disabled once the kernel is initialized
+fn reschedule(handler: &mut ThreadsState) {
+    check_stack(handler, handler.idx);
+    if handler.restarts != 0 {
+        contain::restart_panicked(handler);
+    }
+    #[cfg(feature = "cpu-usage")]
+    {
+        let spent = cycles::lap(handler);
+        handler.threads[handler.idx].cycles += spent;
+        handler.total_cycles += spent;
+        let now = handler.ticks;
+        handler.load.account(handler.idx == 0, spent, now);
+    }
+    if handler.suspended > 0
+        && handler.threads[handler.idx].status == ThreadStatus::Idle
+        && handler.curr == handler.next
+    {
+        // the running thread keeps the CPU, switch when it resumes the scheduler
+        if get_next_thread_idx(handler) != handler.idx {
+            handler.switch_missed = true;
         }
-        if handler.curr != handler.next {
-            unsafe {
-                let pend = ptr::read_volatile(0xE000ED04 as *const u32);
-                ptr::write_volatile(0xE000ED04 as *mut u32, pend | 1 << 28);
-            }
+        return;
+    }
+    if handler.curr == handler.next {
+        // schedule a thread to be run
+        let prev = handler.idx;
+        let next = get_next_thread_idx(handler);
+        if next != prev {
+            switch_to(handler, prev, next);
         }
     }
-    unsafe {
-        __CORTEXM_THREADS_cpsie();
+    if handler.curr != handler.next {
+        Port::request_switch();
+    }
+}
+
+/// Make thread `next` the one PendSV switches to, replacing `prev`
+fn switch_to(handler: &mut ThreadsState, prev: usize, next: usize) {
+    handler.idx = next;
+    handler.yielded = 0;
+    #[cfg(feature = "kernel-stats")]
+    stats::count_switch(handler, next);
+    record(handler, TraceKind::Switch, next, prev as u32);
+    // an aged thread gets one run at its boosted priority, a preempted one waits again
+    let now = handler.ticks;
+    handler.threads[prev].ready_since = now;
+    let base = handler.threads[next].base_priority;
+    let ceiling = handler.threads[next].ceiling;
+    handler.set_priority(next, base.max(ceiling));
+    #[cfg(feature = "mpu-stack-guard")]
+    mpu::set_stack_guard(handler.threads[next].stack_base as u32);
+    #[cfg(feature = "mpu-isolation")]
+    mpu::load_regions(&handler.threads[next]);
+    #[cfg(feature = "trustzone")]
+    trustzone::switch(handler, prev, next);
+    if let Some(hook) = handler.switch_hook {
+        budget::run_callback(&handler.callback_budget, "switch hook", || {
+            hook(ThreadHandle(prev), ThreadHandle(next))
+        });
+    }
+    handler.next = &handler.threads[next] as *const ThreadControlBlock as usize;
+}
+
+/// Stop switching threads until `scheduler_resume`, while leaving interrupts enabled. The
+/// running thread keeps the CPU even if a higher priority thread becomes ready, but it still
+/// gives it up when it sleeps or blocks. Calls nest, each must be matched by a resume.
+///
+/// # Example
+/// ```
+/// scheduler_suspend();
+/// config.update(new_gains);
+/// config.update_checksum();
+/// scheduler_resume();
+/// ```
+pub fn scheduler_suspend() {
+    with_state(|s| s.suspended += 1);
+}
+
+/// Undo one `scheduler_suspend`. When the last one is undone, a switch which was due while
+/// the scheduler was suspended is done now.
+pub fn scheduler_resume() {
+    let missed = with_state(|s| {
+        s.suspended = s.suspended.saturating_sub(1);
+        s.suspended == 0 && core::mem::replace(&mut s.switch_missed, false)
+    });
+    if missed {
+        schedule();
+    }
+}
+
+/// Register the function called, from scheduler context, with the handle of a thread whose
+/// stack has overflowed. The bottom `CANARY_WORDS` u32's of every thread's stack are painted
+/// with `STACK_CANARY` at creation and checked each time the scheduler runs while that thread
+/// is current. Each overflow is reported once. Without a hook, an overflow panics. The hook
+/// runs while the scheduler holds the kernel state, so it must not call other functions of
+/// this crate taking a thread handle; those panic.
+///
+/// # Example
+/// ```
+/// fn overflow(thread: ThreadHandle) {
+///     let _ = hprintln!("stack overflow in thread {}", thread.id());
+///     cortex_m::peripheral::SCB::sys_reset();
+/// }
+/// set_stack_overflow_hook(overflow);
+/// ```
+pub fn set_stack_overflow_hook(hook: fn(ThreadHandle)) {
+    with_state(|s| s.stack_overflow_hook = Some(hook));
+}
+
+/// Register the function called, from scheduler context with interrupts disabled, each time
+/// the scheduler switches threads. It receives the previous and the next thread
+/// and runs just before PendSV is pended, so the switch itself follows right after it
+/// returns. Keep it short, e.g. toggle a GPIO or copy the ids into a trace buffer, and do
+/// not call back into the kernel, which panics.
+///
+/// # Example
+/// ```
+/// fn switched(from: ThreadHandle, to: ThreadHandle) {
+///     if to.id() == 0 {
+///         led.set_low();
+///     } else {
+///         led.set_high();
+///     }
+/// }
+/// set_switch_hook(switched);
+/// ```
+pub fn set_switch_hook(hook: fn(ThreadHandle, ThreadHandle)) {
+    with_state(|s| s.switch_hook = Some(hook));
+}
+
+/// Register the function called by the idle thread each time before it waits for an event
+/// with `wfe`, e.g. to kick a watchdog, gather idle statistics or enter a low-power mode.
+/// The idle thread runs privileged whenever no other thread is ready. It must never block, so
+/// the hook must not call `sleep`, `join` or any other blocking function.
+///
+/// # Example
+/// ```
+/// fn idle() {
+///     watchdog.feed();
+/// }
+/// set_idle_hook(idle);
+/// ```
+pub fn set_idle_hook(hook: fn()) {
+    with_state(|s| s.idle_hook = Some(hook));
+}
+
+/// Functions called around the idle thread's sleep, kept in the kernel state
+#[derive(Clone, Copy)]
+struct SleepHooks {
+    pre: fn(u64),
+    post: fn(),
+}
+
+/// Register the functions called by the idle thread right before the core sleeps and right
+/// after it wakes up, e.g. to gate peripheral clocks, switch the regulator to low-power mode
+/// and back. `pre` receives the number of ticks the core may sleep: 1 around the regular
+/// `wfe`, which the next tick ends at the latest, and the length of the stopped tick period
+/// around tickless sleep. Tickless sleep calls both with interrupts disabled, so `post`
+/// runs before any interrupt handler; the regular `wfe` with interrupts enabled. Neither may
+/// block.
+///
+/// # Example
+/// ```
+/// set_sleep_hooks(
+///     |ticks| {
+///         if ticks > 10 {
+///             regulator_low_power(true);
+///         }
+///     },
+///     || regulator_low_power(false),
+/// );
+/// ```
+pub fn set_sleep_hooks(pre: fn(u64), post: fn()) {
+    with_state(|s| s.sleep_hooks = Some(SleepHooks { pre, post }));
+}
+
+/// Get handle of current thread
+pub fn get_thread_id() -> ThreadHandle {
+    if svc::from_unprivileged_thread() {
+        return svc::thread_id();
+    }
+    with_state(|s| ThreadHandle(s.idx))
+}
+
+/// Get a snapshot of the state of thread `thread`, including why and when it was last
+/// woken. Returns None if the thread has exited.
+///
+/// # Example
+/// ```
+/// if let Some(info) = thread_info(worker) {
+///     let _ = hprintln!("{:?} since tick {}, woken by {:?}",
+///         info.status, info.status_tick, info.wake_reason);
+/// }
+/// ```
+pub fn thread_info(thread: ThreadHandle) -> Option<ThreadInfo> {
+    if svc::from_unprivileged_thread() {
+        return svc::thread_info(thread);
+    }
+    with_state(|handler| live_info(handler, thread.0))
+}
+
+/// Snapshot of thread `thread_id`, None if it has exited, kernel part of `thread_info`
+fn live_info(handler: &ThreadsState, thread_id: usize) -> Option<ThreadInfo> {
+    if thread_id >= handler.threads.len() {
+        return None;
+    }
+    let tcb = &handler.threads[thread_id];
+    if tcb.status == ThreadStatus::Free {
+        return None;
+    }
+    Some(info_of(tcb, thread_id))
+}
+
+/// Snapshot of thread `idx`, whose control block is `tcb`
+fn info_of(tcb: &ThreadControlBlock, idx: usize) -> ThreadInfo {
+    ThreadInfo {
+        id: ThreadHandle(idx),
+        name: tcb.name,
+        priority: tcb.priority,
+        privileged: tcb.privileged != 0,
+        status: tcb.status,
+        wake_reason: tcb.wake_reason,
+        status_tick: tcb.status_tick,
+        stack_size: tcb.stack_words as usize,
+        stack_used: stack_used(tcb),
+        deadline_misses: tcb.deadline_misses,
+    }
+}
+
+/// Call `f` with information about every thread, including the idle thread, in id order.
+///
+/// # Example
+/// ```
+/// for_each_thread(|info| {
+///     let _ = writeln!(uart, "{:2} {:8} {:3} {:?} {}/{}", info.id.id(), info.name,
+///         info.priority, info.status, info.stack_used, info.stack_size);
+/// });
+/// ```
+pub fn for_each_thread<F: FnMut(&ThreadInfo)>(mut f: F) {
+    for thread_id in 0..MAX_THREADS {
+        if let Some(info) = thread_info(ThreadHandle(thread_id)) {
+            f(&info);
+        }
+    }
+}
+
+/// CPU time used by a thread, see `cpu_usage`
+#[cfg(feature = "cpu-usage")]
+#[derive(Clone, Copy, Debug)]
+pub struct CpuUsage {
+    /// cycles spent running the thread since init
+    pub cycles: u64,
+    /// cycles spent running all threads, including idle, since init
+    pub total: u64,
+}
+
+#[cfg(feature = "cpu-usage")]
+impl CpuUsage {
+    /// Share of the CPU used by the thread, in percent
+    pub fn percent(&self) -> u32 {
+        if self.total == 0 {
+            return 0;
+        }
+        (self.cycles * 100 / self.total) as u32
+    }
+}
+
+/// CPU time used by thread `thread` since init, accounted each time the scheduler runs.
+/// Cycles come from the DWT cycle counter on Cortex-M3/M4, and are derived from SysTick on
+/// Cortex-M0/M0+. Slots of exited threads restart from 0 when reused.
+///
+/// # Example
+/// ```
+/// for_each_thread(|info| {
+///     if let Some(usage) = cpu_usage(info.id) {
+///         let _ = hprintln!("{} {}%", info.name, usage.percent());
+///     }
+/// });
+/// ```
+#[cfg(feature = "cpu-usage")]
+pub fn cpu_usage(thread: ThreadHandle) -> Option<CpuUsage> {
+    let thread_id = thread.0;
+    with_state(|handler| {
+        if thread_id < handler.threads.len()
+            && handler.threads[thread_id].status != ThreadStatus::Free
+        {
+            Some(CpuUsage {
+                cycles: handler.threads[thread_id].cycles,
+                total: handler.total_cycles,
+            })
+        } else {
+            None
+        }
+    })
+}
+
+/// Switch the current thread to unprivileged mode for good, e.g. once it has configured the
+/// peripherals it needs. From then on it is an unprivileged thread like one created so: the
+/// kernel serves it through kernel calls, and `restart` starts it unprivileged. Does nothing
+/// if the thread already runs unprivileged.
+///
+/// Returns `Error::NoSuchThread` if called from the idle thread or before `init`, and with
+/// `mpu-isolation` `Error::BadRegion` if the thread's stack is not a valid MPU region, see
+/// `set_thread_regions`. The thread stays privileged then.
+///
+/// # Example
+/// ```
+/// fn uart_task() -> ! {
+///     configure_uart();
+///     drop_privileges().unwrap();
+///     loop {
+///         // the UART must be given with set_thread_regions under mpu-isolation
+///         poll_uart();
+///     }
+/// }
+/// ```
+pub fn drop_privileges() -> Result<(), Error> {
+    if svc::from_unprivileged_thread() {
+        return Ok(());
     }
+    let mut cs = CriticalSection::enter();
+    let handler = state(&mut cs);
+    let idx = handler.idx;
+    if !handler.inited || idx == 0 {
+        return Err(Error::NoSuchThread);
+    }
+    #[cfg(feature = "mpu-isolation")]
+    {
+        let tcb = &handler.threads[idx];
+        let stack = unsafe {
+            core::slice::from_raw_parts(tcb.stack_base as *const u32, tcb.stack_words as usize)
+        };
+        mpu::check_stack_region(stack)?;
+    }
+    // PendSV saves the thread from the stack it ran on, and restores it from psp with nPRIV
+    // set, so the switch holds from the next interrupt on
+    handler.threads[idx].privileged = 0;
+    #[cfg(feature = "mpu-isolation")]
+    mpu::load_regions(&handler.threads[idx]);
+    // exceptions must no longer be taken on the thread's stack. The idle thread is not
+    // running, so the stack below its saved context is free, as after a switch from it.
+    let idle = &handler.threads[0];
+    #[cfg(armv8m)]
+    let limits = (idle.stack_limit, handler.threads[idx].stack_limit);
+    #[cfg(not(armv8m))]
+    let limits = (0, 0);
+    let msp = idle.sp;
+    cs.leave_masked();
+    unsafe { Port::drop_privileges(msp, limits.0, limits.1) };
+    Ok(())
 }
 
-/// Get id of current thread
-pub fn get_thread_id() -> usize {
-    let handler = unsafe { &mut __CORTEXM_THREADS_GLOBAL };
-    handler.idx
+/// Name thread `thread`, as shown by `thread_info` and `for_each_thread`. Returns
+/// Err(Error::NoCreatePrivilege) if called from an unprivileged thread, see `svc`.
+///
+/// # Example
+/// ```
+/// let comms = create_thread(STACK1.take().unwrap(), comms_task)?;
+/// set_thread_name(comms, "comms");
+/// ```
+pub fn set_thread_name(thread: ThreadHandle, name: &'static str) -> Result<(), Error> {
+    if svc::from_unprivileged_thread() {
+        return Err(Error::NoCreatePrivilege);
+    }
+    let thread_id = thread.0;
+    let found = with_state(|handler| {
+        let found = thread_id < handler.threads.len()
+            && handler.threads[thread_id].status != ThreadStatus::Free;
+        if found {
+            handler.threads[thread_id].name = name;
+            #[cfg(feature = "trace-systemview")]
+            systemview::task_info(handler, thread_id);
+        }
+        found
+    });
+    if found {
+        Ok(())
+    } else {
+        Err(Error::NoSuchThread)
+    }
+}
+
+/// High-water mark of a thread's stack in u32's: everything above the lowest word which no
+/// longer holds STACK_FILL
+fn stack_used(tcb: &ThreadControlBlock) -> usize {
+    let base = tcb.stack_base as *const u32;
+    let words = tcb.stack_words as usize;
+    // the MPU guard of the running thread must not be read
+    #[cfg(feature = "mpu-stack-guard")]
+    let first = ((mpu::guard_end(tcb.stack_base as u32) - tcb.stack_base as u32) / 4) as usize;
+    #[cfg(not(feature = "mpu-stack-guard"))]
+    let first = CANARY_WORDS;
+    let untouched = (first..words)
+        .take_while(|&i| unsafe { ptr::read_volatile(base.add(i)) } == STACK_FILL)
+        .count();
+    words - untouched
 }
 
 /// Make current thread sleep for `ticks` ticks. Current thread will be put in `Sleeping`
 /// state and another thread will be scheduled immediately. Current thread will not be considered
-/// for scheduling until `tick()` is called at least `tick` times.
+/// for scheduling until `SysTick()` has been called at least `ticks` times. Panics if called
+/// from an interrupt handler.
 ///
 /// # Example
 /// ```
-/// let mut stack1 = [0xDEADBEEF; 512];
+/// static STACK1: Stack<512> = Stack::new();
 /// let _ = create_thread(
-///     &mut stack1,
+///     STACK1.take().unwrap(),
 ///     || {
 ///         loop {
 ///             let _ = hprintln!("in task 1 !!");
@@ -288,88 +1855,440 @@ pub fn get_thread_id() -> usize {
 ///     });
 /// ```
 pub fn sleep(ticks: u32) {
-    let handler = unsafe { &mut __CORTEXM_THREADS_GLOBAL };
-    if handler.idx > 0 {
-        handler.threads[handler.idx].status = ThreadStatus::Sleeping;
-        handler.threads[handler.idx].sleep_ticks = ticks;
+    expect_thread("sleep");
+    if svc::from_unprivileged_thread() {
+        return svc::sleep(ticks);
+    }
+    let now = crate::ticks();
+    sleep_until(now + ticks as u64);
+}
+
+/// Make current thread sleep until the tick count reaches `tick`. Unlike `sleep`, the wake
+/// time does not depend on when this is called, so a thread computing its next wake tick
+/// from the previous one runs at an exact rate. Returns immediately if `tick` has passed.
+/// See also `Periodic`. Panics if called from an interrupt handler.
+pub fn sleep_until(tick: u64) {
+    expect_thread("sleep_until");
+    if svc::from_unprivileged_thread() {
+        return svc::sleep_until(tick);
+    }
+    if with_state(|handler| sleep_current(handler, tick)) {
         // schedule another thread
-        SysTick();
+        schedule();
     }
 }
 
-fn get_next_thread_idx() -> usize {
-    let handler = unsafe { &mut __CORTEXM_THREADS_GLOBAL };
-    if handler.add_idx <= 1 {
-        // no user threads, schedule idle thread
-        return 0;
+/// Sleep like `sleep`, but return early when the thread is woken by `wake`, or by one of its
+/// wakers, see `thread_waker`. Returns `WakeReason::Timeout` after `ticks` ticks, and
+/// `WakeReason::Woken` if the thread was woken first. `wake` does nothing to a thread which
+/// is not waiting, whereas a waker woken while the thread runs is remembered and makes the
+/// next call return at once, so an interrupt handler signalling with a waker is never missed.
+/// Panics if called from an interrupt handler.
+///
+/// # Example
+/// ```
+/// // thread
+/// loop {
+///     if sleep_or_event(100) == WakeReason::Woken {
+///         handle_rx();
+///     }
+///     poll_sensors();
+/// }
+///
+/// #[interrupt]
+/// fn UART0() {
+///     thread_waker(rx_thread()).wake();
+/// }
+/// ```
+pub fn sleep_or_event(ticks: u32) -> WakeReason {
+    expect_thread("sleep_or_event");
+    if svc::from_unprivileged_thread() {
+        return svc::sleep_or_event(ticks);
+    }
+    let mut cs = CriticalSection::enter();
+    let handler = state(&mut cs);
+    let idx = handler.idx;
+    let deadline = handler.ticks + ticks as u64;
+    if let Some(reason) = executor::nap_current(handler, deadline) {
+        return reason;
     }
-    // user threads exist
-    // update sleeping threads
-    for i in 1..handler.add_idx {
-        if handler.threads[i].status == ThreadStatus::Sleeping {
-            if handler.threads[i].sleep_ticks > 0 {
-                handler.threads[i].sleep_ticks = handler.threads[i].sleep_ticks - 1;
-            } else {
-                handler.threads[i].status = ThreadStatus::Idle;
+    drop(cs);
+    schedule();
+    with_state(|handler| handler.threads[idx].wake_reason)
+}
+
+/// Put the current thread to sleep until tick `tick`, kernel part of `sleep_until`. Returns
+/// false if it does not sleep.
+fn sleep_current(handler: &mut ThreadsState, tick: u64) -> bool {
+    let now = handler.ticks;
+    let sleeping = handler.idx > 0 && tick > now;
+    if sleeping {
+        let idx = handler.idx;
+        handler.threads[idx].wake_tick = tick;
+        handler.set_status(idx, ThreadStatus::Sleeping, now);
+        record(handler, TraceKind::Sleep, idx, tick as u32);
+    }
+    sleeping
+}
+
+/// Whether the caller runs in an interrupt or exception handler, as opposed to a thread or
+/// the code before `init`. Blocking calls such as `sleep` or `Mutex::lock` must not be made
+/// from a handler, which would put the interrupted thread to sleep: they panic there.
+///
+/// # Example
+/// ```
+/// fn log(line: &str) {
+///     if is_in_isr() {
+///         let _ = LOG_QUEUE.try_send(line);
+///     } else {
+///         LOG.lock().push(line);
+///     }
+/// }
+/// ```
+pub fn is_in_isr() -> bool {
+    Port::in_handler()
+}
+
+/// Whether `init` has started the scheduler, so threads run and blocking calls give up the
+/// CPU. Before, blocking calls return at once.
+pub fn scheduler_started() -> bool {
+    // only threads started by init run unprivileged
+    svc::from_unprivileged_thread() || with_state(|s| s.inited)
+}
+
+/// Panic if blocking call `call` is made from an interrupt handler
+fn expect_thread(call: &str) {
+    if is_in_isr() {
+        panic!("{} called from an interrupt handler", call);
+    }
+}
+
+/// Make sleeping or blocked thread `thread` ready to run, and switch to it if it has a
+/// higher priority than the running thread. Its `sleep` returns early, and a blocking call it
+/// is waiting in sees `WakeReason::Woken`; calls which wait for a condition, like `join`,
+/// wait again if it does not hold. Does nothing if the thread is not waiting.
+///
+/// Can be called from interrupt handlers: the switch is done by PendSV when the handler
+/// returns, and the tick count is not advanced.
+///
+/// # Example
+/// ```
+/// #[interrupt]
+/// fn UART0() {
+///     let _ = wake(rx_thread());
+/// }
+/// ```
+pub fn wake(thread: ThreadHandle) -> Result<(), Error> {
+    if svc::from_unprivileged_thread() {
+        return svc::wake(thread);
+    }
+    if with_state(|handler| wake_thread(handler, thread.0))? {
+        schedule();
+    }
+    Ok(())
+}
+
+/// Make thread `thread_id` ready if it sleeps or is blocked, kernel part of `wake`. Returns
+/// whether it was woken.
+fn wake_thread(handler: &mut ThreadsState, thread_id: usize) -> Result<bool, Error> {
+    if thread_id >= handler.threads.len() || handler.threads[thread_id].status == ThreadStatus::Free
+    {
+        #[cfg(feature = "defmt")]
+        defmt::trace!("wake: no thread {}", thread_id);
+        return Err(Error::NoSuchThread);
+    }
+    match handler.threads[thread_id].status {
+        ThreadStatus::Sleeping | ThreadStatus::Blocked => {
+            // a blocked thread leaves its wait list itself when it runs again
+            let now = handler.ticks;
+            handler.wake(thread_id, WakeReason::Woken, now);
+            record(
+                handler,
+                TraceKind::Wake,
+                thread_id,
+                WakeReason::Woken as u32,
+            );
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Block the current thread on `list` until it is woken by `wake_one` or `wake_all`, or the
+/// tick count reaches `deadline` (NO_DEADLINE to wait forever), and return why it was woken.
+/// The critical section `cs` is released before switching to another thread. Returns
+/// `WakeReason::None` without blocking if not called from a user thread, and
+/// `WakeReason::Timeout` without blocking if the deadline has passed.
+///
+/// # Safety
+/// `list` must stay valid until this returns, and must not point into the kernel state.
+pub(crate) unsafe fn block_current(
+    cs: CriticalSection,
+    list: *mut WaitList,
+    deadline: u64,
+) -> WakeReason {
+    block_on_list(cs, |_| &mut *list, deadline)
+}
+
+/// Like `block_current`, for a wait list found through `list`, e.g. one in the kernel state
+fn block_on_list<L>(cs: CriticalSection, list: L, deadline: u64) -> WakeReason
+where
+    L: Fn(&mut ThreadsState) -> &mut WaitList,
+{
+    block_with(
+        cs,
+        deadline,
+        |handler, idx| list(handler).insert(idx),
+        |handler, idx| list(handler).remove(idx),
+    )
+}
+
+/// Like `block_current`, for a thread waiting on several lists: `enter` puts thread `idx` on
+/// its lists before it blocks, and `leave` takes it off them once it runs again.
+pub(crate) fn block_with<E, L>(
+    mut cs: CriticalSection,
+    deadline: u64,
+    enter: E,
+    leave: L,
+) -> WakeReason
+where
+    E: FnOnce(&mut ThreadsState, usize),
+    L: FnOnce(&mut ThreadsState, usize),
+{
+    expect_thread("blocking call");
+    let handler = state(&mut cs);
+    let idx = handler.idx;
+    if let Some(reason) = block_enter(handler, deadline, enter) {
+        return reason;
+    }
+    drop(cs);
+    schedule();
+    // running again, leave the lists in case it was not the waker that removed us
+    with_state(|handler| {
+        leave(handler, idx);
+        handler.threads[idx].wake_reason
+    })
+}
+
+/// First half of `block_with`: mark the current thread blocked until `deadline` and have
+/// `enter` put it on its lists. Returns the reason it does not block, if it does not.
+pub(crate) fn block_enter<E>(
+    handler: &mut ThreadsState,
+    deadline: u64,
+    enter: E,
+) -> Option<WakeReason>
+where
+    E: FnOnce(&mut ThreadsState, usize),
+{
+    let idx = handler.idx;
+    if idx == 0 {
+        return Some(WakeReason::None);
+    }
+    let now = handler.ticks;
+    if deadline <= now {
+        return Some(WakeReason::Timeout);
+    }
+    handler.wait_seq = handler.wait_seq.wrapping_add(1);
+    handler.threads[idx].wait_seq = handler.wait_seq;
+    handler.threads[idx].wake_tick = deadline;
+    handler.set_status(idx, ThreadStatus::Blocked, now);
+    record(handler, TraceKind::Block, idx, 0);
+    enter(handler, idx);
+    None
+}
+
+/// Wake the first thread blocked on `list`. Returns the id of the woken thread; call
+/// `schedule()` after leaving the critical section to let it preempt the caller if it has a
+/// higher priority.
+pub(crate) fn wake_one(
+    handler: &mut ThreadsState,
+    list: &mut WaitList,
+    reason: WakeReason,
+) -> Option<usize> {
+    loop {
+        let idx = list.first(&handler.threads)?;
+        list.remove(idx);
+        // skip threads already woken by a timeout, `wake` or another list they wait on
+        if handler.threads[idx].status != ThreadStatus::Blocked {
+            continue;
+        }
+        let now = handler.ticks;
+        handler.wake(idx, reason, now);
+        record(handler, TraceKind::Wake, idx, reason as u32);
+        return Some(idx);
+    }
+}
+
+/// Wake all threads blocked on `list`. Returns the number of woken threads.
+pub(crate) fn wake_all(
+    handler: &mut ThreadsState,
+    list: &mut WaitList,
+    reason: WakeReason,
+) -> usize {
+    let mut woken = 0;
+    while wake_one(handler, list, reason).is_some() {
+        woken += 1;
+    }
+    woken
+}
+
+/// Number of ticks handled since `init`. This is a 64-bit count, so it never wraps in
+/// practice. See `ticks_to_ms` to convert it to time.
+///
+/// # Example
+/// ```
+/// let start = ticks();
+/// do_work();
+/// let _ = hprintln!("took {} ms", ticks_to_ms(ticks() - start));
+/// ```
+pub fn ticks() -> u64 {
+    if svc::from_unprivileged_thread() {
+        return svc::ticks();
+    }
+    with_state(|s| s.ticks)
+}
+
+/// Report a stack overflow of thread `idx` to the hook if its canary was overwritten.
+/// Each overflow is reported once; without a hook, it panics.
+fn check_stack(handler: &mut ThreadsState, idx: usize) {
+    let tcb = &mut handler.threads[idx];
+    if tcb.overflowed || tcb.status == ThreadStatus::Free {
+        return;
+    }
+    let base = tcb.stack_base as *const u32;
+    let intact =
+        (0..CANARY_WORDS).all(|i| unsafe { ptr::read_volatile(base.add(i)) } == STACK_CANARY);
+    if !intact {
+        tcb.overflowed = true;
+        #[cfg(feature = "defmt")]
+        defmt::trace!("stack overflow in thread {}", idx);
+        lifecycle::report(handler, ThreadEvent::StackOverflow, idx);
+        match handler.stack_overflow_hook {
+            Some(hook) => {
+                budget::run_callback(&handler.callback_budget, "stack overflow hook", || {
+                    hook(ThreadHandle(idx))
+                })
             }
+            None => panic!("stack overflow in thread {}", idx),
         }
     }
-    match handler
-        .threads
-        .into_iter()
-        .enumerate()
-        .filter(|&(idx, x)| idx > 0 && idx < handler.add_idx && x.status != ThreadStatus::Sleeping)
-        .max_by(|&(_, a), &(_, b)| a.priority.cmp(&b.priority))
-    {
-        Some((idx, _)) => idx,
-        _ => 0,
+}
+
+fn get_next_thread_idx(handler: &mut ThreadsState) -> usize {
+    let now = handler.ticks;
+    // wake sleeping threads whose wake tick has been reached, and blocked threads whose
+    // timeout expired. Those leave their wait list themselves in block_current.
+    while let Some(i) = handler.timeouts.first() {
+        if handler.threads[i].wake_tick > now {
+            break;
+        }
+        handler.wake(i, WakeReason::Timeout, now);
+        record(handler, TraceKind::Wake, i, WakeReason::Timeout as u32);
+    }
+    aging::age(handler, now);
+    // schedule idle thread if no user thread is ready
+    let default = handler.ready.select(handler.yielded);
+    match handler.scheduler {
+        Some(policy) => {
+            let ready = ReadyThreads {
+                queue: &handler.ready,
+                threads: &handler.threads,
+                current: handler.idx,
+                yielded: handler.yielded,
+                tick: now,
+            };
+            match policy.pick(&ready) {
+                Some(thread) if ready.contains(thread) => thread.0,
+                None => 0,
+                _ => default,
+            }
+        }
+        None => default,
     }
 }
 
 fn create_tcb(
     stack: &mut [u32],
-    handler: fn() -> !,
+    pc: usize,
+    r0: usize,
+    r1: usize,
     priority: u8,
     priviliged: bool,
-) -> Result<ThreadControlBlock, u8> {
-    if stack.len() < 32 {
-        return Err(ERR_STACK_TOO_SMALL);
-    }
-    let idx = stack.len() - 1;
-    stack[idx] = 1 << 24; // xPSR
-    let pc: usize = unsafe { core::intrinsics::transmute(handler as *const fn()) };
-    stack[idx - 1] = pc as u32; // PC
-    stack[idx - 2] = 0xFFFFFFFD; // LR
-    stack[idx - 3] = 0xCCCCCCCC; // R12
-    stack[idx - 4] = 0x33333333; // R3
-    stack[idx - 5] = 0x22222222; // R2
-    stack[idx - 6] = 0x11111111; // R1
-    stack[idx - 7] = 0x00000000; // R0
-                                 // aditional regs
-    stack[idx - 08] = 0x77777777; // R7
-    stack[idx - 09] = 0x66666666; // R6
-    stack[idx - 10] = 0x55555555; // R5
-    stack[idx - 11] = 0x44444444; // R4
-    stack[idx - 12] = 0xBBBBBBBB; // R11
-    stack[idx - 13] = 0xAAAAAAAA; // R10
-    stack[idx - 14] = 0x99999999; // R9
-    stack[idx - 15] = 0x88888888; // R8
-    unsafe {
-        let sp: usize = core::intrinsics::transmute(&stack[stack.len() - 16]);
-        let tcb = ThreadControlBlock {
-            sp: sp as u32,
-            priority: priority,
-            privileged: if priviliged { 0x1 } else { 0x0 },
-            status: ThreadStatus::Idle,
-            sleep_ticks: 0,
-        };
-        Ok(tcb)
+) -> Result<ThreadControlBlock, Error> {
+    if stack.len() < MIN_STACK_WORDS {
+        #[cfg(feature = "defmt")]
+        defmt::trace!(
+            "stack of {} words below minimum of {}",
+            stack.len(),
+            MIN_STACK_WORDS
+        );
+        return Err(Error::StackTooSmall);
     }
+    #[cfg(feature = "mpu-stack-guard")]
+    mpu::check_stack(stack)?;
+    // an unprivileged thread may write its stack only, which the MPU must cover exactly
+    #[cfg(feature = "mpu-isolation")]
+    if !priviliged {
+        mpu::check_stack_region(stack)?;
+    }
+    let top = Port::init_frame(stack, pc, [r0, r1], priviliged);
+    for word in stack.iter_mut().take(CANARY_WORDS) {
+        *word = STACK_CANARY;
+    }
+    for word in stack.iter_mut().take(top).skip(CANARY_WORDS) {
+        *word = STACK_FILL;
+    }
+    let sp = stack.as_ptr() as usize + top * 4;
+    Ok(ThreadControlBlock {
+        sp: sp as u32,
+        priority: priority,
+        base_priority: priority,
+        ceiling: 0,
+        privileged: if priviliged { 0x1 } else { 0x0 },
+        // just above the canary, limits are 8 byte aligned
+        #[cfg(armv8m)]
+        stack_limit: (stack.as_ptr() as usize as u32 + CANARY_WORDS as u32 * 4 + 7) & !7,
+        status: ThreadStatus::Idle,
+        wake_tick: 0,
+        ready_since: 0,
+        timeout_next: 0,
+        joiners: WaitList::new(WaitOrder::Priority),
+        wait_seq: 0,
+        notified: false,
+        parked: false,
+        suspended: false,
+        #[cfg(any(feature = "deadlock-detection", feature = "inversion-detection"))]
+        blocked_on: 0,
+        #[cfg(feature = "inversion-detection")]
+        inversion_reported: false,
+        wake_reason: WakeReason::None,
+        status_tick: 0,
+        stack_base: stack.as_ptr() as usize,
+        stack_words: stack.len() as u32,
+        entry: pc,
+        args: [r0, r1],
+        restartable: true,
+        name: "",
+        cycles: 0,
+        deadline_misses: 0,
+        quota: None,
+        #[cfg(feature = "tls")]
+        tls: [0; TLS_SLOTS],
+        #[cfg(feature = "alloc")]
+        heap_words: 0,
+        #[cfg(feature = "kernel-stats")]
+        switch_ins: 0,
+        overflowed: false,
+        #[cfg(feature = "trustzone")]
+        secure_context: 0,
+        #[cfg(feature = "mpu-isolation")]
+        regions: [MpuRegion::NONE; MPU_REGIONS],
+    })
 }
 
-fn insert_tcb(idx: usize, tcb: ThreadControlBlock) {
-    unsafe {
-        let handler = &mut __CORTEXM_THREADS_GLOBAL;
-        handler.threads[idx] = tcb;
-    }
+fn insert_tcb(handler: &mut ThreadsState, idx: usize, tcb: ThreadControlBlock) {
+    let now = handler.ticks;
+    let status = tcb.status;
+    handler.threads[idx] = tcb;
+    handler.set_status(idx, status, now);
 }
@@ -0,0 +1,47 @@
+//! Thread lifecycle events reported to the application.
+//!
+//! A hook set with `set_lifecycle_hook` is called when a thread is created or exits, when
+//! priority aging finds it starved, and when its stack overflows, with a snapshot of the
+//! thread, so the application can forward kernel events to its own logging or telemetry.
+
+use crate::{budget, info_of, with_state, ThreadInfo, ThreadsState};
+
+/// Event of a thread's life, passed to the lifecycle hook
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ThreadEvent {
+    /// the thread was created, or started over by `restart` or after a panic
+    Created,
+    /// the thread exited, was deleted, or is being started over
+    Exited,
+    /// the thread was ready without running for the aging period, see `set_priority_aging`;
+    /// reported with each boost
+    Starved,
+    /// the canary at the bottom of the thread's stack was overwritten, reported once, before
+    /// the stack overflow hook
+    StackOverflow,
+}
+
+/// Call `hook` on each thread lifecycle event, with the event and a snapshot of the thread.
+/// An exiting thread is reported before its slot is freed. The hook runs inside the kernel
+/// with interrupts disabled, so it must be short and must not block.
+///
+/// # Example
+/// ```
+/// fn lifecycle(event: ThreadEvent, info: &ThreadInfo) {
+///     let _ = TELEMETRY.try_send(Record::thread(event, info.id, info.name, info.stack_used));
+/// }
+///
+/// set_lifecycle_hook(lifecycle);
+/// ```
+pub fn set_lifecycle_hook(hook: fn(ThreadEvent, &ThreadInfo)) {
+    with_state(|s| s.lifecycle_hook = Some(hook));
+}
+
+/// Report `event` of thread `idx` to the lifecycle hook, if any
+pub(crate) fn report(s: &mut ThreadsState, event: ThreadEvent, idx: usize) {
+    if let Some(hook) = s.lifecycle_hook {
+        let info = info_of(&s.threads[idx], idx);
+        budget::run_callback(&s.callback_budget, "lifecycle hook", || hook(event, &info));
+    }
+}
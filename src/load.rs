@@ -0,0 +1,90 @@
+//! CPU load over a sliding window, with the `cpu-usage` feature.
+//!
+//! The cycles the scheduler accounts to threads are also summed per slot of `LOAD_SLOT_TICKS`
+//! ticks, split into idle and busy ones. The load is the busy share of the last
+//! `LOAD_SLOTS` slots and of the slot in progress, so it follows changes in the workload
+//! within a window while ignoring the history before it.
+
+use crate::with_state;
+
+/// Number of completed slots the load is computed over
+pub const LOAD_SLOTS: usize = 10;
+/// Length of a slot in ticks, the window covers `LOAD_SLOTS` times as many
+pub const LOAD_SLOT_TICKS: u64 = 100;
+
+/// Idle and total cycles of the slots in the window, kept in the kernel state
+pub(crate) struct Load {
+    /// (idle cycles, total cycles) of completed slots
+    slots: [(u64, u64); LOAD_SLOTS],
+    /// slot overwritten next
+    oldest: usize,
+    /// cycles accounted in the slot in progress
+    idle: u64,
+    total: u64,
+    /// tick at which the slot in progress started
+    start: u64,
+}
+
+impl Load {
+    pub(crate) const fn new() -> Self {
+        Load {
+            slots: [(0, 0); LOAD_SLOTS],
+            oldest: 0,
+            idle: 0,
+            total: 0,
+            start: 0,
+        }
+    }
+
+    /// Account `cycles` spent by the idle thread if `idle` is set, by another thread
+    /// otherwise, at tick `now`
+    pub(crate) fn account(&mut self, idle: bool, cycles: u64, now: u64) {
+        if idle {
+            self.idle += cycles;
+        }
+        self.total += cycles;
+        let elapsed = now.saturating_sub(self.start) / LOAD_SLOT_TICKS;
+        if elapsed == 0 {
+            return;
+        }
+        // close the slot in progress, slots passed without any accounting, e.g. during a
+        // tickless sleep, stay empty
+        for i in 0..elapsed.min(LOAD_SLOTS as u64) {
+            self.slots[self.oldest] = if i == 0 {
+                (self.idle, self.total)
+            } else {
+                (0, 0)
+            };
+            self.oldest = (self.oldest + 1) % LOAD_SLOTS;
+        }
+        self.idle = 0;
+        self.total = 0;
+        self.start += elapsed * LOAD_SLOT_TICKS;
+    }
+
+    /// Busy share of the window, in percent
+    fn percent(&self) -> u32 {
+        let (idle, total) = self
+            .slots
+            .iter()
+            .fold((self.idle, self.total), |(idle, total), slot| {
+                (idle + slot.0, total + slot.1)
+            });
+        if total == 0 {
+            return 0;
+        }
+        ((total - idle) * 100 / total) as u32
+    }
+}
+
+/// Share of the CPU used by threads other than idle over the last `LOAD_SLOTS` slots of
+/// `LOAD_SLOT_TICKS` ticks, in percent. The headroom left for more work is 100 minus it.
+///
+/// # Example
+/// ```
+/// // once a second, in a logging thread
+/// let _ = hprintln!("load {}%", cpu_load_percent());
+/// ```
+pub fn cpu_load_percent() -> u32 {
+    with_state(|s| s.load.percent())
+}
@@ -0,0 +1,113 @@
+//! Latest-value mailbox between threads and interrupt handlers.
+//!
+//! A `Mailbox` holds at most one value. Posting replaces a value nobody has taken yet, so a
+//! slow reader always gets the most recent one instead of working through a backlog.
+
+use core::cell::UnsafeCell;
+
+use crate::critical::CriticalSection;
+use crate::select::sealed::Source;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, wake_one, Error, WakeReason, NO_DEADLINE};
+
+/// Slot for the latest value of type `T`. Only privileged threads and interrupt handlers can
+/// use it: it has no kernel call, see `svc`.
+///
+/// Example:
+/// ```
+/// static TEMPERATURE: Mailbox<i16> = Mailbox::new();
+///
+/// // ADC interrupt handler
+/// TEMPERATURE.post(adc.read());
+///
+/// // display thread
+/// loop {
+///     show(TEMPERATURE.take());
+/// }
+/// ```
+pub struct Mailbox<T> {
+    value: UnsafeCell<Option<T>>,
+    readers: UnsafeCell<WaitList>,
+}
+
+unsafe impl<T: Send> Sync for Mailbox<T> {}
+
+impl<T> Mailbox<T> {
+    pub const fn new() -> Self {
+        Mailbox {
+            value: UnsafeCell::new(None),
+            readers: UnsafeCell::new(WaitList::new(WaitOrder::Priority)),
+        }
+    }
+
+    /// Store `value`, replacing an unread one, and wake the highest priority thread waiting
+    /// in `take`. Can be called from interrupt handlers.
+    pub fn post(&self, value: T) {
+        let old = {
+            let mut cs = CriticalSection::enter();
+            let old = unsafe { (*self.value.get()).replace(value) };
+            wake_one(
+                crate::state(&mut cs),
+                unsafe { &mut *self.readers.get() },
+                WakeReason::Signaled,
+            );
+            old
+        };
+        // dropped outside the critical section
+        drop(old);
+        schedule();
+    }
+
+    /// Take the value posted since the last `take`, blocking until one is posted
+    pub fn take(&self) -> T {
+        match self.take_until(NO_DEADLINE) {
+            Ok(value) => value,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `take`, but gives up with Err(Error::TimedOut) if nothing was posted within
+    /// `ticks` ticks
+    pub fn take_timeout(&self, ticks: u32) -> Result<T, Error> {
+        self.take_until(crate::ticks() + ticks as u64)
+    }
+
+    /// Take the value posted since the last `take`, if any, without blocking
+    pub fn try_take(&self) -> Option<T> {
+        let _cs = CriticalSection::enter();
+        unsafe { (*self.value.get()).take() }
+    }
+
+    fn take_until(&self, deadline: u64) -> Result<T, Error> {
+        unsafe {
+            let mut cs = CriticalSection::enter();
+            loop {
+                if let Some(value) = (*self.value.get()).take() {
+                    return Ok(value);
+                }
+                if block_current(cs, self.readers.get(), deadline) == WakeReason::Timeout {
+                    let _cs = CriticalSection::enter();
+                    return (*self.value.get()).take().ok_or(Error::TimedOut);
+                }
+                cs = CriticalSection::enter();
+            }
+        }
+    }
+}
+
+impl<T> Default for Mailbox<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Ready for `select` when a value was posted
+impl<T> Source for Mailbox<T> {
+    fn ready(&self) -> bool {
+        unsafe { (*self.value.get()).is_some() }
+    }
+
+    fn wait_list(&self) -> *mut WaitList {
+        self.readers.get()
+    }
+}
@@ -0,0 +1,149 @@
+//! Run modes switching sets of threads on and off.
+//!
+//! A `ThreadGroup` is a set of threads suspended and resumed together. A suspended thread
+//! keeps its state, and still sleeps and waits, but does not run until it is resumed. `Modes`
+//! builds on groups: the application defines each of its modes, e.g. INIT, RUN and LOW_POWER,
+//! as the group of threads active in it, and `switch_mode` resumes the threads of the new mode
+//! and suspends those of the other modes in one step, so no thread runs in a half-switched
+//! system.
+
+use core::cell::UnsafeCell;
+
+use crate::critical::CriticalSection;
+use crate::{schedule, with_state, ThreadHandle, ThreadsState};
+
+/// Set of threads, identified by their ids. Suspending and resuming a group manages other
+/// threads, which only privileged threads may do, see `svc`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ThreadGroup {
+    /// bit i set if thread i is in the group
+    mask: u32,
+}
+
+impl ThreadGroup {
+    /// An empty group
+    pub const fn new() -> Self {
+        ThreadGroup { mask: 0 }
+    }
+
+    /// This group with `thread` added
+    pub const fn with(self, thread: ThreadHandle) -> Self {
+        ThreadGroup {
+            mask: self.mask | 1 << thread.0,
+        }
+    }
+
+    /// Add `thread` to the group
+    pub fn insert(&mut self, thread: ThreadHandle) {
+        self.mask |= 1 << thread.0;
+    }
+
+    /// Remove `thread` from the group
+    pub fn remove(&mut self, thread: ThreadHandle) {
+        self.mask &= !(1 << thread.0);
+    }
+
+    pub fn contains(&self, thread: ThreadHandle) -> bool {
+        self.mask & 1 << thread.0 != 0
+    }
+
+    /// Suspend the threads of the group, the calling one included if it is in it. The idle
+    /// thread is never suspended.
+    pub fn suspend(&self) {
+        with_state(|s| self.set_suspended(s, true));
+        schedule();
+    }
+
+    /// Resume the suspended threads of the group
+    pub fn resume(&self) {
+        with_state(|s| self.set_suspended(s, false));
+        schedule();
+    }
+
+    fn set_suspended(&self, s: &mut ThreadsState, suspended: bool) {
+        let now = s.ticks;
+        for idx in 1..s.threads.len() {
+            if self.mask & 1 << idx != 0 {
+                s.set_suspended(idx, suspended, now);
+            }
+        }
+    }
+}
+
+/// `N` run modes, numbered from 0, each with the group of threads active in it. Like the
+/// groups, modes are switched by privileged threads.
+///
+/// Example:
+/// ```
+/// const INIT: usize = 0;
+/// const RUN: usize = 1;
+/// const LOW_POWER: usize = 2;
+///
+/// static MODES: Modes<3> = Modes::new();
+///
+/// MODES.define(INIT, ThreadGroup::new().with(supervisor));
+/// MODES.define(RUN, ThreadGroup::new().with(supervisor).with(sensor).with(radio));
+/// MODES.define(LOW_POWER, ThreadGroup::new().with(supervisor));
+/// MODES.switch_mode(INIT);
+///
+/// // supervisor thread
+/// MODES.switch_mode(if battery_low() { LOW_POWER } else { RUN });
+/// ```
+pub struct Modes<const N: usize> {
+    groups: UnsafeCell<[ThreadGroup; N]>,
+    /// mode switched to last, None before the first switch
+    current: UnsafeCell<Option<usize>>,
+}
+
+unsafe impl<const N: usize> Sync for Modes<N> {}
+
+impl<const N: usize> Modes<N> {
+    /// `N` modes without threads
+    pub const fn new() -> Self {
+        Modes {
+            groups: UnsafeCell::new([ThreadGroup::new(); N]),
+            current: UnsafeCell::new(None),
+        }
+    }
+
+    /// Set the threads active in mode `mode`. The threads of all modes are managed by
+    /// `switch_mode`; threads in none of them are left alone. A change to the current mode
+    /// applies from the next switch. Panics if `mode` is not below `N`.
+    pub fn define(&self, mode: usize, threads: ThreadGroup) {
+        let _cs = CriticalSection::enter();
+        unsafe { (*self.groups.get())[mode] = threads };
+    }
+
+    /// Resume the threads of mode `mode` and suspend the threads of the other modes not in
+    /// it, inside one critical section, then let the highest priority ready thread run. The
+    /// calling thread is suspended too if it is not active in the new mode. Returns the mode
+    /// switched from, None on the first switch. Panics if `mode` is not below `N`.
+    pub fn switch_mode(&self, mode: usize) -> Option<usize> {
+        let previous = with_state(|s| {
+            let groups = unsafe { &*self.groups.get() };
+            let active = groups[mode];
+            let managed = groups.iter().fold(0, |all, group| all | group.mask);
+            let now = s.ticks;
+            for idx in 1..s.threads.len() {
+                if managed & 1 << idx != 0 {
+                    s.set_suspended(idx, active.mask & 1 << idx == 0, now);
+                }
+            }
+            unsafe { (*self.current.get()).replace(mode) }
+        });
+        schedule();
+        previous
+    }
+
+    /// Mode switched to last, None before the first switch
+    pub fn current(&self) -> Option<usize> {
+        let _cs = CriticalSection::enter();
+        unsafe { *self.current.get() }
+    }
+}
+
+impl<const N: usize> Default for Modes<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,273 @@
+//! Memory Protection Unit support for ARMv7-M parts (Cortex-M3/M4).
+//!
+//! With the `mpu-stack-guard` feature, a 32 byte no-access region is placed just above the
+//! stack canary of the thread about to run, so a stack overflow faults (MemManage, or HardFault
+//! if that is disabled) on the first write into the guard instead of silently corrupting memory
+//! below the stack.
+//!
+//! Unprivileged threads can only access memory covered by an MPU region once the MPU is on.
+//! Without the `mpu-isolation` feature, `init` sets up a background region granting full
+//! access to the whole address space. It uses strongly-ordered attributes, which are correct
+//! for both memory and peripherals.
+//!
+//! With the `mpu-isolation` feature, the background region covers the code area only, read-only
+//! for unprivileged threads. An unprivileged thread can then write its own stack and the
+//! regions given to it with `set_thread_regions`, nothing else: the regions are loaded on each
+//! switch to the thread. Privileged threads and the kernel still see the default memory map.
+//!
+//! Region 0 is the background region, region 1 the stack of the running thread, regions 2 to
+//! 5 the regions given to it, and region 7, which has the highest priority, is the guard.
+//! Regions not used by the enabled features are left to the application.
+
+#[cfg(feature = "mpu-isolation")]
+use core::ops::Range;
+use core::ptr;
+
+use crate::arch::{Arch, Port};
+use crate::Error;
+#[cfg(feature = "mpu-stack-guard")]
+use crate::CANARY_WORDS;
+#[cfg(feature = "mpu-isolation")]
+use crate::{svc, with_state, ThreadControlBlock, ThreadHandle, ThreadStatus};
+
+const MPU_CTRL: u32 = 0xE000ED94;
+const MPU_RNR: u32 = 0xE000ED98;
+const MPU_RBAR: u32 = 0xE000ED9C;
+const MPU_RASR: u32 = 0xE000EDA0;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+const RASR_ENABLE: u32 = 1 << 0;
+const RASR_XN: u32 = 1 << 28;
+const RASR_AP_FULL: u32 = 0b011 << 24;
+#[cfg(feature = "mpu-stack-guard")]
+const RASR_AP_NONE: u32 = 0b000 << 24;
+/// read-write when privileged, read-only when unprivileged
+#[cfg(feature = "mpu-isolation")]
+const RASR_AP_UNPRIV_RO: u32 = 0b010 << 24;
+
+const BACKGROUND_REGION: u32 = 0;
+#[cfg(feature = "mpu-isolation")]
+const STACK_REGION: u32 = 1;
+#[cfg(feature = "mpu-isolation")]
+const THREAD_REGIONS: u32 = 2;
+#[cfg(feature = "mpu-stack-guard")]
+const GUARD_REGION: u32 = 7;
+
+/// Size in bytes of the smallest MPU region
+const MIN_REGION_SIZE: u32 = 32;
+#[cfg(feature = "mpu-stack-guard")]
+const GUARD_SIZE: u32 = MIN_REGION_SIZE;
+
+/// Size in bytes of the code area, from address 0, which unprivileged threads may read and run
+#[cfg(feature = "mpu-isolation")]
+const CODE_SIZE_LOG2: u32 = 29;
+
+/// Number of regions which can be given to a thread with `set_thread_regions`
+#[cfg(feature = "mpu-isolation")]
+pub const MPU_REGIONS: usize = 4;
+
+/// SIZE field of RASR for a region of 2^log2 bytes
+const fn rasr_size(log2: u32) -> u32 {
+    (log2 - 1) << 1
+}
+
+#[cfg(feature = "mpu-stack-guard")]
+/// Address of the guard region of a stack starting at `stack_base`
+pub(crate) fn guard_address(stack_base: u32) -> u32 {
+    let above_canary = stack_base + (CANARY_WORDS as u32) * 4;
+    (above_canary + GUARD_SIZE - 1) & !(GUARD_SIZE - 1)
+}
+
+#[cfg(feature = "mpu-stack-guard")]
+/// End address of the guard region of a stack starting at `stack_base`
+pub(crate) fn guard_end(stack_base: u32) -> u32 {
+    guard_address(stack_base) + GUARD_SIZE
+}
+
+#[cfg(feature = "mpu-stack-guard")]
+/// Check that the guard of a stack area does not reach into its initial frame
+pub(crate) fn check_stack(stack: &[u32]) -> Result<(), Error> {
+    let base = stack.as_ptr() as usize as u32;
+    // 16 words of initial frame, plus EXC_RETURN with an FPU and a word of alignment padding
+    let frame = base + (stack.len() as u32 - 18) * 4;
+    if guard_end(base) > frame {
+        return Err(Error::StackTooSmall);
+    }
+    Ok(())
+}
+
+/// Set up the background region and enable the MPU
+pub(crate) fn init() {
+    #[cfg(not(feature = "mpu-isolation"))]
+    let background = RASR_AP_FULL | rasr_size(32) | RASR_ENABLE;
+    #[cfg(feature = "mpu-isolation")]
+    let background = RASR_AP_UNPRIV_RO | rasr_size(CODE_SIZE_LOG2) | RASR_ENABLE;
+    unsafe {
+        ptr::write_volatile(MPU_RNR as *mut u32, BACKGROUND_REGION);
+        ptr::write_volatile(MPU_RBAR as *mut u32, 0);
+        ptr::write_volatile(MPU_RASR as *mut u32, background);
+        // the regions of the first thread are loaded when init switches to it
+        #[cfg(feature = "mpu-stack-guard")]
+        write_region(GUARD_REGION, 0, 0);
+        ptr::write_volatile(MPU_CTRL as *mut u32, CTRL_PRIVDEFENA | CTRL_ENABLE);
+        Port::barrier();
+    }
+}
+
+#[cfg(feature = "mpu-stack-guard")]
+/// Move the guard region below the stack starting at `stack_base`
+pub(crate) fn set_stack_guard(stack_base: u32) {
+    unsafe {
+        write_region(
+            GUARD_REGION,
+            guard_address(stack_base),
+            RASR_XN | RASR_AP_NONE | rasr_size(5) | RASR_ENABLE,
+        );
+        Port::barrier();
+    }
+}
+
+/// Program region `number`, a zero `rasr` disables it
+unsafe fn write_region(number: u32, rbar: u32, rasr: u32) {
+    ptr::write_volatile(MPU_RNR as *mut u32, number);
+    ptr::write_volatile(MPU_RBAR as *mut u32, rbar);
+    ptr::write_volatile(MPU_RASR as *mut u32, rasr);
+}
+
+/// Access a thread has to an `MpuRegion` while it runs unprivileged
+#[cfg(feature = "mpu-isolation")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegionAccess {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Memory area an unprivileged thread may access besides its stack, see `set_thread_regions`.
+/// Code cannot be run from it, and accesses are strongly-ordered, which suits both memory and
+/// peripherals.
+#[cfg(feature = "mpu-isolation")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MpuRegion {
+    rbar: u32,
+    rasr: u32,
+}
+
+#[cfg(feature = "mpu-isolation")]
+impl MpuRegion {
+    /// Disabled region
+    pub(crate) const NONE: MpuRegion = MpuRegion { rbar: 0, rasr: 0 };
+
+    /// The `size` bytes at `base`. Fails with `Error::BadRegion` unless `size` is a power of
+    /// two of at least 32 and `base` is a multiple of it, as the MPU requires.
+    ///
+    /// # Example
+    /// ```
+    /// // USART2 registers
+    /// let usart = MpuRegion::new(0x4000_4400, 0x400, RegionAccess::ReadWrite)?;
+    /// ```
+    pub fn new(base: u32, size: u32, access: RegionAccess) -> Result<Self, Error> {
+        if size < MIN_REGION_SIZE || !size.is_power_of_two() || base & (size - 1) != 0 {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("MPU region of {} bytes at {:x} not aligned", size, base);
+            return Err(Error::BadRegion);
+        }
+        let ap = match access {
+            RegionAccess::ReadOnly => RASR_AP_UNPRIV_RO,
+            RegionAccess::ReadWrite => RASR_AP_FULL,
+        };
+        Ok(MpuRegion {
+            rbar: base,
+            rasr: RASR_XN | ap | rasr_size(size.trailing_zeros()) | RASR_ENABLE,
+        })
+    }
+}
+
+/// Check that a stack area can be covered by a single region, see `MpuRegion::new`
+#[cfg(feature = "mpu-isolation")]
+pub(crate) fn check_stack_region(stack: &[u32]) -> Result<(), Error> {
+    let base = stack.as_ptr() as usize as u32;
+    MpuRegion::new(base, stack.len() as u32 * 4, RegionAccess::ReadWrite).map(|_| ())
+}
+
+/// Give thread `thread` access to `regions`, replacing those given before. They apply while
+/// the thread runs unprivileged, in addition to its own stack and to reading and running the
+/// code area. Data the thread shares with others, e.g. a `Mutex` or a `Queue`, must be in one
+/// of them. Returns `Error::BadRegion` for more than `MPU_REGIONS` regions, and
+/// `Error::NoCreatePrivilege` if called by an unprivileged thread.
+///
+/// An unprivileged thread's stack area must itself be a valid region, a power of two of bytes
+/// aligned on its size. Threads it creates get its regions.
+///
+/// # Example
+/// ```
+/// #[repr(C, align(2048))]
+/// struct SensorStack([u32; 512]);
+/// static mut SENSOR_STACK: SensorStack = SensorStack([0; 512]);
+///
+/// let sensor = create_thread(unsafe { &mut SENSOR_STACK.0 }, sensor_task)?;
+/// let i2c = MpuRegion::new(0x4000_5400, 0x400, RegionAccess::ReadWrite)?;
+/// let readings = MpuRegion::new(readings_addr, 256, RegionAccess::ReadWrite)?;
+/// set_thread_regions(sensor, &[i2c, readings])?;
+/// ```
+#[cfg(feature = "mpu-isolation")]
+pub fn set_thread_regions(thread: ThreadHandle, regions: &[MpuRegion]) -> Result<(), Error> {
+    if svc::from_unprivileged_thread() {
+        return Err(Error::NoCreatePrivilege);
+    }
+    if regions.len() > MPU_REGIONS {
+        return Err(Error::BadRegion);
+    }
+    let thread_id = thread.0;
+    with_state(|handler| {
+        if thread_id >= handler.threads.len()
+            || handler.threads[thread_id].status == ThreadStatus::Free
+        {
+            return Err(Error::NoSuchThread);
+        }
+        let tcb = &mut handler.threads[thread_id];
+        tcb.regions = [MpuRegion::NONE; MPU_REGIONS];
+        tcb.regions[..regions.len()].copy_from_slice(regions);
+        if thread_id == handler.idx && handler.inited {
+            load_regions(&handler.threads[thread_id]);
+        }
+        Ok(())
+    })
+}
+
+/// Whether unprivileged thread `tcb` may write all of the address range `range`: its stack,
+/// or one of its read-write regions, holds it
+#[cfg(feature = "mpu-isolation")]
+pub(crate) fn may_write(tcb: &ThreadControlBlock, range: Range<usize>) -> bool {
+    let inside = |base: u32, size: usize| {
+        let base = base as usize;
+        base <= range.start && range.end <= base.saturating_add(size)
+    };
+    inside(tcb.stack_base as u32, tcb.stack_words as usize * 4)
+        || tcb.regions.iter().any(|region| {
+            region.rasr & RASR_ENABLE != 0
+                && region.rasr & (0b111 << 24) == RASR_AP_FULL
+                && inside(region.rbar, 2 << ((region.rasr >> 1) & 0x1f))
+        })
+}
+
+/// Load the stack region and the regions of thread `tcb`, about to run
+#[cfg(feature = "mpu-isolation")]
+pub(crate) fn load_regions(tcb: &ThreadControlBlock) {
+    // only unprivileged threads have stacks checked to fit a region, privileged threads see
+    // the default memory map anyway
+    let stack = if tcb.privileged == 0 {
+        let size = rasr_size((tcb.stack_words * 4).trailing_zeros());
+        RASR_XN | RASR_AP_FULL | size | RASR_ENABLE
+    } else {
+        0
+    };
+    unsafe {
+        write_region(STACK_REGION, tcb.stack_base as u32, stack);
+        for (i, region) in tcb.regions.iter().enumerate() {
+            write_region(THREAD_REGIONS + i as u32, region.rbar, region.rasr);
+        }
+        Port::barrier();
+    }
+}
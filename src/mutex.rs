@@ -0,0 +1,294 @@
+//! Mutual exclusion between threads.
+//!
+//! A `Mutex` gives one thread at a time access to its data. Threads which find it locked
+//! block until it is unlocked, and are then woken highest priority first. A locked mutex
+//! remembers the thread holding it, for deadlock detection.
+//!
+//! A mutex made with `Mutex::with_ceiling` follows the immediate priority ceiling protocol:
+//! the thread locking it runs at the ceiling priority until it unlocks it. With each ceiling
+//! at least the priority of every thread using the mutex, no thread which could lock it can
+//! preempt the holder, so a thread never blocks on it more than once per lock, and mutexes
+//! which all have ceilings cannot deadlock.
+//!
+//! A plain mutex has no protection against priority inversion: there is no priority
+//! inheritance, and a holder keeps its own priority while higher priority threads wait for
+//! it. Give the mutex a ceiling to bound the wait, or enable the `inversion-detection`
+//! feature to be told of such waits, see `set_inversion_hook`.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+use crate::critical::CriticalSection;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{
+    block_current, block_enter, deadlock, schedule, svc, wake_one, Error, ThreadsState, WakeReason,
+    NO_DEADLINE,
+};
+
+struct State {
+    locked: bool,
+    /// thread holding the mutex while it is locked
+    holder: usize,
+    waiters: WaitList,
+    /// priority the holder runs at, None for a plain mutex
+    ceiling: Option<u8>,
+    /// ceiling of the holder before it locked the mutex, restored on unlock
+    outer_ceiling: u8,
+}
+
+impl State {
+    /// Lock for thread `idx`, raising it to the ceiling if there is one
+    fn acquire(&mut self, handler: &mut ThreadsState, idx: usize) {
+        self.locked = true;
+        self.holder = idx;
+        if let Some(ceiling) = self.ceiling {
+            let outer = handler.threads[idx].ceiling;
+            handler.set_ceiling(idx, outer.max(ceiling));
+            self.outer_ceiling = outer;
+        }
+    }
+}
+
+/// Data shared between threads, accessed by one of them at a time
+///
+/// Example:
+/// ```
+/// static LOG: Mutex<Log> = Mutex::new(Log::new());
+///
+/// LOG.lock().push("sensor fault");
+/// ```
+// the state comes first, so that the address of a mutex is the address of its state
+#[repr(C)]
+pub struct Mutex<T> {
+    state: UnsafeCell<State>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::make(value, None)
+    }
+
+    /// A mutex following the priority ceiling protocol: a thread holding it runs at priority
+    /// `ceiling`, or at its own priority if that is higher. The ceiling should be the highest
+    /// priority of the threads locking the mutex. Nested mutexes with ceilings must be
+    /// unlocked in reverse locking order, which dropping their guards does.
+    ///
+    /// Example:
+    /// ```
+    /// // locked by threads of priority 2 to 5
+    /// static BUS: Mutex<I2c> = Mutex::with_ceiling(I2c::new(), 5);
+    /// ```
+    pub const fn with_ceiling(value: T, ceiling: u8) -> Self {
+        Self::make(value, Some(ceiling))
+    }
+
+    const fn make(value: T, ceiling: Option<u8>) -> Self {
+        Mutex {
+            state: UnsafeCell::new(State {
+                locked: false,
+                holder: 0,
+                waiters: WaitList::new(WaitOrder::Priority),
+                ceiling,
+                outer_ceiling: 0,
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Block until the mutex is unlocked, lock it and return a guard giving access to the
+    /// data. The mutex is unlocked when the guard is dropped.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        match self.lock_until(NO_DEADLINE) {
+            Ok(guard) => guard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `lock`, but gives up with Err(Error::TimedOut) if the mutex was not unlocked
+    /// within `ticks` ticks
+    pub fn lock_timeout(&self, ticks: u32) -> Result<MutexGuard<'_, T>, Error> {
+        self.lock_until(crate::ticks() + ticks as u64)
+    }
+
+    /// Lock the mutex if it is unlocked, without blocking
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if svc::from_unprivileged_thread() {
+            // a deadline which has passed fails instead of blocking
+            let locked = svc::lock_mutex(self as *const Self as usize, 0);
+            return locked.ok().map(|_| MutexGuard::new(self));
+        }
+        let state = self.state.get();
+        let mut cs = CriticalSection::enter();
+        unsafe {
+            if (*state).locked {
+                return None;
+            }
+            let handler = crate::state(&mut cs);
+            (*state).acquire(handler, handler.idx);
+        }
+        Some(MutexGuard::new(self))
+    }
+
+    fn lock_until(&self, deadline: u64) -> Result<MutexGuard<'_, T>, Error> {
+        if svc::from_unprivileged_thread() {
+            svc::lock_mutex(self as *const Self as usize, deadline)?;
+            return Ok(MutexGuard::new(self));
+        }
+        let state = self.state.get();
+        unsafe {
+            let mut cs = CriticalSection::enter();
+            while (*state).locked {
+                deadlock::wait_for(crate::state(&mut cs), self as *const Self as usize);
+                if block_current(cs, &mut (*state).waiters, deadline) == WakeReason::Timeout {
+                    crate::with_state(deadlock::done_waiting);
+                    return Err(Error::TimedOut);
+                }
+                cs = CriticalSection::enter();
+            }
+            let handler = crate::state(&mut cs);
+            deadlock::done_waiting(handler);
+            (*state).acquire(handler, handler.idx);
+        }
+        Ok(MutexGuard::new(self))
+    }
+
+    /// Whether the calling thread holds the mutex. Only the holder unlocks it, so the answer
+    /// cannot change under a thread which holds it.
+    #[cfg(feature = "ffi")]
+    pub(crate) fn held(&self) -> bool {
+        let state = unsafe { &*self.state.get() };
+        state.locked && state.holder == crate::get_thread_id().0
+    }
+
+    /// Unlock the mutex, drop its holder back from the ceiling and wake the first thread
+    /// waiting to lock it
+    pub(crate) fn release(&self, handler: &mut ThreadsState) {
+        let state = self.state.get();
+        unsafe {
+            (*state).locked = false;
+            if (*state).ceiling.is_some() {
+                handler.set_ceiling((*state).holder, (*state).outer_ceiling);
+            }
+            wake_one(handler, &mut (*state).waiters, WakeReason::Signaled);
+        }
+    }
+}
+
+/// Size of the state at the start of a mutex, which a kernel call may write
+pub(crate) const STATE_SIZE: usize = core::mem::size_of::<State>();
+
+/// Kernel part of `lock_until` for the mutex at address `lock`, run by a kernel call: lock it
+/// and return Ok(true), or block the current thread on it until `deadline` and return
+/// Ok(false). `retry` is set when the thread runs again after blocking.
+///
+/// # Safety
+/// `lock` must be the address of a live `Mutex`
+pub(crate) unsafe fn lock_or_block(
+    handler: &mut ThreadsState,
+    lock: usize,
+    deadline: u64,
+    retry: bool,
+) -> Result<bool, Error> {
+    let state = &mut *(*(lock as *const Mutex<()>)).state.get();
+    let idx = handler.idx;
+    if retry {
+        // leave the wait list in case it was not the waker that removed us
+        state.waiters.remove(idx);
+        if handler.threads[idx].wake_reason == WakeReason::Timeout {
+            deadlock::done_waiting(handler);
+            return Err(Error::TimedOut);
+        }
+    }
+    if !state.locked {
+        deadlock::done_waiting(handler);
+        state.acquire(handler, idx);
+        return Ok(true);
+    }
+    deadlock::wait_for(handler, lock);
+    let waiters = &mut state.waiters;
+    match block_enter(handler, deadline, |_, idx| waiters.insert(idx)) {
+        None => Ok(false),
+        Some(_) => {
+            deadlock::done_waiting(handler);
+            Err(Error::TimedOut)
+        }
+    }
+}
+
+/// Unlock the mutex at address `lock` for a kernel call, if the current thread holds it
+///
+/// # Safety
+/// `lock` must be the address of a live `Mutex`
+pub(crate) unsafe fn unlock_held(handler: &mut ThreadsState, lock: usize) {
+    let mutex = &*(lock as *const Mutex<()>);
+    let state = &*mutex.state.get();
+    if state.locked && state.holder == handler.idx {
+        mutex.release(handler);
+    }
+}
+
+/// Thread holding the mutex at address `lock`, None if it is unlocked
+///
+/// # Safety
+/// `lock` must be the address of a live `Mutex`
+#[cfg(any(feature = "deadlock-detection", feature = "inversion-detection"))]
+pub(crate) unsafe fn holder(lock: usize) -> Option<usize> {
+    // any T will do, the state is at the same place whatever the data
+    let state = &*(*(lock as *const Mutex<()>)).state.get();
+    if state.locked {
+        Some(state.holder)
+    } else {
+        None
+    }
+}
+
+/// Access to the data of a locked `Mutex`, which is unlocked when the guard is dropped
+pub struct MutexGuard<'a, T> {
+    pub(crate) mutex: &'a Mutex<T>,
+    // the mutex must be unlocked by the thread which locked it, whose ceiling it restores
+    _not_send: PhantomData<*const ()>,
+}
+
+// a shared guard only hands out &T
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// Guard of `mutex`, locked by the calling thread
+    pub(crate) fn new(mutex: &'a Mutex<T>) -> Self {
+        MutexGuard {
+            mutex,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if svc::from_unprivileged_thread() {
+            return svc::unlock_mutex(self.mutex as *const Mutex<T> as usize);
+        }
+        {
+            let mut cs = CriticalSection::enter();
+            self.mutex.release(crate::state(&mut cs));
+        }
+        schedule();
+    }
+}
@@ -0,0 +1,232 @@
+//! Fixed rate release of a thread's work, without drift.
+//!
+//! `Periodic` releases a single thread at a fixed rate. `PhaseGroup` releases several threads
+//! on a shared grid of ticks, each at its own offset, so their relative phase is kept even when
+//! one of them overruns.
+//!
+//! `create_periodic_thread` goes one step further: the kernel owns the release loop and calls
+//! the task body once per period, counting the instances which overrun their period.
+//!
+//! Periods and release ticks are counted in ticks, and kept as they are by `set_tick_rate`:
+//! at half the rate, a period lasts twice as long.
+
+use crate::{
+    create_thread_closure_with_config, sleep_until, svc, ticks, with_state, Error, ThreadHandle,
+    ThreadsState,
+};
+
+/// Releases a thread every `period` ticks. The next release tick is computed from the previous
+/// one rather than from the time the thread finished its work, so time spent processing does
+/// not accumulate as drift.
+///
+/// Example:
+/// ```
+/// let _ = create_thread(STACK1.take().unwrap(), || {
+///     let mut period = Periodic::new(10);
+///     loop {
+///         run_control_loop();
+///         period.next_period();
+///     }
+/// });
+/// ```
+pub struct Periodic {
+    period: u32,
+    next: u64,
+}
+
+impl Periodic {
+    /// Create a helper whose first release is `period` ticks from now
+    pub fn new(period: u32) -> Self {
+        Periodic {
+            period,
+            next: ticks() + period as u64,
+        }
+    }
+
+    /// Sleep until the next release tick. If the thread overran and the release tick has
+    /// already passed, returns immediately so the thread can catch up.
+    pub fn next_period(&mut self) {
+        let release = self.next;
+        self.next += self.period as u64;
+        sleep_until(release);
+    }
+
+    /// Tick at which the upcoming `next_period` call returns
+    pub fn next_release(&self) -> u64 {
+        self.next
+    }
+}
+
+/// A set of threads released every `period` ticks at fixed offsets from each other. Release
+/// ticks are multiples of the period since `init` plus each member's offset, so members with
+/// the same offset always run on the same tick and relative phase never drifts.
+///
+/// Example:
+/// ```
+/// static FUSION: PhaseGroup = PhaseGroup::new(10);
+///
+/// // sampling thread
+/// let mut phase = FUSION.member(0);
+/// loop {
+///     sample_sensors();
+///     phase.next_period();
+/// }
+///
+/// // fusion thread, runs 2 ticks after every sample
+/// let mut phase = FUSION.member(2);
+/// loop {
+///     fuse();
+///     phase.next_period();
+/// }
+/// ```
+pub struct PhaseGroup {
+    period: u32,
+}
+
+impl PhaseGroup {
+    pub const fn new(period: u32) -> Self {
+        PhaseGroup {
+            period: if period == 0 { 1 } else { period },
+        }
+    }
+
+    /// Join the group with release ticks `offset` ticks after the group's grid points.
+    /// The first release is the next such tick after now.
+    pub fn member(&self, offset: u32) -> PhaseMember {
+        let period = self.period as u64;
+        let offset = offset as u64 % period;
+        let now = ticks();
+        let mut next = now - now % period + offset;
+        if next <= now {
+            next += period;
+        }
+        PhaseMember {
+            period: self.period,
+            next,
+        }
+    }
+}
+
+/// A thread's membership in a `PhaseGroup`
+pub struct PhaseMember {
+    period: u32,
+    next: u64,
+}
+
+impl PhaseMember {
+    /// Sleep until the next release tick of this member. If the thread overran one or more
+    /// release ticks, those are skipped rather than caught up, keeping the member in phase
+    /// with the rest of the group. Returns the number of skipped releases.
+    pub fn next_period(&mut self) -> u32 {
+        let period = self.period as u64;
+        let now = ticks();
+        let mut skipped = 0;
+        if self.next < now {
+            skipped = (now - self.next).div_ceil(period);
+            self.next += skipped * period;
+        }
+        let release = self.next;
+        self.next += period;
+        sleep_until(release);
+        skipped as u32
+    }
+
+    /// Tick at which the upcoming `next_period` call returns, unless the thread overruns it
+    pub fn next_release(&self) -> u64 {
+        self.next
+    }
+}
+
+/// Create a thread calling `f` once every `period_ticks` ticks, with default configuration
+/// (lowest priority, unprivileged). The first call is made when the thread first runs, the
+/// following ones at fixed release ticks, without drift. An instance still running when the
+/// next release tick has passed missed its deadline: it is counted in the thread's
+/// `ThreadInfo::deadline_misses`, the hook set with `set_deadline_miss_hook` is called, and the
+/// releases which passed meanwhile are skipped instead of run late back to back.
+///
+/// # Example
+/// ```
+/// let _ = create_periodic_thread(STACK1.take().unwrap(), move || {
+///     let speed = encoder.read();
+///     motor.set_duty(pid.update(speed));
+/// }, 10);
+/// ```
+pub fn create_periodic_thread<F>(
+    stack: &'static mut [u32],
+    f: F,
+    period_ticks: u32,
+) -> Result<ThreadHandle, Error>
+where
+    F: FnMut() + Send + 'static,
+{
+    create_periodic_thread_with_config(stack, f, period_ticks, 0x00, false)
+}
+
+/// Create a periodic thread with explicit configuration, see `create_periodic_thread` and
+/// `create_thread_with_config`.
+pub fn create_periodic_thread_with_config<F>(
+    stack: &'static mut [u32],
+    mut f: F,
+    period_ticks: u32,
+    priority: u8,
+    priviliged: bool,
+) -> Result<ThreadHandle, Error>
+where
+    F: FnMut() + Send + 'static,
+{
+    let period = core::cmp::max(period_ticks, 1) as u64;
+    create_thread_closure_with_config(
+        stack,
+        move || {
+            let mut release = ticks();
+            loop {
+                f();
+                let deadline = release + period;
+                let now = ticks();
+                release = if now > deadline {
+                    deadline_missed();
+                    deadline + (now - deadline).div_ceil(period) * period
+                } else {
+                    deadline
+                };
+                sleep_until(release);
+            }
+        },
+        priority,
+        priviliged,
+    )
+}
+
+/// Count a deadline miss of the current thread and report it to the hook
+fn deadline_missed() {
+    let (thread, hook) = if svc::from_unprivileged_thread() {
+        (svc::thread_id(), svc::deadline_missed())
+    } else {
+        with_state(|s| (ThreadHandle(s.idx), count_miss(s)))
+    };
+    if let Some(hook) = hook {
+        hook(thread);
+    }
+}
+
+/// Count a deadline miss of the current thread, and return the hook to report it to
+pub(crate) fn count_miss(s: &mut ThreadsState) -> Option<fn(ThreadHandle)> {
+    let tcb = &mut s.threads[s.idx];
+    tcb.deadline_misses = tcb.deadline_misses.wrapping_add(1);
+    s.deadline_miss_hook
+}
+
+/// Set a function called by periodic threads when an instance overruns its period, with the
+/// id of the thread. It runs in the periodic thread, before the thread waits for its next
+/// release.
+///
+/// # Example
+/// ```
+/// fn missed(thread: ThreadHandle) {
+///     let _ = hprintln!("thread {} missed its deadline", thread.id());
+/// }
+/// set_deadline_miss_hook(missed);
+/// ```
+pub fn set_deadline_miss_hook(hook: fn(ThreadHandle)) {
+    with_state(|s| s.deadline_miss_hook = Some(hook));
+}
@@ -0,0 +1,161 @@
+//! Fixed-size block allocator, for passing owned buffers around without a heap.
+//!
+//! A `Pool` holds `N` blocks of type `T`. `alloc` moves a value into a free block and returns
+//! a `PoolBox` owning it, which gives the block back when dropped. A box taken from a pool in
+//! a `static` can be sent through a queue to another thread or out of an interrupt handler,
+//! so a message is written once and handed over without copying. Allocating and freeing take
+//! constant time and only mask interrupts for a few instructions, so both can be done from
+//! interrupt handlers. Unprivileged threads cannot mask interrupts, so they must not share a
+//! pool with interrupt handlers.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::critical::with_critical;
+
+/// End of the free list
+const NONE: u16 = u16::MAX;
+
+/// Bookkeeping of a pool, only accessed with interrupts disabled
+struct State<const N: usize> {
+    /// first block of the free list, NONE if empty
+    free: u16,
+    /// blocks never handed out are `fresh..N`, they are not on the free list
+    fresh: u16,
+    /// next block on the free list, for each free block
+    next: [u16; N],
+}
+
+/// `N` blocks of type `T`
+///
+/// Example:
+/// ```
+/// static BUFFERS: Pool<[u8; 256], 8> = Pool::new();
+/// static mut FRAMES: RingBuffer<PoolBox<'static, [u8; 256], 8>, 8> = RingBuffer::new();
+///
+/// let (mut producer, mut consumer) = unsafe { FRAMES.split() };
+///
+/// // receive interrupt handler
+/// if let Ok(mut frame) = BUFFERS.alloc([0; 256]) {
+///     radio.read_frame(&mut frame[..]);
+///     let _ = producer.push(frame);
+/// }
+///
+/// // thread, the block is freed at the end of each iteration
+/// loop {
+///     let frame = consumer.pop();
+///     parse(&frame[..]);
+/// }
+/// ```
+pub struct Pool<T, const N: usize> {
+    blocks: UnsafeCell<MaybeUninit<[T; N]>>,
+    state: UnsafeCell<State<N>>,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Pool<T, N> {}
+
+impl<T, const N: usize> Pool<T, N> {
+    // block indices fit in a u16, NONE excluded
+    const CHECK: () = assert!(N < NONE as usize);
+
+    pub const fn new() -> Self {
+        let () = Self::CHECK;
+        Pool {
+            blocks: UnsafeCell::new(MaybeUninit::uninit()),
+            state: UnsafeCell::new(State {
+                free: NONE,
+                fresh: 0,
+                next: [NONE; N],
+            }),
+        }
+    }
+
+    /// Move `value` into a free block. Gives `value` back if all blocks are in use.
+    pub fn alloc(&self, value: T) -> Result<PoolBox<'_, T, N>, T> {
+        let idx = with_critical(|| {
+            let state = unsafe { &mut *self.state.get() };
+            if state.free != NONE {
+                let idx = state.free;
+                state.free = state.next[idx as usize];
+                Some(idx as usize)
+            } else if (state.fresh as usize) < N {
+                state.fresh += 1;
+                Some(state.fresh as usize - 1)
+            } else {
+                None
+            }
+        });
+        match idx {
+            Some(idx) => {
+                unsafe { ptr::write(self.block(idx), value) };
+                Ok(PoolBox { pool: self, idx })
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Number of free blocks
+    pub fn available(&self) -> usize {
+        with_critical(|| {
+            let state = unsafe { &*self.state.get() };
+            let mut count = N - state.fresh as usize;
+            let mut idx = state.free;
+            while idx != NONE {
+                count += 1;
+                idx = state.next[idx as usize];
+            }
+            count
+        })
+    }
+
+    fn block(&self, idx: usize) -> *mut T {
+        unsafe { (*self.blocks.get()).as_mut_ptr().cast::<T>().add(idx) }
+    }
+
+    /// Put block `idx`, whose value was dropped, back on the free list
+    fn free(&self, idx: usize) {
+        with_critical(|| {
+            let state = unsafe { &mut *self.state.get() };
+            state.next[idx] = state.free;
+            state.free = idx as u16;
+        });
+    }
+}
+
+impl<T, const N: usize> Default for Pool<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A value in a block of a `Pool`, which is freed when the box is dropped
+pub struct PoolBox<'a, T, const N: usize> {
+    pub(crate) pool: &'a Pool<T, N>,
+    pub(crate) idx: usize,
+}
+
+unsafe impl<T: Send, const N: usize> Send for PoolBox<'_, T, N> {}
+unsafe impl<T: Sync, const N: usize> Sync for PoolBox<'_, T, N> {}
+
+impl<T, const N: usize> Deref for PoolBox<'_, T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.pool.block(self.idx) }
+    }
+}
+
+impl<T, const N: usize> DerefMut for PoolBox<'_, T, N> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.pool.block(self.idx) }
+    }
+}
+
+impl<T, const N: usize> Drop for PoolBox<'_, T, N> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.pool.block(self.idx)) };
+        self.pool.free(self.idx);
+    }
+}
@@ -0,0 +1,103 @@
+//! Post-mortem trace log in RAM, with the `trace-ram` feature.
+//!
+//! The kernel keeps the last `POSTMORTEM_EVENTS` trace events, encoded as in `trace`, in a
+//! circular buffer placed in the `.uninit` section, which the `cortex-m-rt` startup code
+//! neither zeroes nor initializes. The buffer survives a soft reset, e.g. by the watchdog, so
+//! after reboot the application can read what the threads were doing before a lockup with
+//! `postmortem_log`. A header word tells a log left by a previous run from random RAM
+//! contents after power-up.
+//!
+//! Recording goes on across resets, so the log holds the end of the previous run followed by
+//! the start of the current one until it is cleared with `postmortem_clear`; tick counts
+//! start from 0 again at each run.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+
+use crate::critical::CriticalSection;
+use crate::trace::{TraceEvent, RECORD_SIZE};
+
+/// Number of events kept
+pub const POSTMORTEM_EVENTS: usize = 64;
+
+/// Header of a valid log
+const MAGIC: u32 = 0x5452_414D;
+
+#[repr(C)]
+struct Log {
+    magic: u32,
+    /// record written next
+    next: u32,
+    /// records written, up to POSTMORTEM_EVENTS
+    count: u32,
+    records: [[u8; RECORD_SIZE]; POSTMORTEM_EVENTS],
+}
+
+struct Uninit(UnsafeCell<MaybeUninit<Log>>);
+
+// only accessed with interrupts disabled
+unsafe impl Sync for Uninit {}
+
+#[link_section = ".uninit.cortexm_threads.postmortem"]
+static LOG: Uninit = Uninit(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// The log, reset if it holds random contents
+///
+/// # Safety
+/// Interrupts must be disabled for as long as the reference is used
+unsafe fn log() -> &'static mut Log {
+    let log = &mut *((*LOG.0.get()).as_mut_ptr());
+    if log.magic != MAGIC
+        || log.next as usize >= POSTMORTEM_EVENTS
+        || log.count as usize > POSTMORTEM_EVENTS
+    {
+        log.magic = MAGIC;
+        log.next = 0;
+        log.count = 0;
+    }
+    log
+}
+
+/// Append `event` to the log, overwriting the oldest event when it is full. Called with
+/// interrupts disabled.
+pub(crate) fn record(event: &TraceEvent) {
+    let log = unsafe { log() };
+    log.records[log.next as usize] = event.encode();
+    log.next = (log.next + 1) % POSTMORTEM_EVENTS as u32;
+    if (log.count as usize) < POSTMORTEM_EVENTS {
+        log.count += 1;
+    }
+}
+
+/// Call `f` with each event of the log, oldest first, e.g. at startup to send the events
+/// recorded before a reset. Interrupts are disabled meanwhile, so `f` should only copy the
+/// events, and must not call the kernel.
+///
+/// # Example
+/// ```
+/// // before init, after a watchdog reset
+/// postmortem_log(|event| {
+///     let _ = uart.write_all(&event.encode());
+/// });
+/// postmortem_clear();
+/// ```
+pub fn postmortem_log(mut f: impl FnMut(&TraceEvent)) {
+    let _cs = CriticalSection::enter();
+    let log = unsafe { log() };
+    let count = log.count as usize;
+    let first = (log.next as usize + POSTMORTEM_EVENTS - count) % POSTMORTEM_EVENTS;
+    for i in 0..count {
+        // records damaged by the reset are skipped
+        if let Some(event) = TraceEvent::decode(&log.records[(first + i) % POSTMORTEM_EVENTS]) {
+            f(&event);
+        }
+    }
+}
+
+/// Empty the log
+pub fn postmortem_clear() {
+    let _cs = CriticalSection::enter();
+    let log = unsafe { log() };
+    log.next = 0;
+    log.count = 0;
+}
@@ -0,0 +1,165 @@
+//! CPU time budgets, to contain threads which run away.
+//!
+//! A thread given a budget may run for that many ticks in each period. The tick handler
+//! charges the tick to the running thread; once the budget is used up, the thread is demoted
+//! to a lower priority or suspended until its next period starts, and a supervisor callback
+//! is told. Time is measured in whole ticks, so a thread which runs between ticks without
+//! ever being running on a tick is not charged. A demoted thread holding a mutex with a
+//! ceiling runs at the ceiling until it unlocks it, as it would at its own priority.
+
+use crate::{
+    budget, record, with_state, Error, ThreadHandle, ThreadStatus, ThreadsState, TraceKind,
+};
+
+/// What the kernel does with a thread which used up its CPU budget
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BudgetAction {
+    /// lower its priority to the given one until its next period
+    Demote(u8),
+    /// stop running it until its next period, as if it slept
+    Suspend,
+}
+
+/// Budget of a thread, kept in its thread control block
+#[derive(Clone, Copy)]
+pub(crate) struct Quota {
+    budget: u32,
+    period: u32,
+    action: BudgetAction,
+    /// ticks charged in the current period
+    used: u32,
+    /// tick at which the current period started
+    period_start: u64,
+    /// budget of the current period used up
+    exhausted: bool,
+    /// priority to restore after a demotion
+    saved_priority: u8,
+}
+
+/// Let thread `thread` run for at most `budget_ticks` ticks in every period of
+/// `period_ticks` ticks, starting now. A thread going over its budget is handled according
+/// to `action` until its next period, and the supervisor set with `set_budget_supervisor` is
+/// called. A `budget_ticks` of 0 removes the budget. Note that a suspended thread keeps the
+/// locks it holds.
+///
+/// # Example
+/// ```
+/// // the third-party parser may use at most 20% of the CPU, measured over 100 ticks
+/// let parser = create_thread(STACK2.take().unwrap(), parser_main)?;
+/// set_cpu_budget(parser, 20, 100, BudgetAction::Suspend)?;
+/// ```
+pub fn set_cpu_budget(
+    thread: ThreadHandle,
+    budget_ticks: u32,
+    period_ticks: u32,
+    action: BudgetAction,
+) -> Result<(), Error> {
+    with_state(|s| {
+        let idx = thread.0;
+        if idx == 0 || idx >= s.threads.len() || s.threads[idx].status == ThreadStatus::Free {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("set cpu budget: no thread {}", idx);
+            return Err(Error::NoSuchThread);
+        }
+        restore(s, idx);
+        s.threads[idx].quota = if budget_ticks == 0 {
+            None
+        } else {
+            Some(Quota {
+                budget: budget_ticks,
+                period: core::cmp::max(period_ticks, budget_ticks),
+                action,
+                used: 0,
+                period_start: s.ticks,
+                exhausted: false,
+                saved_priority: s.threads[idx].base_priority,
+            })
+        };
+        Ok(())
+    })
+}
+
+/// Set a function called with the id of a thread which used up its CPU budget. It runs in
+/// the tick handler, with interrupts disabled.
+///
+/// # Example
+/// ```
+/// fn runaway(thread: ThreadHandle) {
+///     PARSER_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+/// }
+/// set_budget_supervisor(runaway);
+/// ```
+pub fn set_budget_supervisor(hook: fn(ThreadHandle)) {
+    with_state(|s| s.budget_supervisor = Some(hook));
+}
+
+/// Start new periods of threads whose period ended, and charge the current tick to the
+/// running thread
+pub(crate) fn charge(s: &mut ThreadsState) {
+    let now = s.ticks;
+    for idx in 1..s.threads.len() {
+        if let Some(quota) = s.threads[idx].quota {
+            if s.threads[idx].status != ThreadStatus::Free
+                && now - quota.period_start >= quota.period as u64
+            {
+                restore(s, idx);
+                if let Some(quota) = s.threads[idx].quota.as_mut() {
+                    quota.used = 0;
+                    quota.period_start = now - (now - quota.period_start) % quota.period as u64;
+                }
+            }
+        }
+    }
+    let idx = s.idx;
+    if idx == 0 || s.threads[idx].status != ThreadStatus::Idle {
+        return;
+    }
+    let quota = match s.threads[idx].quota.as_mut() {
+        Some(quota) if !quota.exhausted => quota,
+        _ => return,
+    };
+    quota.used += 1;
+    if quota.used < quota.budget {
+        return;
+    }
+    quota.exhausted = true;
+    let action = quota.action;
+    let release = quota.period_start + quota.period as u64;
+    #[cfg(feature = "defmt")]
+    defmt::trace!("thread {} used up its cpu budget", idx);
+    match action {
+        BudgetAction::Demote(priority) => {
+            // not below the ceiling of a mutex it holds
+            s.threads[idx].base_priority = priority;
+            let ceiling = s.threads[idx].ceiling;
+            s.set_ceiling(idx, ceiling);
+        }
+        BudgetAction::Suspend => {
+            s.threads[idx].wake_tick = release;
+            s.set_status(idx, ThreadStatus::Sleeping, now);
+            record(s, TraceKind::Sleep, idx, release as u32);
+        }
+    }
+    if let Some(hook) = s.budget_supervisor {
+        budget::run_callback(&s.callback_budget, "budget supervisor", || {
+            hook(ThreadHandle(idx))
+        });
+    }
+}
+
+/// Undo the demotion of thread `idx` if its budget was used up. A suspended thread is woken
+/// by its wake tick, which is the end of the period.
+fn restore(s: &mut ThreadsState, idx: usize) {
+    let quota = match s.threads[idx].quota.as_mut() {
+        Some(quota) if quota.exhausted => quota,
+        _ => return,
+    };
+    quota.exhausted = false;
+    if let BudgetAction::Demote(_) = quota.action {
+        let priority = quota.saved_priority;
+        s.threads[idx].base_priority = priority;
+        let ceiling = s.threads[idx].ceiling;
+        s.set_ceiling(idx, ceiling);
+    }
+}
@@ -0,0 +1,168 @@
+//! Host-side replay of scheduling traces.
+//!
+//! Feeds a log recorded with the `trace` feature through a model of the thread table and asks
+//! the same ready queue the kernel uses which thread should run at every recorded switch. A replay can run a whole log at once with `run`, or be stepped one event at a time
+//! with `step` to inspect the model around a timing bug captured in the field.
+//!
+//! Example:
+//! ```
+//! let log = std::fs::read("field.trace")?;
+//! let mut replay = Replay::new();
+//! match replay.run(&log) {
+//!     Ok(n) => println!("{} events replayed, all switches reproduced", n),
+//!     Err(d) => println!("event {}: recorded switch to {}, scheduler picks {}",
+//!         d.index, d.event.thread, d.expected),
+//! }
+//! ```
+
+use crate::sched;
+use crate::trace::{TraceEvent, TraceKind, RECORD_SIZE};
+use crate::MAX_THREADS;
+
+/// State of a thread in the replay model
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModelState {
+    Free,
+    Ready,
+    Sleeping,
+    Blocked,
+}
+
+/// Outcome of replaying a single event
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Step {
+    /// event updated the model
+    Applied,
+    /// recorded switch matches the scheduler's decision
+    Agreed,
+    /// recorded switch differs from the scheduler's decision, which is `expected`
+    Diverged { expected: usize },
+    /// event refers to a thread id outside the thread table
+    Invalid,
+}
+
+/// First point at which a replayed log disagrees with the scheduler
+#[derive(Clone, Copy, Debug)]
+pub struct Divergence {
+    /// index of the event in the log
+    pub index: usize,
+    pub event: TraceEvent,
+    /// thread the scheduler picks at this point, or the recorded thread for invalid events
+    pub expected: usize,
+}
+
+/// Model of the thread table driven by trace events
+pub struct Replay {
+    threads: [(ModelState, u8); MAX_THREADS],
+    ready: sched::ReadyQueue,
+    current: usize,
+    /// thread which yielded since the last switch, 0 if none
+    yielded: usize,
+    tick: u64,
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Replay {
+            threads: [(ModelState::Free, 0); MAX_THREADS],
+            ready: sched::ReadyQueue::new(),
+            current: 0,
+            yielded: 0,
+            tick: 0,
+        }
+    }
+
+    /// Apply one event to the model. For switch events, also check that the scheduler would
+    /// have picked the same thread.
+    pub fn step(&mut self, event: &TraceEvent) -> Step {
+        let idx = event.thread as usize;
+        if idx >= MAX_THREADS {
+            return Step::Invalid;
+        }
+        self.tick = event.tick;
+        let state = match event.kind {
+            TraceKind::Create | TraceKind::Wake => ModelState::Ready,
+            TraceKind::Exit => ModelState::Free,
+            TraceKind::Sleep => ModelState::Sleeping,
+            TraceKind::Block => ModelState::Blocked,
+            TraceKind::Yield => {
+                self.yielded = idx;
+                return Step::Applied;
+            }
+            TraceKind::Priority => self.threads[idx].0,
+            TraceKind::Switch => {
+                let expected = self.next_decision();
+                self.current = idx;
+                self.yielded = 0;
+                if expected != idx {
+                    return Step::Diverged { expected };
+                }
+                return Step::Agreed;
+            }
+        };
+        if idx > 0 {
+            self.ready.remove(idx, self.threads[idx].1);
+            if state == ModelState::Ready {
+                self.ready.insert(idx, event.priority);
+            }
+        }
+        self.threads[idx] = (state, event.priority);
+        Step::Applied
+    }
+
+    /// Replay a whole log of encoded records. Returns the number of events replayed, or the
+    /// first divergence. A trailing partial record is ignored.
+    pub fn run(&mut self, log: &[u8]) -> Result<usize, Divergence> {
+        let mut count = 0;
+        for (index, record) in log.chunks_exact(RECORD_SIZE).enumerate() {
+            let event = match TraceEvent::decode(record) {
+                Some(event) => event,
+                None => continue,
+            };
+            match self.step(&event) {
+                Step::Diverged { expected } => {
+                    return Err(Divergence {
+                        index,
+                        event,
+                        expected,
+                    })
+                }
+                Step::Invalid => {
+                    return Err(Divergence {
+                        index,
+                        event,
+                        expected: event.thread as usize,
+                    })
+                }
+                _ => count += 1,
+            }
+        }
+        Ok(count)
+    }
+
+    /// Thread the scheduler would pick given the current model
+    pub fn next_decision(&self) -> usize {
+        self.ready.select(self.yielded)
+    }
+
+    /// Thread running after the last replayed switch
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Tick of the last replayed event
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// State and priority of thread `thread_id` in the model
+    pub fn thread(&self, thread_id: usize) -> Option<(ModelState, u8)> {
+        self.threads.get(thread_id).copied()
+    }
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
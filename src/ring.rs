@@ -0,0 +1,231 @@
+//! Single producer, single consumer ring buffer for passing data out of interrupt handlers.
+//!
+//! A `RingBuffer` is split into a `Producer`, typically owned by an interrupt handler, and a
+//! `Consumer` owned by a thread. Pushing and popping only move an index each, without a
+//! critical section, so `push` is wait-free. The consumer can block until data arrives; only
+//! then does `push` enter the kernel to wake it.
+//!
+//! Unprivileged threads block and wake through kernel calls, see `svc`.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::critical::CriticalSection;
+use crate::select::sealed::Source;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, svc, wake_one, Error, WakeReason, NO_DEADLINE};
+
+/// Buffer of `N` slots holding up to `N - 1` items of type `T`
+///
+/// Example:
+/// ```
+/// static mut RX: RingBuffer<u8, 64> = RingBuffer::new();
+///
+/// let (mut producer, mut consumer) = unsafe { RX.split() };
+///
+/// // UART interrupt handler
+/// let _ = producer.push(uart.read_byte());
+///
+/// // thread
+/// loop {
+///     let byte = consumer.pop();
+///     parser.feed(byte);
+/// }
+/// ```
+pub struct RingBuffer<T, const N: usize> {
+    buf: UnsafeCell<MaybeUninit<[T; N]>>,
+    /// index of the next item to pop, only written by the consumer
+    head: AtomicUsize,
+    /// index of the next slot to push to, only written by the producer
+    tail: AtomicUsize,
+    /// the consumer is blocked, or about to block, waiting for data
+    waiting: AtomicBool,
+    consumer: UnsafeCell<WaitList>,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: UnsafeCell::new(MaybeUninit::uninit()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            waiting: AtomicBool::new(false),
+            consumer: UnsafeCell::new(WaitList::new(WaitOrder::Fifo)),
+        }
+    }
+
+    /// Split the buffer into its two ends
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        let ring: &Self = self;
+        (Producer { ring }, Consumer { ring })
+    }
+
+    fn slot(&self, idx: usize) -> *mut T {
+        unsafe { (*self.buf.get()).as_mut_ptr().cast::<T>().add(idx) }
+    }
+}
+
+impl<T, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for RingBuffer<T, N> {
+    fn drop(&mut self) {
+        let mut head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        while head != tail {
+            unsafe { ptr::drop_in_place(self.slot(head)) };
+            head = (head + 1) % N;
+        }
+    }
+}
+
+/// Pushing end of a `RingBuffer`, can be used from interrupt handlers
+pub struct Producer<'a, T, const N: usize> {
+    ring: &'a RingBuffer<T, N>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Producer<'_, T, N> {}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Append `value`, and wake the consumer if it is waiting for data. Gives `value` back if
+    /// the buffer is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let ring = self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % N;
+        if next == ring.head.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        unsafe { ptr::write(ring.slot(tail), value) };
+        ring.tail.store(next, Ordering::Release);
+        if ring.waiting.load(Ordering::Acquire) {
+            if svc::from_unprivileged_thread() {
+                svc::wake_list(ring.consumer.get() as usize, false);
+                return Ok(());
+            }
+            {
+                let mut cs = CriticalSection::enter();
+                wake_one(
+                    crate::state(&mut cs),
+                    unsafe { &mut *ring.consumer.get() },
+                    WakeReason::Signaled,
+                );
+            }
+            schedule();
+        }
+        Ok(())
+    }
+
+    /// Number of items which can still be pushed
+    pub fn free(&self) -> usize {
+        let ring = self.ring;
+        let used = (ring.tail.load(Ordering::Relaxed) + N - ring.head.load(Ordering::Acquire)) % N;
+        N - 1 - used
+    }
+}
+
+/// Popping end of a `RingBuffer`, owned by a thread
+pub struct Consumer<'a, T, const N: usize> {
+    ring: &'a RingBuffer<T, N>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Consumer<'_, T, N> {}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Take the oldest item, or None if the buffer is empty
+    pub fn try_pop(&mut self) -> Option<T> {
+        let ring = self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        if head == ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { ptr::read(ring.slot(head)) };
+        ring.head.store((head + 1) % N, Ordering::Release);
+        Some(value)
+    }
+
+    /// Take the oldest item, blocking until one is pushed
+    pub fn pop(&mut self) -> T {
+        match self.pop_until(NO_DEADLINE) {
+            Ok(value) => value,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `pop`, but gives up with Err(Error::TimedOut) if nothing was pushed within
+    /// `ticks` ticks
+    pub fn pop_timeout(&mut self, ticks: u32) -> Result<T, Error> {
+        self.pop_until(crate::ticks() + ticks as u64)
+    }
+
+    /// Number of items which can be popped
+    pub fn len(&self) -> usize {
+        let ring = self.ring;
+        (ring.tail.load(Ordering::Acquire) + N - ring.head.load(Ordering::Relaxed)) % N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn pop_until(&mut self, deadline: u64) -> Result<T, Error> {
+        loop {
+            if let Some(value) = self.try_pop() {
+                return Ok(value);
+            }
+            let ring = self.ring;
+            if svc::from_unprivileged_thread() {
+                // the kernel checks the tail again before blocking, so a push landing after
+                // this read wakes the thread or keeps it from blocking
+                ring.waiting.store(true, Ordering::Release);
+                let tail = ring.tail.load(Ordering::Acquire);
+                let reason = if tail == ring.head.load(Ordering::Relaxed) {
+                    let list = ring.consumer.get() as usize;
+                    svc::wait_word(list, &ring.tail as *const _ as usize, tail, deadline)
+                } else {
+                    WakeReason::Signaled
+                };
+                ring.waiting.store(false, Ordering::Release);
+                if reason == WakeReason::Timeout {
+                    return self.try_pop().ok_or(Error::TimedOut);
+                }
+                continue;
+            }
+            // interrupts are disabled from the check until the thread is blocked, so a push
+            // either lands before the check or sees `waiting` set
+            let cs = CriticalSection::enter();
+            ring.waiting.store(true, Ordering::Release);
+            let reason = if self.is_empty() {
+                unsafe { block_current(cs, ring.consumer.get(), deadline) }
+            } else {
+                drop(cs);
+                WakeReason::Signaled
+            };
+            ring.waiting.store(false, Ordering::Release);
+            if reason == WakeReason::Timeout {
+                return self.try_pop().ok_or(Error::TimedOut);
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Source for Consumer<'_, T, N> {
+    fn ready(&self) -> bool {
+        !self.is_empty()
+    }
+
+    fn wait_list(&self) -> *mut WaitList {
+        self.ring.consumer.get()
+    }
+
+    fn set_selected(&self, selected: bool) {
+        self.ring.waiting.store(selected, Ordering::Release);
+    }
+}
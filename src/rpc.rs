@@ -0,0 +1,214 @@
+//! Request/response calls between threads.
+//!
+//! An `Rpc` endpoint is served by one thread and called by any number of others. A call hands
+//! the request to the server and blocks the caller until the reply is ready, so request and
+//! reply never need hand-rolled flags or separate queues.
+
+use core::cell::UnsafeCell;
+
+use crate::critical::CriticalSection;
+use crate::select::sealed::Source;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, wake_one, Error, WakeReason, NO_DEADLINE};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// no call in progress
+    Idle,
+    /// request posted, server has not taken it yet
+    Requested,
+    /// server is processing the request
+    Serving,
+    /// reply is ready for the caller
+    Replied,
+}
+
+struct State<Req, Rep> {
+    phase: Phase,
+    request: Option<Req>,
+    reply: Option<Rep>,
+    /// caller gave up waiting while the server was processing its request
+    abandoned: bool,
+    /// callers waiting for the endpoint to become idle
+    callers: WaitList,
+    /// server waiting for a request
+    server: WaitList,
+    /// caller waiting for its reply
+    replied: WaitList,
+}
+
+/// A call endpoint taking requests of type `Req` and answering with `Rep`. The server and its
+/// clients must be privileged threads, see `svc`.
+///
+/// Example:
+/// ```
+/// static ADC: Rpc<u8, u16> = Rpc::new();
+///
+/// // server thread
+/// loop {
+///     ADC.serve(|channel| adc.read(channel));
+/// }
+///
+/// // client thread
+/// let value = ADC.call_timeout(3, 10)?;
+/// ```
+pub struct Rpc<Req, Rep> {
+    state: UnsafeCell<State<Req, Rep>>,
+}
+
+unsafe impl<Req: Send, Rep: Send> Sync for Rpc<Req, Rep> {}
+
+impl<Req, Rep> Rpc<Req, Rep> {
+    pub const fn new() -> Self {
+        Rpc {
+            state: UnsafeCell::new(State {
+                phase: Phase::Idle,
+                request: None,
+                reply: None,
+                abandoned: false,
+                callers: WaitList::new(WaitOrder::Priority),
+                server: WaitList::new(WaitOrder::Priority),
+                replied: WaitList::new(WaitOrder::Priority),
+            }),
+        }
+    }
+
+    /// Send `request` to the serving thread and block until it replies
+    pub fn call(&self, request: Req) -> Rep {
+        match self.call_until(request, NO_DEADLINE) {
+            Ok(reply) => reply,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `call`, but gives up with Err(Error::TimedOut) if no reply arrived within `ticks`
+    /// ticks. A reply produced after the timeout is dropped.
+    pub fn call_timeout(&self, request: Req, ticks: u32) -> Result<Rep, Error> {
+        self.call_until(request, crate::ticks() + ticks as u64)
+    }
+
+    /// Block until a request arrives, answer it with `f` and hand the reply to the caller
+    pub fn serve<F: FnOnce(Req) -> Rep>(&self, f: F) {
+        let _ = self.serve_until(f, NO_DEADLINE);
+    }
+
+    /// Like `serve`, but gives up with Err(Error::TimedOut) if no request arrived within
+    /// `ticks` ticks
+    pub fn serve_timeout<F: FnOnce(Req) -> Rep>(&self, f: F, ticks: u32) -> Result<(), Error> {
+        self.serve_until(f, crate::ticks() + ticks as u64)
+    }
+
+    fn call_until(&self, request: Req, deadline: u64) -> Result<Rep, Error> {
+        let state = self.state.get();
+        unsafe {
+            let mut cs = CriticalSection::enter();
+            while (*state).phase != Phase::Idle {
+                if block_current(cs, &mut (*state).callers, deadline) == WakeReason::Timeout {
+                    return Err(Error::TimedOut);
+                }
+                cs = CriticalSection::enter();
+            }
+            (*state).phase = Phase::Requested;
+            (*state).request = Some(request);
+            wake_one(
+                crate::state(&mut cs),
+                &mut (*state).server,
+                WakeReason::Signaled,
+            );
+            while (*state).phase != Phase::Replied {
+                if block_current(cs, &mut (*state).replied, deadline) == WakeReason::Timeout {
+                    cs = CriticalSection::enter();
+                    if (*state).phase == Phase::Replied {
+                        // reply arrived together with the timeout
+                        break;
+                    }
+                    if (*state).phase == Phase::Requested {
+                        // server never took the request
+                        (*state).request = None;
+                        (*state).phase = Phase::Idle;
+                        wake_one(
+                            crate::state(&mut cs),
+                            &mut (*state).callers,
+                            WakeReason::Signaled,
+                        );
+                    } else {
+                        (*state).abandoned = true;
+                    }
+                    return Err(Error::TimedOut);
+                }
+                cs = CriticalSection::enter();
+            }
+            let reply = (*state).reply.take();
+            (*state).phase = Phase::Idle;
+            wake_one(
+                crate::state(&mut cs),
+                &mut (*state).callers,
+                WakeReason::Signaled,
+            );
+            drop(cs);
+            schedule();
+            match reply {
+                Some(reply) => Ok(reply),
+                None => unreachable!(),
+            }
+        }
+    }
+
+    fn serve_until<F: FnOnce(Req) -> Rep>(&self, f: F, deadline: u64) -> Result<(), Error> {
+        let state = self.state.get();
+        let request = unsafe {
+            let mut cs = CriticalSection::enter();
+            while (*state).phase != Phase::Requested {
+                if block_current(cs, &mut (*state).server, deadline) == WakeReason::Timeout {
+                    return Err(Error::TimedOut);
+                }
+                cs = CriticalSection::enter();
+            }
+            (*state).phase = Phase::Serving;
+            (*state).request.take()
+        };
+        let reply = match request {
+            Some(request) => f(request),
+            None => unreachable!(),
+        };
+        unsafe {
+            let mut cs = CriticalSection::enter();
+            if (*state).abandoned {
+                (*state).abandoned = false;
+                (*state).phase = Phase::Idle;
+                wake_one(
+                    crate::state(&mut cs),
+                    &mut (*state).callers,
+                    WakeReason::Signaled,
+                );
+            } else {
+                (*state).reply = Some(reply);
+                (*state).phase = Phase::Replied;
+                wake_one(
+                    crate::state(&mut cs),
+                    &mut (*state).replied,
+                    WakeReason::Signaled,
+                );
+            }
+        }
+        schedule();
+        Ok(())
+    }
+}
+
+/// Ready for `select` when a request is waiting to be served
+impl<Req, Rep> Source for Rpc<Req, Rep> {
+    fn ready(&self) -> bool {
+        unsafe { (*self.state.get()).phase == Phase::Requested }
+    }
+
+    fn wait_list(&self) -> *mut WaitList {
+        unsafe { &mut (*self.state.get()).server }
+    }
+}
+
+impl<Req, Rep> Default for Rpc<Req, Rep> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,167 @@
+//! Minimal SEGGER RTT control block for the `trace-systemview` feature.
+//!
+//! The debug probe finds `_SEGGER_RTT` in RAM by its id string and then reads up buffers and
+//! writes down buffers in the background while the target runs. Channel 0 is the usual
+//! terminal and is left unused; channel 1 carries SystemView data. Applications using another
+//! RTT implementation cannot enable this feature, as both would define `_SEGGER_RTT`.
+//!
+//! The control block and buffers are shared with the probe, so they are only accessed through
+//! raw pointers. On the target side, they are only used by the SystemView recorder while it
+//! holds the kernel state, which serializes all accesses.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+
+/// Channel SystemView expects its data on
+pub(crate) const SYSVIEW_CHANNEL: usize = 1;
+
+const CHANNELS: usize = 2;
+const TERMINAL_SIZE: usize = 16;
+const SYSVIEW_UP_SIZE: usize = 1024;
+const SYSVIEW_DOWN_SIZE: usize = 16;
+
+#[repr(C)]
+struct Buffer {
+    name: *const u8,
+    buf: *mut u8,
+    size: u32,
+    /// offset the next byte is written at, owned by the writer
+    write: u32,
+    /// offset the next byte is read from, owned by the reader
+    read: u32,
+    /// 0: skip data which does not fit
+    flags: u32,
+}
+
+#[repr(C)]
+struct ControlBlock {
+    id: [u8; 16],
+    max_up: u32,
+    max_down: u32,
+    up: [Buffer; CHANNELS],
+    down: [Buffer; CHANNELS],
+}
+
+const EMPTY: Buffer = Buffer {
+    name: ptr::null(),
+    buf: ptr::null_mut(),
+    size: 0,
+    write: 0,
+    read: 0,
+    flags: 0,
+};
+
+/// Memory shared with the probe
+#[repr(transparent)]
+struct ProbeShared<T>(UnsafeCell<T>);
+
+unsafe impl<T> Sync for ProbeShared<T> {}
+
+impl<T> ProbeShared<T> {
+    fn get(&self) -> *mut T {
+        self.0.get()
+    }
+}
+
+#[no_mangle]
+static _SEGGER_RTT: ProbeShared<ControlBlock> = ProbeShared(UnsafeCell::new(ControlBlock {
+    id: [0; 16],
+    max_up: CHANNELS as u32,
+    max_down: CHANNELS as u32,
+    up: [EMPTY, EMPTY],
+    down: [EMPTY, EMPTY],
+}));
+
+static TERMINAL_UP: ProbeShared<[u8; TERMINAL_SIZE]> =
+    ProbeShared(UnsafeCell::new([0; TERMINAL_SIZE]));
+static TERMINAL_DOWN: ProbeShared<[u8; TERMINAL_SIZE]> =
+    ProbeShared(UnsafeCell::new([0; TERMINAL_SIZE]));
+static SYSVIEW_UP: ProbeShared<[u8; SYSVIEW_UP_SIZE]> =
+    ProbeShared(UnsafeCell::new([0; SYSVIEW_UP_SIZE]));
+static SYSVIEW_DOWN: ProbeShared<[u8; SYSVIEW_DOWN_SIZE]> =
+    ProbeShared(UnsafeCell::new([0; SYSVIEW_DOWN_SIZE]));
+
+/// Set up the control block. The id is written last, so the probe never finds a partially
+/// initialized block.
+pub(crate) fn init() {
+    unsafe {
+        let cb = _SEGGER_RTT.get();
+        if ptr::read_volatile(&(*cb).id[0]) != 0 {
+            return;
+        }
+        (*cb).up[0] = Buffer {
+            name: b"Terminal\0".as_ptr(),
+            buf: TERMINAL_UP.get() as *mut u8,
+            size: TERMINAL_SIZE as u32,
+            ..EMPTY
+        };
+        (*cb).down[0] = Buffer {
+            name: b"Terminal\0".as_ptr(),
+            buf: TERMINAL_DOWN.get() as *mut u8,
+            size: TERMINAL_SIZE as u32,
+            ..EMPTY
+        };
+        (*cb).up[SYSVIEW_CHANNEL] = Buffer {
+            name: b"SysView\0".as_ptr(),
+            buf: SYSVIEW_UP.get() as *mut u8,
+            size: SYSVIEW_UP_SIZE as u32,
+            ..EMPTY
+        };
+        (*cb).down[SYSVIEW_CHANNEL] = Buffer {
+            name: b"SysView\0".as_ptr(),
+            buf: SYSVIEW_DOWN.get() as *mut u8,
+            size: SYSVIEW_DOWN_SIZE as u32,
+            ..EMPTY
+        };
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+        let id = b"SEGGER RTT";
+        for (i, &b) in id.iter().enumerate() {
+            ptr::write_volatile(&mut (*cb).id[i], b);
+        }
+    }
+}
+
+/// Write `data` to up channel `channel` if all of it fits, otherwise write nothing. Returns
+/// false if the data was skipped.
+pub(crate) fn write(channel: usize, data: &[u8]) -> bool {
+    unsafe {
+        let up = &mut (*_SEGGER_RTT.get()).up[channel];
+        let size = up.size;
+        let read = ptr::read_volatile(&up.read);
+        let mut write = up.write;
+        let free = if read > write {
+            read - write - 1
+        } else {
+            size - write + read - 1
+        };
+        if data.len() as u32 > free {
+            return false;
+        }
+        for &b in data {
+            ptr::write_volatile(up.buf.add(write as usize), b);
+            write += 1;
+            if write == size {
+                write = 0;
+            }
+        }
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+        ptr::write_volatile(&mut up.write, write);
+        true
+    }
+}
+
+/// Next byte sent by the host on down channel `channel`, if any
+pub(crate) fn read_byte(channel: usize) -> Option<u8> {
+    unsafe {
+        let down = &mut (*_SEGGER_RTT.get()).down[channel];
+        let write = ptr::read_volatile(&down.write);
+        let read = down.read;
+        if read == write {
+            return None;
+        }
+        let b = ptr::read_volatile(down.buf.add(read as usize));
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+        ptr::write_volatile(&mut down.read, (read + 1) % down.size);
+        Some(b)
+    }
+}
@@ -0,0 +1,320 @@
+//! Reader-writer lock between threads.
+//!
+//! An `RwLock` lets any number of threads read the data at the same time, or one thread
+//! write it. Threads which cannot take the lock block until it is released. Whether waiting
+//! writers hold off new readers is chosen when the lock is created.
+//!
+//! Unprivileged threads lock and unlock through kernel calls, see `svc`.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use crate::critical::CriticalSection;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{
+    block_current, block_enter, schedule, svc, wake_all, wake_one, Error, ThreadsState, WakeReason,
+    NO_DEADLINE,
+};
+
+/// Which side of an `RwLock` goes first when both are waiting
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RwPreference {
+    /// new readers wait while a writer is waiting, so writers are never starved
+    Writers,
+    /// readers share the lock whenever no writer holds it, for the highest read throughput
+    Readers,
+}
+
+struct State {
+    prefer: RwPreference,
+    /// number of threads holding the lock for reading
+    readers: u32,
+    /// a thread holds the lock for writing
+    writer: bool,
+    /// number of threads waiting to write
+    waiting_writers: u32,
+    read_waiters: WaitList,
+    write_waiters: WaitList,
+}
+
+impl State {
+    fn can_read(&self) -> bool {
+        let writer_waiting = self.prefer == RwPreference::Writers && self.waiting_writers > 0;
+        !(self.writer || writer_waiting)
+    }
+
+    fn can_write(&self) -> bool {
+        !self.writer && self.readers == 0
+    }
+
+    /// A waiting writer gave up: readers it held off may go ahead
+    fn give_up_write(&mut self, handler: &mut ThreadsState) {
+        self.waiting_writers = self.waiting_writers.saturating_sub(1);
+        if self.can_read() {
+            wake_all(handler, &mut self.read_waiters, WakeReason::Signaled);
+        }
+    }
+
+    /// Drop a read lock, waking a writer once the last reader is gone
+    fn release_read(&mut self, handler: &mut ThreadsState) {
+        self.readers = self.readers.saturating_sub(1);
+        if self.readers == 0 {
+            wake_one(handler, &mut self.write_waiters, WakeReason::Signaled);
+        }
+    }
+
+    /// Drop the write lock, waking the readers, or a writer if one goes first or no reader
+    /// waits
+    fn release_write(&mut self, handler: &mut ThreadsState) {
+        self.writer = false;
+        let writer_first = self.prefer == RwPreference::Writers && self.waiting_writers > 0;
+        if writer_first || wake_all(handler, &mut self.read_waiters, WakeReason::Signaled) == 0 {
+            wake_one(handler, &mut self.write_waiters, WakeReason::Signaled);
+        }
+    }
+}
+
+/// Data shared between threads, readable by many at once or writable by one
+///
+/// Example:
+/// ```
+/// static ATTITUDE: RwLock<Quaternion> = RwLock::new(Quaternion::IDENTITY);
+///
+/// // fusion thread
+/// *ATTITUDE.write() = fuse(gyro, accel);
+///
+/// // consumer threads
+/// let q = *ATTITUDE.read();
+/// ```
+// the state comes first, so that the address of a lock is the address of its state
+#[repr(C)]
+pub struct RwLock<T> {
+    state: UnsafeCell<State>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Create a lock holding `value`, preferring writers
+    pub const fn new(value: T) -> Self {
+        Self::with_preference(value, RwPreference::Writers)
+    }
+
+    /// Create a lock holding `value`, preferring `prefer` when readers and writers wait
+    pub const fn with_preference(value: T, prefer: RwPreference) -> Self {
+        RwLock {
+            state: UnsafeCell::new(State {
+                prefer,
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+                read_waiters: WaitList::new(WaitOrder::Priority),
+                write_waiters: WaitList::new(WaitOrder::Priority),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Block until the data can be read, and return a guard giving shared access to it
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        match self.read_until(NO_DEADLINE) {
+            Ok(guard) => guard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `read`, but gives up with Err(Error::TimedOut) if the data could not be read
+    /// within `ticks` ticks
+    pub fn read_timeout(&self, ticks: u32) -> Result<RwLockReadGuard<'_, T>, Error> {
+        self.read_until(crate::ticks() + ticks as u64)
+    }
+
+    /// Block until no other thread reads or writes the data, and return a guard giving
+    /// exclusive access to it
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        match self.write_until(NO_DEADLINE) {
+            Ok(guard) => guard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `write`, but gives up with Err(Error::TimedOut) if the data could not be written
+    /// within `ticks` ticks
+    pub fn write_timeout(&self, ticks: u32) -> Result<RwLockWriteGuard<'_, T>, Error> {
+        self.write_until(crate::ticks() + ticks as u64)
+    }
+
+    fn read_until(&self, deadline: u64) -> Result<RwLockReadGuard<'_, T>, Error> {
+        if svc::from_unprivileged_thread() {
+            svc::lock_rwlock(self as *const Self as usize, false, deadline)?;
+            return Ok(RwLockReadGuard { lock: self });
+        }
+        let state = self.state.get();
+        unsafe {
+            let mut cs = CriticalSection::enter();
+            while !(*state).can_read() {
+                if block_current(cs, &mut (*state).read_waiters, deadline) == WakeReason::Timeout {
+                    return Err(Error::TimedOut);
+                }
+                cs = CriticalSection::enter();
+            }
+            (*state).readers += 1;
+        }
+        Ok(RwLockReadGuard { lock: self })
+    }
+
+    fn write_until(&self, deadline: u64) -> Result<RwLockWriteGuard<'_, T>, Error> {
+        if svc::from_unprivileged_thread() {
+            svc::lock_rwlock(self as *const Self as usize, true, deadline)?;
+            return Ok(RwLockWriteGuard { lock: self });
+        }
+        let state = self.state.get();
+        unsafe {
+            let mut cs = CriticalSection::enter();
+            (*state).waiting_writers += 1;
+            while !(*state).can_write() {
+                if block_current(cs, &mut (*state).write_waiters, deadline) == WakeReason::Timeout {
+                    cs = CriticalSection::enter();
+                    (*state).give_up_write(crate::state(&mut cs));
+                    drop(cs);
+                    schedule();
+                    return Err(Error::TimedOut);
+                }
+                cs = CriticalSection::enter();
+            }
+            (*state).waiting_writers -= 1;
+            (*state).writer = true;
+        }
+        Ok(RwLockWriteGuard { lock: self })
+    }
+
+    fn release(&self, write: bool) {
+        if svc::from_unprivileged_thread() {
+            return svc::unlock_rwlock(self as *const Self as usize, write);
+        }
+        {
+            let mut cs = CriticalSection::enter();
+            let state = unsafe { &mut *self.state.get() };
+            if write {
+                state.release_write(crate::state(&mut cs));
+            } else {
+                state.release_read(crate::state(&mut cs));
+            }
+        }
+        schedule();
+    }
+}
+
+/// Size of the state at the start of a lock, which a kernel call may write
+pub(crate) const STATE_SIZE: usize = core::mem::size_of::<State>();
+
+/// Kernel part of `read_until` or `write_until` for the lock at address `lock`, run by a
+/// kernel call: lock it and return Ok(true), or block the current thread on it until
+/// `deadline` and return Ok(false). `retry` is set when the thread runs again after blocking.
+///
+/// # Safety
+/// `lock` must be the address of a live `RwLock`
+pub(crate) unsafe fn lock_or_block(
+    handler: &mut ThreadsState,
+    lock: usize,
+    write: bool,
+    deadline: u64,
+    retry: bool,
+) -> Result<bool, Error> {
+    let state = &mut *(*(lock as *const RwLock<()>)).state.get();
+    let idx = handler.idx;
+    if retry {
+        // leave the wait list in case it was not the waker that removed us
+        state.read_waiters.remove(idx);
+        state.write_waiters.remove(idx);
+        if handler.threads[idx].wake_reason == WakeReason::Timeout {
+            if write {
+                state.give_up_write(handler);
+            }
+            return Err(Error::TimedOut);
+        }
+    } else if write {
+        state.waiting_writers += 1;
+    }
+    if write && state.can_write() {
+        state.waiting_writers -= 1;
+        state.writer = true;
+        return Ok(true);
+    }
+    if !write && state.can_read() {
+        state.readers += 1;
+        return Ok(true);
+    }
+    let waiters = if write {
+        &mut state.write_waiters
+    } else {
+        &mut state.read_waiters
+    };
+    match block_enter(handler, deadline, |_, idx| waiters.insert(idx)) {
+        None => Ok(false),
+        Some(_) => {
+            if write {
+                state.give_up_write(handler);
+            }
+            Err(Error::TimedOut)
+        }
+    }
+}
+
+/// Unlock the lock at address `lock` for a kernel call, held for writing if `write` is set
+///
+/// # Safety
+/// `lock` must be the address of a live `RwLock`
+pub(crate) unsafe fn unlock(handler: &mut ThreadsState, lock: usize, write: bool) {
+    let state = &mut *(*(lock as *const RwLock<()>)).state.get();
+    if write && state.writer {
+        state.release_write(handler);
+    } else if !write && state.readers > 0 {
+        state.release_read(handler);
+    }
+}
+
+/// Shared access to the data of an `RwLock`, released when dropped
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(false);
+    }
+}
+
+/// Exclusive access to the data of an `RwLock`, released when dropped
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(true);
+    }
+}
@@ -0,0 +1,194 @@
+//! Scheduling decisions, independent of the hardware.
+//!
+//! The kernel and the host-side `replay` module both keep ready threads in a `ReadyQueue` and
+//! pick the next thread with its `select`, so a replayed trace reproduces exactly the
+//! decisions made on the target.
+//!
+//! A ready queue holds a mask of the ready threads, plus a table of the priorities with a
+//! ready thread, sorted by priority, each with the mask of its ready threads. Picking a
+//! thread reads the mask of the last entry, the highest priority, and takes its most
+//! significant bit, so it costs the same whatever the number of threads. The table changes
+//! shape only when a priority gains its first ready thread or loses its last, which shifts
+//! the entries above it. No more priorities than threads can be ready, so the table has one
+//! entry per thread, 5 bytes each, where a mask per priority would take 1 KiB.
+//!
+//! This fixed-priority choice is the default policy. An application can install another one,
+//! e.g. round-robin or earliest deadline first, by implementing `Scheduler`; `replay` only
+//! reproduces the default policy.
+
+use crate::{with_state, ThreadControlBlock, ThreadHandle, MAX_THREADS};
+
+/// Ready threads by priority
+pub(crate) struct ReadyQueue {
+    /// bit i set when thread i is ready
+    all: u32,
+    /// number of priorities with a ready thread, the used entries of the tables below
+    len: usize,
+    /// priorities with a ready thread, by increasing priority
+    priorities: [u8; MAX_THREADS],
+    /// bit i of entry k set when thread i is ready, at priority `priorities[k]`
+    masks: [u32; MAX_THREADS],
+}
+
+// a thread mask holds all thread ids
+const _: () = assert!(MAX_THREADS <= 32);
+
+impl ReadyQueue {
+    pub(crate) const fn new() -> Self {
+        ReadyQueue {
+            all: 0,
+            len: 0,
+            priorities: [0; MAX_THREADS],
+            masks: [0; MAX_THREADS],
+        }
+    }
+
+    /// Mark thread `idx` of priority `priority` ready
+    pub(crate) fn insert(&mut self, idx: usize, priority: u8) {
+        self.all |= 1 << idx;
+        let k = match self.priorities[..self.len].binary_search(&priority) {
+            Ok(k) => k,
+            Err(k) => {
+                // a ready thread has a slot of its own, so there is room
+                self.priorities.copy_within(k..self.len, k + 1);
+                self.masks.copy_within(k..self.len, k + 1);
+                self.priorities[k] = priority;
+                self.masks[k] = 0;
+                self.len += 1;
+                k
+            }
+        };
+        self.masks[k] |= 1 << idx;
+    }
+
+    /// Mark thread `idx` of priority `priority` not ready. Does nothing if it was not ready.
+    pub(crate) fn remove(&mut self, idx: usize, priority: u8) {
+        self.all &= !(1 << idx);
+        if let Ok(k) = self.priorities[..self.len].binary_search(&priority) {
+            self.masks[k] &= !(1 << idx);
+            if self.masks[k] == 0 {
+                self.priorities.copy_within(k + 1..self.len, k);
+                self.masks.copy_within(k + 1..self.len, k);
+                self.len -= 1;
+            }
+        }
+    }
+
+    /// Pick the thread to run: the highest priority wins, and among equal priorities any
+    /// thread but `yielded`, then the one with the highest id. Returns 0, the idle thread, if
+    /// no thread is ready. Pass 0 as `yielded` if no thread yielded.
+    pub(crate) fn select(&self, yielded: usize) -> usize {
+        if self.len == 0 {
+            return 0;
+        }
+        let mut ready = self.masks[self.len - 1];
+        if yielded > 0 && ready & !(1 << yielded) != 0 {
+            ready &= !(1 << yielded);
+        }
+        msb(ready)
+    }
+}
+
+/// Policy picking the thread to run, see `set_scheduler`
+pub trait Scheduler: Sync {
+    /// Pick the thread to run among `ready`, or None to run the idle thread. Called with
+    /// interrupts disabled each time the kernel reschedules: on every tick, and when a thread
+    /// is created, blocks, sleeps, yields, exits or is woken. A thread which is not ready is
+    /// ignored, and the default fixed-priority choice made instead.
+    fn pick(&self, ready: &ReadyThreads) -> Option<ThreadHandle>;
+}
+
+/// Threads ready to run, offered to a `Scheduler`
+pub struct ReadyThreads<'a> {
+    pub(crate) queue: &'a ReadyQueue,
+    pub(crate) threads: &'a [ThreadControlBlock],
+    pub(crate) current: usize,
+    pub(crate) yielded: usize,
+    pub(crate) tick: u64,
+}
+
+impl ReadyThreads<'_> {
+    /// Ready threads and their priority, by increasing thread id. The idle thread is never
+    /// listed, the running thread is if it is still ready.
+    pub fn iter(&self) -> impl Iterator<Item = (ThreadHandle, u8)> + '_ {
+        (1..self.threads.len())
+            .filter(move |&idx| self.queue.all & (1 << idx) != 0)
+            .map(move |idx| (ThreadHandle(idx), self.threads[idx].priority))
+    }
+
+    /// Whether thread `thread` is ready to run
+    pub fn contains(&self, thread: ThreadHandle) -> bool {
+        thread.0 > 0 && thread.0 < self.threads.len() && self.queue.all & (1 << thread.0) != 0
+    }
+
+    /// Thread which ran last, which is not ready if it just blocked, slept or exited
+    pub fn current(&self) -> ThreadHandle {
+        ThreadHandle(self.current)
+    }
+
+    /// Thread which called `yield_now` since the last switch, if any
+    pub fn yielded(&self) -> Option<ThreadHandle> {
+        if self.yielded > 0 {
+            Some(ThreadHandle(self.yielded))
+        } else {
+            None
+        }
+    }
+
+    /// Current tick count
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Choice of the default fixed-priority policy
+    pub fn default_pick(&self) -> Option<ThreadHandle> {
+        match self.queue.select(self.yielded) {
+            0 => None,
+            idx => Some(ThreadHandle(idx)),
+        }
+    }
+}
+
+/// Install `policy` to pick the thread to run instead of the default fixed-priority one.
+/// Wait lists still wake threads by priority or in arrival order.
+///
+/// # Example
+/// ```
+/// // round-robin between ready threads, whatever their priority
+/// struct RoundRobin;
+///
+/// impl Scheduler for RoundRobin {
+///     fn pick(&self, ready: &ReadyThreads) -> Option<ThreadHandle> {
+///         // the next ready thread by id after the one which ran last, wrapping around
+///         let current = ready.current().id();
+///         let next = ready.iter().find(|(thread, _)| thread.id() > current);
+///         next.or_else(|| ready.iter().next()).map(|(thread, _)| thread)
+///     }
+/// }
+///
+/// static ROUND_ROBIN: RoundRobin = RoundRobin;
+/// set_scheduler(&ROUND_ROBIN);
+/// ```
+pub fn set_scheduler(policy: &'static dyn Scheduler) {
+    with_state(|s| s.scheduler = Some(policy));
+}
+
+/// Index of the most significant set bit of non-zero `x`, with CLZ
+#[cfg(not(armv6m))]
+fn msb(x: u32) -> usize {
+    31 - x.leading_zeros() as usize
+}
+
+/// Index of the most significant set bit of non-zero `x`. ARMv6-M has no CLZ, so it is
+/// found by halving the search range.
+#[cfg(armv6m)]
+fn msb(mut x: u32) -> usize {
+    let mut n = 0;
+    for shift in [16, 8, 4, 2, 1].iter() {
+        if x >> shift != 0 {
+            x >>= shift;
+            n += shift;
+        }
+    }
+    n
+}
@@ -0,0 +1,90 @@
+//! Waiting on several kernel objects at once.
+//!
+//! `select` blocks a thread until any of a set of objects is ready, and returns which one, so
+//! a single thread can serve several inputs. The thread sits on the wait list of every object
+//! while it is blocked, and whichever becomes ready first wakes it.
+//!
+//! Objects which can be waited on implement `Select`: the `Consumer` of a `RingBuffer` and the
+//! `BlockingConsumer` of a heapless queue are ready when they hold data, the `StreamReader`
+//! of a `StreamBuffer` when its trigger level is reached, a `Mailbox` when a value was
+//! posted, a `BufQueue` when a buffer was sent, a `Broadcast` `Subscriber` when a value was
+//! published, and an `Rpc` when a request is waiting to be served.
+
+use crate::critical::CriticalSection;
+use crate::{block_with, Error, WakeReason, NO_DEADLINE};
+
+pub(crate) mod sealed {
+    use crate::wait::WaitList;
+
+    pub trait Source {
+        /// The object can be taken without blocking. Called inside a critical section.
+        fn ready(&self) -> bool;
+
+        /// List the selecting thread waits on
+        fn wait_list(&self) -> *mut WaitList;
+
+        /// Called when a selecting thread starts and stops waiting on the object
+        fn set_selected(&self, _selected: bool) {}
+    }
+}
+
+/// An object `select` can wait on. Implemented by the crate's kernel objects only.
+pub trait Select: sealed::Source {}
+
+impl<T: sealed::Source> Select for T {}
+
+/// Block until one of `sources` is ready, and return its index. If several are ready, the
+/// first of them is returned. The ready object is not taken, the caller does that next, e.g.
+/// with `try_pop` or `serve`. Only privileged threads can select, see `svc`.
+///
+/// # Example
+/// ```
+/// loop {
+///     match select(&[&uart_rx, &CONFIG_RPC]) {
+///         0 => handle_byte(uart_rx.try_pop().unwrap()),
+///         _ => CONFIG_RPC.serve(apply_config),
+///     }
+/// }
+/// ```
+pub fn select(sources: &[&dyn Select]) -> usize {
+    match select_until(sources, NO_DEADLINE) {
+        Ok(idx) => idx,
+        Err(_) => unreachable!(),
+    }
+}
+
+/// Like `select`, but gives up with Err(Error::TimedOut) if none of `sources` became ready
+/// within `ticks` ticks
+pub fn select_timeout(sources: &[&dyn Select], ticks: u32) -> Result<usize, Error> {
+    select_until(sources, crate::ticks() + ticks as u64)
+}
+
+fn select_until(sources: &[&dyn Select], deadline: u64) -> Result<usize, Error> {
+    let ready = || sources.iter().position(|source| source.ready());
+    loop {
+        let cs = CriticalSection::enter();
+        if let Some(idx) = ready() {
+            return Ok(idx);
+        }
+        let reason = block_with(
+            cs,
+            deadline,
+            |_, thread| {
+                for source in sources {
+                    source.set_selected(true);
+                    unsafe { (*source.wait_list()).insert(thread) };
+                }
+            },
+            |_, thread| {
+                for source in sources {
+                    unsafe { (*source.wait_list()).remove(thread) };
+                    source.set_selected(false);
+                }
+            },
+        );
+        if reason == WakeReason::Timeout {
+            let _cs = CriticalSection::enter();
+            return ready().ok_or(Error::TimedOut);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! Host simulator, to unit test application threads with `cargo test` on the PC.
+//!
+//! With the `sim` feature the crate builds for the host with `std`, and runs the kernel
+//! itself: scheduling, sleeps, timeouts, mutexes, queues and every other primitive behave as
+//! on the target. Only the processor is simulated, by the `Host` port of `arch`. Each kernel
+//! thread runs on a host thread, and exactly one of them holds the simulated processor at a
+//! time: a thread switch hands it over where PendSV would switch stacks, and waits on the
+//! host until it comes back.
+//!
+//! The processor is infinitely fast. Time passes only while every thread waits, when the
+//! test thread, which plays the idle thread, calls `SysTick()` for the next tick, so runs are
+//! deterministic and a sleep of an hour takes no wall time. A thread busy-waiting for the tick
+//! count to change never gives time a chance to pass: threads must sleep or block.
+//! `delay_us` returns at once, and stack usage is not measured, as threads run on the stacks
+//! of their host threads.
+//!
+//! Unprivileged threads reach the kernel through kernel calls, run as the SVCall handler
+//! would, and panic on kernel objects which have none, as on the target. Unlike on the
+//! target, they cannot create threads, whose requests are not on their stack area.
+//!
+//! A `Simulator` holds the kernel for one test, starting with an empty thread table; tests
+//! using it run one after the other. Threads are created as in the application, before the
+//! first `run_for` or later from the test thread, which may also send to queues, lock mutexes
+//! and call other functions which do not block, as the idle thread would. A panic in a thread,
+//! e.g. a failed assertion, stops the simulation and fails the test.
+//!
+//! Example:
+//! ```
+//! // test of the blinker thread, run with `cargo test --features sim`
+//! let mut sim = Simulator::new();
+//! create_thread(sim::stack(256), blinker).unwrap();
+//! assert_eq!(sim.run_for(2_000), Stop::TimeUp);
+//! assert_eq!(LED_TOGGLES.load(Ordering::Relaxed), 4);
+//! ```
+
+use std::any::Any;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use crate::arch::Arch;
+use crate::tick::TickSource;
+use crate::{
+    create_tcb, insert_tcb, kernel, schedule, set_tick_hz, svc, with_state, SysTick,
+    ThreadControlBlock, ThreadStatus, ThreadsState, IDLE_STACK_WORDS, INITIAL_STATE, MAX_THREADS,
+    STACK_FILL,
+};
+
+/// `sp` of a thread running on a host thread, never the end of a stack area
+const ON_HOST: u32 = 1;
+
+/// Held by the `Simulator` of the running test
+static SIMULATOR: Mutex<()> = Mutex::new(());
+/// Simulated PRIMASK
+static PRIMASK: AtomicU32 = AtomicU32::new(0);
+/// Simulated PendSV pending bit
+static PENDSV: AtomicBool = AtomicBool::new(false);
+static CPU: Mutex<Cpu> = Mutex::new(Cpu {
+    running: (0, 0),
+    generations: [0; MAX_THREADS],
+    failure: None,
+});
+/// Signalled when the processor is handed over
+static TURN: Condvar = Condvar::new();
+
+thread_local! {
+    /// Slot and generation of the kernel thread run by this host thread
+    static ME: Cell<(usize, u64)> = const { Cell::new((0, 0)) };
+    /// Set while this host thread runs a kernel call, in simulated handler mode
+    static IN_CALL: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The simulated processor
+struct Cpu {
+    /// slot and generation of the host thread allowed to run
+    running: (usize, u64),
+    /// generation of the host thread of each slot. It changes when a new thread starts in
+    /// the slot, or the simulation ends, and host threads of older generations never run
+    /// again.
+    generations: [u64; MAX_THREADS],
+    /// payload of the panic which stopped the simulation
+    failure: Option<Box<dyn Any + Send>>,
+}
+
+fn cpu() -> MutexGuard<'static, Cpu> {
+    CPU.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Why `Simulator::run_for` returned
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stop {
+    /// the ticks to run for passed
+    TimeUp,
+    /// all threads exited
+    Exited,
+    /// all threads wait without a timeout, for something only the test can do
+    Stalled,
+}
+
+/// The kernel, running application threads on the host for one test
+pub struct Simulator {
+    _lock: MutexGuard<'static, ()>,
+    /// stack of the idle thread, whose frame is never used
+    _idle_stack: Box<[u32]>,
+    started: bool,
+    // the test thread plays the idle thread
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Simulator {
+    /// Wait for simulations of other tests to end, and reset the kernel to the state it has
+    /// before `init`. The calling thread becomes the idle thread.
+    pub fn new() -> Self {
+        let lock = SIMULATOR.lock().unwrap_or_else(PoisonError::into_inner);
+        PRIMASK.store(0, Ordering::SeqCst);
+        PENDSV.store(false, Ordering::SeqCst);
+        {
+            let mut cpu = cpu();
+            cpu.generations[0] += 1;
+            cpu.running = (0, cpu.generations[0]);
+            cpu.failure = None;
+            ME.with(|me| me.set(cpu.running));
+        }
+        let mut idle_stack = vec![STACK_FILL; IDLE_STACK_WORDS].into_boxed_slice();
+        let mut tcb = match create_tcb(&mut idle_stack, 0, 0, 0, 0xff, true) {
+            Ok(tcb) => tcb,
+            Err(_) => panic!("Could not create idle thread"),
+        };
+        tcb.sp = ON_HOST;
+        tcb.name = "idle";
+        with_state(|s| {
+            *s = INITIAL_STATE;
+            s.tick_source = &SimTick;
+            insert_tcb(s, 0, tcb);
+        });
+        // uptime starts over with the tick count
+        set_tick_hz(1000);
+        Simulator {
+            _lock: lock,
+            _idle_stack: idle_stack,
+            started: false,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Run the threads for `ticks` ticks, starting them on the first call as `init` would.
+    /// Returns early if all threads exited, or all wait without a timeout. Resumes the panic
+    /// of a thread which panicked.
+    pub fn run_for(&mut self, ticks: u64) -> Stop {
+        if !self.started {
+            self.started = true;
+            with_state(|s| {
+                s.curr = &s.threads[0] as *const ThreadControlBlock as usize;
+                s.next = s.curr;
+                s.inited = true;
+            });
+            // switches to the first thread, and returns once all threads wait
+            schedule();
+        }
+        let end = crate::ticks() + ticks;
+        loop {
+            check_failure();
+            let stop = with_state(|s| {
+                if (1..MAX_THREADS).all(|i| s.threads[i].status == ThreadStatus::Free) {
+                    Some(Stop::Exited)
+                } else if s.ticks >= end {
+                    Some(Stop::TimeUp)
+                } else if s.timeouts.first().is_none() && s.ready.select(0) == 0 {
+                    Some(Stop::Stalled)
+                } else {
+                    None
+                }
+            });
+            if let Some(stop) = stop {
+                return stop;
+            }
+            if let Some((hook, budget)) =
+                with_state(|s| s.idle_hook.map(|hook| (hook, s.callback_budget)))
+            {
+                crate::budget::run_callback(&budget, "idle hook", hook);
+            }
+            // the threads woken run before SysTick returns
+            SysTick();
+        }
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        // the host threads still waiting for the processor never get it
+        let failure = {
+            let mut cpu = cpu();
+            for generation in cpu.generations.iter_mut() {
+                *generation += 1;
+            }
+            TURN.notify_all();
+            cpu.failure.take()
+        };
+        with_state(|s| s.inited = false);
+        if let Some(payload) = failure {
+            if !thread::panicking() {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+/// Stack area of `words` u32's for a thread of the simulator. It is leaked, and only holds
+/// the thread's canary.
+pub fn stack(words: usize) -> &'static mut [u32] {
+    Box::leak(vec![STACK_FILL; words].into_boxed_slice())
+}
+
+/// Resume the panic of a thread, if one panicked
+fn check_failure() {
+    if let Some(payload) = cpu().failure.take() {
+        panic::resume_unwind(payload);
+    }
+}
+
+/// Tick source of the simulator, ticking at any rate
+struct SimTick;
+
+impl TickSource for SimTick {
+    fn set_rate(&self, _old_hz: u32, _hz: u32) -> bool {
+        true
+    }
+
+    fn enabled(&self) -> Option<bool> {
+        Some(true)
+    }
+}
+
+/// PendSV: hand the processor to the thread the kernel switched to, starting a host thread
+/// for it if it never ran, and wait until it comes back to this one
+fn take_pendsv() {
+    if !PENDSV.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    // the processor is held by this host thread, as by the handler on the core
+    let s = unsafe { &mut *kernel().as_ptr() };
+    if s.curr == s.next {
+        return;
+    }
+    let prev = slot(s, s.curr);
+    let next = slot(s, s.next);
+    s.curr = s.next;
+    let mut cpu = cpu();
+    if s.threads[prev].status == ThreadStatus::Free && s.restarts & 1 << prev == 0 {
+        // exited or deleted, its host thread ends here
+        cpu.generations[prev] += 1;
+    }
+    if s.threads[next].sp != ON_HOST {
+        s.threads[next].sp = ON_HOST;
+        cpu.generations[next] += 1;
+        let generation = cpu.generations[next];
+        let (entry, args) = (s.threads[next].entry, s.threads[next].args);
+        thread::Builder::new()
+            .name(format!("thread {}", next))
+            .spawn(move || run_thread(next, generation, entry, args))
+            .expect("sim: could not spawn a host thread");
+    }
+    cpu.running = (next, cpu.generations[next]);
+    TURN.notify_all();
+    let me = ME.with(Cell::get);
+    while cpu.running != me {
+        if cpu.generations[me.0] != me.1 {
+            drop(cpu);
+            // the thread is gone, as is its stack on the core
+            loop {
+                thread::park();
+            }
+        }
+        cpu = TURN.wait(cpu).unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+/// Body of the host thread running thread `slot` from `entry`
+fn run_thread(slot: usize, generation: u64, entry: usize, args: [usize; 2]) {
+    ME.with(|me| me.set((slot, generation)));
+    {
+        let mut cpu = cpu();
+        while cpu.running != (slot, generation) {
+            if cpu.generations[slot] != generation {
+                return;
+            }
+            cpu = TURN.wait(cpu).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+    // as on the core, the entry is called with R0 and R1 whatever its signature
+    let entry: extern "C-unwind" fn(usize, usize) -> ! = unsafe { core::mem::transmute(entry) };
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| entry(args[0], args[1]))) {
+        // the kernel may be half updated, stop the simulation and let the test fail
+        PRIMASK.store(0, Ordering::SeqCst);
+        let mut cpu = cpu();
+        cpu.failure = Some(payload);
+        cpu.running = (0, cpu.generations[0]);
+        TURN.notify_all();
+    }
+}
+
+/// Slot of the thread control block at `tcb`
+fn slot(s: &ThreadsState, tcb: usize) -> usize {
+    (tcb - &s.threads[0] as *const ThreadControlBlock as usize)
+        / core::mem::size_of::<ThreadControlBlock>()
+}
+
+/// Port of the kernel to the simulated processor
+pub(crate) struct Host;
+
+impl Arch for Host {
+    fn disable_interrupts() -> u32 {
+        PRIMASK.swap(1, Ordering::SeqCst)
+    }
+
+    fn restore_interrupts(mask: u32) {
+        PRIMASK.store(mask, Ordering::SeqCst);
+        if mask & 1 == 0 {
+            take_pendsv();
+        }
+    }
+
+    /// Pend PendSV, taken once interrupts are enabled
+    fn request_switch() {
+        PENDSV.store(true, Ordering::SeqCst);
+        if PRIMASK.load(Ordering::SeqCst) == 0 {
+            take_pendsv();
+        }
+    }
+
+    fn wait_for_event() {
+        thread::yield_now();
+    }
+
+    fn spin(_iterations: u32) {}
+
+    /// Thread mode, as threads and the test thread are, outside kernel calls
+    fn in_handler() -> bool {
+        IN_CALL.with(Cell::get)
+    }
+
+    /// Whether the thread run by this host thread is unprivileged. The test thread plays the
+    /// idle thread, which is privileged.
+    fn unprivileged() -> bool {
+        let slot = ME.with(Cell::get).0;
+        // the processor is held by this host thread
+        let s = unsafe { &*kernel().as_ptr() };
+        slot != 0 && !Self::in_handler() && s.threads[slot].privileged == 0
+    }
+
+    /// No frame, the host thread of the thread calls `entry` with `args` from its thread
+    /// control block
+    fn init_frame(stack: &mut [u32], _entry: usize, _args: [usize; 2], _privileged: bool) -> usize {
+        stack.len()
+    }
+
+    fn set_kernel_state(_state: usize) {}
+
+    unsafe fn start() -> ! {
+        unreachable!("init does not run in the simulator")
+    }
+
+    /// Run the call as the SVCall handler would, followed by the switches it requests
+    fn kernel_call(call: u32, a: usize, b: usize, c: usize) -> usize {
+        let mut frame = [call as usize, a, b, c, 0, 0, 0, 0];
+        IN_CALL.with(|in_call| in_call.set(true));
+        svc::__CORTEXM_THREADS_kernel_call_handler(&mut frame);
+        IN_CALL.with(|in_call| in_call.set(false));
+        frame[0]
+    }
+
+    unsafe fn drop_privileges(_msp: u32, _msplim: u32, _psplim: u32) {}
+}
@@ -0,0 +1,203 @@
+//! Blocking consumers for `heapless::spsc` queues, with the `heapless` feature.
+//!
+//! Code passing data out of interrupt handlers through a `heapless::spsc::Queue` keeps its
+//! queue: an `SpscSignal` next to it lets the consuming thread block until data arrives. The
+//! interrupt handler wraps its `Producer` in a `SignalingProducer`, or keeps enqueueing
+//! through the plain `Producer` and calls `SpscSignal::notify` after. As with `RingBuffer`,
+//! only a producer finding the consumer waiting enters the kernel.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use heapless::spsc::{Consumer, Producer};
+
+use crate::critical::CriticalSection;
+use crate::select::sealed::Source;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, wake_one, Error, WakeReason, NO_DEADLINE};
+
+/// Lets the consumer of a `heapless::spsc::Queue` wait for its producer
+///
+/// Example:
+/// ```
+/// static mut RX: Queue<u8, 64> = Queue::new();
+/// static RX_SIGNAL: SpscSignal = SpscSignal::new();
+///
+/// let (producer, consumer) = unsafe { RX.split() };
+/// let mut producer = RX_SIGNAL.producer(producer);
+/// let mut consumer = RX_SIGNAL.consumer(consumer);
+///
+/// // UART interrupt handler
+/// let _ = producer.enqueue(uart.read_byte());
+///
+/// // thread
+/// loop {
+///     let byte = consumer.dequeue();
+///     parser.feed(byte);
+/// }
+/// ```
+pub struct SpscSignal {
+    /// the consumer is blocked, or about to block, waiting for data
+    waiting: AtomicBool,
+    consumer: UnsafeCell<WaitList>,
+}
+
+unsafe impl Sync for SpscSignal {}
+
+impl SpscSignal {
+    pub const fn new() -> Self {
+        SpscSignal {
+            waiting: AtomicBool::new(false),
+            consumer: UnsafeCell::new(WaitList::new(WaitOrder::Fifo)),
+        }
+    }
+
+    /// Wake the consumer if it is waiting for data. Call after enqueueing through a plain
+    /// `Producer`; `SignalingProducer` does it itself. Can be called from interrupt handlers.
+    pub fn notify(&self) {
+        if self.waiting.load(Ordering::Acquire) {
+            {
+                let mut cs = CriticalSection::enter();
+                wake_one(
+                    crate::state(&mut cs),
+                    unsafe { &mut *self.consumer.get() },
+                    WakeReason::Signaled,
+                );
+            }
+            schedule();
+        }
+    }
+
+    /// `producer`, waking the consumer of this signal on each item it enqueues
+    pub fn producer<'a, T, const N: usize>(
+        &'a self,
+        producer: Producer<'a, T, N>,
+    ) -> SignalingProducer<'a, T, N> {
+        SignalingProducer {
+            producer,
+            signal: self,
+        }
+    }
+
+    /// `consumer`, able to block until the producer of this signal enqueues an item
+    pub fn consumer<'a, T, const N: usize>(
+        &'a self,
+        consumer: Consumer<'a, T, N>,
+    ) -> BlockingConsumer<'a, T, N> {
+        BlockingConsumer {
+            consumer,
+            signal: self,
+        }
+    }
+}
+
+impl Default for SpscSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `heapless::spsc::Producer` notifying an `SpscSignal`, can be used from interrupt handlers
+pub struct SignalingProducer<'a, T, const N: usize> {
+    producer: Producer<'a, T, N>,
+    signal: &'a SpscSignal,
+}
+
+impl<'a, T, const N: usize> SignalingProducer<'a, T, N> {
+    /// Append `value`, and wake the consumer if it is waiting for data. Gives `value` back if
+    /// the queue is full.
+    pub fn enqueue(&mut self, value: T) -> Result<(), T> {
+        self.producer.enqueue(value)?;
+        self.signal.notify();
+        Ok(())
+    }
+
+    /// Whether an item can be enqueued
+    pub fn ready(&self) -> bool {
+        self.producer.ready()
+    }
+
+    /// The wrapped `Producer`
+    pub fn into_inner(self) -> Producer<'a, T, N> {
+        self.producer
+    }
+}
+
+/// `heapless::spsc::Consumer` waiting on an `SpscSignal`, owned by a privileged thread: it
+/// has no kernel call, see `svc`
+pub struct BlockingConsumer<'a, T, const N: usize> {
+    consumer: Consumer<'a, T, N>,
+    signal: &'a SpscSignal,
+}
+
+impl<'a, T, const N: usize> BlockingConsumer<'a, T, N> {
+    /// Take the oldest item, or None if the queue is empty
+    pub fn try_dequeue(&mut self) -> Option<T> {
+        self.consumer.dequeue()
+    }
+
+    /// Take the oldest item, blocking until one is enqueued
+    pub fn dequeue(&mut self) -> T {
+        match self.dequeue_until(NO_DEADLINE) {
+            Ok(value) => value,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `dequeue`, but gives up with Err(Error::TimedOut) if nothing was enqueued within
+    /// `ticks` ticks
+    pub fn dequeue_timeout(&mut self, ticks: u32) -> Result<T, Error> {
+        self.dequeue_until(crate::ticks() + ticks as u64)
+    }
+
+    /// Number of items which can be dequeued
+    pub fn len(&self) -> usize {
+        self.consumer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.consumer.ready()
+    }
+
+    /// The wrapped `Consumer`
+    pub fn into_inner(self) -> Consumer<'a, T, N> {
+        self.consumer
+    }
+
+    fn dequeue_until(&mut self, deadline: u64) -> Result<T, Error> {
+        loop {
+            if let Some(value) = self.consumer.dequeue() {
+                return Ok(value);
+            }
+            let signal = self.signal;
+            // interrupts are disabled from the check until the thread is blocked, so an
+            // enqueue either lands before the check or sees `waiting` set
+            let cs = CriticalSection::enter();
+            signal.waiting.store(true, Ordering::Release);
+            let reason = if self.is_empty() {
+                unsafe { block_current(cs, signal.consumer.get(), deadline) }
+            } else {
+                drop(cs);
+                WakeReason::Signaled
+            };
+            signal.waiting.store(false, Ordering::Release);
+            if reason == WakeReason::Timeout {
+                return self.consumer.dequeue().ok_or(Error::TimedOut);
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Source for BlockingConsumer<'_, T, N> {
+    fn ready(&self) -> bool {
+        !self.is_empty()
+    }
+
+    fn wait_list(&self) -> *mut WaitList {
+        self.signal.consumer.get()
+    }
+
+    fn set_selected(&self, selected: bool) {
+        self.signal.waiting.store(selected, Ordering::Release);
+    }
+}
@@ -0,0 +1,66 @@
+//! Statically allocated thread stacks.
+//!
+//! A `Stack` is declared as a plain `static`, without `unsafe`. It is 8-byte aligned as the
+//! AAPCS requires, holds the overflow canary below its `WORDS` usable words, and hands out its
+//! memory only once, so the same stack cannot end up under two threads.
+
+use core::cell::UnsafeCell;
+use core::slice;
+
+use crate::critical::with_critical;
+use crate::{CANARY_WORDS, MIN_STACK_WORDS};
+
+/// Stack area of a thread, with `WORDS` u32's above the canary
+///
+/// Example:
+/// ```
+/// static STACK1: Stack<512> = Stack::new();
+///
+/// let _ = create_thread(STACK1.take().unwrap(), task1);
+/// ```
+#[repr(C, align(8))]
+pub struct Stack<const WORDS: usize> {
+    // the canary is painted over the bottom words of the area by create_thread
+    canary: UnsafeCell<[u32; CANARY_WORDS]>,
+    words: UnsafeCell<[u32; WORDS]>,
+    taken: UnsafeCell<bool>,
+}
+
+unsafe impl<const WORDS: usize> Sync for Stack<WORDS> {}
+
+impl<const WORDS: usize> Stack<WORDS> {
+    // the whole area must be at least MIN_STACK_WORDS long
+    const CHECK: () = assert!(CANARY_WORDS + WORDS >= MIN_STACK_WORDS);
+
+    /// An unused stack, in zero-initialized RAM. Fails to compile if it is too small for a
+    /// thread.
+    pub const fn new() -> Self {
+        let () = Self::CHECK;
+        Stack {
+            canary: UnsafeCell::new([0; CANARY_WORDS]),
+            words: UnsafeCell::new([0; WORDS]),
+            taken: UnsafeCell::new(false),
+        }
+    }
+
+    /// The stack area, canary included, to pass to `create_thread` or its variants. Returns
+    /// None if it was already taken.
+    // the area is handed out once, guarded by `taken`
+    #[allow(clippy::mut_from_ref)]
+    pub fn take(&'static self) -> Option<&'static mut [u32]> {
+        let taken = with_critical(|| unsafe { core::mem::replace(&mut *self.taken.get(), true) });
+        if taken {
+            return None;
+        }
+        // canary and words are contiguous, both are u32 arrays in a repr(C) struct
+        Some(unsafe {
+            slice::from_raw_parts_mut(self.canary.get().cast::<u32>(), CANARY_WORDS + WORDS)
+        })
+    }
+}
+
+impl<const WORDS: usize> Default for Stack<WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,60 @@
+//! Scheduler statistics, with the `kernel-stats` feature.
+//!
+//! Counts thread switches, in total and per thread, and measures how long the scheduler runs
+//! with interrupts disabled each time it picks a thread, which bounds the interrupt latency it
+//! adds. Cycles come from the DWT cycle counter on Cortex-M3 and above, and are derived from
+//! SysTick on Cortex-M0/M0+, like for `cpu-usage`.
+
+use crate::{cycles, with_state, ThreadsState, MAX_THREADS};
+
+/// Counters kept since init, returned by `kernel_stats`
+#[derive(Clone, Copy, Debug)]
+pub struct KernelStats {
+    /// thread switches, including to and from the idle thread
+    pub switches: u64,
+    /// times each thread slot was switched to, restarting from 0 when a slot is reused
+    pub switch_ins: [u32; MAX_THREADS],
+    /// longest run of the scheduler, in cycles
+    pub max_schedule_cycles: u32,
+}
+
+/// Record a switch to thread `next`
+pub(crate) fn count_switch(s: &mut ThreadsState, next: usize) {
+    s.switches += 1;
+    s.threads[next].switch_ins = s.threads[next].switch_ins.wrapping_add(1);
+}
+
+/// Cycle count at the start of a scheduler run
+pub(crate) fn start(s: &ThreadsState) -> u64 {
+    cycles::now(s.ticks)
+}
+
+/// Record the end of the scheduler run started at cycle count `start`
+pub(crate) fn finish(s: &mut ThreadsState, start: u64) {
+    let spent = (cycles::now(s.ticks) as u32).wrapping_sub(start as u32);
+    if spent > s.max_schedule_cycles {
+        s.max_schedule_cycles = spent;
+    }
+}
+
+/// Switch counts and the longest scheduler run since init
+///
+/// # Example
+/// ```
+/// let stats = kernel_stats();
+/// let _ = hprintln!("{} switches, scheduler at most {} cycles",
+///     stats.switches, stats.max_schedule_cycles);
+/// ```
+pub fn kernel_stats() -> KernelStats {
+    with_state(|s| {
+        let mut switch_ins = [0; MAX_THREADS];
+        for (count, thread) in switch_ins.iter_mut().zip(s.threads.iter()) {
+            *count = thread.switch_ins;
+        }
+        KernelStats {
+            switches: s.switches,
+            switch_ins,
+            max_schedule_cycles: s.max_schedule_cycles,
+        }
+    })
+}
@@ -0,0 +1,228 @@
+//! Byte stream from an interrupt handler to a thread.
+//!
+//! A `StreamBuffer` carries continuous byte data, e.g. UART input or audio samples, where a
+//! `RingBuffer` carries discrete items. It is split into a `StreamWriter`, typically owned by
+//! an interrupt handler, and a `StreamReader` owned by a thread. Writing never blocks: bytes
+//! which do not fit are dropped. The reader blocks until at least the trigger level of bytes
+//! is available, so it is not woken for every byte; only a write reaching that level enters
+//! the kernel.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::critical::CriticalSection;
+use crate::select::sealed::Source;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{block_current, schedule, wake_one, Error, WakeReason, NO_DEADLINE};
+
+/// Buffer of `N` bytes holding up to `N - 1` bytes
+///
+/// Example:
+/// ```
+/// static mut RX: StreamBuffer<256> = StreamBuffer::new(16);
+///
+/// let (mut writer, mut reader) = unsafe { RX.split() };
+///
+/// // UART interrupt handler
+/// let _ = writer.write(&uart.read_fifo());
+///
+/// // thread, woken once 16 bytes arrived
+/// let mut frame = [0; 64];
+/// loop {
+///     let n = reader.read(&mut frame);
+///     parser.feed(&frame[..n]);
+/// }
+/// ```
+pub struct StreamBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    /// index of the next byte to read, only written by the reader
+    head: AtomicUsize,
+    /// index of the next byte to write, only written by the writer
+    tail: AtomicUsize,
+    /// bytes a reader waits for, 0 if it does not wait
+    wanted: AtomicUsize,
+    trigger: AtomicUsize,
+    reader: UnsafeCell<WaitList>,
+}
+
+unsafe impl<const N: usize> Sync for StreamBuffer<N> {}
+
+impl<const N: usize> StreamBuffer<N> {
+    /// An empty buffer, whose reader blocks until `trigger` bytes are available, see
+    /// `StreamReader::set_trigger_level`
+    pub const fn new(trigger: usize) -> Self {
+        StreamBuffer {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            wanted: AtomicUsize::new(0),
+            trigger: AtomicUsize::new(clamp_trigger(trigger, N)),
+            reader: UnsafeCell::new(WaitList::new(WaitOrder::Fifo)),
+        }
+    }
+
+    /// Split the buffer into its two ends
+    pub fn split(&mut self) -> (StreamWriter<'_, N>, StreamReader<'_, N>) {
+        let stream: &Self = self;
+        (StreamWriter { stream }, StreamReader { stream })
+    }
+
+    fn len(&self) -> usize {
+        (self.tail.load(Ordering::Acquire) + N - self.head.load(Ordering::Acquire)) % N
+    }
+
+    fn byte(&self, idx: usize) -> *mut u8 {
+        unsafe { self.buf.get().cast::<u8>().add(idx) }
+    }
+}
+
+/// Trigger level between 1 and the capacity of a buffer of `n` bytes
+const fn clamp_trigger(trigger: usize, n: usize) -> usize {
+    if trigger == 0 {
+        1
+    } else if trigger > n - 1 {
+        n - 1
+    } else {
+        trigger
+    }
+}
+
+/// Writing end of a `StreamBuffer`, can be used from interrupt handlers
+pub struct StreamWriter<'a, const N: usize> {
+    stream: &'a StreamBuffer<N>,
+}
+
+unsafe impl<const N: usize> Send for StreamWriter<'_, N> {}
+
+impl<const N: usize> StreamWriter<'_, N> {
+    /// Append as many bytes of `data` as fit, and wake the reader if it now has the bytes it
+    /// waits for. Returns the number of bytes written; the others are dropped.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let stream = self.stream;
+        let mut tail = stream.tail.load(Ordering::Relaxed);
+        let count = data.len().min(self.free());
+        for &byte in &data[..count] {
+            unsafe { *stream.byte(tail) = byte };
+            tail = (tail + 1) % N;
+        }
+        stream.tail.store(tail, Ordering::Release);
+        let wanted = stream.wanted.load(Ordering::Acquire);
+        if count > 0 && wanted != 0 && stream.len() >= wanted {
+            {
+                let mut cs = CriticalSection::enter();
+                wake_one(
+                    crate::state(&mut cs),
+                    unsafe { &mut *stream.reader.get() },
+                    WakeReason::Signaled,
+                );
+            }
+            schedule();
+        }
+        count
+    }
+
+    /// Number of bytes which can still be written
+    pub fn free(&self) -> usize {
+        N - 1 - self.stream.len()
+    }
+}
+
+/// Reading end of a `StreamBuffer`, owned by a privileged thread: it has no kernel call, see
+/// `svc`
+pub struct StreamReader<'a, const N: usize> {
+    stream: &'a StreamBuffer<N>,
+}
+
+unsafe impl<const N: usize> Send for StreamReader<'_, N> {}
+
+impl<const N: usize> StreamReader<'_, N> {
+    /// Move the available bytes to `buf`, as many as fit, without blocking. Returns the
+    /// number of bytes read.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> usize {
+        let stream = self.stream;
+        let mut head = stream.head.load(Ordering::Relaxed);
+        let count = buf.len().min(stream.len());
+        for byte in &mut buf[..count] {
+            *byte = unsafe { *stream.byte(head) };
+            head = (head + 1) % N;
+        }
+        stream.head.store(head, Ordering::Release);
+        count
+    }
+
+    /// Block until the trigger level of bytes is available, or as many as `buf` holds if
+    /// that is less, then read like `try_read`. Returns the number of bytes read.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        match self.read_until(buf, NO_DEADLINE) {
+            Ok(count) => count,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Like `read`, but reads the bytes which arrived so far if the trigger level was not
+    /// reached within `ticks` ticks. Returns Err(Error::TimedOut) if there were none.
+    pub fn read_timeout(&mut self, buf: &mut [u8], ticks: u32) -> Result<usize, Error> {
+        self.read_until(buf, crate::ticks() + ticks as u64)
+    }
+
+    /// Set the number of bytes `read` waits for, between 1 and `N - 1`
+    pub fn set_trigger_level(&mut self, trigger: usize) {
+        let trigger = clamp_trigger(trigger, N);
+        self.stream.trigger.store(trigger, Ordering::Relaxed);
+    }
+
+    /// Number of bytes which can be read
+    pub fn len(&self) -> usize {
+        self.stream.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn read_until(&mut self, buf: &mut [u8], deadline: u64) -> Result<usize, Error> {
+        let stream = self.stream;
+        let wanted = stream.trigger.load(Ordering::Relaxed).min(buf.len());
+        loop {
+            if self.len() >= wanted {
+                return Ok(self.try_read(buf));
+            }
+            // interrupts are disabled from the check until the thread is blocked, so a write
+            // either lands before the check or sees `wanted` set
+            let cs = CriticalSection::enter();
+            stream.wanted.store(wanted, Ordering::Release);
+            let reason = if self.len() < wanted {
+                unsafe { block_current(cs, stream.reader.get(), deadline) }
+            } else {
+                drop(cs);
+                WakeReason::Signaled
+            };
+            stream.wanted.store(0, Ordering::Release);
+            if reason == WakeReason::Timeout {
+                return match self.try_read(buf) {
+                    0 => Err(Error::TimedOut),
+                    count => Ok(count),
+                };
+            }
+        }
+    }
+}
+
+impl<const N: usize> Source for StreamReader<'_, N> {
+    fn ready(&self) -> bool {
+        self.len() >= self.stream.trigger.load(Ordering::Relaxed)
+    }
+
+    fn wait_list(&self) -> *mut WaitList {
+        self.stream.reader.get()
+    }
+
+    fn set_selected(&self, selected: bool) {
+        let wanted = if selected {
+            self.stream.trigger.load(Ordering::Relaxed)
+        } else {
+            0
+        };
+        self.stream.wanted.store(wanted, Ordering::Release);
+    }
+}
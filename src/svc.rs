@@ -0,0 +1,657 @@
+//! Kernel calls through the SVCall exception.
+//!
+//! Unprivileged threads cannot disable interrupts, so they cannot update the kernel state
+//! themselves. They ask the kernel with `svc 1` instead: R0 holds the call number and R1 to
+//! R3 its arguments. The SVCall handler runs the call in handler mode, privileged, and
+//! writes the result to the R0 of the caller's exception frame. A call which blocks or wakes
+//! a thread pends PendSV, which switches threads as soon as the handler returns.
+//!
+//! The handler checks each request against the calling thread, so an unprivileged thread
+//! cannot get more than it has: a thread it creates is unprivileged too, with at most its own
+//! priority, it can only unlock a mutex it holds, and the kernel never writes its own state
+//! through an address it was passed.
+//!
+//! Thread creation, `exit`, `sleep`, `sleep_until`, `sleep_or_event`, `yield_now`, `wake`,
+//! `ticks`, `get_thread_id`, `thread_info`, `Mutex`, `CondVar`, `RwLock`, `Barrier`, the
+//! `Consumer` and `Producer` of a `RingBuffer`, thread-local storage, `block_on`,
+//! `contain_panic` and the deadline miss count of periodic threads take this path when called
+//! from an unprivileged thread. A call naming an object passes its address, which the kernel
+//! checks like any other address before using the object.
+//!
+//! The rest is restricted to privileged threads and interrupt handlers, as noted on each type:
+//! `Mailbox`, `Broadcast`, `BufQueue`, `Rpc`, `select`, the readers of a `StreamBuffer` and of
+//! an `SpscSignal`, `CoreChannel`, thread groups and modes, `defer`, the C queues, and the
+//! functions managing other threads. They update their state in a critical section, which an
+//! unprivileged thread cannot enter, and panic when it tries. `set_thread_name` returns
+//! Err(Error::NoCreatePrivilege) instead, as the name would point the kernel at memory of the
+//! caller's choosing. `svc 0` is left to `init`, which starts the first thread with it: once a
+//! thread runs, the SVCall handler turns `svc 0` into kernel call 0, which fails.
+
+#[cfg(feature = "rp2040-amp")]
+use core::mem::size_of_val;
+use core::mem::{align_of, size_of, MaybeUninit};
+use core::ops::Range;
+use core::panic::PanicInfo;
+use core::ptr;
+
+use crate::arch::{Arch, Port};
+use crate::contain::{self, PanicAction};
+use crate::critical::CriticalSection;
+use crate::wait::WaitList;
+#[cfg(not(feature = "rp2040-amp"))]
+use crate::KERNEL;
+#[cfg(feature = "rp2040-amp")]
+use crate::KERNELS;
+use crate::{barrier, executor, mutex, periodic, rwlock};
+use crate::{
+    block_enter, insert_thread, live_info, release_thread, schedule_in, sleep_current, state,
+    wake_all, wake_one, wake_thread, yield_current, Barrier, Error, ThreadHandle, ThreadInfo,
+    ThreadsState, WakeReason,
+};
+
+/// Create a thread, R1 holds the address of a `SpawnRequest`
+const SPAWN: u32 = 1;
+/// Sleep for R1 ticks
+const SLEEP: u32 = 2;
+/// Sleep until the tick in R1 (low word) and R2 (high word)
+const SLEEP_UNTIL: u32 = 3;
+/// Yield to threads of the same priority
+const YIELD: u32 = 4;
+/// Wake thread R1
+const WAKE: u32 = 5;
+/// Lock the mutex at address R1, or block on it until the tick in R2 and R3
+const MUTEX_LOCK: u32 = 6;
+/// `MUTEX_LOCK` again, once the caller runs after blocking
+const MUTEX_RELOCK: u32 = 7;
+/// Unlock the mutex at address R1
+const MUTEX_UNLOCK: u32 = 8;
+/// Park the caller in `block_on`
+const PARK: u32 = 9;
+/// Unpark thread R1
+const UNPARK: u32 = 10;
+/// Park until R1 ticks from now, returning the `WakeReason` if the caller does not block and
+/// 0 if it does
+const SLEEP_OR_EVENT: u32 = 11;
+/// `WakeReason` of the caller's last wake
+const WAKE_REASON: u32 = 12;
+/// Terminate the caller
+const EXIT: u32 = 13;
+/// Tick count, the low word if R1 is 0 and the high word otherwise
+const TICKS: u32 = 14;
+/// Id of the caller
+const THREAD_ID: u32 = 15;
+/// Write the `ThreadInfo` of thread R1 at address R2, returning 1 if the thread exists
+const THREAD_INFO: u32 = 16;
+/// Address of the panic hook, 0 if none
+const PANIC_HOOK: u32 = 17;
+/// Stop the caller after a panic, to be started over if R1 is 1
+const PANICKED: u32 = 18;
+/// Count a deadline miss of the caller, returning the address of the deadline miss hook, 0 if
+/// none
+const DEADLINE_MISSED: u32 = 19;
+/// Unlock the mutex of the `WaitRequest` at address R1 and block on its wait list, returning
+/// the `WakeReason` if the caller does not block and 0 if it does
+const CONDVAR_WAIT: u32 = 20;
+/// Block on the wait list of the `WaitRequest` at address R1 while its word holds its value,
+/// returning the `WakeReason` if the caller does not block and 0 if it does
+const WAIT_WORD: u32 = 21;
+/// Leave the wait list at address R1, returning the `WakeReason` of the caller's last wake
+const LEAVE_LIST: u32 = 22;
+/// Wake the first thread on the wait list at address R1, or all of them if R2 is 1
+const WAKE_LIST: u32 = 23;
+/// Lock the `RwLock` at address R1 for reading, or block on it until the tick in R2 and R3
+const READ_LOCK: u32 = 24;
+/// `READ_LOCK` again, once the caller runs after blocking
+const READ_RELOCK: u32 = 25;
+/// Lock the `RwLock` at address R1 for writing, or block on it until the tick in R2 and R3
+const WRITE_LOCK: u32 = 26;
+/// `WRITE_LOCK` again, once the caller runs after blocking
+const WRITE_RELOCK: u32 = 27;
+/// Unlock the `RwLock` at address R1, held for writing if R2 is 1
+const RWLOCK_UNLOCK: u32 = 28;
+/// Wait on the `Barrier` at address R1 until the tick in R2 and R3, returning 2 if the caller
+/// released the round, 1 once it was released, and 0 while blocked
+const BARRIER_WAIT: u32 = 29;
+/// `BARRIER_WAIT` again, once the caller runs after blocking
+const BARRIER_REWAIT: u32 = 30;
+/// Value of thread-local slot R1 of the caller
+#[cfg(feature = "tls")]
+const TLS_GET: u32 = 31;
+/// Store R2 in thread-local slot R1 of the caller
+#[cfg(feature = "tls")]
+const TLS_SET: u32 = 32;
+
+/// Results with this bit set are errors, the low bits are the `Error`
+const ERR: usize = 1 << (usize::BITS - 1);
+
+/// Errors in declaration order, so `ERRORS[e as usize] == e`
+const ERRORS: [Error; 11] = [
+    Error::TooManyThreads,
+    Error::StackTooSmall,
+    Error::NoCreatePrivilege,
+    Error::NoSuchThread,
+    Error::TimedOut,
+    Error::QueueFull,
+    Error::NotRestartable,
+    Error::OutOfMemory,
+    Error::StackInUse,
+    Error::BadRegion,
+    Error::NotHolder,
+];
+
+/// Wake reasons in declaration order, so `WAKE_REASONS[r as usize] == r`
+const WAKE_REASONS: [WakeReason; 5] = [
+    WakeReason::None,
+    WakeReason::Timeout,
+    WakeReason::Exited,
+    WakeReason::Signaled,
+    WakeReason::Woken,
+];
+
+/// Arguments of `SPAWN`, on the stack of the calling thread
+#[repr(C)]
+struct SpawnRequest {
+    stack: *mut u32,
+    words: usize,
+    pc: usize,
+    r0: usize,
+    r1: usize,
+    priority: u8,
+    privileged: bool,
+}
+
+/// Arguments of `CONDVAR_WAIT` and `WAIT_WORD`, in memory of the calling thread
+#[repr(C)]
+struct WaitRequest {
+    list: usize,
+    /// mutex to unlock for `CONDVAR_WAIT`, word compared with `value` for `WAIT_WORD`
+    object: usize,
+    value: usize,
+    deadline: u64,
+}
+
+fn encode(result: Result<usize, Error>) -> usize {
+    match result {
+        Ok(value) => value,
+        Err(e) => ERR | e as usize,
+    }
+}
+
+fn decode(value: usize) -> Result<usize, Error> {
+    if value & ERR == 0 {
+        Ok(value)
+    } else {
+        Err(ERRORS[value & !ERR])
+    }
+}
+
+fn call(number: u32, a: usize, b: usize, c: usize) -> Result<usize, Error> {
+    decode(Port::kernel_call(number, a, b, c))
+}
+
+/// Whether the caller is a thread running unprivileged, which must go through a kernel call.
+/// This reads the core's registers only, as the kernel state cannot be borrowed yet.
+pub(crate) fn from_unprivileged_thread() -> bool {
+    Port::unprivileged()
+}
+
+/// Have the kernel create a thread starting at address `pc` on `stack`, with `r0` and `r1`
+/// as its first two arguments
+pub(crate) fn spawn(
+    stack: &mut [u32],
+    pc: usize,
+    r0: usize,
+    r1: usize,
+    priority: u8,
+    privileged: bool,
+) -> Result<ThreadHandle, Error> {
+    let request = SpawnRequest {
+        stack: stack.as_mut_ptr(),
+        words: stack.len(),
+        pc,
+        r0,
+        r1,
+        priority,
+        privileged,
+    };
+    let request = &request as *const SpawnRequest as usize;
+    call(SPAWN, request, 0, 0).map(ThreadHandle)
+}
+
+pub(crate) fn exit() {
+    let _ = call(EXIT, 0, 0, 0);
+}
+
+pub(crate) fn sleep(ticks: u32) {
+    let _ = call(SLEEP, ticks as usize, 0, 0);
+}
+
+pub(crate) fn sleep_until(tick: u64) {
+    let (low, high) = split(tick);
+    let _ = call(SLEEP_UNTIL, low, high, 0);
+}
+
+pub(crate) fn yield_now() {
+    let _ = call(YIELD, 0, 0, 0);
+}
+
+pub(crate) fn wake(thread: ThreadHandle) -> Result<(), Error> {
+    call(WAKE, thread.0, 0, 0).map(|_| ())
+}
+
+pub(crate) fn ticks() -> u64 {
+    // results are read raw, as the low word may have the error bit set. The high word
+    // changes once every 2^32 ticks, it is read again in case it just did.
+    loop {
+        let high = Port::kernel_call(TICKS, 1, 0, 0) as u64;
+        let low = Port::kernel_call(TICKS, 0, 0, 0) as u64;
+        if Port::kernel_call(TICKS, 1, 0, 0) as u64 == high {
+            return high << 32 | low;
+        }
+    }
+}
+
+pub(crate) fn thread_id() -> ThreadHandle {
+    ThreadHandle(call(THREAD_ID, 0, 0, 0).unwrap_or(0))
+}
+
+pub(crate) fn thread_info(thread: ThreadHandle) -> Option<ThreadInfo> {
+    let mut info = MaybeUninit::<ThreadInfo>::uninit();
+    let out = info.as_mut_ptr() as usize;
+    if call(THREAD_INFO, thread.0, out, 0) == Ok(1) {
+        Some(unsafe { info.assume_init() })
+    } else {
+        None
+    }
+}
+
+/// Lock the mutex at address `lock`, blocking until `deadline` while it is locked
+pub(crate) fn lock_mutex(lock: usize, deadline: u64) -> Result<(), Error> {
+    let (low, high) = split(deadline);
+    let mut number = MUTEX_LOCK;
+    // 0 while blocked: the thread runs again once the mutex was unlocked or the wait timed out
+    while call(number, lock, low, high)? == 0 {
+        number = MUTEX_RELOCK;
+    }
+    Ok(())
+}
+
+pub(crate) fn unlock_mutex(lock: usize) {
+    let _ = call(MUTEX_UNLOCK, lock, 0, 0);
+}
+
+pub(crate) fn park() {
+    let _ = call(PARK, 0, 0, 0);
+}
+
+pub(crate) fn unpark(thread: usize) -> bool {
+    call(UNPARK, thread, 0, 0) == Ok(1)
+}
+
+pub(crate) fn sleep_or_event(ticks: u32) -> WakeReason {
+    let reason = match call(SLEEP_OR_EVENT, ticks as usize, 0, 0) {
+        // blocked, and running again once woken
+        Ok(0) => call(WAKE_REASON, 0, 0, 0),
+        result => result,
+    };
+    WAKE_REASONS[reason.unwrap_or(0)]
+}
+
+pub(crate) fn panic_hook() -> Option<fn(ThreadHandle, &PanicInfo) -> PanicAction> {
+    // read raw, as code may live at addresses with the error bit set
+    match Port::kernel_call(PANIC_HOOK, 0, 0, 0) {
+        0 => None,
+        hook => Some(unsafe {
+            core::mem::transmute::<usize, fn(ThreadHandle, &PanicInfo) -> PanicAction>(hook)
+        }),
+    }
+}
+
+pub(crate) fn panicked(restart: bool) {
+    let _ = call(PANICKED, restart as usize, 0, 0);
+}
+
+/// Unlock the mutex at address `lock` and block on the wait list at address `list` until
+/// `deadline`, in one step
+pub(crate) fn condvar_wait(list: usize, lock: usize, deadline: u64) -> WakeReason {
+    let request = WaitRequest {
+        list,
+        object: lock,
+        value: 0,
+        deadline,
+    };
+    wait(CONDVAR_WAIT, &request)
+}
+
+/// Block on the wait list at address `list` until `deadline`, unless the word at address
+/// `word` no longer holds `value`
+pub(crate) fn wait_word(list: usize, word: usize, value: usize, deadline: u64) -> WakeReason {
+    let request = WaitRequest {
+        list,
+        object: word,
+        value,
+        deadline,
+    };
+    wait(WAIT_WORD, &request)
+}
+
+fn wait(number: u32, request: &WaitRequest) -> WakeReason {
+    let reason = match call(number, request as *const WaitRequest as usize, 0, 0) {
+        // blocked, and running again once woken
+        Ok(0) => call(LEAVE_LIST, request.list, 0, 0),
+        result => result,
+    };
+    WAKE_REASONS[reason.unwrap_or(0)]
+}
+
+/// Wake the first thread on the wait list at address `list`, or all of them
+pub(crate) fn wake_list(list: usize, all: bool) {
+    let _ = call(WAKE_LIST, list, all as usize, 0);
+}
+
+/// Lock the `RwLock` at address `lock` for reading or writing, blocking until `deadline`
+/// while it cannot be
+pub(crate) fn lock_rwlock(lock: usize, write: bool, deadline: u64) -> Result<(), Error> {
+    let (low, high) = split(deadline);
+    let (first, again) = if write {
+        (WRITE_LOCK, WRITE_RELOCK)
+    } else {
+        (READ_LOCK, READ_RELOCK)
+    };
+    let mut number = first;
+    while call(number, lock, low, high)? == 0 {
+        number = again;
+    }
+    Ok(())
+}
+
+pub(crate) fn unlock_rwlock(lock: usize, write: bool) {
+    let _ = call(RWLOCK_UNLOCK, lock, write as usize, 0);
+}
+
+/// Wait on the barrier at address `barrier` until `deadline`, returning whether the caller
+/// released the round
+pub(crate) fn barrier_wait(barrier: usize, deadline: u64) -> Result<bool, Error> {
+    let (low, high) = split(deadline);
+    let mut number = BARRIER_WAIT;
+    loop {
+        match call(number, barrier, low, high)? {
+            0 => number = BARRIER_REWAIT,
+            released => return Ok(released == 2),
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+pub(crate) fn tls_get(slot: usize) -> usize {
+    // read raw, as the value may have the error bit set
+    Port::kernel_call(TLS_GET, slot, 0, 0)
+}
+
+#[cfg(feature = "tls")]
+pub(crate) fn tls_set(slot: usize, value: usize) {
+    let _ = call(TLS_SET, slot, value, 0);
+}
+
+pub(crate) fn deadline_missed() -> Option<fn(ThreadHandle)> {
+    // read raw, like the panic hook
+    match Port::kernel_call(DEADLINE_MISSED, 0, 0, 0) {
+        0 => None,
+        hook => Some(unsafe { core::mem::transmute::<usize, fn(ThreadHandle)>(hook) }),
+    }
+}
+
+/// Low and high words of `tick`, passed in two registers
+fn split(tick: u64) -> (usize, usize) {
+    (tick as u32 as usize, (tick >> 32) as usize)
+}
+
+/// Run the kernel call whose arguments are in the exception frame `frame` of the caller, and
+/// leave its result in the frame's R0. Called by the SVCall handler.
+#[no_mangle]
+pub(crate) extern "C" fn __CORTEXM_THREADS_kernel_call_handler(frame: &mut [usize; 8]) {
+    let mut cs = CriticalSection::enter();
+    let s = state(&mut cs);
+    let tick = |low: usize, high: usize| (high as u64) << 32 | low as u64;
+    let result = match frame[0] as u32 {
+        SPAWN => handle_spawn(s, frame[1]),
+        SLEEP => {
+            let tick = s.ticks + frame[1] as u64;
+            Ok(sleep_current(s, tick) as usize)
+        }
+        SLEEP_UNTIL => Ok(sleep_current(s, tick(frame[1], frame[2])) as usize),
+        YIELD => {
+            yield_current(s);
+            Ok(0)
+        }
+        WAKE => wake_thread(s, frame[1]).map(|woken| woken as usize),
+        MUTEX_LOCK | MUTEX_RELOCK => {
+            let lock = frame[1];
+            if writable(s, lock..lock.saturating_add(mutex::STATE_SIZE)) {
+                let retry = frame[0] as u32 == MUTEX_RELOCK;
+                unsafe { mutex::lock_or_block(s, lock, tick(frame[2], frame[3]), retry) }
+                    .map(|locked| locked as usize)
+            } else {
+                Err(Error::NoCreatePrivilege)
+            }
+        }
+        MUTEX_UNLOCK => {
+            let lock = frame[1];
+            if writable(s, lock..lock.saturating_add(mutex::STATE_SIZE)) {
+                unsafe { mutex::unlock_held(s, lock) };
+                Ok(0)
+            } else {
+                Err(Error::NoCreatePrivilege)
+            }
+        }
+        PARK => Ok(executor::park_current(s) as usize),
+        UNPARK => Ok(executor::unpark_thread(s, frame[1]) as usize),
+        SLEEP_OR_EVENT => {
+            let deadline = s.ticks + frame[1] as u64;
+            Ok(executor::nap_current(s, deadline).map_or(0, |reason| reason as usize))
+        }
+        WAKE_REASON => Ok(s.threads[s.idx].wake_reason as usize),
+        EXIT | PANICKED if s.idx == 0 => Err(Error::NoSuchThread),
+        EXIT => {
+            release_thread(s, s.idx);
+            Ok(0)
+        }
+        TICKS if frame[1] == 0 => Ok(s.ticks as u32 as usize),
+        TICKS => Ok((s.ticks >> 32) as usize),
+        THREAD_ID => Ok(s.idx),
+        THREAD_INFO => {
+            let out = frame[2];
+            let range = out..out.saturating_add(size_of::<ThreadInfo>());
+            if out & (align_of::<ThreadInfo>() - 1) == 0 && writable(s, range) {
+                let info = live_info(s, frame[1]);
+                if let Some(info) = info {
+                    unsafe { ptr::write(out as *mut ThreadInfo, info) };
+                }
+                Ok(info.is_some() as usize)
+            } else {
+                Err(Error::NoCreatePrivilege)
+            }
+        }
+        PANIC_HOOK => Ok(s.panic_hook.map_or(0, |hook| hook as usize)),
+        PANICKED => {
+            contain::stop_panicked(s, s.idx, frame[1] != 0);
+            Ok(0)
+        }
+        DEADLINE_MISSED => Ok(periodic::count_miss(s).map_or(0, |hook| hook as usize)),
+        CONDVAR_WAIT | WAIT_WORD => handle_wait(s, frame[0] as u32, frame[1]),
+        LEAVE_LIST => match unsafe { object::<WaitList>(s, frame[1]) } {
+            Some(list) => {
+                list.remove(s.idx);
+                Ok(s.threads[s.idx].wake_reason as usize)
+            }
+            None => Err(Error::NoCreatePrivilege),
+        },
+        WAKE_LIST => match unsafe { object::<WaitList>(s, frame[1]) } {
+            Some(list) if frame[2] == 1 => Ok(wake_all(s, list, WakeReason::Signaled)),
+            Some(list) => Ok(wake_one(s, list, WakeReason::Signaled).is_some() as usize),
+            None => Err(Error::NoCreatePrivilege),
+        },
+        READ_LOCK | READ_RELOCK | WRITE_LOCK | WRITE_RELOCK => {
+            let lock = frame[1];
+            if writable(s, lock..lock.saturating_add(rwlock::STATE_SIZE)) {
+                let number = frame[0] as u32;
+                let write = number == WRITE_LOCK || number == WRITE_RELOCK;
+                let retry = number == READ_RELOCK || number == WRITE_RELOCK;
+                let deadline = tick(frame[2], frame[3]);
+                unsafe { rwlock::lock_or_block(s, lock, write, deadline, retry) }
+                    .map(|locked| locked as usize)
+            } else {
+                Err(Error::NoCreatePrivilege)
+            }
+        }
+        RWLOCK_UNLOCK => {
+            let lock = frame[1];
+            if writable(s, lock..lock.saturating_add(rwlock::STATE_SIZE)) {
+                unsafe { rwlock::unlock(s, lock, frame[2] == 1) };
+                Ok(0)
+            } else {
+                Err(Error::NoCreatePrivilege)
+            }
+        }
+        BARRIER_WAIT | BARRIER_REWAIT => {
+            let barrier = frame[1];
+            if writable(s, barrier..barrier.saturating_add(size_of::<Barrier>())) {
+                let retry = frame[0] as u32 == BARRIER_REWAIT;
+                unsafe { barrier::wait_or_block(s, barrier, tick(frame[2], frame[3]), retry) }
+            } else {
+                Err(Error::NoCreatePrivilege)
+            }
+        }
+        #[cfg(feature = "tls")]
+        TLS_GET => Ok(s.threads[s.idx].tls.get(frame[1]).copied().unwrap_or(0)),
+        #[cfg(feature = "tls")]
+        TLS_SET => match s.threads[s.idx].tls.get_mut(frame[1]) {
+            Some(slot) => {
+                *slot = frame[2];
+                Ok(0)
+            }
+            None => Err(Error::NoSuchThread),
+        },
+        // not a kernel call
+        _ => Err(Error::NoSuchThread),
+    };
+    // let a thread made ready, or a switch away from a caller which blocked, take place
+    schedule_in(s);
+    frame[0] = encode(result);
+}
+
+fn handle_spawn(s: &mut ThreadsState, request: usize) -> Result<usize, Error> {
+    let caller = &s.threads[s.idx];
+    // the request must be on the caller's stack, it cannot point the kernel elsewhere
+    let caller_stack = stack_bounds(caller.stack_base, caller.stack_words);
+    if request < caller_stack.start
+        || request.saturating_add(size_of::<SpawnRequest>()) > caller_stack.end
+    {
+        return Err(Error::NoCreatePrivilege);
+    }
+    let request = unsafe { &*(request as *const SpawnRequest) };
+    if caller.privileged == 0 && (request.privileged || request.priority > caller.base_priority) {
+        #[cfg(feature = "defmt")]
+        defmt::trace!("create thread: thread {} asked for more than it has", s.idx);
+        return Err(Error::NoCreatePrivilege);
+    }
+    // the stack must not be in use by a thread, including the caller
+    let stack = stack_bounds(request.stack as usize, request.words as u32);
+    if !writable(s, stack.clone()) {
+        return Err(Error::NoCreatePrivilege);
+    }
+    let in_use = (0..s.threads.len()).any(|i| {
+        let other = stack_bounds(s.threads[i].stack_base, s.threads[i].stack_words);
+        !s.slot_available(i) && stack.start < other.end && other.start < stack.end
+    });
+    if in_use {
+        return Err(Error::StackInUse);
+    }
+    let stack = unsafe { core::slice::from_raw_parts_mut(request.stack, request.words) };
+    #[cfg(feature = "mpu-isolation")]
+    let regions = caller.regions;
+    let thread = insert_thread(
+        s,
+        stack,
+        request.pc,
+        request.r0,
+        request.r1,
+        request.priority,
+        request.privileged,
+    )?;
+    // the new thread may access what its creator may, no more
+    #[cfg(feature = "mpu-isolation")]
+    {
+        s.threads[thread.0].regions = regions;
+    }
+    Ok(thread.0)
+}
+
+/// `CONDVAR_WAIT` or `WAIT_WORD` for the `WaitRequest` at address `request`
+fn handle_wait(s: &mut ThreadsState, number: u32, request: usize) -> Result<usize, Error> {
+    let request: &WaitRequest = match unsafe { object(s, request) } {
+        Some(request) => request,
+        None => return Err(Error::NoCreatePrivilege),
+    };
+    if number == CONDVAR_WAIT {
+        let lock = request.object;
+        if !writable(s, lock..lock.saturating_add(mutex::STATE_SIZE)) {
+            return Err(Error::NoCreatePrivilege);
+        }
+        unsafe { mutex::unlock_held(s, lock) };
+    } else {
+        match unsafe { object::<usize>(s, request.object) } {
+            // changed since the caller looked at it, there is no need to wait
+            Some(word) if *word != request.value => return Ok(WakeReason::Signaled as usize),
+            Some(_) => {}
+            None => return Err(Error::NoCreatePrivilege),
+        }
+    }
+    let list = match unsafe { object::<WaitList>(s, request.list) } {
+        Some(list) => list,
+        None => return Err(Error::NoCreatePrivilege),
+    };
+    let blocked = block_enter(s, request.deadline, |_, idx| list.insert(idx));
+    Ok(blocked.map_or(0, |reason| reason as usize))
+}
+
+/// The `T` at address `addr`, passed by the caller, if it is aligned and the kernel may write
+/// it for the caller, see `writable`
+///
+/// # Safety
+/// If there is a `T` at `addr`, nothing else may use it while the result lives
+unsafe fn object<'a, T>(s: &ThreadsState, addr: usize) -> Option<&'a mut T> {
+    let range = addr..addr.saturating_add(size_of::<T>());
+    if addr & (align_of::<T>() - 1) == 0 && writable(s, range) {
+        Some(&mut *(addr as *mut T))
+    } else {
+        None
+    }
+}
+
+/// Address range of a stack area of `words` u32's at `base`
+fn stack_bounds(base: usize, words: u32) -> Range<usize> {
+    base..base.saturating_add((words as usize).saturating_mul(4))
+}
+
+/// Whether the kernel may write the address range `range` for the calling thread: it must
+/// leave the kernel state alone, and with `mpu-isolation` be writable by the caller itself
+fn writable(s: &ThreadsState, range: Range<usize>) -> bool {
+    #[cfg(feature = "mpu-isolation")]
+    if !crate::mpu::may_write(&s.threads[s.idx], range.clone()) {
+        return false;
+    }
+    #[cfg(not(feature = "mpu-isolation"))]
+    let _ = s;
+    outside_kernel(range)
+}
+
+/// Whether the address range `range`, passed by a thread, leaves the kernel state alone
+fn outside_kernel(range: Range<usize>) -> bool {
+    #[cfg(not(feature = "rp2040-amp"))]
+    let (kernel, size) = (KERNEL.as_ptr() as usize, size_of::<ThreadsState>());
+    // nor the kernel state of the other core
+    #[cfg(feature = "rp2040-amp")]
+    let (kernel, size) = (KERNELS.as_ptr() as usize, size_of_val(&KERNELS));
+    range.start <= range.end && (range.end <= kernel || kernel + size <= range.start)
+}
@@ -0,0 +1,258 @@
+//! SEGGER SystemView recording over RTT, with the `trace-systemview` feature.
+//!
+//! Scheduling events reported to the trace module are also encoded as SystemView packets and
+//! written to RTT channel 1, where SystemView reads them through a J-Link. Thread ids are used
+//! as SystemView task ids; the idle thread is not a task, switching to it is shown as idle
+//! time. Timestamps are CPU cycles, see the `cycles` module.
+//!
+//! Recording starts with `start_systemview`, and is restarted whenever SystemView sends its
+//! start command, which resends the system description and the task list. Packets which do
+//! not fit in the RTT buffer are dropped and reported to SystemView as an overflow.
+
+use crate::cycles;
+use crate::rtt::{self, SYSVIEW_CHANNEL};
+use crate::trace::{TraceEvent, TraceKind};
+use crate::{with_state, ThreadStatus, ThreadsState};
+
+const EVTID_OVERFLOW: u8 = 1;
+const EVTID_TASK_START_EXEC: u8 = 4;
+const EVTID_TASK_START_READY: u8 = 6;
+const EVTID_TASK_STOP_READY: u8 = 7;
+const EVTID_TASK_CREATE: u8 = 8;
+const EVTID_TASK_INFO: u8 = 9;
+const EVTID_TRACE_START: u8 = 10;
+const EVTID_TRACE_STOP: u8 = 11;
+const EVTID_SYSTIME_CYCLES: u8 = 12;
+const EVTID_SYSDESC: u8 = 14;
+const EVTID_IDLE: u8 = 17;
+const EVTID_INIT: u8 = 24;
+const EVTID_NUMMODULES: u8 = 27;
+const EVTID_TASK_TERMINATE: u8 = 29;
+
+const COMMAND_START: u8 = 1;
+const COMMAND_STOP: u8 = 2;
+const COMMAND_GET_SYSTIME: u8 = 3;
+const COMMAND_GET_TASKLIST: u8 = 4;
+const COMMAND_GET_SYSDESC: u8 = 5;
+const COMMAND_GET_NUMMODULES: u8 = 6;
+const COMMAND_GET_MODULEDESC: u8 = 7;
+
+/// Cause reported with TASK_STOP_READY
+const STOP_SLEEP: u32 = 1;
+const STOP_BLOCK: u32 = 2;
+
+const SYSDESC: &str = "N=cortexm-threads,O=cortexm-threads";
+
+/// Longest name sent in task info packets
+const MAX_NAME: usize = 32;
+const MAX_PACKET: usize = MAX_NAME + 32;
+
+/// Recorder state, kept in the kernel state
+pub(crate) struct State {
+    recording: bool,
+    cpu_hz: u32,
+    /// timestamp of the last packet sent
+    last: u32,
+    /// packets dropped since the last overflow packet
+    dropped: u32,
+}
+
+impl State {
+    pub(crate) const fn new() -> Self {
+        State {
+            recording: false,
+            cpu_hz: 0,
+            last: 0,
+            dropped: 0,
+        }
+    }
+}
+
+struct Packet {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Packet {
+    fn new(id: u8) -> Self {
+        let mut packet = Packet {
+            buf: [0; MAX_PACKET],
+            len: 0,
+        };
+        packet.buf[0] = id;
+        packet.len = 1;
+        packet
+    }
+
+    /// Append `value` as a variable length integer, 7 bits per byte, low bits first
+    fn u32(mut self, mut value: u32) -> Self {
+        while value > 0x7f {
+            self.buf[self.len] = (value as u8) | 0x80;
+            self.len += 1;
+            value >>= 7;
+        }
+        self.buf[self.len] = value as u8;
+        self.len += 1;
+        self
+    }
+
+    /// Append `s`, truncated to MAX_NAME bytes, preceded by its length
+    fn str(mut self, s: &str) -> Self {
+        let bytes = &s.as_bytes()[..s.len().min(MAX_NAME)];
+        self.buf[self.len] = bytes.len() as u8;
+        self.len += 1;
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        self
+    }
+}
+
+/// Start recording, with timestamps counting `cpu_hz` cycles per second. Events of threads
+/// created earlier are not replayed, but the task list sent at start includes them.
+///
+/// # Example
+/// ```
+/// start_systemview(64_000_000);
+/// let _ = create_thread(STACK1.take().unwrap(), handler1);
+/// init();
+/// ```
+pub fn start_systemview(cpu_hz: u32) {
+    with_state(|s| {
+        s.systemview.cpu_hz = cpu_hz;
+        rtt::init();
+        cycles::enable();
+        start(s);
+    });
+}
+
+/// Encode a scheduling event
+pub(crate) fn record(s: &mut ThreadsState, event: &TraceEvent) {
+    poll(s);
+    if !s.systemview.recording {
+        return;
+    }
+    let id = event.thread as u32;
+    match event.kind {
+        TraceKind::Create => {
+            send(s, Packet::new(EVTID_TASK_CREATE).u32(id));
+            task_info(s, event.thread as usize);
+            send(s, Packet::new(EVTID_TASK_START_READY).u32(id));
+        }
+        TraceKind::Exit => send(s, Packet::new(EVTID_TASK_TERMINATE).u32(id)),
+        TraceKind::Sleep => send(
+            s,
+            Packet::new(EVTID_TASK_STOP_READY).u32(id).u32(STOP_SLEEP),
+        ),
+        TraceKind::Block => send(
+            s,
+            Packet::new(EVTID_TASK_STOP_READY).u32(id).u32(STOP_BLOCK),
+        ),
+        TraceKind::Wake => send(s, Packet::new(EVTID_TASK_START_READY).u32(id)),
+        TraceKind::Switch if id == 0 => send(s, Packet::new(EVTID_IDLE)),
+        TraceKind::Switch => send(s, Packet::new(EVTID_TASK_START_EXEC).u32(id)),
+        // a yield only shows as the switch it causes
+        TraceKind::Yield => {}
+        TraceKind::Priority => {}
+    }
+}
+
+/// Send name and priority of thread `idx`, e.g. after it was renamed
+pub(crate) fn task_info(s: &mut ThreadsState, idx: usize) {
+    if !s.systemview.recording || idx == 0 {
+        return;
+    }
+    let tcb = s.threads[idx];
+    send(
+        s,
+        Packet::new(EVTID_TASK_INFO)
+            .u32(idx as u32)
+            .u32(tcb.priority as u32)
+            .str(tcb.name),
+    );
+}
+
+/// Handle commands sent by SystemView
+fn poll(s: &mut ThreadsState) {
+    while let Some(command) = rtt::read_byte(SYSVIEW_CHANNEL) {
+        match command {
+            COMMAND_START => start(s),
+            COMMAND_STOP => {
+                send(s, Packet::new(EVTID_TRACE_STOP));
+                s.systemview.recording = false;
+            }
+            COMMAND_GET_SYSTIME => systime(s),
+            COMMAND_GET_TASKLIST => task_list(s),
+            COMMAND_GET_SYSDESC => send(s, Packet::new(EVTID_SYSDESC).str(SYSDESC)),
+            COMMAND_GET_NUMMODULES => send(s, Packet::new(EVTID_NUMMODULES).u32(0)),
+            COMMAND_GET_MODULEDESC => {
+                // no modules, discard the module index
+                let _ = rtt::read_byte(SYSVIEW_CHANNEL);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn start(s: &mut ThreadsState) {
+    s.systemview.recording = true;
+    s.systemview.dropped = 0;
+    s.systemview.last = cycles::timestamp(s.ticks);
+    // sync bytes let SystemView find the start of the first packet
+    let _ = rtt::write(SYSVIEW_CHANNEL, &[0; 10]);
+    send(s, Packet::new(EVTID_TRACE_START));
+    let cpu_hz = s.systemview.cpu_hz;
+    // timestamp frequency, CPU frequency, RAM base and shift of task ids
+    send(
+        s,
+        Packet::new(EVTID_INIT)
+            .u32(cpu_hz)
+            .u32(cpu_hz)
+            .u32(0)
+            .u32(0),
+    );
+    send(s, Packet::new(EVTID_SYSDESC).str(SYSDESC));
+    systime(s);
+    task_list(s);
+}
+
+fn systime(s: &mut ThreadsState) {
+    let now = cycles::timestamp(s.ticks);
+    send(s, Packet::new(EVTID_SYSTIME_CYCLES).u32(now));
+}
+
+fn task_list(s: &mut ThreadsState) {
+    for idx in 1..s.threads.len() {
+        if s.threads[idx].status != ThreadStatus::Free {
+            task_info(s, idx);
+        }
+    }
+}
+
+/// Send a packet, preceded by an overflow packet if earlier ones were dropped
+fn send(s: &mut ThreadsState, packet: Packet) {
+    let ticks = s.ticks;
+    let sv = &mut s.systemview;
+    if sv.dropped > 0 {
+        let overflow = Packet::new(EVTID_OVERFLOW).u32(sv.dropped);
+        if !send_raw(sv, ticks, overflow) {
+            sv.dropped += 1;
+            return;
+        }
+        sv.dropped = 0;
+    }
+    if !send_raw(sv, ticks, packet) {
+        sv.dropped += 1;
+    }
+}
+
+/// Append the time since the last packet and write the packet to RTT
+fn send_raw(sv: &mut State, ticks: u64, packet: Packet) -> bool {
+    let now = cycles::timestamp(ticks);
+    let packet = packet.u32(now.wrapping_sub(sv.last));
+    if rtt::write(SYSVIEW_CHANNEL, &packet.buf[..packet.len]) {
+        sv.last = now;
+        true
+    } else {
+        false
+    }
+}
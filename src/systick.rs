@@ -0,0 +1,78 @@
+//! Kernel tick from the SysTick timer, with the `cortex-m` feature.
+//!
+//! `init_with_systick` replaces the setup every application otherwise repeats before `init`:
+//! the reload value for the tick rate and the clock source.
+//!
+//! With the `systick-handler` feature the kernel also defines the SysTick exception handler
+//! with cortex-m-rt, so the application neither defines it nor forwards it to `SysTick()`;
+//! code to run on every tick goes in a hook set with `set_tick_hook`.
+
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+#[cfg(feature = "systick-handler")]
+use cortex_m_rt::exception;
+
+#[cfg(feature = "systick-handler")]
+use crate::with_state;
+use crate::{init, set_core_hz, set_tick_hz};
+
+/// Largest value of the 24-bit SysTick reload register
+const MAX_RELOAD: u32 = 0x00FF_FFFF;
+
+/// Configure `syst` to tick `tick_hz` times per second from the `core_hz` processor clock,
+/// record `core_hz` for `delay_us`, then call `init`, which also sets the SysTick and PendSV
+/// priorities. The kernel's `SysTick` function is the SysTick exception handler, so the
+/// application must not define another one. Panics if the tick rate cannot be reached with
+/// the 24-bit reload register.
+///
+/// # Example
+/// ```
+/// let cp = cortex_m::Peripherals::take().unwrap();
+/// let _ = create_thread(STACK1.take().unwrap(), task1);
+/// init_with_systick(cp.SYST, 1_000, 8_000_000);
+/// ```
+pub fn init_with_systick(mut syst: SYST, tick_hz: u32, core_hz: u32) -> ! {
+    let cycles = core_hz.checked_div(tick_hz).unwrap_or(0);
+    assert!(
+        cycles > 1 && cycles - 1 <= MAX_RELOAD,
+        "SysTick cannot tick at this rate"
+    );
+    set_tick_hz(tick_hz);
+    set_core_hz(core_hz);
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(cycles - 1);
+    syst.clear_current();
+    // SysTick ignores ticks until init is done
+    syst.enable_interrupt();
+    syst.enable_counter();
+    init()
+}
+
+/// Call `hook` from the SysTick exception handler on every tick, before the kernel advances
+/// the tick count. It runs as an interrupt handler, so it may signal threads, e.g. with
+/// `wake` or `Producer::push`, but must not block. It counts against the callback budget.
+///
+/// # Example
+/// ```
+/// fn tick() {
+///     WATCHDOG.feed();
+/// }
+///
+/// set_tick_hook(tick);
+/// init_with_systick(cp.SYST, 1_000, 8_000_000);
+/// ```
+#[cfg(feature = "systick-handler")]
+pub fn set_tick_hook(hook: fn()) {
+    with_state(|s| s.tick_hook = Some(hook));
+}
+
+#[cfg(feature = "systick-handler")]
+#[exception]
+fn SysTick() {
+    // run outside the critical section, so the hook can signal threads
+    if let Some((hook, budget)) = with_state(|s| s.tick_hook.map(|hook| (hook, s.callback_budget)))
+    {
+        crate::budget::run_callback(&budget, "tick hook", hook);
+    }
+    crate::SysTick();
+}
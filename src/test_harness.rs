@@ -0,0 +1,103 @@
+//! Collect pass/fail results from test threads and end the QEMU run when all are done.
+//!
+//! Each test is a thread created with `create_test_thread`, which ends by calling `pass()`
+//! or `fail()`. When the last test thread reports, the run is terminated with a semihosting
+//! exit call, so QEMU exits with status 0 if every test passed and 1 otherwise.
+//!
+//! Example:
+//! ```
+//! static STACK1: Stack<512> = Stack::new();
+//! let _ = create_test_thread(STACK1.take().unwrap(), || {
+//!     sleep(10);
+//!     match thread_info(get_thread_id()) {
+//!         Some(info) if info.wake_reason == WakeReason::Timeout => pass(),
+//!         _ => fail(),
+//!     }
+//! });
+//! init();
+//! ```
+
+use crate::{create_thread_with_config, exit, Error, Mutex, ThreadHandle};
+
+// semihosting exit reasons, QEMU exits with 0 for the first and 1 for the second
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+const ADP_STOPPED_RUN_TIME_ERROR: u32 = 0x20023;
+
+extern "C" {
+    fn __CORTEXM_THREADS_semihosting_exit(reason: u32);
+}
+
+struct Results {
+    expected: usize,
+    passed: usize,
+    failed: usize,
+}
+
+// a mutex, as test threads may be unprivileged
+static RESULTS: Mutex<Results> = Mutex::new(Results {
+    expected: 0,
+    passed: 0,
+    failed: 0,
+});
+
+/// Create a test thread with default configuration. The run ends once every test thread
+/// has called `pass()` or `fail()`.
+pub fn create_test_thread(
+    stack: &'static mut [u32],
+    handler_fn: fn() -> !,
+) -> Result<ThreadHandle, Error> {
+    create_test_thread_with_config(stack, handler_fn, 0x00, false)
+}
+
+/// Create a test thread with explicit configuration, see `create_thread_with_config`
+pub fn create_test_thread_with_config(
+    stack: &'static mut [u32],
+    handler_fn: fn() -> !,
+    priority: u8,
+    privileged: bool,
+) -> Result<ThreadHandle, Error> {
+    let id = create_thread_with_config(stack, handler_fn, priority, privileged)?;
+    RESULTS.lock().expected += 1;
+    Ok(id)
+}
+
+/// Report success of the current test thread and exit it
+pub fn pass() -> ! {
+    finish(true)
+}
+
+/// Report failure of the current test thread and exit it
+pub fn fail() -> ! {
+    finish(false)
+}
+
+/// Number of (passed, failed) tests reported so far
+pub fn results() -> (usize, usize) {
+    let results = RESULTS.lock();
+    (results.passed, results.failed)
+}
+
+fn finish(passed: bool) -> ! {
+    let (done, failed) = {
+        let mut results = RESULTS.lock();
+        if passed {
+            results.passed += 1;
+        } else {
+            results.failed += 1;
+        }
+        (
+            results.passed + results.failed >= results.expected,
+            results.failed,
+        )
+    };
+    if done {
+        unsafe {
+            __CORTEXM_THREADS_semihosting_exit(if failed == 0 {
+                ADP_STOPPED_APPLICATION_EXIT
+            } else {
+                ADP_STOPPED_RUN_TIME_ERROR
+            });
+        }
+    }
+    exit()
+}
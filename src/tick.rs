@@ -0,0 +1,217 @@
+//! Timers driving the kernel tick.
+//!
+//! The kernel counts ticks in `SysTick()`, whichever interrupt handler calls it. SysTick is
+//! the default source; a low-power timer or RTC which keeps counting in STOP mode can drive
+//! the tick instead, by calling `SysTick()` from its own interrupt handler, and be registered
+//! with `set_tick_source` so the kernel can also stop and restart it, e.g. for tickless idle.
+//! On ARMv6-M, CPU usage and SystemView timestamps are still derived from SysTick.
+//!
+//! `set_tick_rate` changes the tick rate at run time, e.g. 10 Hz through quiet periods and
+//! 1 kHz during bursts of activity, keeping the time left before each thread's wake tick.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{tick_hz, time, with_state, NO_DEADLINE};
+
+/// SysTick control and status register
+const SYST_CSR: u32 = 0xE000E010;
+/// SysTick reload value register
+const SYST_RVR: u32 = 0xE000E014;
+/// SysTick current value register
+const SYST_CVR: u32 = 0xE000E018;
+/// Interrupt control and state register, PENDSTSET is bit 26 and PENDSTCLR bit 25
+const ICSR: u32 = 0xE000ED04;
+/// Largest value of the 24-bit SysTick reload register
+const MAX_RELOAD: u32 = 0x00FF_FFFF;
+
+/// Timer whose interrupt handler calls `SysTick()` once per tick. The kernel calls its
+/// functions from thread mode with interrupts disabled.
+pub trait TickSource: Sync {
+    /// Stop the periodic tick and arrange a single interrupt in `ticks` ticks, or fewer if the
+    /// timer cannot count that far. Returns the number of ticks arranged, or 0 to keep
+    /// ticking, which sources without a one-shot mode always do.
+    fn stop(&self, ticks: u64) -> u64 {
+        let _ = ticks;
+        0
+    }
+
+    /// Restart the periodic tick after `stop`, and return the number of whole ticks elapsed
+    /// since. Clear the interrupt arranged by `stop` if it is pending: its tick is part of
+    /// the returned count, and must not reach `SysTick()` as well.
+    fn start(&self) -> u64 {
+        0
+    }
+
+    /// Change the tick rate from `old_hz` to `hz` ticks per second. Returns false, leaving the
+    /// rate as it is, if the timer cannot tick at `hz`, which sources without a rate setting
+    /// always do.
+    fn set_rate(&self, old_hz: u32, hz: u32) -> bool {
+        let _ = (old_hz, hz);
+        false
+    }
+
+    /// Whether the timer counts with its interrupt enabled, checked by `check_config`. None
+    /// if unknown, which is the default.
+    fn enabled(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// The SysTick timer, set up by the application or by `init_with_systick`. It stops in deep
+/// sleep on most parts.
+pub struct SysTickSource;
+
+/// Cycles per tick, saved while SysTick counts a longer period
+static CYCLES_PER_TICK: AtomicU32 = AtomicU32::new(0);
+/// Ticks programmed by `stop`
+static STOPPED_TICKS: AtomicU32 = AtomicU32::new(0);
+/// SysTick current value when it was stopped, the cycles left in the tick running then
+static FIRST_CYCLES: AtomicU32 = AtomicU32::new(0);
+
+impl TickSource for SysTickSource {
+    /// Let SysTick count up to `ticks` ticks before its next interrupt, keeping the phase of
+    /// the current tick. Declines if a tick is already pending, which would be lost otherwise.
+    fn stop(&self, ticks: u64) -> u64 {
+        unsafe {
+            if ptr::read_volatile(ICSR as *const u32) & 1 << 26 != 0 {
+                return 0;
+            }
+            let cycles = ptr::read_volatile(SYST_RVR as *const u32) + 1;
+            let first = ptr::read_volatile(SYST_CVR as *const u32);
+            let ticks = ticks.min(((MAX_RELOAD + 1 - first) / cycles) as u64 + 1) as u32;
+            CYCLES_PER_TICK.store(cycles, Ordering::Relaxed);
+            STOPPED_TICKS.store(ticks, Ordering::Relaxed);
+            FIRST_CYCLES.store(first, Ordering::Relaxed);
+            // writing the current value restarts the count from the new reload value and
+            // clears COUNTFLAG
+            let reload = (first + (ticks - 1) * cycles).max(2) - 1;
+            ptr::write_volatile(SYST_RVR as *mut u32, reload);
+            ptr::write_volatile(SYST_CVR as *mut u32, 0);
+            ticks as u64
+        }
+    }
+
+    /// Go back to one SysTick interrupt per tick, and count the ticks elapsed since `stop`.
+    /// An early wakeup loses the fraction of the tick it happened in.
+    fn start(&self) -> u64 {
+        let cycles = CYCLES_PER_TICK.load(Ordering::Relaxed);
+        let ticks = STOPPED_TICKS.load(Ordering::Relaxed);
+        let first = FIRST_CYCLES.load(Ordering::Relaxed);
+        unsafe {
+            let counted = ptr::read_volatile(SYST_CSR as *const u32) & 1 << 16 != 0;
+            let reload = ptr::read_volatile(SYST_RVR as *const u32);
+            let current = ptr::read_volatile(SYST_CVR as *const u32);
+            ptr::write_volatile(SYST_RVR as *mut u32, cycles - 1);
+            ptr::write_volatile(SYST_CVR as *mut u32, 0);
+            // the elapsed ticks are counted here, not by the pending SysTick exception
+            ptr::write_volatile(ICSR as *mut u32, 1 << 25);
+            if counted {
+                return ticks as u64;
+            }
+            let elapsed = reload - current;
+            if elapsed < first {
+                0
+            } else {
+                ((elapsed - first) / cycles + 1) as u64
+            }
+        }
+    }
+
+    /// Scale the reload value by `old_hz / hz`, so the core clock need not be known. The
+    /// next tick comes a full tick of the new rate later.
+    fn set_rate(&self, old_hz: u32, hz: u32) -> bool {
+        unsafe {
+            let cycles = ptr::read_volatile(SYST_RVR as *const u32) as u64 + 1;
+            let cycles = cycles * old_hz as u64 / hz as u64;
+            if cycles < 2 || cycles - 1 > MAX_RELOAD as u64 {
+                return false;
+            }
+            ptr::write_volatile(SYST_RVR as *mut u32, cycles as u32 - 1);
+            ptr::write_volatile(SYST_CVR as *mut u32, 0);
+        }
+        true
+    }
+
+    /// ENABLE and TICKINT of the control register
+    fn enabled(&self) -> Option<bool> {
+        let csr = unsafe { ptr::read_volatile(SYST_CSR as *const u32) };
+        Some(csr & 0b11 == 0b11)
+    }
+}
+
+/// Register `source` as the timer driving the tick, instead of SysTick. Its interrupt handler
+/// must call `SysTick()`, and the SysTick interrupt should be left disabled.
+///
+/// # Example
+/// ```
+/// struct Lptim;
+///
+/// impl TickSource for Lptim {
+///     fn stop(&self, ticks: u64) -> u64 {
+///         let ticks = ticks.min(LPTIM_MAX_TICKS);
+///         lptim_one_shot(ticks as u32);
+///         set_sleepdeep(true);
+///         ticks
+///     }
+///
+///     fn start(&self) -> u64 {
+///         set_sleepdeep(false);
+///         lptim_clear_interrupt();
+///         lptim_periodic() as u64
+///     }
+/// }
+///
+/// static LPTIM: Lptim = Lptim;
+///
+/// #[interrupt]
+/// fn LPTIM1() {
+///     lptim_clear_interrupt();
+///     SysTick();
+/// }
+///
+/// set_tick_source(&LPTIM);
+/// ```
+pub fn set_tick_source(source: &'static dyn TickSource) {
+    with_state(|s| s.tick_source = source);
+}
+
+/// Reprogram the tick source to tick `hz` times per second, like `set_tick_hz` for
+/// conversions: `uptime_ms` keeps the time counted so far and counts the following ticks at
+/// the new rate. Sleeping and blocked threads keep the time left until their wake tick,
+/// rounded up to whole ticks of the new rate. Tick counts held elsewhere are not rescaled, so
+/// they last longer at a slower rate: the periods and next release ticks of `Periodic`,
+/// `PhaseMember` and periodic threads, budgets, and deadlines computed by the application.
+/// Returns false, changing nothing, if the source cannot tick at `hz`.
+///
+/// # Example
+/// ```
+/// // nothing to do for a while, wake up ten times per second
+/// set_tick_rate(10);
+/// wait_for_button();
+/// set_tick_rate(1_000);
+/// ```
+pub fn set_tick_rate(hz: u32) -> bool {
+    let hz = hz.max(1);
+    with_state(|s| {
+        let old = tick_hz();
+        if hz == old {
+            return true;
+        }
+        if !s.tick_source.set_rate(old, hz) {
+            return false;
+        }
+        let now = s.ticks;
+        time::set_rate(hz, now);
+        for tcb in s.threads.iter_mut() {
+            if tcb.wake_tick != NO_DEADLINE && tcb.wake_tick > now {
+                let left = (tcb.wake_tick - now) as u128 * hz as u128;
+                let left = left
+                    .div_ceil(old as u128)
+                    .min((NO_DEADLINE - 1 - now) as u128);
+                tcb.wake_tick = now + left as u64;
+            }
+        }
+        true
+    })
+}
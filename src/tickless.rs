@@ -0,0 +1,64 @@
+//! Tickless idle, with the `tickless` feature.
+//!
+//! Sleeping and blocked threads wait for an absolute wake tick, so when no thread is ready the
+//! idle thread knows how long nothing will happen: until the nearest wake tick, or until an
+//! interrupt makes a thread ready. Instead of waking up on every tick in between, it stops
+//! the periodic tick, lets the tick source wake it up at that deadline, and adds the ticks
+//! which elapsed meanwhile when it wakes up.
+//!
+//! The default SysTick source keeps the core in normal sleep: SysTick stops in deep sleep on
+//! most parts. Applications with a low-power timer running in deep sleep register it with
+//! `set_tick_source` and set SLEEPDEEP in its `stop`. With `cpu-usage`, idle periods longer
+//! than a DWT cycle counter wrap are not accounted for correctly.
+
+use crate::arch::{Arch, Port};
+use crate::critical::CriticalSection;
+use crate::{schedule, NO_DEADLINE};
+
+/// Idle periods shorter than this many ticks are spent waiting for the next tick as usual
+const MIN_IDLE_TICKS: u64 = 2;
+
+/// If no thread is ready and none needs to wake up within the next tick, stop the tick and
+/// sleep until the nearest wake tick or an interrupt, whichever comes first. Returns false
+/// without sleeping otherwise. Called by the default idle thread, and meant for custom idle
+/// bodies passed to `init_with_idle`.
+pub fn tickless_idle() -> bool {
+    {
+        let mut cs = CriticalSection::enter();
+        let s = crate::state(&mut cs);
+        // a panicked thread is restarted by the next scheduler run
+        if !s.inited
+            || s.curr != s.next
+            || s.suspended > 0
+            || s.restarts != 0
+            || s.ready.select(0) != 0
+        {
+            return false;
+        }
+        let wake_tick = match s.timeouts.first() {
+            Some(idx) => s.threads[idx].wake_tick,
+            None => NO_DEADLINE,
+        };
+        let idle_ticks = wake_tick.saturating_sub(s.ticks);
+        if idle_ticks < MIN_IDLE_TICKS {
+            return false;
+        }
+        let stopped = s.tick_source.stop(idle_ticks);
+        if stopped == 0 {
+            return false;
+        }
+        if let Some(hooks) = s.sleep_hooks {
+            (hooks.pre)(stopped);
+        }
+        // a pending interrupt ends wfi even with interrupts disabled, it runs once the
+        // elapsed ticks are accounted for
+        Port::wait_for_interrupt();
+        if let Some(hooks) = s.sleep_hooks {
+            (hooks.post)();
+        }
+        s.ticks += s.tick_source.start();
+    }
+    // wake the threads whose wake tick was reached
+    schedule();
+    true
+}
@@ -0,0 +1,140 @@
+//! Conversion between ticks and wall-clock time.
+//!
+//! The kernel only counts `SysTick()` calls; the application tells it how often that happens
+//! with `set_tick_hz`, typically right after configuring the SysTick reload value.
+//!
+//! The rate may change while the kernel runs, see `set_tick_rate`. Each change records the
+//! tick at which it took effect and the uptime at that tick, and `uptime_ms` only converts the
+//! ticks counted since then at the new rate. The record spans several words, which cannot be
+//! written at once: readers take a snapshot between two reads of a sequence number, which is
+//! odd while the record is being written, and try again if it changed.
+
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+use crate::critical::with_critical;
+use crate::ticks;
+
+/// Incremented before and after each update of the rate record below
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+static TICK_HZ: AtomicU32 = AtomicU32::new(1000);
+/// Tick at which `TICK_HZ` took effect, low and high word
+static EPOCH_TICK: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+/// Milliseconds since `init` at `EPOCH_TICK`, low and high word
+static EPOCH_MS: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Set the number of ticks per second, i.e. the rate at which `SysTick()` is called.
+/// Defaults to 1000 (1 ms ticks). The uptime already counted is kept, later ticks count at
+/// the new rate.
+///
+/// # Example
+/// ```
+/// // 8 MHz core clock
+/// syst.set_reload(8_000); // tick every 1 ms
+/// set_tick_hz(1_000);
+/// ```
+pub fn set_tick_hz(hz: u32) {
+    let now = ticks();
+    with_critical(|| set_rate(hz, now));
+}
+
+/// Tick at `hz` ticks per second from tick `now` on. Called with interrupts disabled.
+pub(crate) fn set_rate(hz: u32, now: u64) {
+    let ms = ms_at(now, snapshot());
+    let sequence = SEQUENCE.load(Ordering::Relaxed);
+    SEQUENCE.store(sequence.wrapping_add(1), Ordering::Relaxed);
+    fence(Ordering::Release);
+    TICK_HZ.store(hz.max(1), Ordering::Relaxed);
+    store(&EPOCH_TICK, now);
+    store(&EPOCH_MS, ms);
+    SEQUENCE.store(sequence.wrapping_add(2), Ordering::Release);
+}
+
+/// Rate record: ticks per second, the tick at which the rate took effect, and the uptime in
+/// milliseconds at that tick
+struct Epoch {
+    hz: u32,
+    tick: u64,
+    ms: u64,
+}
+
+/// Consistent copy of the rate record
+fn snapshot() -> Epoch {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        let epoch = Epoch {
+            hz: TICK_HZ.load(Ordering::Relaxed),
+            tick: load(&EPOCH_TICK),
+            ms: load(&EPOCH_MS),
+        };
+        fence(Ordering::Acquire);
+        if sequence & 1 == 0 && SEQUENCE.load(Ordering::Relaxed) == sequence {
+            return epoch;
+        }
+    }
+}
+
+/// Milliseconds since `init` at tick `tick`, at or after the start of `epoch`
+fn ms_at(tick: u64, epoch: Epoch) -> u64 {
+    epoch.ms + (tick - epoch.tick) * 1000 / epoch.hz as u64
+}
+
+fn load(words: &[AtomicU32; 2]) -> u64 {
+    (words[1].load(Ordering::Relaxed) as u64) << 32 | words[0].load(Ordering::Relaxed) as u64
+}
+
+fn store(words: &[AtomicU32; 2], value: u64) {
+    words[0].store(value as u32, Ordering::Relaxed);
+    words[1].store((value >> 32) as u32, Ordering::Relaxed);
+}
+
+/// Number of ticks per second as set with `set_tick_hz`
+pub fn tick_hz() -> u32 {
+    TICK_HZ.load(Ordering::Relaxed)
+}
+
+/// Convert a number of ticks at the current rate to milliseconds, rounding down
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / tick_hz() as u64
+}
+
+/// Convert milliseconds to a number of ticks, rounding up so that sleeping for the result
+/// lasts at least `ms`
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let hz = tick_hz() as u64;
+    (ms * hz).div_ceil(1000)
+}
+
+/// Convert nanoseconds to a number of ticks, rounding up
+#[cfg(any(feature = "embedded-hal", feature = "embedded-hal-02"))]
+pub(crate) fn ns_to_ticks(ns: u64) -> u64 {
+    let hz = tick_hz() as u64;
+    // at most 2^32 ticks per second, the product overflows only after about 136 years
+    (ns.saturating_mul(hz)).div_ceil(1_000_000_000)
+}
+
+/// Sleep for `ticks` ticks, which may be more than `sleep` takes at once
+#[cfg(any(
+    feature = "embedded-hal",
+    feature = "embedded-hal-02",
+    feature = "fugit"
+))]
+pub(crate) fn sleep_ticks(mut ticks: u64) {
+    while ticks > 0 {
+        let chunk = ticks.min(u32::MAX as u64);
+        crate::sleep(chunk as u32);
+        ticks -= chunk;
+    }
+}
+
+/// Milliseconds since `init`, each tick counted at the rate in effect when it happened
+pub fn uptime_ms() -> u64 {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        let epoch = snapshot();
+        let now = ticks();
+        // the rate did not change between the snapshot and the tick count
+        if SEQUENCE.load(Ordering::Acquire) == sequence {
+            return ms_at(now, epoch);
+        }
+    }
+}
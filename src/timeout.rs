@@ -0,0 +1,67 @@
+//! Threads waiting for a wake tick.
+//!
+//! Sleeping threads, and blocked threads with a timeout, are kept in a list sorted by wake
+//! tick, linked through their thread control blocks. The tick handler only looks at the head
+//! of the list to find threads to wake, however many threads wait; the cost of sorting is paid
+//! once, when a thread starts waiting.
+
+use crate::ThreadControlBlock;
+
+/// Sorted list of waiting threads, earliest wake tick first, in arrival order among equal
+/// wake ticks
+pub(crate) struct TimeoutList {
+    /// first thread, 0 if the list is empty: the idle thread never waits
+    head: usize,
+}
+
+impl TimeoutList {
+    pub(crate) const fn new() -> Self {
+        TimeoutList { head: 0 }
+    }
+
+    /// Add thread `idx`, whose wake tick is set
+    pub(crate) fn insert(&mut self, threads: &mut [ThreadControlBlock], idx: usize) {
+        let wake_tick = threads[idx].wake_tick;
+        let mut prev = 0;
+        let mut next = self.head;
+        while next != 0 && threads[next].wake_tick <= wake_tick {
+            prev = next;
+            next = threads[next].timeout_next as usize;
+        }
+        threads[idx].timeout_next = next as u8;
+        if prev == 0 {
+            self.head = idx;
+        } else {
+            threads[prev].timeout_next = idx as u8;
+        }
+    }
+
+    /// Take thread `idx` off the list. Does nothing if it is not on it.
+    pub(crate) fn remove(&mut self, threads: &mut [ThreadControlBlock], idx: usize) {
+        let mut prev = 0;
+        let mut next = self.head;
+        while next != 0 && next != idx {
+            prev = next;
+            next = threads[next].timeout_next as usize;
+        }
+        if next == 0 {
+            return;
+        }
+        let after = threads[idx].timeout_next;
+        if prev == 0 {
+            self.head = after as usize;
+        } else {
+            threads[prev].timeout_next = after;
+        }
+        threads[idx].timeout_next = 0;
+    }
+
+    /// First thread, whose wake tick is the nearest, if any
+    pub(crate) fn first(&self) -> Option<usize> {
+        if self.head == 0 {
+            None
+        } else {
+            Some(self.head)
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Thread-local storage, with the `tls` feature.
+//!
+//! Each thread has `TLS_SLOTS` words of its own, read and written with `tls_get` and
+//! `tls_set`. Library code can keep per-thread state there, e.g. a pointer to an error context,
+//! without a global array indexed by thread id. Slots are cleared to 0 when a thread is created
+//! or restarted. Unprivileged threads reach their slots through kernel calls, see `svc`.
+
+use crate::{svc, with_state};
+
+/// Number of storage slots of each thread
+pub const TLS_SLOTS: usize = 4;
+
+/// Store `value` in slot `slot` of the current thread. Called from an interrupt handler, it
+/// uses the slots of the interrupted thread.
+///
+/// # Panics
+/// If `slot` is `TLS_SLOTS` or more
+///
+/// # Example
+/// ```
+/// const ERROR_CONTEXT: usize = 0;
+/// tls_set(ERROR_CONTEXT, &CONTEXT as *const _ as usize);
+/// ```
+pub fn tls_set(slot: usize, value: usize) {
+    if svc::from_unprivileged_thread() {
+        assert!(slot < TLS_SLOTS, "no such thread-local slot");
+        return svc::tls_set(slot, value);
+    }
+    with_state(|s| {
+        let idx = s.idx;
+        s.threads[idx].tls[slot] = value;
+    });
+}
+
+/// Value stored in slot `slot` of the current thread, 0 if none was stored
+///
+/// # Panics
+/// If `slot` is `TLS_SLOTS` or more
+pub fn tls_get(slot: usize) -> usize {
+    if svc::from_unprivileged_thread() {
+        assert!(slot < TLS_SLOTS, "no such thread-local slot");
+        return svc::tls_get(slot);
+    }
+    with_state(|s| s.threads[s.idx].tls[slot])
+}
@@ -0,0 +1,170 @@
+//! Binary trace of scheduling events.
+//!
+//! With the `trace` feature, the kernel reports every event that changes which threads can
+//! run (creation, exit, sleep, block, wake, yield, priority change) and every thread switch
+//! to a sink registered with `set_trace_sink`. Each event encodes to a fixed size
+//! little-endian record, so a log captured on the target can be stored as raw bytes and fed
+//! to the `replay` module on a host.
+//!
+//! Record layout, `RECORD_SIZE` bytes:
+//!
+//! | offset | size | field                         |
+//! |--------|------|-------------------------------|
+//! | 0      | 8    | tick                          |
+//! | 8      | 1    | kind                          |
+//! | 9      | 1    | thread id                     |
+//! | 10     | 1    | priority of the thread        |
+//! | 11     | 1    | reserved, 0                   |
+//! | 12     | 4    | kind specific argument        |
+
+#[cfg(feature = "trace")]
+use crate::with_state;
+use crate::ThreadsState;
+
+/// Size in bytes of an encoded `TraceEvent`
+pub const RECORD_SIZE: usize = 16;
+
+/// What happened to a thread
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TraceKind {
+    /// thread was created, ready to run
+    Create = 1,
+    /// thread exited
+    Exit = 2,
+    /// thread went to sleep, argument is the low 32 bits of the wake tick
+    Sleep = 3,
+    /// thread blocked on a wait list
+    Block = 4,
+    /// sleeping or blocked thread became ready, argument is the `WakeReason` as u32
+    Wake = 5,
+    /// scheduler picked thread to run next, argument is the id of the previous thread
+    Switch = 6,
+    /// running thread gave up the CPU to threads of equal priority
+    Yield = 7,
+    /// priority of the thread changed to the one in the event, argument is the previous one
+    Priority = 8,
+}
+
+impl TraceKind {
+    fn from_u8(kind: u8) -> Option<TraceKind> {
+        Some(match kind {
+            1 => TraceKind::Create,
+            2 => TraceKind::Exit,
+            3 => TraceKind::Sleep,
+            4 => TraceKind::Block,
+            5 => TraceKind::Wake,
+            6 => TraceKind::Switch,
+            7 => TraceKind::Yield,
+            8 => TraceKind::Priority,
+            _ => return None,
+        })
+    }
+}
+
+/// A single scheduling event
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TraceEvent {
+    pub tick: u64,
+    pub kind: TraceKind,
+    pub thread: u8,
+    pub priority: u8,
+    pub arg: u32,
+}
+
+impl TraceEvent {
+    /// Encode as a `RECORD_SIZE` byte record
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0u8; RECORD_SIZE];
+        buf[0..8].copy_from_slice(&self.tick.to_le_bytes());
+        buf[8] = self.kind as u8;
+        buf[9] = self.thread;
+        buf[10] = self.priority;
+        buf[12..16].copy_from_slice(&self.arg.to_le_bytes());
+        buf
+    }
+
+    /// Decode a record produced by `encode`. Returns None if `buf` is too short or holds an
+    /// unknown event kind.
+    pub fn decode(buf: &[u8]) -> Option<TraceEvent> {
+        if buf.len() < RECORD_SIZE {
+            return None;
+        }
+        let mut tick = [0u8; 8];
+        tick.copy_from_slice(&buf[0..8]);
+        let mut arg = [0u8; 4];
+        arg.copy_from_slice(&buf[12..16]);
+        Some(TraceEvent {
+            tick: u64::from_le_bytes(tick),
+            kind: TraceKind::from_u8(buf[8])?,
+            thread: buf[9],
+            priority: buf[10],
+            arg: u32::from_le_bytes(arg),
+        })
+    }
+}
+
+/// Register the function receiving every trace event. It is called from scheduler context
+/// with interrupts disabled, so it should only copy the event, e.g. into a ring buffer. It
+/// must not call back into the kernel, which panics.
+///
+/// # Example
+/// ```
+/// fn sink(event: &TraceEvent) {
+///     let _ = rtt_channel.write(&event.encode());
+/// }
+/// set_trace_sink(sink);
+/// ```
+#[cfg(feature = "trace")]
+pub fn set_trace_sink(sink: fn(&TraceEvent)) {
+    with_state(|s| s.trace_sink = Some(sink));
+}
+
+/// Report an event of thread `thread` to the trace sink, to SystemView, to the post-mortem
+/// log and to defmt
+#[cfg(any(
+    feature = "trace",
+    feature = "trace-systemview",
+    feature = "trace-ram",
+    feature = "defmt"
+))]
+pub(crate) fn record(s: &mut ThreadsState, kind: TraceKind, thread: usize, arg: u32) {
+    let event = TraceEvent {
+        tick: s.ticks,
+        kind,
+        thread: thread as u8,
+        priority: s.threads[thread].priority,
+        arg,
+    };
+    #[cfg(feature = "trace")]
+    {
+        if let Some(sink) = s.trace_sink {
+            crate::budget::run_callback(&s.callback_budget, "trace sink", || sink(&event));
+        }
+    }
+    #[cfg(feature = "trace-systemview")]
+    crate::systemview::record(s, &event);
+    #[cfg(feature = "trace-ram")]
+    crate::postmortem::record(&event);
+    #[cfg(feature = "defmt")]
+    defmt::trace!(
+        "{} thread {} priority {} arg {} at tick {}",
+        event.kind,
+        event.thread,
+        event.priority,
+        event.arg,
+        event.tick
+    );
+}
+
+/// Report an event of thread `thread` to the trace sink
+#[cfg(not(any(
+    feature = "trace",
+    feature = "trace-systemview",
+    feature = "trace-ram",
+    feature = "defmt"
+)))]
+#[inline(always)]
+pub(crate) fn record(_s: &mut ThreadsState, _kind: TraceKind, _thread: usize, _arg: u32) {}
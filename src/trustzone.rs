@@ -0,0 +1,69 @@
+//! Threads calling secure services on ARMv8-M with TrustZone, with the `trustzone` feature.
+//!
+//! The kernel runs in the non-secure state; PendSV takes the security state to return to from
+//! EXC_RETURN, so thread stacks are the non-secure PSP_NS and MSP_NS. A thread interrupted
+//! while running secure code through an SG veneer also has state on the secure stack, which
+//! only the secure image can save and restore. Threads which call secure services are tagged
+//! with a context handle allocated by the secure image, and on every switch away from or to
+//! such a thread the kernel calls the secure image's save and load functions with it.
+
+use crate::{with_state, Error, ThreadHandle, ThreadStatus, ThreadsState};
+
+/// Secure context functions, kept in the kernel state
+#[derive(Clone, Copy)]
+pub(crate) struct SecureContextOps {
+    save: fn(u32),
+    load: fn(u32),
+}
+
+/// Register the non-secure callable functions saving and loading the secure context of a
+/// thread. They are called from scheduler context with interrupts disabled.
+///
+/// # Example
+/// ```
+/// extern "C" {
+///     // non-secure callable veneers of the secure image
+///     fn secure_context_save(context: u32);
+///     fn secure_context_load(context: u32);
+///     fn secure_context_alloc() -> u32;
+/// }
+///
+/// set_secure_context_ops(
+///     |context| unsafe { secure_context_save(context) },
+///     |context| unsafe { secure_context_load(context) },
+/// );
+/// let crypto = create_thread(STACK1.take().unwrap(), crypto_task)?;
+/// set_secure_context(crypto, unsafe { secure_context_alloc() })?;
+/// ```
+pub fn set_secure_context_ops(save: fn(u32), load: fn(u32)) {
+    with_state(|s| s.secure_context_ops = Some(SecureContextOps { save, load }));
+}
+
+/// Tag thread `thread` as a caller of secure services, whose secure context is `context`. A
+/// context of 0 removes the tag. The secure image owns the context, and should free it once
+/// the thread has exited.
+pub fn set_secure_context(thread: ThreadHandle, context: u32) -> Result<(), Error> {
+    let thread_id = thread.0;
+    with_state(|s| {
+        if thread_id >= s.threads.len() || s.threads[thread_id].status == ThreadStatus::Free {
+            return Err(Error::NoSuchThread);
+        }
+        s.threads[thread_id].secure_context = context;
+        Ok(())
+    })
+}
+
+/// Save the secure context of thread `prev` and load the one of `next`, called when the
+/// scheduler switches between them
+pub(crate) fn switch(s: &ThreadsState, prev: usize, next: usize) {
+    if let Some(ops) = s.secure_context_ops {
+        let saved = s.threads[prev].secure_context;
+        let loaded = s.threads[next].secure_context;
+        if saved != 0 {
+            (ops.save)(saved);
+        }
+        if loaded != 0 {
+            (ops.load)(loaded);
+        }
+    }
+}
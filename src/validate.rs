@@ -0,0 +1,236 @@
+//! Up-front checks of a thread's configuration.
+//!
+//! `create_thread_with_config` stops at the first problem and returns a single `Error`.
+//! `validate_thread` runs every check and collects all issues found in a `ValidationReport`,
+//! which is easier to act on while bringing up a new board or thread layout.
+//!
+//! `check_config` covers the system as a whole: the thread stacks, the PendSV priority and the
+//! tick timer, whose mistakes otherwise show up as hard faults or threads which never switch.
+//! `init` runs it before starting the first thread.
+
+use crate::{
+    create_thread_with_config, exception, with_state, ThreadHandle, ThreadStatus, MIN_STACK_WORDS,
+};
+
+/// A single problem found in a thread's configuration
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Issue {
+    /// all thread slots are in use
+    TooManyThreads,
+    /// stack area has `words` u32's, fewer than the `min` required
+    StackTooSmall { words: u32, min: u32 },
+    /// top of the stack area, at address `top`, is not 8-byte aligned as required by AAPCS
+    StackMisaligned { top: u32 },
+    /// an unprivileged thread can only create unprivileged threads, with at most its own
+    /// priority
+    NoCreatePrivilege,
+    /// MPU guard region at the bottom of the stack area would overlap the initial frame
+    #[cfg(feature = "mpu-stack-guard")]
+    StackTooSmallForGuard,
+    /// stack area of an unprivileged thread is not a valid MPU region, see `MpuRegion::new`
+    #[cfg(feature = "mpu-isolation")]
+    StackNotRegion,
+}
+
+const MAX_ISSUES: usize = 6;
+
+/// All issues found by `validate_thread`
+#[derive(Clone, Copy, Debug)]
+pub struct ValidationReport {
+    issues: [Option<Issue>; MAX_ISSUES],
+    count: usize,
+}
+
+impl ValidationReport {
+    fn new() -> Self {
+        ValidationReport {
+            issues: [None; MAX_ISSUES],
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, issue: Issue) {
+        if self.count < MAX_ISSUES {
+            self.issues[self.count] = Some(issue);
+            self.count += 1;
+        }
+    }
+
+    /// true if no issue was found
+    pub fn is_ok(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Issues found, in the order they were checked
+    pub fn issues(&self) -> impl Iterator<Item = &Issue> {
+        self.issues[..self.count].iter().filter_map(|i| i.as_ref())
+    }
+}
+
+/// Check whether a thread with this stack and configuration can be created, reporting all
+/// issues at once.
+///
+/// # Example
+/// ```
+/// let report = validate_thread(&stack1, 0x01, false);
+/// for issue in report.issues() {
+///     let _ = hprintln!("thread 1: {:?}", issue);
+/// }
+/// ```
+pub fn validate_thread(stack: &[u32], priority: u8, privileged: bool) -> ValidationReport {
+    let mut report = ValidationReport::new();
+    let (slot_free, may_create) = with_state(|handler| {
+        let caller = &handler.threads[handler.idx];
+        (
+            (1..handler.threads.len()).any(|i| handler.threads[i].status == ThreadStatus::Free),
+            // unprivileged threads create unprivileged threads, up to their own priority
+            !handler.inited
+                || caller.privileged != 0
+                || (!privileged && priority <= caller.base_priority),
+        )
+    });
+    if !slot_free {
+        report.push(Issue::TooManyThreads);
+    }
+    if stack.len() < MIN_STACK_WORDS {
+        report.push(Issue::StackTooSmall {
+            words: stack.len() as u32,
+            min: MIN_STACK_WORDS as u32,
+        });
+    }
+    #[cfg(feature = "mpu-stack-guard")]
+    {
+        if stack.len() >= MIN_STACK_WORDS && crate::mpu::check_stack(stack).is_err() {
+            report.push(Issue::StackTooSmallForGuard);
+        }
+    }
+    #[cfg(feature = "mpu-isolation")]
+    {
+        if !privileged && crate::mpu::check_stack_region(stack).is_err() {
+            report.push(Issue::StackNotRegion);
+        }
+    }
+    let top = stack.as_ptr() as usize + stack.len() * 4;
+    if top & 0x7 != 0 {
+        report.push(Issue::StackMisaligned { top: top as u32 });
+    }
+    if !may_create {
+        report.push(Issue::NoCreatePrivilege);
+    }
+    // every u8 priority is valid for the current scheduler, and privileged threads need no
+    // extra resources, so both are only checked against the caller
+    report
+}
+
+/// Words the FPU context adds to a thread's saved context: S0-S15, FPSCR and a reserved word in
+/// the exception frame, and S16-S31 saved by PendSV
+#[cfg(feature = "cortex-m4f")]
+const FPU_CONTEXT_WORDS: u32 = 34;
+
+/// A problem with the system configuration, found by `check_config`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConfigError {
+    /// top of the stack area of `thread`, at address `top`, is not 8-byte aligned
+    StackMisaligned { thread: ThreadHandle, top: u32 },
+    /// stack area of `thread` has `words` u32's, fewer than the `min` it needs once its context
+    /// holds the FPU registers
+    #[cfg(feature = "cortex-m4f")]
+    StackTooSmallForFpu {
+        thread: ThreadHandle,
+        words: u32,
+        min: u32,
+    },
+    /// PendSV has priority `pendsv`, more urgent than `lowest`, the least urgent the core
+    /// implements, so it can switch threads under a running interrupt handler
+    PendSvNotLowest { pendsv: u8, lowest: u8 },
+    /// the tick timer is stopped or its interrupt is disabled, so threads never switch on a
+    /// tick and sleeping threads never wake
+    TickNotRunning,
+}
+
+/// Check the configuration the kernel runs with, and return the first problem found: the
+/// stacks of the threads created so far, other than the idle thread, the PendSV priority, see `set_exception_priorities`,
+/// and whether the tick timer is running, if its `TickSource` tells. Call it once the tick
+/// timer is set up; `init` runs it too.
+///
+/// # Example
+/// ```
+/// if let Err(e) = check_config() {
+///     let _ = hprintln!("bad configuration: {:?}", e);
+/// }
+/// init();
+/// ```
+pub fn check_config() -> Result<(), ConfigError> {
+    check(with_state(|s| s.inited))
+}
+
+/// Body of `check_config`. `programmed` tells whether the exception priorities were
+/// programmed already; before, the ones `init` will program are checked.
+pub(crate) fn check(programmed: bool) -> Result<(), ConfigError> {
+    let lowest = exception::lowest_priority();
+    with_state(|s| {
+        // the idle thread's stack is set up by init, and the idle thread does not use the FPU
+        for idx in 1..s.threads.len() {
+            let tcb = &s.threads[idx];
+            if tcb.status == ThreadStatus::Free {
+                continue;
+            }
+            let top = tcb.stack_base as u32 + tcb.stack_words * 4;
+            if top & 0x7 != 0 {
+                return Err(ConfigError::StackMisaligned {
+                    thread: ThreadHandle(idx),
+                    top,
+                });
+            }
+            #[cfg(feature = "cortex-m4f")]
+            {
+                let min = MIN_STACK_WORDS as u32 + FPU_CONTEXT_WORDS;
+                if tcb.stack_words < min {
+                    return Err(ConfigError::StackTooSmallForFpu {
+                        thread: ThreadHandle(idx),
+                        words: tcb.stack_words,
+                        min,
+                    });
+                }
+            }
+        }
+        let pendsv = match (programmed, s.exception_priorities) {
+            (true, _) => exception::programmed().pendsv,
+            // the core ignores the bits it does not implement
+            (false, Some(priorities)) => priorities.pendsv & lowest,
+            (false, None) => lowest,
+        };
+        if pendsv != lowest {
+            return Err(ConfigError::PendSvNotLowest { pendsv, lowest });
+        }
+        if s.tick_source.enabled() == Some(false) {
+            return Err(ConfigError::TickNotRunning);
+        }
+        Ok(())
+    })
+}
+
+/// Validate the configuration with `validate_thread`, and create the thread if no issue
+/// was found. Returns the id of the created thread.
+pub fn create_thread_checked(
+    stack: &'static mut [u32],
+    handler_fn: fn() -> !,
+    priority: u8,
+    privileged: bool,
+) -> Result<ThreadHandle, ValidationReport> {
+    let report = validate_thread(stack, priority, privileged);
+    if !report.is_ok() {
+        return Err(report);
+    }
+    let (base, len) = (stack.as_ptr(), stack.len());
+    create_thread_with_config(stack, handler_fn, priority, privileged).map_err(|_| {
+        // state changed since validation, e.g. another thread took the last slot. The thread
+        // was not created, so nothing else uses the stack
+        let stack = unsafe { core::slice::from_raw_parts(base, len) };
+        let mut report = validate_thread(stack, priority, privileged);
+        if report.is_ok() {
+            report.push(Issue::TooManyThreads);
+        }
+        report
+    })
+}
@@ -0,0 +1,71 @@
+//! Lists of threads blocked on a kernel object.
+//!
+//! Every blocking primitive keeps its waiters in a `WaitList`. A list is a bit set of thread
+//! ids, so a thread can be on several lists at once, and it picks the thread to wake either by
+//! priority (earliest arrival among equal priorities) or in arrival order. The bit set is a
+//! u32, which is why `MAX_THREADS` is at most 32.
+
+use crate::ThreadControlBlock;
+
+/// Order in which threads waiting on a primitive are woken
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WaitOrder {
+    /// highest priority waiter first, in arrival order among equal priorities
+    Priority,
+    /// first come, first served regardless of priority
+    Fifo,
+}
+
+/// Threads blocked on one kernel object
+#[derive(Clone, Copy)]
+pub struct WaitList {
+    waiters: u32,
+    order: WaitOrder,
+}
+
+impl WaitList {
+    pub(crate) const fn new(order: WaitOrder) -> Self {
+        WaitList { waiters: 0, order }
+    }
+
+    pub(crate) fn contains(&self, idx: usize) -> bool {
+        self.waiters & (1 << idx) != 0
+    }
+
+    pub(crate) fn insert(&mut self, idx: usize) {
+        self.waiters |= 1 << idx;
+    }
+
+    pub(crate) fn remove(&mut self, idx: usize) {
+        self.waiters &= !(1 << idx);
+    }
+
+    /// Thread which should be woken first, if any
+    pub(crate) fn first(&self, threads: &[ThreadControlBlock]) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for idx in 0..threads.len() {
+            if !self.contains(idx) {
+                continue;
+            }
+            best = match best {
+                None => Some(idx),
+                Some(b) => {
+                    let (t, tb) = (&threads[idx], &threads[b]);
+                    let earlier = (t.wait_seq.wrapping_sub(tb.wait_seq) as i32) < 0;
+                    let better = match self.order {
+                        WaitOrder::Priority => {
+                            t.priority > tb.priority || (t.priority == tb.priority && earlier)
+                        }
+                        WaitOrder::Fifo => earlier,
+                    };
+                    if better {
+                        Some(idx)
+                    } else {
+                        Some(b)
+                    }
+                }
+            };
+        }
+        best
+    }
+}
@@ -0,0 +1,154 @@
+//! Deferred interrupt processing.
+//!
+//! Interrupt handlers queue work with `defer`, and a worker thread started with
+//! `start_work_queue` runs it in thread context, in the order it was queued. Handlers only
+//! store a function and its argument, so they stay short, and the work itself can take as
+//! long as it needs and be preempted by other interrupts.
+//!
+//! Work is queued inside a short critical section, so `defer` is safe to call from handlers
+//! of any priority, including nested ones, and from threads.
+
+use core::cell::UnsafeCell;
+
+use crate::critical::CriticalSection;
+use crate::wait::{WaitList, WaitOrder};
+use crate::{
+    block_current, create_thread_with_config, schedule, wake_one, Error, ThreadHandle, WakeReason,
+    NO_DEADLINE,
+};
+
+/// Number of work items which can be queued and not yet started
+pub const WORK_QUEUE_LEN: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Work {
+    f: fn(usize),
+    arg: usize,
+}
+
+struct Queue {
+    items: [Option<Work>; WORK_QUEUE_LEN],
+    /// index of the oldest item
+    head: usize,
+    len: usize,
+    /// worker waiting for work
+    worker: WaitList,
+}
+
+struct SharedQueue(UnsafeCell<Queue>);
+
+unsafe impl Sync for SharedQueue {}
+
+const EMPTY: Queue = Queue {
+    items: [None; WORK_QUEUE_LEN],
+    head: 0,
+    len: 0,
+    worker: WaitList::new(WaitOrder::Fifo),
+};
+
+#[cfg(not(feature = "rp2040-amp"))]
+static QUEUE: SharedQueue = SharedQueue(UnsafeCell::new(EMPTY));
+/// Work queue of each core, see the amp module
+#[cfg(feature = "rp2040-amp")]
+static QUEUES: [SharedQueue; crate::amp::CORES] = [
+    SharedQueue(UnsafeCell::new(EMPTY)),
+    SharedQueue(UnsafeCell::new(EMPTY)),
+];
+
+/// Work queue of the calling core
+#[cfg(not(feature = "rp2040-amp"))]
+fn queue() -> *mut Queue {
+    QUEUE.0.get()
+}
+
+/// Work queue of the calling core
+#[cfg(feature = "rp2040-amp")]
+fn queue() -> *mut Queue {
+    QUEUES[crate::amp::core_id()].0.get()
+}
+
+impl Queue {
+    fn push(&mut self, work: Work) -> bool {
+        if self.len == WORK_QUEUE_LEN {
+            return false;
+        }
+        self.items[(self.head + self.len) % WORK_QUEUE_LEN] = Some(work);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<Work> {
+        if self.len == 0 {
+            return None;
+        }
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % WORK_QUEUE_LEN;
+        self.len -= 1;
+        work
+    }
+}
+
+/// Create the worker thread running deferred work, with `stack` as its stack. It is
+/// privileged, and should have a priority above the threads the work was deferred from, so
+/// work runs as soon as the handler returns.
+///
+/// # Example
+/// ```
+/// static WORKER_STACK: Stack<512> = Stack::new();
+/// let _ = start_work_queue(WORKER_STACK.take().unwrap(), 0xff);
+/// ```
+pub fn start_work_queue(stack: &'static mut [u32], priority: u8) -> Result<ThreadHandle, Error> {
+    create_thread_with_config(stack, worker, priority, true)
+}
+
+/// Queue `f(arg)` to be run by the worker thread. Returns `Error::QueueFull` if
+/// WORK_QUEUE_LEN items are already waiting. Threads calling it must be privileged, see
+/// `svc`.
+///
+/// # Example
+/// ```
+/// fn handle_rx(len: usize) {
+///     parse_packet(&RX_BUF[..len]);
+/// }
+///
+/// #[interrupt]
+/// fn UART0() {
+///     let len = uart.rx_count();
+///     let _ = defer(handle_rx, len);
+/// }
+/// ```
+pub fn defer(f: fn(usize), arg: usize) -> Result<(), Error> {
+    let queue = queue();
+    unsafe {
+        let mut cs = CriticalSection::enter();
+        if !(*queue).push(Work { f, arg }) {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("defer: work queue full");
+            return Err(Error::QueueFull);
+        }
+        wake_one(
+            crate::state(&mut cs),
+            &mut (*queue).worker,
+            WakeReason::Signaled,
+        );
+    }
+    schedule();
+    Ok(())
+}
+
+fn worker() -> ! {
+    let queue = queue();
+    loop {
+        let work = unsafe {
+            let mut cs = CriticalSection::enter();
+            loop {
+                if let Some(work) = (*queue).pop() {
+                    break work;
+                }
+                block_current(cs, &mut (*queue).worker, NO_DEADLINE);
+                cs = CriticalSection::enter();
+            }
+        };
+        (work.f)(work.arg);
+    }
+}
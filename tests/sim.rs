@@ -0,0 +1,206 @@
+//! Threads run by the host simulator, see the sim module
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use cortexm_threads::sim::{self, Simulator, Stop};
+use cortexm_threads::{
+    create_periodic_thread, create_thread_closure, create_thread_closure_with_config,
+    create_thread_with_config, get_thread_id, set_deadline_miss_hook, set_tick_rate, sleep,
+    thread_info, ticks, uptime_ms, Barrier, CondVar, Mailbox, Mutex, RwLock, ThreadHandle,
+};
+
+#[test]
+fn sleeping_thread_wakes_on_time() {
+    static WAKES: AtomicU64 = AtomicU64::new(0);
+    fn blink() -> ! {
+        loop {
+            sleep(10);
+            WAKES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    let mut sim = Simulator::new();
+    create_thread_with_config(sim::stack(256), blink, 1, true).unwrap();
+    assert_eq!(sim.run_for(95), Stop::TimeUp);
+    assert_eq!(WAKES.load(Ordering::Relaxed), 9);
+    assert_eq!(ticks(), 95);
+}
+
+#[test]
+fn higher_priority_thread_runs_first() {
+    static ORDER: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+    let mut sim = Simulator::new();
+    create_thread_closure_with_config(sim::stack(256), || ORDER.lock().push(1), 1, true).unwrap();
+    create_thread_closure_with_config(sim::stack(256), || ORDER.lock().push(2), 2, true).unwrap();
+    assert_eq!(sim.run_for(10), Stop::Exited);
+    assert_eq!(*ORDER.lock(), [2, 1]);
+}
+
+#[test]
+fn test_posts_to_a_waiting_thread() {
+    static REQUESTS: Mailbox<u32> = Mailbox::new();
+    static SUM: AtomicU32 = AtomicU32::new(0);
+    let mut sim = Simulator::new();
+    let taker = || loop {
+        let value = REQUESTS.take();
+        SUM.fetch_add(value, Ordering::Relaxed);
+    };
+    create_thread_closure_with_config(sim::stack(256), taker, 0, true).unwrap();
+    assert_eq!(sim.run_for(10), Stop::Stalled);
+    // the thread takes each value before post returns
+    REQUESTS.post(3);
+    REQUESTS.post(4);
+    assert_eq!(SUM.load(Ordering::Relaxed), 7);
+}
+
+#[test]
+fn timeout_expires_in_simulated_time() {
+    static EMPTY: Mailbox<u32> = Mailbox::new();
+    static TIMED_OUT_AT: AtomicU64 = AtomicU64::new(0);
+    let mut sim = Simulator::new();
+    let taker = || {
+        assert!(EMPTY.take_timeout(1_000_000).is_err());
+        TIMED_OUT_AT.store(ticks(), Ordering::Relaxed);
+    };
+    create_thread_closure_with_config(sim::stack(256), taker, 0, true).unwrap();
+    assert_eq!(sim.run_for(2_000_000), Stop::Exited);
+    assert_eq!(TIMED_OUT_AT.load(Ordering::Relaxed), 1_000_000);
+}
+
+#[test]
+fn barrier_timeout_leaves_the_round() {
+    static PAIR: Barrier = Barrier::new(2);
+    static TIMEOUTS: AtomicU32 = AtomicU32::new(0);
+    let mut sim = Simulator::new();
+    let early = || {
+        if PAIR.wait_timeout(10).is_err() {
+            TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    let late = || {
+        sleep(20);
+        // alone in the round, the first thread having given up
+        if PAIR.wait_timeout(10).is_err() {
+            TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    create_thread_closure_with_config(sim::stack(256), early, 0, true).unwrap();
+    create_thread_closure_with_config(sim::stack(256), late, 0, true).unwrap();
+    assert_eq!(sim.run_for(100), Stop::Exited);
+    assert_eq!(TIMEOUTS.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn uptime_keeps_the_rate_of_past_ticks() {
+    let mut sim = Simulator::new();
+    create_thread_closure(sim::stack(256), || sleep(10_000)).unwrap();
+    assert_eq!(sim.run_for(1_000), Stop::TimeUp);
+    assert_eq!(uptime_ms(), 1_000);
+    // a tenth of the ticks, each 100 ms long
+    assert!(set_tick_rate(10));
+    assert_eq!(sim.run_for(20), Stop::TimeUp);
+    assert_eq!(uptime_ms(), 3_000);
+}
+
+#[test]
+fn unprivileged_threads_use_kernel_calls() {
+    static BUS: Mutex<u32> = Mutex::new(0);
+    static GOT_BUS_AT: AtomicU64 = AtomicU64::new(0);
+    let mut sim = Simulator::new();
+    // threads are unprivileged unless created otherwise
+    create_thread_closure(sim::stack(256), || {
+        let mut bus = BUS.lock();
+        sleep(20);
+        *bus += 1;
+    })
+    .unwrap();
+    create_thread_closure(sim::stack(256), || {
+        assert!(!thread_info(get_thread_id()).unwrap().privileged);
+        sleep(5);
+        assert!(BUS.lock_timeout(5).is_err());
+        assert_eq!(ticks(), 10);
+        *BUS.lock() += 1;
+        GOT_BUS_AT.store(ticks(), Ordering::Relaxed);
+    })
+    .unwrap();
+    assert_eq!(sim.run_for(100), Stop::Exited);
+    assert_eq!(GOT_BUS_AT.load(Ordering::Relaxed), 20);
+    assert_eq!(*BUS.lock(), 2);
+}
+
+#[test]
+fn unprivileged_threads_wait_on_condvar_rwlock_and_barrier() {
+    static READY: Mutex<bool> = Mutex::new(false);
+    static SET: CondVar = CondVar::new();
+    static TABLE: RwLock<u32> = RwLock::new(0);
+    static BOTH: Barrier = Barrier::new(2);
+    static SEEN: AtomicU32 = AtomicU32::new(0);
+    let mut sim = Simulator::new();
+    create_thread_closure(sim::stack(256), || {
+        let mut ready = READY.lock();
+        while !*ready {
+            ready = SET.wait(ready);
+        }
+        drop(ready);
+        SEEN.store(*TABLE.read(), Ordering::Relaxed);
+        BOTH.wait();
+    })
+    .unwrap();
+    create_thread_closure(sim::stack(256), || {
+        let mut table = TABLE.write();
+        sleep(5);
+        *table = 42;
+        *READY.lock() = true;
+        SET.notify_one();
+        // the reader holds off until the write lock is gone
+        sleep(5);
+        drop(table);
+        BOTH.wait();
+    })
+    .unwrap();
+    assert_eq!(sim.run_for(100), Stop::Exited);
+    assert_eq!(SEEN.load(Ordering::Relaxed), 42);
+}
+
+#[test]
+fn unprivileged_periodic_thread_counts_misses() {
+    static CALLS: AtomicU32 = AtomicU32::new(0);
+    static MISSES_REPORTED: AtomicU32 = AtomicU32::new(0);
+    fn missed(_thread: ThreadHandle) {
+        MISSES_REPORTED.fetch_add(1, Ordering::Relaxed);
+    }
+    let mut sim = Simulator::new();
+    set_deadline_miss_hook(missed);
+    let task = || {
+        // the first instance overruns its period of 10 ticks
+        if CALLS.fetch_add(1, Ordering::Relaxed) == 0 {
+            sleep(15);
+        }
+    };
+    let thread = create_periodic_thread(sim::stack(256), task, 10).unwrap();
+    assert_eq!(sim.run_for(55), Stop::TimeUp);
+    assert_eq!(thread_info(thread).unwrap().deadline_misses, 1);
+    assert_eq!(MISSES_REPORTED.load(Ordering::Relaxed), 1);
+    // released at 0 and 20, skipping 10, then at 30, 40 and 50
+    assert_eq!(CALLS.load(Ordering::Relaxed), 5);
+}
+
+#[test]
+#[should_panic(expected = "not available to unprivileged threads")]
+fn unprivileged_thread_cannot_use_a_mailbox() {
+    static READINGS: Mailbox<u32> = Mailbox::new();
+    let mut sim = Simulator::new();
+    create_thread_closure(sim::stack(256), || READINGS.post(7)).unwrap();
+    sim.run_for(10);
+}
+
+#[test]
+#[should_panic(expected = "sensor out of range")]
+fn panic_in_thread_fails_the_test() {
+    let mut sim = Simulator::new();
+    create_thread_closure(sim::stack(256), || {
+        sleep(5);
+        panic!("sensor out of range");
+    })
+    .unwrap();
+    sim.run_for(10);
+}
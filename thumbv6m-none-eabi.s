@@ -3,30 +3,110 @@
 
 .global __CORTEXM_THREADS_GLOBAL_PTR
 
+/* \r = &OS_PTR, the kernel state of this core, clobbers \t */
+.macro kernel_state r, t
+	ldr		\r,			=__CORTEXM_THREADS_GLOBAL_PTR /* \r = &&OS_PTR */
+.ifdef RP2040_AMP
+	ldr		\t,			=0xD0000000
+	ldr		\t,			[\t, 0x0] /* \t = SIO CPUID, 0 or 1 */
+	lsls	\t,			\t, #2
+	adds	\r,			\r, \t /* \r = &&OS_PTR of this core */
+.endif
+	ldr		\r,			[\r, 0x0]
+.endm
+
 .global __CORTEXM_THREADS_wfe
 .thumb_func
 __CORTEXM_THREADS_wfe:
 	wfe
 	bx		lr
 
-.global __CORTEXM_THREADS_cpsid
+.global __CORTEXM_THREADS_sev
 .thumb_func
-__CORTEXM_THREADS_cpsid:
+__CORTEXM_THREADS_sev:
+	sev
+	bx		lr
+
+.global __CORTEXM_THREADS_wfi
+.thumb_func
+__CORTEXM_THREADS_wfi:
+	wfi
+	bx		lr
+
+.global __CORTEXM_THREADS_primask_save
+.thumb_func
+__CORTEXM_THREADS_primask_save:
+	mrs		r0,			primask /* r0 = previous PRIMASK */
 	cpsid	i
 	bx		lr
 
-.global __CORTEXM_THREADS_cpsie
+.global __CORTEXM_THREADS_primask_restore
 .thumb_func
-__CORTEXM_THREADS_cpsie:
-	cpsie	i
+__CORTEXM_THREADS_primask_restore:
+	msr		primask,	r0 /* PRIMASK = r0 */
+	bx		lr
+
+.global __CORTEXM_THREADS_barrier
+.thumb_func
+__CORTEXM_THREADS_barrier:
+	dsb		/* complete outstanding writes, e.g. to the SCB or MPU */
+	isb		/* and make following instructions see their effect */
+	bx		lr
+
+.global __CORTEXM_THREADS_spin
+.thumb_func
+__CORTEXM_THREADS_spin:
+	subs	r0,			#1 /* r0 = iterations left, at least 1 on entry */
+	bne		__CORTEXM_THREADS_spin
+	bx		lr
+
+.global __CORTEXM_THREADS_start
+.thumb_func
+__CORTEXM_THREADS_start:
+	svc		0 /* SVCall switches to the first thread, this never returns */
+	b		__CORTEXM_THREADS_start
+
+.global __CORTEXM_THREADS_semihosting_exit
+.thumb_func
+__CORTEXM_THREADS_semihosting_exit:
+	mov		r1,			r0 /* r1 = exit reason */
+	movs	r0,			#0x18 /* SYS_EXIT */
+	bkpt	0xab
+	bx		lr
+
+.global __CORTEXM_THREADS_kernel_call
+.thumb_func
+__CORTEXM_THREADS_kernel_call:
+	svc		1 /* r0 = call, r1-r3 = arguments, SVCall leaves the result in r0 */
+	bx		lr
+
+.global __CORTEXM_THREADS_ipsr
+.thumb_func
+__CORTEXM_THREADS_ipsr:
+	mrs		r0,			ipsr /* r0 = number of the active exception, 0 in thread mode */
+	bx		lr
+
+.global __CORTEXM_THREADS_control
+.thumb_func
+__CORTEXM_THREADS_control:
+	mrs		r0,			control /* bit 0 set: thread mode is unprivileged */
+	bx		lr
+
+.global __CORTEXM_THREADS_drop_privileges
+.thumb_func
+__CORTEXM_THREADS_drop_privileges:
+	/* called with interrupts disabled, threads already run on psp */
+	cpsie	i /* unprivileged code cannot enable interrupts */
+	movs	r0,			#0x3
+	msr		control,	r0 /* nPRIV: thread mode is unprivileged, SPSEL: on psp */
+	isb
 	bx		lr
 
 .global PendSV
 .thumb_func
 PendSV:
 	cpsid	i
-	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR /* r1 = &&OS_PTR */
-	ldr		r1,			[r1, 0x0] /* r1 = &OS_PTR */
+	kernel_state	r1, r0
 	ldr		r1,			[r1, 0x0] /* r1 = OS_PTR.curr ( &current_thread ) */
 	cmp		r1,			0x0
 	beq		__CORTEXM_THREADS_PENDSV_RESTORE
@@ -42,12 +122,10 @@ PendSV:
 	subs 	r0,			#16
 	str		r0,			[r1, 0x0] /* current_thread.sp = sp */
 	__CORTEXM_THREADS_PENDSV_RESTORE:
-	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR	/* r1 = &&OS_PTR */
-	ldr		r1,			[r1, 0x0]	/* r1 = &OS_PTR */
+	kernel_state	r1, r0
 	ldr 	r2,			[r1, 0x4]	/* r2 = OS_PTR.next */
 	ldr		r3,			[r2, 0x0]	/* r3 = OS_PTR.next.sp */
-	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR	/* r1 = &&OS_PTR */
-	ldr		r1,			[r1, 0x0]	/* r1 = &OS_PTR */
+	kernel_state	r1, r0
 	ldr		r2,			[r1, 0x4]	/* r2 = &OS.next */
 	str		r2,			[r1, 0x0]	/* set OS.curr = os.next */
 	ldmia	r3!,		{r4-r7}
@@ -56,7 +134,44 @@ PendSV:
 	mov		r10,		r6
 	mov		r11,		r7
 	ldmia	r3!,		{r4-r7}
+	ldr		r0,			[r2, 0x4]	/* r0 = OS_PTR.next.privileged */
+	movs	r1,			#0x1
+	eors	r0,			r1
+	msr		control,	r0 /* nPRIV unless the thread is privileged */
+	isb
 	msr 	psp,		r3
 	ldr 	r0,			=0xFFFFFFFD
 	cpsie	i
 	bx 		r0
+
+.global SVCall
+.thumb_func
+SVCall:
+	movs	r1,			#0x4
+	mov		r0,			lr
+	tst		r0,			r1 /* EXC_RETURN bit 2 set: the caller's frame is on psp */
+	bne		__svc_psp
+	mrs		r0,			msp
+	b		__svc_frame
+	__svc_psp:
+	mrs		r0,			psp
+	__svc_frame:
+	ldr		r1,			[r0, #24] /* r1 = stacked pc, after the svc instruction */
+	subs	r1,			#2
+	ldrb	r1,			[r1, #0] /* r1 = svc number, low byte of the svc instruction */
+	cmp		r1,			#0x0
+	bne		__svc_kernel_call
+	kernel_state	r1, r2
+	ldr		r1,			[r1, 0x0] /* r1 = OS_PTR.curr, 0 until the first thread runs */
+	cmp		r1,			#0x0
+	beq		__svc_start
+	/* svc 0 from a thread: rejected as kernel call 0, which does not exist */
+	movs	r1,			#0x0
+	str		r1,			[r0, #0] /* caller's r0 = call number 0 */
+	b		__svc_kernel_call
+	__svc_start:
+	/* svc 0 from init: no thread to save, restore OS_PTR.next */
+	b		__CORTEXM_THREADS_PENDSV_RESTORE
+	__svc_kernel_call:
+	ldr		r1,			=__CORTEXM_THREADS_kernel_call_handler
+	bx		r1 /* r0 = caller's frame, returns to the caller */
@@ -0,0 +1,171 @@
+.thumb
+.syntax unified
+.fpu fpv4-sp-d16
+
+.global __CORTEXM_THREADS_GLOBAL_PTR
+
+.global __CORTEXM_THREADS_wfe
+.thumb_func
+__CORTEXM_THREADS_wfe:
+	wfe
+	bx		lr
+
+.global __CORTEXM_THREADS_wfi
+.thumb_func
+__CORTEXM_THREADS_wfi:
+	wfi
+	bx		lr
+
+.global __CORTEXM_THREADS_primask_save
+.thumb_func
+__CORTEXM_THREADS_primask_save:
+	mrs		r0,			primask /* r0 = previous PRIMASK */
+	cpsid	i
+	bx		lr
+
+.global __CORTEXM_THREADS_primask_restore
+.thumb_func
+__CORTEXM_THREADS_primask_restore:
+	msr		primask,	r0 /* PRIMASK = r0 */
+	bx		lr
+
+.global __CORTEXM_THREADS_barrier
+.thumb_func
+__CORTEXM_THREADS_barrier:
+	dsb		/* complete outstanding writes, e.g. to the SCB or MPU */
+	isb		/* and make following instructions see their effect */
+	bx		lr
+
+.global __CORTEXM_THREADS_spin
+.thumb_func
+__CORTEXM_THREADS_spin:
+	subs	r0,			#1 /* r0 = iterations left, at least 1 on entry */
+	bne		__CORTEXM_THREADS_spin
+	bx		lr
+
+.global __CORTEXM_THREADS_start
+.thumb_func
+__CORTEXM_THREADS_start:
+	/* drop the FPU context of init, or SVCall leaves a lazy save of it pending over msp */
+	mrs		r0,			control
+	bic		r0,			r0,			#0x4
+	msr		control,	r0 /* clear FPCA */
+	isb
+	svc		0 /* SVCall switches to the first thread, this never returns */
+	b		__CORTEXM_THREADS_start
+
+.global __CORTEXM_THREADS_semihosting_exit
+.thumb_func
+__CORTEXM_THREADS_semihosting_exit:
+	mov		r1,			r0 /* r1 = exit reason */
+	movs	r0,			#0x18 /* SYS_EXIT */
+	bkpt	0xab
+	bx		lr
+
+.global __CORTEXM_THREADS_kernel_call
+.thumb_func
+__CORTEXM_THREADS_kernel_call:
+	svc		1 /* r0 = call, r1-r3 = arguments, SVCall leaves the result in r0 */
+	bx		lr
+
+.global __CORTEXM_THREADS_ipsr
+.thumb_func
+__CORTEXM_THREADS_ipsr:
+	mrs		r0,			ipsr /* r0 = number of the active exception, 0 in thread mode */
+	bx		lr
+
+.global __CORTEXM_THREADS_control
+.thumb_func
+__CORTEXM_THREADS_control:
+	mrs		r0,			control /* bit 0 set: thread mode is unprivileged */
+	bx		lr
+
+.global __CORTEXM_THREADS_drop_privileges
+.thumb_func
+__CORTEXM_THREADS_drop_privileges:
+	/* called with interrupts disabled, r0 = new msp */
+	mrs		r3,			msp
+	msr		psp,		r3 /* the thread keeps its stack, now through psp */
+	mrs		r3,			control
+	orr		r3,			r3,			#0x2
+	msr		control,	r3 /* SPSEL: thread mode uses psp */
+	isb
+	msr		msp,		r0 /* exceptions are taken below the saved context of the idle thread */
+	cpsie	i /* unprivileged code cannot enable interrupts */
+	orr		r3,			r3,			#0x1
+	msr		control,	r3 /* nPRIV: thread mode is unprivileged */
+	isb
+	bx		lr
+
+.global PendSV
+.thumb_func
+PendSV:
+	cpsid	i
+	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR /* r1 = &&OS_PTR */
+	ldr		r1,			[r1, 0x0] /* r1 = &OS_PTR */
+	ldr		r1,			[r1, 0x0] /* r1 = OS_PTR.curr ( &current_thread ) */
+	cmp		r1,			0x0
+	beq		__CORTEXM_THREADS_PENDSV_RESTORE
+	tst		lr,			#0x4 /* EXC_RETURN bit 2 set: the thread ran on psp */
+	ite		eq
+	mrseq	r0,			msp
+	mrsne	r0,			psp
+	tst		lr,			#0x10 /* EXC_RETURN bit 4 clear: thread has FPU context */
+	it		eq
+	vstmdbeq	r0!,	{s16-s31} /* also completes a pending lazy save of s0-s15 */
+	stmdb	r0!,		{r4-r11, lr} /* lr = EXC_RETURN of current_thread */
+	str		r0,			[r1, 0x0] /* current_thread.sp = sp */
+	tst		lr,			#0x4
+	it		eq
+	msreq	msp,		r0 /* exceptions are taken below the saved context, not over it */
+	__CORTEXM_THREADS_PENDSV_RESTORE:
+	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR	/* r1 = &&OS_PTR */
+	ldr		r1,			[r1, 0x0]	/* r1 = &OS_PTR */
+	ldr 	r2,			[r1, 0x4]	/* r2 = OS_PTR.next */
+	ldr		r3,			[r2, 0x0]	/* r3 = OS_PTR.next.sp */
+	ldr		r0,			[r2, 0x4]	/* r0 = OS_PTR.next.privileged */
+	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR	/* r1 = &&OS_PTR */
+	ldr		r1,			[r1, 0x0]	/* r1 = &OS_PTR */
+	ldr		r2,			[r1, 0x4]	/* r2 = &OS.next */
+	str		r2,			[r1, 0x0]	/* set OS.curr = os.next */
+	ldmia	r3!,		{r4-r11, lr} /* lr = EXC_RETURN of next_thread */
+	tst		lr,			#0x10
+	it		eq
+	vldmiaeq	r3!,	{s16-s31}
+	cmp		r0, 		0x0
+	beq		__load_unpriv
+	movs	r0,			#0x0
+	msr		control,	r0
+	isb
+	msr 	msp,		r3
+	b		__load_end
+	__load_unpriv:
+	movs	r0,			#0x1
+	msr		control,	r0
+	isb
+	msr 	psp,		r3
+	__load_end:
+	cpsie	i
+	bx 		lr
+
+.global SVCall
+.thumb_func
+SVCall:
+	tst		lr,			#0x4 /* EXC_RETURN bit 2 set: the caller's frame is on psp */
+	ite		eq
+	mrseq	r0,			msp
+	mrsne	r0,			psp
+	ldr		r1,			[r0, #24] /* r1 = stacked pc, after the svc instruction */
+	ldrb	r1,			[r1, #-2] /* r1 = svc number, low byte of the svc instruction */
+	cmp		r1,			#0x0
+	bne		__svc_kernel_call
+	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR /* r1 = &&OS_PTR */
+	ldr		r1,			[r1, 0x0] /* r1 = &OS_PTR */
+	ldr		r1,			[r1, 0x0] /* r1 = OS_PTR.curr, 0 until the first thread runs */
+	cmp		r1,			#0x0
+	beq		__CORTEXM_THREADS_PENDSV_RESTORE /* svc 0 from init: no thread to save, restore OS_PTR.next */
+	/* svc 0 from a thread: rejected as kernel call 0, which does not exist */
+	movs	r1,			#0x0
+	str		r1,			[r0, #0] /* caller's r0 = call number 0 */
+	__svc_kernel_call:
+	b		__CORTEXM_THREADS_kernel_call_handler /* r0 = caller's frame, returns to the caller */
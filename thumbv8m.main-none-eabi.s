@@ -0,0 +1,171 @@
+.thumb
+.syntax unified
+.arch armv8-m.main
+
+.global __CORTEXM_THREADS_GLOBAL_PTR
+
+.global __CORTEXM_THREADS_wfe
+.thumb_func
+__CORTEXM_THREADS_wfe:
+	wfe
+	bx		lr
+
+.global __CORTEXM_THREADS_wfi
+.thumb_func
+__CORTEXM_THREADS_wfi:
+	wfi
+	bx		lr
+
+.global __CORTEXM_THREADS_primask_save
+.thumb_func
+__CORTEXM_THREADS_primask_save:
+	mrs		r0,			primask /* r0 = previous PRIMASK */
+	cpsid	i
+	bx		lr
+
+.global __CORTEXM_THREADS_primask_restore
+.thumb_func
+__CORTEXM_THREADS_primask_restore:
+	msr		primask,	r0 /* PRIMASK = r0 */
+	bx		lr
+
+.global __CORTEXM_THREADS_barrier
+.thumb_func
+__CORTEXM_THREADS_barrier:
+	dsb		/* complete outstanding writes, e.g. to the SCB or MPU */
+	isb		/* and make following instructions see their effect */
+	bx		lr
+
+.global __CORTEXM_THREADS_spin
+.thumb_func
+__CORTEXM_THREADS_spin:
+	subs	r0,			#1 /* r0 = iterations left, at least 1 on entry */
+	bne		__CORTEXM_THREADS_spin
+	bx		lr
+
+.global __CORTEXM_THREADS_start
+.thumb_func
+__CORTEXM_THREADS_start:
+	svc		0 /* SVCall switches to the first thread, this never returns */
+	b		__CORTEXM_THREADS_start
+
+.global __CORTEXM_THREADS_semihosting_exit
+.thumb_func
+__CORTEXM_THREADS_semihosting_exit:
+	mov		r1,			r0 /* r1 = exit reason */
+	movs	r0,			#0x18 /* SYS_EXIT */
+	bkpt	0xab
+	bx		lr
+
+.global __CORTEXM_THREADS_kernel_call
+.thumb_func
+__CORTEXM_THREADS_kernel_call:
+	svc		1 /* r0 = call, r1-r3 = arguments, SVCall leaves the result in r0 */
+	bx		lr
+
+.global __CORTEXM_THREADS_ipsr
+.thumb_func
+__CORTEXM_THREADS_ipsr:
+	mrs		r0,			ipsr /* r0 = number of the active exception, 0 in thread mode */
+	bx		lr
+
+.global __CORTEXM_THREADS_control
+.thumb_func
+__CORTEXM_THREADS_control:
+	mrs		r0,			control /* bit 0 set: thread mode is unprivileged */
+	bx		lr
+
+.global __CORTEXM_THREADS_drop_privileges
+.thumb_func
+__CORTEXM_THREADS_drop_privileges:
+	/* called with interrupts disabled, r0 = new msp, r1 = its limit, r2 = limit of psp */
+	mrs		r3,			msp
+	msr		psplim,		r2
+	msr		psp,		r3 /* the thread keeps its stack, now through psp */
+	mrs		r3,			control
+	orr		r3,			r3,			#0x2
+	msr		control,	r3 /* SPSEL: thread mode uses psp */
+	isb
+	movs	r2,			#0x0
+	msr		msplim,		r2 /* no limit while msp moves to the idle thread's stack */
+	msr		msp,		r0 /* exceptions are taken below the saved context of the idle thread */
+	msr		msplim,		r1
+	cpsie	i /* unprivileged code cannot enable interrupts */
+	orr		r3,			r3,			#0x1
+	msr		control,	r3 /* nPRIV: thread mode is unprivileged */
+	isb
+	bx		lr
+
+.global PendSV
+.thumb_func
+PendSV:
+	cpsid	i
+	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR /* r1 = &&OS_PTR */
+	ldr		r1,			[r1, 0x0] /* r1 = &OS_PTR */
+	ldr		r1,			[r1, 0x0] /* r1 = OS_PTR.curr ( &current_thread ) */
+	cmp		r1,			0x0
+	beq		__CORTEXM_THREADS_PENDSV_RESTORE
+	tst		lr,			#0x4 /* EXC_RETURN bit 2 set: the thread ran on psp */
+	ite		eq
+	mrseq	r0,			msp
+	mrsne	r0,			psp
+	stmdb	r0!,		{r4-r11}
+	str		r0,			[r1, 0x0] /* current_thread.sp = sp */
+	tst		lr,			#0x4
+	it		eq
+	msreq	msp,		r0 /* exceptions are taken below the saved context, not over it */
+	__CORTEXM_THREADS_PENDSV_RESTORE:
+	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR	/* r1 = &&OS_PTR */
+	ldr		r1,			[r1, 0x0]	/* r1 = &OS_PTR */
+	ldr 	r2,			[r1, 0x4]	/* r2 = OS_PTR.next */
+	ldr		r3,			[r2, 0x0]	/* r3 = OS_PTR.next.sp */
+	ldr		r0,			[r2, 0x4]	/* r0 = OS_PTR.next.privileged */
+	ldr		r12,		[r2, 0x8]	/* r12 = OS_PTR.next.stack_limit */
+	str		r2,			[r1, 0x0]	/* set OS.curr = os.next */
+	ldmia	r3!,		{r4-r11}
+	/* return to thread mode with a basic frame, keeping the security state bits of lr */
+	orr		lr,			lr,			#0x18
+	movs	r2,			#0x0
+	cmp		r0, 		0x0
+	beq		__load_unpriv
+	movs	r0,			#0x0
+	msr		control,	r0
+	isb
+	msr		msplim,		r2 /* no limit while msp moves to the next stack */
+	msr 	msp,		r3
+	msr		msplim,		r12
+	bic		lr,			lr,			#0x4 /* return to msp */
+	b		__load_end
+	__load_unpriv:
+	movs	r0,			#0x1
+	msr		control,	r0
+	isb
+	msr		psplim,		r2 /* no limit while psp moves to the next stack */
+	msr 	psp,		r3
+	msr		psplim,		r12
+	orr		lr,			lr,			#0x4 /* return to psp */
+	__load_end:
+	cpsie	i
+	bx 		lr
+
+.global SVCall
+.thumb_func
+SVCall:
+	tst		lr,			#0x4 /* EXC_RETURN bit 2 set: the caller's frame is on psp */
+	ite		eq
+	mrseq	r0,			msp
+	mrsne	r0,			psp
+	ldr		r1,			[r0, #24] /* r1 = stacked pc, after the svc instruction */
+	ldrb	r1,			[r1, #-2] /* r1 = svc number, low byte of the svc instruction */
+	cmp		r1,			#0x0
+	bne		__svc_kernel_call
+	ldr		r1,			=__CORTEXM_THREADS_GLOBAL_PTR /* r1 = &&OS_PTR */
+	ldr		r1,			[r1, 0x0] /* r1 = &OS_PTR */
+	ldr		r1,			[r1, 0x0] /* r1 = OS_PTR.curr, 0 until the first thread runs */
+	cmp		r1,			#0x0
+	beq		__CORTEXM_THREADS_PENDSV_RESTORE /* svc 0 from init: no thread to save, restore OS_PTR.next */
+	/* svc 0 from a thread: rejected as kernel call 0, which does not exist */
+	movs	r1,			#0x0
+	str		r1,			[r0, #0] /* caller's r0 = call number 0 */
+	__svc_kernel_call:
+	b		__CORTEXM_THREADS_kernel_call_handler /* r0 = caller's frame, returns to the caller */